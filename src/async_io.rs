@@ -0,0 +1,416 @@
+//! Async variants of the readers and the decrypter, enabled with the `tokio` feature
+
+use crate::cipher::BlockCipher;
+use crate::decrypter::{self, CellKey, DecryptReader, DecryptionInfo, S63Decrypter};
+use crate::errors::E;
+use crate::permit::{self, GetPermit, MetaData, PermitRecord, RawPermitRecord, Section};
+use crate::secret::Zeroizing;
+use crc::{crc32, Hasher32};
+use flate2::{Decompress, FlushDecompress, Status};
+use futures::future::poll_fn;
+use futures::stream::{self, Stream, StreamExt};
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::task::Poll;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use zip::result::ZipError;
+use zip::CompressionMethod;
+
+// the amount of data decrypted or unzipped before the task yields to the executor
+const STEP: usize = 64 * 1024;
+
+/// async counterpart of `permit::PermitFile`
+pub struct AsyncPermitFile<R> {
+    rdr: R,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncPermitFile<R> {
+    pub async fn new(mut rdr: R) -> Result<(MetaData, AsyncPermitFile<R>), E> {
+        let (mut date_str, mut version_str) = (String::new(), String::new());
+        rdr.read_line(&mut date_str).await?;
+        let date = permit::get_date(&date_str)?;
+        rdr.read_line(&mut version_str).await?;
+        let version = permit::check_version(permit::get_version(&version_str)?)?;
+
+        Ok((MetaData { date, version }, AsyncPermitFile { rdr }))
+    }
+
+    /// the permits of the file, decrypting the cell keys with `key`
+    pub fn permits(self, key: &str) -> impl Stream<Item = Result<PermitRecord, E>> {
+        let key = Zeroizing::new(key.to_owned());
+        self.raw_permits()
+            .map(move |p| p.and_then(|p| p.decrypt_keys(&key)))
+    }
+
+    /// the permits of the file without decrypting the cell keys
+    pub fn raw_permits(self) -> impl Stream<Item = Result<RawPermitRecord, E>> {
+        stream::unfold(
+            (self.rdr, Section::Enc, 2),
+            |(mut rdr, mut section, mut line)| async move {
+                loop {
+                    let mut buf = Vec::new();
+                    line += 1;
+                    let res = rdr.read_until(b'\n', &mut buf).await;
+                    let s = permit::decode_line(buf);
+                    match res {
+                        Ok(0) => return None,
+                        Ok(_) if s.trim().is_empty() => (),
+                        Ok(_) => match Section::from_marker(&s) {
+                            Some(sec) => section = sec,
+                            None => {
+                                let p = permit::parse_raw_permit(&s, section)
+                                    .map_err(|e| e.at_line(line, &s));
+                                return Some((p, (rdr, section, line)));
+                            }
+                        },
+                        Err(e) => return Some((Err(e.into()), (rdr, section, line))),
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// async variants of the decrypter. The encrypted cell and the decrypted archive are held
+/// in memory, the reader needn't be seekable to retry with key 2. The cell is decrypted and
+/// unzipped in steps on the calling task, which yields to the executor between them, and
+/// the unzipped file is written while it is unzipped. Set
+/// `S63DecrypterBuilder::max_output_size` to bound the memory used
+impl<P: GetPermit, C: BlockCipher> S63Decrypter<P, C> {
+    /// async counterpart of `with_cell`
+    pub async fn with_cell_async<R, W>(
+        &self,
+        cell: &str,
+        mut rdr: R,
+        mut wtr: W,
+    ) -> Result<DecryptionInfo, decrypter::E>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut enc = Vec::new();
+        rdr.read_to_end(&mut enc)
+            .await
+            .map_err(decrypter::E::Read)?;
+        let res = self.try_keys_async(cell, &enc, &mut wtr).await;
+        self.audit_decryption(cell, &res);
+        let ((decrypted_len, extracted_name), key_used, attempts) = res?;
+        Ok(DecryptionInfo {
+            key_used,
+            attempts,
+            decrypted_len,
+            extracted_name,
+        })
+    }
+
+    /// async counterpart of `with_key`
+    pub async fn with_key_async<R, W>(
+        &self,
+        key: &[u8],
+        mut rdr: R,
+        mut wtr: W,
+    ) -> Result<(), decrypter::E>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut enc = Vec::new();
+        rdr.read_to_end(&mut enc)
+            .await
+            .map_err(decrypter::E::Read)?;
+        self.extract_first_async(key, &enc, &mut wtr, None)
+            .await
+            .map(|_| ())
+    }
+
+    // like `try_keys` of the decrypter, nothing is written before the key is found
+    async fn try_keys_async<W: AsyncWrite + Unpin>(
+        &self,
+        cell: &str,
+        enc: &[u8],
+        wtr: &mut W,
+    ) -> Result<((u64, Option<String>), CellKey, usize), decrypter::E> {
+        let mut err = decrypter::E::WrongKey;
+        for (i, (which, key)) in self.cell_keys(cell)?.into_iter().enumerate() {
+            match self.extract_first_async(key, enc, wtr, Some(cell)).await {
+                Ok(res) => return Ok((res, which, i + 1)),
+                Err(e @ decrypter::E::WrongKey) => err = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(decrypter::E::DecryptionFailed(Box::new(err)))
+    }
+
+    // writes the first file of the archive, returns the archive size and the file name.
+    // The edition of `cell` is checked on the whole output before it is written, if enabled
+    async fn extract_first_async<W: AsyncWrite + Unpin>(
+        &self,
+        key: &[u8],
+        enc: &[u8],
+        wtr: &mut W,
+        cell: Option<&str>,
+    ) -> Result<(u64, Option<String>), decrypter::E> {
+        let zipfile = self.decrypt_async(key, enc).await?;
+        let len = zipfile.len() as u64;
+        if let Some(cell) = cell.filter(|_| self.options.check_edition) {
+            let mut res = Vec::new();
+            let name = self.unzip_first_async(zipfile, &mut res).await?;
+            self.check_permit_edition(cell, &res)?;
+            write_all(wtr, &res).await?;
+            return Ok((len, name));
+        }
+        let name = self.unzip_first_async(zipfile, wtr).await?;
+        Ok((len, name))
+    }
+
+    // decrypts and depads `enc` a step at a time
+    async fn decrypt_async(&self, key: &[u8], enc: &[u8]) -> Result<Vec<u8>, decrypter::E> {
+        let mut dec = DecryptReader::with_cipher(C::new(key), enc)
+            .strict_padding(self.options.strict_padding);
+        let mut res = Vec::new();
+        loop {
+            let n = io::copy(&mut (&mut dec).take(STEP as u64), &mut res)
+                .map_err(decrypter::decrypt_err)?;
+            decrypter::check_size(res.len() as u64, self.options.max_output)?;
+            if n == 0 {
+                return Ok(res);
+            }
+            yield_now().await;
+        }
+    }
+
+    // writes the first file of the archive a step at a time, returns its name. Data
+    // without a ZIP header is written as is if the options allow unzipped data
+    async fn unzip_first_async<W: AsyncWrite + Unpin>(
+        &self,
+        zipfile: Vec<u8>,
+        wtr: &mut W,
+    ) -> Result<Option<String>, decrypter::E> {
+        let opts = &self.options;
+        if opts.unzipped && !zipfile.starts_with(decrypter::LOCAL_FILE_HEADER) {
+            write_all(wtr, &zipfile).await?;
+            return Ok(None);
+        }
+        let mut archive = decrypter::open_archive(zipfile)?;
+        // the file is unzipped here rather than read from the archive, which can't be held
+        // while the task yields
+        let (name, method, start, size, expected) = {
+            let zf = archive.by_index_raw(0).map_err(decrypter::E::Extract)?;
+            let start = usize::try_from(zf.data_start()).ok();
+            let size = usize::try_from(zf.compressed_size()).ok();
+            (
+                zf.name().to_owned(),
+                zf.compression(),
+                start,
+                size,
+                zf.crc32(),
+            )
+        };
+        let zipfile = archive.into_inner().into_inner();
+        let data = start
+            .zip(size)
+            .and_then(|(start, size)| zipfile.get(start..start.checked_add(size)?))
+            .ok_or(decrypter::E::CorruptArchive(ZipError::InvalidArchive(
+                "file data beyond the end of the archive",
+            )))?;
+        let mut crc = crc32::Digest::new(crc32::IEEE);
+        let mut n = 0;
+        let mut emit = |out: &[u8]| -> Result<(), decrypter::E> {
+            n += out.len() as u64;
+            decrypter::check_size(n, opts.max_output)?;
+            crc.write(out);
+            Ok(())
+        };
+        match method {
+            CompressionMethod::Stored => {
+                for out in data.chunks(STEP) {
+                    emit(out)?;
+                    wtr.write_all(out).await.map_err(decrypter::E::Write)?;
+                    yield_now().await;
+                }
+            }
+            CompressionMethod::Deflated => {
+                let mut inflate = Decompress::new(false);
+                let mut out = vec![0u8; STEP];
+                loop {
+                    let read = inflate.total_in() as usize;
+                    let before = inflate.total_out();
+                    let status = inflate
+                        .decompress(&data[read..], &mut out, FlushDecompress::None)
+                        .map_err(|e| {
+                            let e = io::Error::new(io::ErrorKind::InvalidData, e);
+                            decrypter::E::Extract(ZipError::Io(e))
+                        })?;
+                    let written = (inflate.total_out() - before) as usize;
+                    emit(&out[..written])?;
+                    wtr.write_all(&out[..written])
+                        .await
+                        .map_err(decrypter::E::Write)?;
+                    match status {
+                        Status::StreamEnd => break,
+                        Status::BufError if written == 0 => {
+                            return Err(decrypter::E::Extract(ZipError::Io(
+                                io::ErrorKind::UnexpectedEof.into(),
+                            )))
+                        }
+                        _ => yield_now().await,
+                    }
+                }
+            }
+            _ => {
+                return Err(decrypter::E::Extract(ZipError::UnsupportedArchive(
+                    "compression method not supported",
+                )))
+            }
+        }
+        let actual = crc.sum32();
+        if opts.validate_crc && actual != expected {
+            return Err(decrypter::E::CrcMismatch { expected, actual });
+        }
+        wtr.flush().await.map_err(decrypter::E::Write)?;
+        Ok(Some(name))
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(wtr: &mut W, data: &[u8]) -> Result<(), decrypter::E> {
+    wtr.write_all(data).await.map_err(decrypter::E::Write)?;
+    wtr.flush().await.map_err(decrypter::E::Write)
+}
+
+// lets the executor run other tasks before the current one continues
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypter::CellKey;
+    use crate::encrypter::S63Encrypter;
+    use futures::executor::block_on;
+
+    #[test]
+    fn decrypt() -> Result<(), decrypter::E> {
+        let p = crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+        let enc = S63Encrypter::new()
+            .with_key_bytes(&[6, 7, 8, 9, 10], "GB100001.000", b"cell")
+            .unwrap();
+        let d = S63Decrypter::new_with_permit(vec![p]);
+        block_on(async {
+            let mut out = Vec::new();
+            let info = d
+                .with_cell_async("GB100001", enc.as_slice(), &mut out)
+                .await?;
+            assert_eq!(out, b"cell");
+            assert_eq!(info.key_used, CellKey::Key2);
+
+            let mut out = Vec::new();
+            d.with_key_async(&[6, 7, 8, 9, 10], enc.as_slice(), &mut out)
+                .await?;
+            assert_eq!(out, b"cell");
+            assert!(matches!(
+                d.with_key_async(&[1, 2, 3, 4, 5], enc.as_slice(), Vec::new())
+                    .await,
+                Err(decrypter::E::WrongKey)
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn decrypt_in_steps() -> Result<(), decrypter::E> {
+        use std::future::Future;
+        use std::task::Context;
+        let key = [6, 7, 8, 9, 10];
+        let data: Vec<u8> = (0..500_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let enc = S63Encrypter::new()
+            .with_key_bytes(&key, "GB100001.000", &data)
+            .unwrap();
+        let d = S63Decrypter::new();
+        let mut out = Vec::new();
+        let mut fut = Box::pin(d.with_key_async(&key, enc.as_slice(), &mut out));
+        fn is_send<T: Send>(_: &T) {}
+        is_send(&fut);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut yields = 0;
+        let res = loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(res) => break res,
+                Poll::Pending => yields += 1,
+            }
+        };
+        res?;
+        drop(fut);
+        assert_eq!(out, data);
+        assert!(yields > 2);
+        Ok(())
+    }
+
+    #[test]
+    fn stored_crc() -> Result<(), decrypter::E> {
+        use std::io::Write;
+        let key = [1, 2, 3, 4, 5];
+        let stored =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("GB100001.000", stored).unwrap();
+        zip.write_all(b"cell").unwrap();
+        let mut plain = zip.finish().unwrap().into_inner();
+        let enc = crate::decrypter::tests::encrypt_plain(&key, plain.clone());
+        let d = S63Decrypter::new();
+        let mut out = Vec::new();
+        block_on(d.with_key_async(&key, enc.as_slice(), &mut out))?;
+        assert_eq!(out, b"cell");
+
+        // the CRC is in the local header and in the central directory
+        let crc = crc32::checksum_ieee(b"cell").to_le_bytes();
+        for i in 0..plain.len() - 3 {
+            if plain[i..i + 4] == crc {
+                plain[i] ^= 1;
+            }
+        }
+        let enc = crate::decrypter::tests::encrypt_plain(&key, plain);
+        assert!(matches!(
+            block_on(d.with_key_async(&key, enc.as_slice(), Vec::new())),
+            Err(decrypter::E::CrcMismatch { .. })
+        ));
+        let d = S63Decrypter::builder().validate_zip_crc(false).build();
+        let mut out = Vec::new();
+        block_on(d.with_key_async(&key, enc.as_slice(), &mut out))?;
+        assert_eq!(out, b"cell");
+        Ok(())
+    }
+
+    #[test]
+    fn read_permits() -> Result<(), E> {
+        let s = ":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+:ECS
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+";
+        block_on(async {
+            let (md, pf) = AsyncPermitFile::new(s.as_bytes()).await?;
+            assert_eq!(md.version, 2);
+            let permits: Vec<_> = pf.permits("12345").collect().await;
+            let permits = permits.into_iter().collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(permits.len(), 2);
+            assert_eq!(permits[0].cell_permit.key1, [54, 62, 171, 50, 198]);
+            assert_eq!(permits[1].section, Section::Ecs);
+            Ok(())
+        })
+    }
+}
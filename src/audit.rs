@@ -0,0 +1,195 @@
+//! Hooks to log the security relevant events of the crate: permits installed, cells
+//! decrypted and signatures checked
+//!
+//! An `AuditSink` is given to the components that produce the events, e.g.
+//! `S63DecrypterBuilder::audit` and `DataClient::audit`. `AuditLog` writes the events as
+//! lines of text, a sink of its own can e.g. sign or hash chain them.
+
+use crate::clock::{Clock, SystemClock};
+use crate::decrypter::{self, CellKey};
+use crate::permit::PermitRecord;
+#[cfg(feature = "signature")]
+use crate::signature;
+use chrono::NaiveDate;
+use std::fmt;
+use std::io::prelude::*;
+use std::sync::Mutex;
+
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditEvent<'a> {
+    /// a permit was installed from the permit file `source`
+    PermitInstalled {
+        cell: &'a str,
+        expiry: NaiveDate,
+        edition: Option<u8>,
+        source: &'a str,
+    },
+    /// an attempt to decrypt a cell, `key` is the key that decrypted it
+    Decryption {
+        cell: &'a str,
+        key: Option<CellKey>,
+        error: Option<&'a decrypter::E>,
+    },
+    /// the signature of an encrypted cell was checked
+    #[cfg(feature = "signature")]
+    Signature {
+        cell: &'a str,
+        error: Option<&'a signature::E>,
+    },
+}
+
+impl<'a> AuditEvent<'a> {
+    pub fn permit_installed(permit: &'a PermitRecord, source: &'a str) -> AuditEvent<'a> {
+        AuditEvent::PermitInstalled {
+            cell: &permit.cell_permit.cell,
+            expiry: permit.cell_permit.date,
+            edition: permit.edition,
+            source,
+        }
+    }
+
+    /// whether the event is a failure
+    pub fn is_failure(&self) -> bool {
+        match self {
+            AuditEvent::PermitInstalled { .. } => false,
+            AuditEvent::Decryption { error, .. } => error.is_some(),
+            #[cfg(feature = "signature")]
+            AuditEvent::Signature { error, .. } => error.is_some(),
+        }
+    }
+}
+
+/// e.g. "decryption GB100001 ok key 2" or "decryption GB100001 failed: no permit for GB100001"
+impl fmt::Display for AuditEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditEvent::PermitInstalled {
+                cell,
+                expiry,
+                edition,
+                source,
+            } => {
+                write!(f, "permit {} expires {}", cell, expiry)?;
+                if let Some(edition) = edition {
+                    write!(f, " edition {}", edition)?;
+                }
+                write!(f, " from {}", source)
+            }
+            AuditEvent::Decryption { cell, key, error } => match (error, key) {
+                (Some(e), _) => write!(f, "decryption {} failed: {}", cell, e),
+                (None, Some(CellKey::Key1)) => write!(f, "decryption {} ok key 1", cell),
+                (None, Some(CellKey::Key2)) => write!(f, "decryption {} ok key 2", cell),
+                (None, None) => write!(f, "decryption {} ok", cell),
+            },
+            #[cfg(feature = "signature")]
+            AuditEvent::Signature { cell, error } => match error {
+                Some(e) => write!(f, "signature {} invalid: {}", cell, e),
+                None => write!(f, "signature {} ok", cell),
+            },
+        }
+    }
+}
+
+/// receives the events as they happen. It is called from the thread of the event, e.g. a
+/// worker of `DataClient::import_with`
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// writes a line per event with the time of `clock` in UTC, e.g.
+/// "2020-06-01T12:00:00 permit GB100001 expires 2020-12-31 from PERMIT.TXT". Write errors
+/// are ignored as they can't fail the operation being logged
+pub struct AuditLog<W, C = SystemClock> {
+    wtr: Mutex<W>,
+    clock: C,
+}
+
+impl<W: Write + Send> AuditLog<W> {
+    pub fn new(wtr: W) -> AuditLog<W> {
+        AuditLog::with_clock(wtr, SystemClock)
+    }
+}
+
+impl<W: Write + Send, C: Clock + Send + Sync> AuditLog<W, C> {
+    pub fn with_clock(wtr: W, clock: C) -> AuditLog<W, C> {
+        AuditLog {
+            wtr: Mutex::new(wtr),
+            clock,
+        }
+    }
+
+    /// the writer, once the log is done
+    pub fn into_inner(self) -> W {
+        self.wtr.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send, C: Clock + Send + Sync> AuditSink for AuditLog<W, C> {
+    fn record(&self, event: &AuditEvent) {
+        let mut wtr = self.wtr.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(wtr, "{} {}", self.clock.now().format(DATE_FORMAT), event);
+        let _ = wtr.flush();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+
+    /// the events recorded, as text
+    #[derive(Default)]
+    pub(crate) struct Events(pub Mutex<Vec<String>>);
+
+    impl Events {
+        pub(crate) fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl AuditSink for Events {
+        fn record(&self, event: &AuditEvent) {
+            self.0.lock().unwrap().push(event.to_string());
+        }
+    }
+
+    #[test]
+    fn log() {
+        let clock = FixedClock(
+            NaiveDate::from_ymd_opt(2020, 6, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+        );
+        let log = AuditLog::with_clock(Vec::new(), clock);
+        log.record(&AuditEvent::PermitInstalled {
+            cell: "GB100001",
+            expiry: NaiveDate::from_ymd_opt(2020, 12, 31).unwrap(),
+            edition: Some(4),
+            source: "PERMIT.TXT",
+        });
+        log.record(&AuditEvent::Decryption {
+            cell: "GB100001",
+            key: Some(CellKey::Key2),
+            error: None,
+        });
+        let e = decrypter::E::NoPermit("GB100002".into());
+        let failure = AuditEvent::Decryption {
+            cell: "GB100002",
+            key: None,
+            error: Some(&e),
+        };
+        assert!(failure.is_failure());
+        log.record(&failure);
+        let lines = String::from_utf8(log.into_inner()).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(
+            lines[0],
+            "2020-06-01T12:00:00 permit GB100001 expires 2020-12-31 edition 4 from PERMIT.TXT"
+        );
+        assert_eq!(lines[1], "2020-06-01T12:00:00 decryption GB100001 ok key 2");
+        assert!(lines[2].starts_with("2020-06-01T12:00:00 decryption GB100002 failed: "));
+    }
+}
@@ -0,0 +1,272 @@
+//! Caches of decrypted cells, so cells that are opened again aren't decrypted again
+
+use crate::cell::CellName;
+use crate::cipher::BlockCipher;
+use crate::decrypter::{DecryptionInfo, S63Decrypter, E};
+use crate::permit::GetPermit;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+/// identifies a decrypted cell file, the base cell is update 0
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub cell: String,
+    pub edition: u32,
+    pub update: u32,
+}
+
+impl CacheKey {
+    pub fn new(cell: &str, edition: u32, update: u32) -> CacheKey {
+        CacheKey {
+            cell: cell.to_owned(),
+            edition,
+            update,
+        }
+    }
+
+    // e.g. GB100001_3_001, None if the cell name isn't valid and could be a path
+    fn file_name(&self) -> Option<String> {
+        CellName::new(&self.cell).ok()?;
+        Some(format!("{}_{}_{:03}", self.cell, self.edition, self.update))
+    }
+}
+
+/// a store of decrypted cells that drops the least recently used ones
+pub trait CellCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>>;
+    fn put(&mut self, key: CacheKey, data: Vec<u8>);
+}
+
+/// keeps decrypted cells in memory, up to `capacity` bytes in total. Cells larger than
+/// that are not kept
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    size: usize,
+    cells: HashMap<CacheKey, Vec<u8>>,
+    // least recently used first
+    order: VecDeque<CacheKey>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> MemoryCache {
+        MemoryCache {
+            capacity,
+            size: 0,
+            cells: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(i) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(i).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
+
+impl CellCache for MemoryCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        let data = self.cells.get(key)?.clone();
+        self.touch(key);
+        Some(data)
+    }
+
+    fn put(&mut self, key: CacheKey, data: Vec<u8>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        if let Some(old) = self.cells.remove(&key) {
+            self.size -= old.len();
+            self.order.retain(|k| k != &key);
+        }
+        while self.size + data.len() > self.capacity {
+            let lru = match self.order.pop_front() {
+                Some(k) => k,
+                None => break,
+            };
+            if let Some(old) = self.cells.remove(&lru) {
+                self.size -= old.len();
+            }
+        }
+        self.size += data.len();
+        self.order.push_back(key.clone());
+        self.cells.insert(key, data);
+    }
+}
+
+/// keeps up to `capacity` decrypted cells as files in a directory. The cache is best
+/// effort, a file that can't be read or written is a miss
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    capacity: usize,
+    // file names, least recently used first
+    order: VecDeque<String>,
+}
+
+impl DiskCache {
+    /// uses `dir` for the cache, creating it if needed. Cells already in `dir` are kept,
+    /// oldest first
+    pub fn new<D: Into<PathBuf>>(dir: D, capacity: usize) -> std::io::Result<DiskCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            if let Ok(name) = entry.file_name().into_string() {
+                files.push((modified, name));
+            }
+        }
+        files.sort();
+        let mut cache = DiskCache {
+            dir,
+            capacity,
+            order: files.into_iter().map(|(_, name)| name).collect(),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(name) = self.order.pop_front() {
+                let _ = fs::remove_file(self.dir.join(name));
+            }
+        }
+    }
+}
+
+impl CellCache for DiskCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        let name = key.file_name()?;
+        let i = self.order.iter().position(|n| n == &name)?;
+        let data = fs::read(self.dir.join(&name)).ok()?;
+        let name = self.order.remove(i).unwrap();
+        self.order.push_back(name);
+        Some(data)
+    }
+
+    fn put(&mut self, key: CacheKey, data: Vec<u8>) {
+        let name = match key.file_name() {
+            Some(name) => name,
+            None => return,
+        };
+        self.order.retain(|n| n != &name);
+        if fs::write(self.dir.join(&name), data).is_ok() {
+            self.order.push_back(name);
+            self.evict();
+        }
+    }
+}
+
+impl<P: GetPermit, C: BlockCipher> S63Decrypter<P, C> {
+    /// like `with_cell` but the decrypted cell is taken from `cache` if it is there, and
+    /// put there if it isn't. Returns `None` when the cell came from the cache, `rdr` is
+    /// not read then
+    pub fn with_cell_cached<R, W, K>(
+        &self,
+        cache: &mut K,
+        key: &CacheKey,
+        rdr: R,
+        mut wtr: W,
+    ) -> Result<Option<DecryptionInfo>, E>
+    where
+        R: Read + Seek,
+        W: Write,
+        K: CellCache,
+    {
+        if let Some(data) = cache.get(key) {
+            wtr.write_all(&data).map_err(E::Write)?;
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        let info = self.with_cell(&key.cell, rdr, &mut data)?;
+        wtr.write_all(&data).map_err(E::Write)?;
+        cache.put(key.clone(), data);
+        Ok(Some(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypter::S63Encrypter;
+    use std::io::Cursor;
+
+    #[test]
+    fn memory_lru() {
+        let mut cache = MemoryCache::new(10);
+        cache.put(CacheKey::new("GB100001", 1, 0), vec![1; 4]);
+        cache.put(CacheKey::new("GB100002", 1, 0), vec![2; 4]);
+        assert!(cache.get(&CacheKey::new("GB100001", 1, 0)).is_some());
+        cache.put(CacheKey::new("GB100003", 1, 0), vec![3; 4]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&CacheKey::new("GB100002", 1, 0)), None);
+        assert_eq!(
+            cache.get(&CacheKey::new("GB100001", 1, 0)),
+            Some(vec![1; 4])
+        );
+        assert_eq!(cache.get(&CacheKey::new("GB100001", 1, 1)), None);
+
+        cache.put(CacheKey::new("GB100004", 1, 0), vec![4; 11]);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn disk_lru() {
+        let dir = std::env::temp_dir().join(format!("s63-cache-{}", std::process::id()));
+        let mut cache = DiskCache::new(&dir, 2).unwrap();
+        cache.put(CacheKey::new("GB100001", 1, 0), vec![1; 4]);
+        cache.put(CacheKey::new("GB100001", 1, 1), vec![2; 4]);
+        let hit = cache.get(&CacheKey::new("GB100001", 1, 0));
+        cache.put(CacheKey::new("GB100002", 1, 0), vec![3; 4]);
+        let evicted = cache.get(&CacheKey::new("GB100001", 1, 1));
+        cache.put(CacheKey::new("../GB1", 1, 0), vec![4; 4]);
+        let invalid = cache.get(&CacheKey::new("../GB1", 1, 0));
+        let reopened = DiskCache::new(&dir, 2)
+            .unwrap()
+            .get(&CacheKey::new("GB100002", 1, 0));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(hit, Some(vec![1; 4]));
+        assert_eq!(evicted, None);
+        assert_eq!(invalid, None);
+        assert_eq!(reopened, Some(vec![3; 4]));
+    }
+
+    #[test]
+    fn cached_decryption() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let p = crate::permit::test_permit("GB100001", &key, &key);
+        let d = S63Decrypter::new_with_permit(vec![p]);
+        let enc = S63Encrypter::new()
+            .with_key_bytes(&key, "GB100001.000", b"cell")
+            .unwrap();
+        let mut cache = MemoryCache::new(1024);
+        let key = CacheKey::new("GB100001", 1, 0);
+
+        let mut out = Vec::new();
+        let info = d.with_cell_cached(&mut cache, &key, Cursor::new(&enc), &mut out)?;
+        assert!(info.is_some());
+        assert_eq!(out, b"cell");
+
+        let mut out = Vec::new();
+        let info = d.with_cell_cached(&mut cache, &key, Cursor::new(b"garbage"), &mut out)?;
+        assert!(info.is_none());
+        assert_eq!(out, b"cell");
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! ENC cell names as defined by S-57 appendix B.1
+//!
+//! A cell name is eight characters: a two character producer code, the navigational purpose
+//! (usage band 1-6) and a five character cell id, e.g. `GB100001`. Other products use other
+//! characters in the place of the usage band, e.g. AIO cells `GB800001`, so only
+//! `CellName::with_usage_band` requires one.
+
+use crate::errors::E;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+const PRODUCERS: &[(&str, &str)] = &[
+    ("AU", "Australian Hydrographic Office"),
+    ("BR", "Directorate of Hydrography and Navigation, Brazil"),
+    ("CA", "Canadian Hydrographic Service"),
+    ("DE", "Federal Maritime and Hydrographic Agency, Germany"),
+    ("DK", "Danish Geodata Agency"),
+    ("ES", "Hydrographic Institute of the Spanish Navy"),
+    ("FI", "Finnish Transport Infrastructure Agency"),
+    ("FR", "SHOM, France"),
+    ("GB", "UK Hydrographic Office"),
+    ("IT", "Italian Navy Hydrographic Institute"),
+    ("JP", "Japan Hydrographic and Oceanographic Department"),
+    ("NL", "Netherlands Hydrographic Service"),
+    ("NO", "Norwegian Hydrographic Service"),
+    ("SE", "Swedish Maritime Administration"),
+    ("US", "NOAA Office of Coast Survey"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellName(String);
+
+impl CellName {
+    /// a name of eight uppercase letters and digits
+    pub fn new(name: &str) -> Result<CellName, E> {
+        let valid = name.len() == 8
+            && name
+                .chars()
+                .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase());
+        if valid {
+            Ok(CellName(name.to_owned()))
+        } else {
+            Err(E::InvalidCellName(name.to_owned()))
+        }
+    }
+
+    /// like `new` but the name must also have the usage band of an ENC cell
+    pub fn with_usage_band(name: &str) -> Result<CellName, E> {
+        match CellName::new(name)? {
+            c if c.usage_band().is_some() => Ok(c),
+            _ => Err(E::InvalidCellName(name.to_owned())),
+        }
+    }
+
+    /// the two character producer code
+    pub fn producer_code(&self) -> &str {
+        &self.0[0..2]
+    }
+
+    /// the name of the producing agency, if the producer code is a known one
+    pub fn producer(&self) -> Option<&'static str> {
+        PRODUCERS
+            .iter()
+            .find(|(code, _)| *code == self.producer_code())
+            .map(|(_, name)| *name)
+    }
+
+    /// the navigational purpose, 1 (overview) to 6 (berthing), None for other products
+    pub fn usage_band(&self) -> Option<u8> {
+        match self.0.as_bytes()[2] {
+            b @ b'1'..=b'6' => Some(b - b'0'),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for CellName {
+    type Err = E;
+    fn from_str(s: &str) -> Result<CellName, E> {
+        CellName::new(s)
+    }
+}
+
+impl Deref for CellName {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for CellName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CellName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<CellName> for String {
+    fn from(c: CellName) -> String {
+        c.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_names() -> Result<(), E> {
+        let c: CellName = "GB100001".parse()?;
+        assert_eq!(c.producer_code(), "GB");
+        assert_eq!(c.producer(), Some("UK Hydrographic Office"));
+        assert_eq!(c.usage_band(), Some(1));
+        assert_eq!(CellName::new("XX5ABCDE")?.producer(), None);
+        assert_eq!(CellName::with_usage_band("GB600001")?.usage_band(), Some(6));
+
+        for invalid in &["GB10000", "GB1000012", "gb100001", "GB10-001", "GB10000Å"] {
+            assert!(CellName::new(invalid).is_err(), "{}", invalid);
+        }
+        for other in &["GB800001", "GB700001", "GBA00001"] {
+            assert_eq!(CellName::new(other)?.usage_band(), None);
+            assert!(CellName::with_usage_band(other).is_err(), "{}", other);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! The block cipher used for S-63 encryption, Blowfish unless another is plugged in
+
+use crypto::blowfish;
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+
+/// a cipher with 8 byte blocks. Implement this to use e.g. hardware crypto or a test double
+/// with `S63Decrypter` and `S63Encrypter`, and with the `_with_cipher` variants of the cell
+/// permit and user permit functions
+pub trait BlockCipher {
+    fn new(key: &[u8]) -> Self
+    where
+        Self: Sized;
+
+    /// `input` and `output` are a single block
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]);
+
+    /// `input` and `output` are a single block
+    fn decrypt_block(&self, input: &[u8], output: &mut [u8]);
+}
+
+/// Blowfish, the cipher of S-63
+pub struct Blowfish(blowfish::Blowfish);
+
+impl BlockCipher for Blowfish {
+    fn new(key: &[u8]) -> Blowfish {
+        Blowfish(blowfish::Blowfish::new(key))
+    }
+
+    fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        self.0.encrypt_block(input, output)
+    }
+
+    fn decrypt_block(&self, input: &[u8], output: &mut [u8]) {
+        self.0.decrypt_block(input, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypter::S63Decrypter;
+    use crate::encrypter::S63Encrypter;
+
+    // xors every byte with the first byte of the key
+    struct Xor(u8);
+
+    impl BlockCipher for Xor {
+        fn new(key: &[u8]) -> Xor {
+            Xor(key[0])
+        }
+
+        fn encrypt_block(&self, input: &[u8], output: &mut [u8]) {
+            for (o, i) in output.iter_mut().zip(input) {
+                *o = i ^ self.0;
+            }
+        }
+
+        fn decrypt_block(&self, input: &[u8], output: &mut [u8]) {
+            self.encrypt_block(input, output)
+        }
+    }
+
+    #[test]
+    fn blowfish() {
+        let c = Blowfish::new(&[1, 2, 3, 4, 5]);
+        let mut enc = [0u8; 8];
+        let mut dec = [0u8; 8];
+        c.encrypt_block(&[1, 2, 3, 4, 5, 6, 7, 8], &mut enc);
+        assert_ne!(enc, [1, 2, 3, 4, 5, 6, 7, 8]);
+        c.decrypt_block(&enc, &mut dec);
+        assert_eq!(dec, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn plugged_in() {
+        let key = [0x5a, 0, 0, 0, 0];
+        let enc = S63Encrypter::new()
+            .cipher::<Xor>()
+            .with_key_bytes(&key, "GB100001.000", b"cell")
+            .unwrap();
+        let d = S63Decrypter::builder().cipher::<Xor>().build();
+        assert_eq!(d.with_key_bytes(&key, &enc).unwrap(), b"cell");
+        assert!(S63Decrypter::new().with_key_bytes(&key, &enc).is_err());
+    }
+
+    #[test]
+    fn plugged_in_permits() {
+        use crate::permit::parse_raw_permit;
+        use crate::permit::Section;
+        use crate::up::{InputPolicy, UserPermit};
+
+        let p = crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+        let row = p.serialize_with_cipher::<Xor>("12345").unwrap();
+        assert_ne!(row, p.serialize("12345").unwrap());
+        let raw = parse_raw_permit(&row, Section::Enc).unwrap();
+        assert!(raw.cell_permit.verify_checksum("12345").is_err());
+        assert_eq!(raw.decrypt_keys_with_cipher::<Xor>("12345").unwrap(), p);
+
+        let up = UserPermit::new("12345", "3130").unwrap();
+        let enc = up
+            .encrypt_with_cipher::<Xor>("10121", InputPolicy::Strict)
+            .unwrap();
+        assert_ne!(enc, up.encrypt("10121").unwrap());
+        let dec = UserPermit::decrypt_with_cipher::<Xor>(&enc, "10121", InputPolicy::Strict);
+        assert_eq!(dec.unwrap(), up);
+    }
+}
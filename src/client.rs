@@ -0,0 +1,1521 @@
+//! A data client, the S-63 workflow of installing permits and importing exchange sets
+//! into a directory of decrypted cells
+
+use crate::audit::{AuditEvent, AuditSink};
+use crate::clock::{Clock, SystemClock};
+use crate::decrypter::{self, S63Decrypter};
+use crate::errors;
+use crate::exchange::{
+    self, Catalog, CellChange, CellDiff, CellFile, CellVersion, Coverage, ExchangeSet, UpdateCheck,
+};
+use crate::expiry::ExpiryCheck;
+use crate::permit::{PermitFile, PermitRecord};
+use crate::registry::CellRegistry;
+use crate::secret::Zeroizing;
+#[cfg(feature = "signature")]
+use crate::signature::{self, SignatureFile, TrustStore};
+use crate::store::{MemoryStore, PermitStore};
+use chrono::{NaiveDate, NaiveDateTime};
+use crc::crc32;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum E {
+    /// the permits can't be read or stored
+    Permit(errors::E),
+    /// the exchange set can't be read
+    Exchange(exchange::E),
+    /// the decrypted cells can't be written
+    Io(io::Error),
+}
+
+impl From<errors::E> for E {
+    fn from(e: errors::E) -> E {
+        E::Permit(e)
+    }
+}
+
+impl From<exchange::E> for E {
+    fn from(e: exchange::E) -> E {
+        E::Exchange(e)
+    }
+}
+
+impl From<io::Error> for E {
+    fn from(e: io::Error) -> E {
+        E::Io(e)
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::Permit(e) => write!(f, "permit error: {}", e),
+            E::Exchange(e) => write!(f, "exchange set error: {}", e),
+            E::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::Permit(_) => None,
+            E::Exchange(e) => Some(e),
+            E::Io(e) => Some(e),
+        }
+    }
+}
+
+/// why a cell of an exchange set wasn't imported
+#[derive(Debug)]
+pub enum ImportError {
+    /// the files don't follow the installed cell
+    Sequence(UpdateCheck),
+    /// the permit of the cell expired on the date
+    PermitExpired(NaiveDate),
+    Decrypt(decrypter::E),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Sequence(UpdateCheck::MissingUpdates(updates)) => {
+                write!(f, "missing updates {:?}", updates)
+            }
+            ImportError::Sequence(UpdateCheck::WrongEdition { installed, found }) => write!(
+                f,
+                "edition {} doesn't follow the installed edition {}",
+                found, installed
+            ),
+            ImportError::Sequence(check) => write!(f, "can't import: {:?}", check),
+            ImportError::PermitExpired(date) => write!(f, "the permit expired {}", date),
+            ImportError::Decrypt(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// the outcome of importing a cell, see `CellImport::status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImportStatus {
+    /// decrypted and installed
+    Imported,
+    /// cancelled and removed with its permit
+    Removed,
+    /// nothing newer than the installed cell
+    Skipped,
+    NoPermit,
+    PermitExpired,
+    /// the CRC of a file doesn't match the catalogue, or the file is corrupt
+    ChecksumFailed,
+    SignatureFailed,
+    /// any other error, e.g. missing updates or a wrong key
+    Failed,
+}
+
+impl fmt::Display for ImportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ImportStatus::Imported => "imported",
+            ImportStatus::Removed => "removed",
+            ImportStatus::Skipped => "skipped, not newer than the installed cell",
+            ImportStatus::NoPermit => "no permit",
+            ImportStatus::PermitExpired => "permit expired",
+            ImportStatus::ChecksumFailed => "checksum failed",
+            ImportStatus::SignatureFailed => "signature failed",
+            ImportStatus::Failed => "failed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// what `DataClient::import_exchange_set` did with a cell
+#[derive(Debug)]
+pub struct CellImport {
+    pub cell: String,
+    pub change: CellChange,
+    /// the installed version after the import, None for cells that aren't installed
+    pub version: Option<CellVersion>,
+    /// the decrypted files written, in order
+    pub files: Vec<PathBuf>,
+    /// why the cell wasn't imported, the installed cell is then left as it was
+    pub error: Option<ImportError>,
+    /// the time spent on the cell
+    pub elapsed: Duration,
+}
+
+impl CellImport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn status(&self) -> ImportStatus {
+        match &self.error {
+            None => match self.change {
+                CellChange::Cancelled => ImportStatus::Removed,
+                CellChange::Unchanged => ImportStatus::Skipped,
+                _ => ImportStatus::Imported,
+            },
+            Some(ImportError::PermitExpired(_)) => ImportStatus::PermitExpired,
+            Some(ImportError::Decrypt(e)) => match e {
+                decrypter::E::NoPermit(_) | decrypter::E::PermitIsNone => ImportStatus::NoPermit,
+                decrypter::E::CrcMismatch { .. } | decrypter::E::CorruptArchive(_) => {
+                    ImportStatus::ChecksumFailed
+                }
+                #[cfg(feature = "signature")]
+                decrypter::E::SignatureInvalid(_) => ImportStatus::SignatureFailed,
+                _ => ImportStatus::Failed,
+            },
+            Some(ImportError::Sequence(_)) => ImportStatus::Failed,
+        }
+    }
+}
+
+/// the result of `DataClient::import`, one entry per cell of the exchange set selected by
+/// the `CellFilter` in the order of `ExchangeSet::cells`
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub cells: Vec<CellImport>,
+    /// the cells skipped as the checkpoint of an earlier, interrupted import has them
+    pub resumed: Vec<String>,
+    /// whether the import was cancelled before every cell was imported
+    pub cancelled: bool,
+    /// the time of the whole import
+    pub elapsed: Duration,
+}
+
+impl ImportReport {
+    /// whether every cell was imported, removed or skipped
+    pub fn is_ok(&self) -> bool {
+        self.cells.iter().all(CellImport::is_ok)
+    }
+
+    pub fn cell(&self, cell: &str) -> Option<&CellImport> {
+        self.cells.iter().find(|c| c.cell == cell)
+    }
+
+    pub fn with_status(&self, status: ImportStatus) -> impl Iterator<Item = &CellImport> {
+        self.cells.iter().filter(move |c| c.status() == status)
+    }
+
+    /// the cells that weren't imported
+    pub fn errors(&self) -> impl Iterator<Item = &CellImport> {
+        self.cells.iter().filter(|c| !c.is_ok())
+    }
+}
+
+/// the cells of an exchange set to import
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum CellFilter {
+    #[default]
+    All,
+    /// the cells with these names
+    Cells(Vec<String>),
+    /// the cells of a producer, by the producer code the cell names start with, e.g. "GB"
+    Producer(String),
+    /// the cells whose coverage intersects the area, see `ExchangeSet::cells_intersecting`
+    Area(Coverage),
+}
+
+/// how `DataClient::import_with` imports an exchange set
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    filter: CellFilter,
+    checkpoint: Option<PathBuf>,
+    workers: usize,
+    cancel: Option<CancellationToken>,
+    check_edition: bool,
+}
+
+impl ImportOptions {
+    /// imports every cell without a checkpoint
+    pub fn new() -> ImportOptions {
+        ImportOptions::default()
+    }
+
+    pub fn filter(mut self, filter: CellFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// the number of threads decrypting cells, 1 by default
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// fails the cells whose decrypted edition isn't the one their permit is for, see
+    /// `S63DecrypterBuilder::check_edition`. Off by default
+    pub fn check_edition(mut self, check: bool) -> Self {
+        self.check_edition = check;
+        self
+    }
+
+    /// stops the import once `token` is cancelled, see `DataClient::import_with`
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// records every cell imported, removed or skipped in the file `path`. When the import
+    /// is interrupted, importing the same exchange set with the same checkpoint resumes
+    /// after the recorded cells. The file is removed once the import is done
+    pub fn checkpoint<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+}
+
+// the cells recorded in a checkpoint file, a line per cell with its name and the installed
+// edition and update, or only its name if it was removed
+fn read_checkpoint(path: &Path) -> io::Result<Vec<(String, Option<CellVersion>)>> {
+    let data = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r?,
+    };
+    let invalid = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid checkpoint line {:?}", line),
+        )
+    };
+    let mut res = Vec::new();
+    for line in data.lines().filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let version = match fields[1..] {
+            [] => None,
+            [edition, update] => Some(CellVersion {
+                edition: edition.parse().map_err(|_| invalid(line))?,
+                update: update.parse().map_err(|_| invalid(line))?,
+            }),
+            _ => return Err(invalid(line)),
+        };
+        res.push((fields[0].to_owned(), version));
+    }
+    Ok(res)
+}
+
+fn write_checkpoint(path: &Path, cell: &str, version: Option<CellVersion>) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    match version {
+        Some(v) => writeln!(f, "{} {} {}", cell, v.edition, v.update)?,
+        None => writeln!(f, "{}", cell)?,
+    }
+    f.sync_data()
+}
+
+// records an imported, removed or skipped cell in the checkpoint
+fn record(checkpoint: Option<&Path>, cell: &CellImport) -> io::Result<()> {
+    match checkpoint {
+        Some(path) if cell.is_ok() => write_checkpoint(path, &cell.cell, cell.version),
+        _ => Ok(()),
+    }
+}
+
+/// cancels an import from another thread, see `ImportOptions::cancel`
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// an encrypted cell file read from the exchange set
+struct EncryptedFile {
+    name: String,
+    data: Vec<u8>,
+    /// from the catalogue
+    crc: Option<u32>,
+    #[cfg(feature = "signature")]
+    signature: Option<Vec<u8>>,
+}
+
+// the files of a cell for a worker to decrypt
+struct Job {
+    // of the cell in the report
+    index: usize,
+    start: Instant,
+    cell: CellImport,
+    // None for a cancellation, the cell is removed once its files are verified
+    available: Option<CellVersion>,
+    // whether the cell directory is replaced, for anything but updates
+    replace: bool,
+    permit: Vec<PermitRecord>,
+    files: Vec<EncryptedFile>,
+}
+
+// decrypts the files of `job` after checking their CRC in the catalogue and, when there is
+// a trust store, their signatures
+fn decrypt(
+    job: &Job,
+    check_edition: bool,
+    audit: Option<&Arc<dyn AuditSink>>,
+    #[cfg(feature = "signature")] trust: Option<&(dyn TrustStore + Send + Sync)>,
+) -> Result<Vec<Vec<u8>>, decrypter::E> {
+    let mut decrypter = S63Decrypter::builder()
+        .permit(&job.permit)
+        .check_edition(check_edition);
+    if let Some(audit) = audit {
+        decrypter = decrypter.audit(audit.clone());
+    }
+    let decrypter = decrypter.build();
+    let cell = &job.cell.cell;
+    let mut res = Vec::new();
+    for file in &job.files {
+        if let Some(expected) = file.crc {
+            let actual = crc32::checksum_ieee(&file.data);
+            if actual != expected {
+                return Err(decrypter::E::CrcMismatch { expected, actual });
+            }
+        }
+        let mut out = Vec::new();
+        #[cfg(feature = "signature")]
+        if let Some(sa) = trust {
+            let sig = match &file.signature {
+                Some(data) => SignatureFile::from_rdr(&data[..]),
+                None => Err(signature::E::Parse(format!(
+                    "no signature file for {}",
+                    file.name
+                ))),
+            };
+            if let (Some(audit), Err(e)) = (audit, &sig) {
+                audit.record(&AuditEvent::Signature {
+                    cell,
+                    error: Some(e),
+                });
+            }
+            let sig = sig.map_err(decrypter::E::SignatureInvalid)?;
+            decrypter.with_cell_signed(cell, &file.data[..], &mut out, &sig, sa)?;
+            res.push(out);
+            continue;
+        }
+        decrypter.with_cell(cell, Cursor::new(&file.data), &mut out)?;
+        res.push(out);
+    }
+    Ok(res)
+}
+
+/// the state of a `DataClient`, see `DataClient::status`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClientStatus {
+    /// the number of installed permits
+    pub permits: usize,
+    /// the installed cells and their versions, by cell name
+    pub installed: Vec<(String, CellVersion)>,
+    /// cells whose permit has expired, the earliest expiry first
+    pub expired: Vec<String>,
+    /// cells whose permit expires within `EXPIRY_WARNING_DAYS`, the earliest expiry first
+    pub expiring: Vec<String>,
+    /// installed cells without a permit
+    pub without_permit: Vec<String>,
+}
+
+/// the file in the cells directory of `DataClient::open` with the installed cells
+pub const CELL_REGISTRY: &str = "CELLS.REG";
+
+/// the permits, trusted SA keys and installed cells of an S-63 data client. Imported
+/// cells are decrypted into a directory per cell under `cells_dir`
+pub struct DataClient<S: PermitStore = MemoryStore> {
+    hwid: Zeroizing<String>,
+    permits: S,
+    installed: CellRegistry,
+    // where `installed` is saved after every change
+    registry_path: Option<PathBuf>,
+    cells_dir: PathBuf,
+    audit: Option<Arc<dyn AuditSink>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+    #[cfg(feature = "signature")]
+    trust: Option<Arc<dyn TrustStore + Send + Sync>>,
+}
+
+impl DataClient<MemoryStore> {
+    /// a client without installed cells that isn't saved, see `open` for one that is
+    pub fn new<D: Into<PathBuf>>(hwid: &str, cells_dir: D) -> DataClient<MemoryStore> {
+        DataClient::with_store(hwid, MemoryStore::new(), cells_dir)
+    }
+
+    pub fn open<D: Into<PathBuf>>(hwid: &str, cells_dir: D) -> Result<DataClient<MemoryStore>, E> {
+        DataClient::open_with_store(hwid, MemoryStore::new(), cells_dir)
+    }
+}
+
+// removes a directory, that it doesn't exist is fine
+fn remove_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// the files to decrypt for `diff`, the base cell and its updates when the cell is
+// replaced, the cancellation update when it is cancelled and otherwise the updates after
+// the installed one
+fn import_files<'a>(set: &'a ExchangeSet, diff: &CellDiff) -> Vec<&'a CellFile> {
+    if diff.change == CellChange::Cancelled {
+        return set
+            .cancellations()
+            .filter(|f| f.cell == diff.cell)
+            .collect();
+    }
+    let replace = matches!(
+        diff.change,
+        CellChange::New | CellChange::NewEdition | CellChange::ReIssue
+    );
+    let (edition, after) = match (replace, diff.available, diff.installed) {
+        (true, Some(v), _) => (v.edition, None),
+        (false, _, Some(i)) => (i.edition, Some(i.update)),
+        _ => return Vec::new(),
+    };
+    let mut files: Vec<_> = set
+        .cells()
+        .filter(|f| f.cell == diff.cell)
+        .filter(|f| f.edition.is_none_or(|e| e == edition))
+        .filter(|f| after.is_none_or(|u| f.update > u))
+        .collect();
+    if replace {
+        // the last base cell and the updates after it
+        let base = files.iter().rposition(|f| f.is_base()).unwrap_or_default();
+        files.drain(..base);
+    }
+    files
+}
+
+impl<S: PermitStore> DataClient<S> {
+    pub fn with_store<D: Into<PathBuf>>(hwid: &str, permits: S, cells_dir: D) -> DataClient<S> {
+        DataClient {
+            hwid: Zeroizing::new(hwid.to_owned()),
+            permits,
+            installed: CellRegistry::new(),
+            registry_path: None,
+            cells_dir: cells_dir.into(),
+            audit: None,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "signature")]
+            trust: None,
+        }
+    }
+
+    /// a client whose installed cells are kept in `CELL_REGISTRY` in `cells_dir`, read
+    /// from it if it exists and saved after every change
+    pub fn open_with_store<D: Into<PathBuf>>(
+        hwid: &str,
+        permits: S,
+        cells_dir: D,
+    ) -> Result<DataClient<S>, E> {
+        let mut res = DataClient::with_store(hwid, permits, cells_dir);
+        let path = res.cells_dir.join(CELL_REGISTRY);
+        if path.exists() {
+            res.installed = CellRegistry::load(&path)?;
+        }
+        res.registry_path = Some(path);
+        Ok(res)
+    }
+
+    /// checks the signature of every imported file with the SA keys of `sa`, files without
+    /// a signature file aren't imported
+    #[cfg(feature = "signature")]
+    pub fn trust_store<T: TrustStore + Send + Sync + 'static>(mut self, sa: T) -> Self {
+        self.trust = Some(Arc::new(sa));
+        self
+    }
+
+    /// records the installed permits, and the decryptions and signature checks of imports
+    /// in `sink`
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// the time of imports and status, defaults to the system clock. `import_at` and
+    /// `status_at` take a clock of their own
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn permits(&self) -> &S {
+        &self.permits
+    }
+
+    /// the installed cells, their versions and when they were imported
+    pub fn installed(&self) -> &CellRegistry {
+        &self.installed
+    }
+
+    // saves the installed cells, when the client was opened from a cells directory
+    fn save_installed(&self) -> Result<(), E> {
+        if let Some(path) = &self.registry_path {
+            fs::create_dir_all(&self.cells_dir)?;
+            self.installed.save(path)?;
+        }
+        Ok(())
+    }
+
+    /// the directory with the decrypted base cell and updates of `cell`
+    pub fn cell_dir(&self, cell: &str) -> PathBuf {
+        self.cells_dir.join(cell)
+    }
+
+    /// installs the permits of the PERMIT.TXT read from `rdr`, replacing the permits for
+    /// the same cells. Returns the number of installed permits
+    pub fn install_permits<R: Read>(&mut self, rdr: R) -> Result<usize, E> {
+        let (_, pf) = PermitFile::new(rdr)?;
+        let permits = pf.permits(&self.hwid).collect::<Result<Vec<_>, _>>()?;
+        let n = permits.len();
+        for p in permits {
+            if let Some(audit) = &self.audit {
+                audit.record(&AuditEvent::permit_installed(&p, "PERMIT.TXT"));
+            }
+            self.permits.insert(p)?;
+        }
+        Ok(n)
+    }
+
+    /// imports the exchange set in the directory `path` at the time of the client clock,
+    /// see `import_with`
+    pub fn import_exchange_set<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: &ImportOptions,
+    ) -> Result<ImportReport, E> {
+        self.import_with(&ExchangeSet::open(path)?, options, self.clock.clone())
+    }
+
+    /// imports the cells of `set` at the time of the client clock, see `import_at`
+    pub fn import(&mut self, set: &ExchangeSet) -> Result<ImportReport, E> {
+        self.import_at(set, self.clock.clone())
+    }
+
+    /// imports every cell of `set` at the time of `clock`, see `import_with`
+    pub fn import_at<C: Clock>(&mut self, set: &ExchangeSet, clock: C) -> Result<ImportReport, E> {
+        self.import_with(set, &ImportOptions::new(), clock)
+    }
+
+    /// decrypts the cells of `set` that are newer than the installed ones into their cell
+    /// directories, and removes cancelled cells with their permits once the cancellation
+    /// update is decrypted and verified like any other file. Cells whose permit has
+    /// expired at `clock` and files that don't match the CRC in the catalogue aren't
+    /// imported. A cell is imported whole or not at all, the errors of single cells are in
+    /// the report. Only the cells of the filter of `options` are imported, and the cells
+    /// in its checkpoint are skipped.
+    ///
+    /// The files are read and the decrypted cells written on the calling thread while the
+    /// cells are decrypted and verified by the workers of `options`. When the import is
+    /// cancelled the cells being decrypted are dropped, the report has the cells written
+    /// until then and the checkpoint is kept
+    pub fn import_with<C: Clock>(
+        &mut self,
+        set: &ExchangeSet,
+        options: &ImportOptions,
+        clock: C,
+    ) -> Result<ImportReport, E> {
+        let start = Instant::now();
+        let catalog = match set.catalog() {
+            Some(_) => Some(set.read_catalog()?),
+            None => None,
+        };
+        let area: Option<HashSet<&str>> = match &options.filter {
+            CellFilter::Area(area) => Some(
+                set.cells_intersecting(area)?
+                    .into_iter()
+                    .map(|f| f.cell.as_str())
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let selected = |cell: &str| match &options.filter {
+            CellFilter::All => true,
+            CellFilter::Cells(cells) => cells.iter().any(|c| c == cell),
+            CellFilter::Producer(code) => cell.starts_with(code.as_str()),
+            CellFilter::Area(_) => area.as_ref().is_some_and(|a| a.contains(cell)),
+        };
+        let mut res = ImportReport::default();
+        let checkpoint = options.checkpoint.as_deref();
+        if let Some(path) = checkpoint {
+            for (cell, version) in read_checkpoint(path)? {
+                match version {
+                    Some(v) => self.installed.insert(&cell, v, clock.now()),
+                    None => self.installed.remove(&cell),
+                };
+                res.resumed.push(cell);
+            }
+            self.save_installed()?;
+        }
+        let diffs: Vec<_> = set
+            .diff(&self.installed)
+            .into_iter()
+            .filter(|d| selected(&d.cell) && !res.resumed.contains(&d.cell))
+            .collect();
+        let total = diffs.len();
+        let now = clock.now();
+        let cancel = options.cancel.clone().unwrap_or_default();
+        let workers = options.workers.max(1);
+        let check_edition = options.check_edition;
+        let audit = self.audit.clone();
+        #[cfg(feature = "signature")]
+        let trust = self.trust.clone();
+        let mut cells = Vec::new();
+        thread::scope(|scope| -> Result<(), E> {
+            let (jobs, job_rx) = mpsc::sync_channel::<Job>(workers);
+            let (done_tx, done) = mpsc::channel();
+            let job_rx = Arc::new(Mutex::new(job_rx));
+            for _ in 0..workers {
+                let (job_rx, done_tx, cancel) = (job_rx.clone(), done_tx.clone(), cancel.clone());
+                let audit = audit.clone();
+                #[cfg(feature = "signature")]
+                let trust = trust.clone();
+                scope.spawn(move || {
+                    loop {
+                        // the lock is held only while waiting for the next job
+                        let job = match job_rx.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                        let decrypted = if cancel.is_cancelled() {
+                            None
+                        } else {
+                            #[cfg(feature = "signature")]
+                            let res =
+                                decrypt(&job, check_edition, audit.as_ref(), trust.as_deref());
+                            #[cfg(not(feature = "signature"))]
+                            let res = decrypt(&job, check_edition, audit.as_ref());
+                            Some(res)
+                        };
+                        if done_tx.send((job, decrypted)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(done_tx);
+            for (index, diff) in diffs.into_iter().enumerate() {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                match self.prepare_cell(set, catalog.as_ref(), diff, now.date(), index)? {
+                    Ok(cell) => {
+                        record(checkpoint, &cell)?;
+                        cells.push((index, cell));
+                    }
+                    Err(job) => jobs.send(job).expect("the import workers stopped"),
+                }
+                while let Ok((job, decrypted)) = done.try_recv() {
+                    if let Some((index, cell)) = self.write_cell(job, decrypted, now)? {
+                        record(checkpoint, &cell)?;
+                        cells.push((index, cell));
+                    }
+                }
+            }
+            drop(jobs);
+            for (job, decrypted) in done {
+                if let Some((index, cell)) = self.write_cell(job, decrypted, now)? {
+                    record(checkpoint, &cell)?;
+                    cells.push((index, cell));
+                }
+            }
+            Ok(())
+        })?;
+        cells.sort_by_key(|(index, _)| *index);
+        res.cells = cells.into_iter().map(|(_, cell)| cell).collect();
+        res.cancelled = res.cells.len() < total;
+        if let (Some(path), false) = (checkpoint, res.cancelled) {
+            remove_file(path)?;
+        }
+        res.elapsed = start.elapsed();
+        Ok(res)
+    }
+
+    // the result of a cell that needs no decryption, or else the job to decrypt it
+    fn prepare_cell(
+        &mut self,
+        set: &ExchangeSet,
+        catalog: Option<&Catalog>,
+        diff: CellDiff,
+        today: NaiveDate,
+        index: usize,
+    ) -> Result<Result<CellImport, Job>, E> {
+        let start = Instant::now();
+        let mut res = CellImport {
+            cell: diff.cell.clone(),
+            change: diff.change,
+            version: diff.installed,
+            files: Vec::new(),
+            error: None,
+            elapsed: Duration::default(),
+        };
+        if diff.change == CellChange::Unchanged {
+            return Ok(Ok(res));
+        }
+        let cancelled = diff.change == CellChange::Cancelled;
+        let available = match (&diff.check, diff.available) {
+            (UpdateCheck::Cancelled { .. }, _) if cancelled => None,
+            (UpdateCheck::Ok, Some(available)) => Some(available),
+            _ => {
+                res.error = Some(ImportError::Sequence(diff.check.clone()));
+                return Ok(Ok(res));
+            }
+        };
+        let permit = self.permits.get_permit(&diff.cell).cloned();
+        // a withdrawn cell is removed even once its permit has expired
+        if let Some(p) = permit
+            .as_ref()
+            .filter(|p| !cancelled && p.is_expired(today))
+        {
+            res.error = Some(ImportError::PermitExpired(p.cell_permit.date));
+            return Ok(Ok(res));
+        }
+        let mut files = Vec::new();
+        for file in import_files(set, &diff) {
+            files.push(EncryptedFile {
+                name: file.path.rsplit('/').next().unwrap_or_default().to_owned(),
+                data: set.read(&file.path)?,
+                crc: catalog.and_then(|c| c.entry(&file.path)?.crc),
+                #[cfg(feature = "signature")]
+                signature: match set.read(&file.signature_path()) {
+                    Ok(data) => Some(data),
+                    Err(exchange::E::NoFile(_)) => None,
+                    Err(e) => return Err(e.into()),
+                },
+            });
+        }
+        Ok(Err(Job {
+            index,
+            start,
+            cell: res,
+            available,
+            replace: !matches!(diff.change, CellChange::Updated),
+            permit: permit.into_iter().collect(),
+            files,
+        }))
+    }
+
+    // writes the files of a decrypted cell to its directory or removes a cancelled cell,
+    // None for a job dropped as the import is cancelled
+    fn write_cell(
+        &mut self,
+        job: Job,
+        decrypted: Option<Result<Vec<Vec<u8>>, decrypter::E>>,
+        now: NaiveDateTime,
+    ) -> Result<Option<(usize, CellImport)>, E> {
+        let mut res = job.cell;
+        let decrypted = match decrypted {
+            None => return Ok(None),
+            Some(Err(e)) => {
+                res.error = Some(ImportError::Decrypt(e));
+                res.elapsed = job.start.elapsed();
+                return Ok(Some((job.index, res)));
+            }
+            Some(Ok(decrypted)) => decrypted,
+        };
+        let dir = self.cell_dir(&res.cell);
+        let available = match job.available {
+            Some(available) => available,
+            None => {
+                self.permits.remove(&res.cell)?;
+                self.installed.remove(&res.cell);
+                self.save_installed()?;
+                remove_dir(&dir)?;
+                res.version = None;
+                res.elapsed = job.start.elapsed();
+                return Ok(Some((job.index, res)));
+            }
+        };
+        // a replaced cell is written to a directory next to it and renamed into place, so a
+        // failed write leaves the installed cell as it was
+        let target = if job.replace {
+            let tmp = dir.with_file_name(format!("{}.tmp", res.cell));
+            remove_dir(&tmp)?;
+            tmp
+        } else {
+            dir.clone()
+        };
+        fs::create_dir_all(&target)?;
+        let written = job
+            .files
+            .iter()
+            .zip(decrypted)
+            .try_for_each(|(file, data)| fs::write(target.join(&file.name), data));
+        if let Err(e) = written {
+            if job.replace {
+                let _ = remove_dir(&target);
+            }
+            return Err(e.into());
+        }
+        if job.replace {
+            remove_dir(&dir)?;
+            fs::rename(&target, &dir)?;
+        }
+        res.files = job.files.iter().map(|f| dir.join(&f.name)).collect();
+        self.installed.insert(&res.cell, available, now);
+        self.save_installed()?;
+        res.version = Some(available);
+        res.elapsed = job.start.elapsed();
+        Ok(Some((job.index, res)))
+    }
+
+    /// the permits and installed cells at the time of the client clock, see `status_at`
+    pub fn status(&self) -> ClientStatus {
+        self.status_at(&*self.clock)
+    }
+
+    /// the permits and installed cells, with the permits expired or expiring at `clock`
+    pub fn status_at<C: Clock>(&self, clock: C) -> ClientStatus {
+        let today = clock.today();
+        let mut res = ClientStatus {
+            installed: self
+                .installed
+                .iter()
+                .map(|c| (c.cell.clone(), c.version))
+                .collect(),
+            without_permit: self
+                .installed
+                .iter()
+                .filter(|c| self.permits.get_permit(&c.cell).is_none())
+                .map(|c| c.cell.clone())
+                .collect(),
+            ..ClientStatus::default()
+        };
+        res.permits = self.permits.iter().count();
+        let report = ExpiryCheck::new().report(self.permits.iter(), today);
+        res.expired = report.errors.into_iter().map(|n| n.cell).collect();
+        res.expiring = report.warnings.into_iter().map(|n| n.cell).collect();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, ManualClock};
+    use crate::decrypter::CellKey;
+    use crate::encrypter::S63Encrypter;
+    use crate::permit::{test_permit, GetPermit, MetaData, PermitFileWriter};
+    use crate::sse::SseCode;
+    use chrono::NaiveDate;
+
+    const HWID: &str = "12345";
+    const KEY: [u8; 5] = [1, 2, 3, 4, 5];
+
+    fn permit(cell: &str, date: NaiveDate) -> PermitRecord {
+        let mut p = test_permit(cell, &KEY, &KEY);
+        p.cell_permit.date = date;
+        p
+    }
+
+    fn permit_txt(permits: &[PermitRecord]) -> Vec<u8> {
+        let md = MetaData {
+            date: NaiveDate::from_ymd_opt(2020, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            version: 2,
+        };
+        let mut w = PermitFileWriter::new(Vec::new(), &md, HWID).unwrap();
+        for p in permits {
+            w.write_permit(p).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    fn encrypt(name: &str, data: &[u8]) -> Vec<u8> {
+        let e = S63Encrypter::new_with_permit(vec![permit(
+            &name[..8],
+            NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(),
+        )]);
+        let mut res = Vec::new();
+        e.with_cell(name, CellKey::Key1, data, &mut res).unwrap();
+        res
+    }
+
+    fn set(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let files: Vec<(String, Vec<u8>)> = files
+            .iter()
+            .map(|(path, data)| {
+                let name = path.rsplit('/').next().unwrap();
+                (path.to_string(), encrypt(name, data))
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(p, d)| (p.as_str(), d.as_slice()))
+            .collect();
+        crate::exchange::tests::write_set(name, &files)
+    }
+
+    #[test]
+    fn import() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-{}", std::process::id()));
+        let first = set(
+            "client-1",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"upd1"),
+                ("ENC_ROOT/GB/GB100002/1/0/GB100002.000", b"other"),
+                ("ENC_ROOT/GB/GB100003/1/0/GB100003.000", b"no permit"),
+            ],
+        );
+        let second = set(
+            "client-2",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/2/GB100001.002", b"upd2"),
+                ("ENC_ROOT/GB/GB100002/0/1/GB100002.001", b"cancel"),
+            ],
+        );
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(0, 0, 0).unwrap());
+            let import = |client: &mut DataClient, path| -> Result<ImportReport, E> {
+                client.import_at(&ExchangeSet::open(path)?, clock)
+            };
+            let mut client = DataClient::new(HWID, &dir);
+            let txt = permit_txt(&[permit("GB100001", date), permit("GB100002", date)]);
+            assert_eq!(client.install_permits(&txt[..])?, 2);
+
+            let report = import(&mut client, &first)?;
+            assert!(!report.is_ok());
+            assert_eq!(report.errors().count(), 1);
+            assert_eq!(report.with_status(ImportStatus::Imported).count(), 2);
+            let res = report.cells;
+            assert_eq!(res.len(), 3);
+            assert!(res[0].is_ok() && res[1].is_ok());
+            assert_eq!(res[0].change, CellChange::New);
+            assert_eq!(res[0].files.len(), 2);
+            assert!(matches!(
+                res[2].error,
+                Some(ImportError::Decrypt(decrypter::E::NoPermit(_)))
+            ));
+            let cell = client.cell_dir("GB100001");
+            assert_eq!(fs::read(cell.join("GB100001.001"))?, b"upd1");
+            assert_eq!(res[2].status(), ImportStatus::NoPermit);
+            assert_eq!(res[2].sse_code(), Some(SseCode::CellPermitNotFound));
+            assert!(!client.cell_dir("GB100003").exists());
+
+            let res = import(&mut client, &second)?.cells;
+            assert_eq!(res[0].change, CellChange::Updated);
+            assert_eq!(res[0].files, [cell.join("GB100001.002")]);
+            assert_eq!(res[1].change, CellChange::Cancelled);
+            assert_eq!(res[1].status(), ImportStatus::Removed);
+            assert!(!client.cell_dir("GB100002").exists());
+            assert!(client.permits().get_permit("GB100002").is_none());
+
+            let res = import(&mut client, &second)?.cells;
+            assert_eq!(res[0].change, CellChange::Unchanged);
+            assert_eq!(res[0].status(), ImportStatus::Skipped);
+            assert!(res[0].files.is_empty());
+
+            let status = client.status_at(clock);
+            assert_eq!(status.permits, 1);
+            assert_eq!(status.expiring, ["GB100001"]);
+            assert_eq!(
+                status.installed,
+                [(
+                    "GB100001".to_owned(),
+                    CellVersion {
+                        edition: 4,
+                        update: 2
+                    }
+                )]
+            );
+            assert!(status.without_permit.is_empty());
+            let later = FixedClock(
+                NaiveDate::from_ymd_opt(2020, 7, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap(),
+            );
+            assert_eq!(client.status_at(later).expired, ["GB100001"]);
+            Ok(())
+        })();
+        for d in [&dir, &first, &second] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn reopen() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-reopen-{}", std::process::id()));
+        let upd2 = set(
+            "client-reopen-upd2",
+            &[("ENC_ROOT/GB/GB100001/4/2/GB100001.002", b"upd2")],
+        );
+        let upd3 = set(
+            "client-reopen-upd3",
+            &[("ENC_ROOT/GB/GB100001/4/3/GB100001.003", b"upd3")],
+        );
+        let set = set(
+            "client-reopen",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"upd1"),
+            ],
+        );
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(8, 0, 0).unwrap());
+            let txt = permit_txt(&[permit("GB100001", date)]);
+            let set = ExchangeSet::open(&set)?;
+            let mut client = DataClient::open(HWID, &dir)?;
+            client.install_permits(&txt[..])?;
+            assert_eq!(
+                client.import_at(&set, clock)?.cells[0].change,
+                CellChange::New
+            );
+            assert!(dir.join(CELL_REGISTRY).exists());
+
+            let mut client = DataClient::open(HWID, &dir)?;
+            client.install_permits(&txt[..])?;
+            let installed = client.installed().get("GB100001").cloned();
+            assert_eq!(
+                installed,
+                Some(crate::registry::InstalledCell {
+                    cell: "GB100001".into(),
+                    version: CellVersion {
+                        edition: 4,
+                        update: 1
+                    },
+                    imported: clock.0,
+                })
+            );
+            // the base cell of the installed edition again, without a later update
+            let report = client.import_at(&set, clock)?;
+            assert_eq!(report.cells[0].change, CellChange::Unchanged);
+
+            // the client clock, a day before and after the expiry
+            let manual = ManualClock::new(clock.0 - chrono::Duration::days(1));
+            let mut client = DataClient::open(HWID, &dir)?.clock(manual.clone());
+            client.install_permits(&txt[..])?;
+            assert_eq!(client.status().expiring, ["GB100001"]);
+            manual.advance(chrono::Duration::days(1));
+            assert!(client.import(&ExchangeSet::open(&upd2)?)?.is_ok());
+            assert_eq!(
+                client.installed().get("GB100001").unwrap().imported,
+                manual.now()
+            );
+            manual.advance(chrono::Duration::days(1));
+            assert_eq!(client.status().expired, ["GB100001"]);
+            let report = client.import(&ExchangeSet::open(&upd3)?)?;
+            assert_eq!(report.cells[0].status(), ImportStatus::PermitExpired);
+            Ok(())
+        })();
+        for d in [&dir, &set, &upd2, &upd3] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn statuses() -> Result<(), E> {
+        let files = [
+            (
+                "GB\\GB100001\\1\\0\\GB100001.000",
+                encrypt("GB100001.000", b"corrupt"),
+            ),
+            (
+                "GB\\GB100002\\1\\0\\GB100002.000",
+                encrypt("GB100002.000", b"expired"),
+            ),
+            (
+                "GB\\GB100003\\1\\0\\GB100003.000",
+                encrypt("GB100003.000", b"ok"),
+            ),
+        ];
+        let catalog = Catalog {
+            entries: files
+                .iter()
+                .map(|(file, data)| exchange::CatalogEntry {
+                    file: file.to_string(),
+                    long_name: String::new(),
+                    volume: "V01X01".into(),
+                    implementation: "BIN".into(),
+                    coverage: None,
+                    crc: Some(crc32::checksum_ieee(data) ^ (file.ends_with("GB100001.000") as u32)),
+                    comment: String::new(),
+                })
+                .collect(),
+        };
+        let mut cat = Vec::new();
+        catalog.write(&mut cat)?;
+        let paths: Vec<_> = files
+            .iter()
+            .map(|(file, _)| format!("ENC_ROOT/{}", file.replace('\\', "/")))
+            .collect();
+        let mut contents: Vec<(&str, &[u8])> = vec![("ENC_ROOT/CATALOG.031", &cat)];
+        contents.extend(
+            paths
+                .iter()
+                .zip(&files)
+                .map(|(p, (_, d))| (p.as_str(), &d[..])),
+        );
+        let set = crate::exchange::tests::write_set("client-status", &contents);
+        let dir = std::env::temp_dir().join(format!("s63-client-status-{}", std::process::id()));
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 1).unwrap();
+            let mut client = DataClient::new(HWID, &dir);
+            client.install_permits(
+                &permit_txt(&[
+                    permit("GB100001", date),
+                    permit("GB100002", date.pred_opt().unwrap()),
+                    permit("GB100003", date),
+                ])[..],
+            )?;
+            let clock = FixedClock(date.and_hms_opt(12, 0, 0).unwrap());
+            let report = client.import_at(&ExchangeSet::open(&set)?, clock)?;
+            let statuses: Vec<_> = report.cells.iter().map(|c| c.status()).collect();
+            assert_eq!(
+                statuses,
+                [
+                    ImportStatus::ChecksumFailed,
+                    ImportStatus::PermitExpired,
+                    ImportStatus::Imported
+                ]
+            );
+            assert_eq!(report.cells[0].sse_code(), Some(SseCode::EncCrcIncorrect));
+            assert_eq!(report.cells[1].sse_code(), Some(SseCode::PermitExpired));
+            let messages = report.sse_messages();
+            assert_eq!(messages.len(), 2);
+            assert!(messages[1]
+                .to_string()
+                .starts_with("SSE 25 - The permit for ENC GB100002 has expired."));
+            assert!(report.cells.iter().all(|c| c.elapsed <= report.elapsed));
+            assert_eq!(client.installed().len(), 1);
+            assert!(report.cell("GB100003").is_some_and(CellImport::is_ok));
+            Ok(())
+        })();
+        for d in [&dir, &set] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn partial() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-partial-{}", std::process::id()));
+        let set = set(
+            "client-partial",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"upd1"),
+                ("ENC_ROOT/GB/GB100002/1/0/GB100002.000", b"other"),
+                ("ENC_ROOT/FR/FR100003/1/0/FR100003.000", b"french"),
+            ],
+        );
+        let checkpoint = dir.join("import.checkpoint");
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(0, 0, 0).unwrap());
+            let txt = permit_txt(&[
+                permit("GB100001", date),
+                permit("GB100002", date),
+                permit("FR100003", date),
+            ]);
+            let set = ExchangeSet::open(&set)?;
+            let imported = |report: &ImportReport| -> Vec<String> {
+                report.cells.iter().map(|c| c.cell.clone()).collect()
+            };
+
+            let mut client = DataClient::new(HWID, &dir);
+            client.install_permits(&txt[..])?;
+            let producer = ImportOptions::new().filter(CellFilter::Producer("FR".into()));
+            let report = client.import_with(&set, &producer, clock)?;
+            assert_eq!(imported(&report), ["FR100003"]);
+            let cells = ImportOptions::new().filter(CellFilter::Cells(vec!["GB100002".into()]));
+            let report = client.import_with(&set, &cells, clock)?;
+            assert_eq!(imported(&report), ["GB100002"]);
+            assert_eq!(client.installed().len(), 2);
+
+            // an import interrupted after GB100001
+            let mut client = DataClient::new(HWID, &dir);
+            client.install_permits(&txt[..])?;
+            let v = CellVersion {
+                edition: 4,
+                update: 1,
+            };
+            write_checkpoint(&checkpoint, "GB100001", Some(v))?;
+            write_checkpoint(&checkpoint, "GB100004", None)?;
+            assert_eq!(
+                read_checkpoint(&checkpoint)?,
+                [
+                    ("GB100001".to_owned(), Some(v)),
+                    ("GB100004".to_owned(), None)
+                ]
+            );
+            let options = ImportOptions::new().checkpoint(&checkpoint);
+            let report = client.import_with(&set, &options, clock)?;
+            assert_eq!(report.resumed, ["GB100001", "GB100004"]);
+            assert_eq!(imported(&report), ["FR100003", "GB100002"]);
+            assert_eq!(
+                client.installed().get("GB100001").map(|c| c.version),
+                Some(v)
+            );
+            assert!(!checkpoint.exists());
+
+            fs::write(&checkpoint, "GB100001 4\n")?;
+            assert!(client.import_with(&set, &options, clock).is_err());
+            Ok(())
+        })();
+        for d in [&dir, &set] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn workers() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-workers-{}", std::process::id()));
+        let cells: Vec<String> = (1..=8).map(|i| format!("GB10000{}", i)).collect();
+        let paths: Vec<String> = cells
+            .iter()
+            .map(|c| format!("ENC_ROOT/GB/{0}/1/0/{0}.000", c))
+            .collect();
+        let files: Vec<(&str, &[u8])> = paths.iter().map(|p| (p.as_str(), &b"cell"[..])).collect();
+        let set = set("client-workers", &files);
+        let checkpoint = dir.join("import.checkpoint");
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(0, 0, 0).unwrap());
+            let permits: Vec<_> = cells.iter().map(|c| permit(c, date)).collect();
+            let set = ExchangeSet::open(&set)?;
+            let mut client = DataClient::new(HWID, &dir);
+            client.install_permits(&permit_txt(&permits)[..])?;
+
+            let token = CancellationToken::new();
+            token.clone().cancel();
+            fs::create_dir_all(&dir)?;
+            let options = ImportOptions::new()
+                .workers(4)
+                .cancel(token)
+                .checkpoint(&checkpoint);
+            let report = client.import_with(&set, &options, clock)?;
+            assert!(report.cancelled && report.cells.is_empty());
+            assert!(client.installed().is_empty());
+
+            let options = options.cancel(CancellationToken::new());
+            let report = client.import_with(&set, &options, clock)?;
+            assert!(!report.cancelled && report.is_ok());
+            let imported: Vec<_> = report.cells.iter().map(|c| c.cell.clone()).collect();
+            assert_eq!(imported, cells);
+            for c in &cells {
+                assert_eq!(
+                    fs::read(client.cell_dir(c).join(format!("{}.000", c)))?,
+                    b"cell"
+                );
+            }
+            assert!(!checkpoint.exists());
+            Ok(())
+        })();
+        for d in [&dir, &set] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn check_edition() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-edition-{}", std::process::id()));
+        let cell = crate::s57::tests::cell("GB100001.000", 4, 0);
+        let set = set(
+            "client-edition",
+            &[("ENC_ROOT/GB/GB100001/4/0/GB100001.000", &cell[..])],
+        );
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(0, 0, 0).unwrap());
+            let mut p = permit("GB100001", date);
+            p.edition = Some(3);
+            let set = ExchangeSet::open(&set)?;
+            let mut client = DataClient::new(HWID, &dir);
+            client.install_permits(&permit_txt(&[p])[..])?;
+
+            let options = ImportOptions::new().check_edition(true);
+            let report = client.import_with(&set, &options, clock)?;
+            assert_eq!(report.cells[0].status(), ImportStatus::Failed);
+            assert!(matches!(
+                report.cells[0].error,
+                Some(ImportError::Decrypt(decrypter::E::EditionNotPermitted {
+                    permitted: 3,
+                    found: 4
+                }))
+            ));
+            assert!(client.installed().is_empty());
+
+            let report = client.import_with(&set, &ImportOptions::new(), clock)?;
+            assert!(report.is_ok());
+            Ok(())
+        })();
+        for d in [&dir, &set] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[test]
+    fn replace() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-client-replace-{}", std::process::id()));
+        let first = set(
+            "client-replace-1",
+            &[("ENC_ROOT/GB/GB100001/1/0/GB100001.000", b"ed1")],
+        );
+        let second = set(
+            "client-replace-2",
+            &[("ENC_ROOT/GB/GB100001/2/0/GB100001.000", b"ed2")],
+        );
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+            let clock = FixedClock(date.and_hms_opt(0, 0, 0).unwrap());
+            let mut client = DataClient::open(HWID, &dir)?;
+            client.install_permits(&permit_txt(&[permit("GB100001", date)])[..])?;
+            assert!(client
+                .import_at(&ExchangeSet::open(&first)?, clock)?
+                .is_ok());
+            let v1 = CellVersion {
+                edition: 1,
+                update: 0,
+            };
+            let cell = client.cell_dir("GB100001").join("GB100001.000");
+
+            // a file in the way of the new edition's directory
+            let tmp = dir.join("GB100001.tmp");
+            fs::write(&tmp, b"")?;
+            assert!(client
+                .import_at(&ExchangeSet::open(&second)?, clock)
+                .is_err());
+            assert_eq!(fs::read(&cell)?, b"ed1");
+            assert_eq!(client.installed().get("GB100001").unwrap().version, v1);
+            let reopened = DataClient::open(HWID, &dir)?;
+            assert_eq!(reopened.installed().get("GB100001").unwrap().version, v1);
+
+            fs::remove_file(&tmp)?;
+            assert!(client
+                .import_at(&ExchangeSet::open(&second)?, clock)?
+                .is_ok());
+            assert_eq!(fs::read(&cell)?, b"ed2");
+            assert!(!tmp.exists());
+            Ok(())
+        })();
+        for d in [&dir, &first, &second] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+
+    #[cfg(feature = "signature")]
+    #[test]
+    fn signatures() -> Result<(), E> {
+        use crate::signature::{tests::key, CellSigner, PrivateKey};
+        let private = |x: u32| PrivateKey::new(key(x).1, dsa::BigUint::from(x)).unwrap();
+        let (sa, ds) = (private(12345), private(67890));
+        let certificate = sa.certify(ds.public_key()).unwrap();
+        let signer = CellSigner::new(ds, certificate);
+        let base = encrypt("GB100001.000", b"base");
+        let mut sig = Vec::new();
+        signer.sign(&base).unwrap().write(&mut sig).unwrap();
+        let dir = std::env::temp_dir().join(format!("s63-client-sig-{}", std::process::id()));
+        let set = crate::exchange::tests::write_set(
+            "client-sig",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", &base),
+                ("ENC_ROOT/GB/GB100001/4/0/SB100001.000", &sig),
+                (
+                    "ENC_ROOT/GB/GB100002/1/0/GB100002.000",
+                    &encrypt("GB100002.000", b"unsigned"),
+                ),
+            ],
+        );
+        let res = (|| {
+            let date = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+            let events = Arc::new(crate::audit::tests::Events::default());
+            let mut client = DataClient::new(HWID, &dir)
+                .trust_store(sa.public_key().clone())
+                .audit(events.clone());
+            client.install_permits(
+                &permit_txt(&[permit("GB100001", date), permit("GB100002", date)])[..],
+            )?;
+            let res = client
+                .import_exchange_set(&set, &ImportOptions::new())?
+                .cells;
+            assert_eq!(res.len(), 2);
+            assert!(res[0].is_ok());
+            assert!(matches!(
+                res[1].error,
+                Some(ImportError::Decrypt(decrypter::E::SignatureInvalid(_)))
+            ));
+            assert_eq!(res[1].status(), ImportStatus::SignatureFailed);
+            let events = events.take();
+            assert_eq!(
+                events[..4],
+                [
+                    "permit GB100001 expires 2030-01-01 from PERMIT.TXT",
+                    "permit GB100002 expires 2030-01-01 from PERMIT.TXT",
+                    "signature GB100001 ok",
+                    "decryption GB100001 ok key 1",
+                ]
+            );
+            assert!(events[4].starts_with("signature GB100002 invalid: "));
+
+            // a cancellation is verified before the cell is removed
+            let cancel = encrypt("GB100001.001", b"cancel");
+            let unsigned = crate::exchange::tests::write_set(
+                "client-sig-unsigned",
+                &[("ENC_ROOT/GB/GB100001/0/1/GB100001.001", &cancel)],
+            );
+            let res = client.import_exchange_set(&unsigned, &ImportOptions::new());
+            fs::remove_dir_all(&unsigned)?;
+            let res = res?.cells;
+            assert_eq!(res[0].change, CellChange::Cancelled);
+            assert_eq!(res[0].status(), ImportStatus::SignatureFailed);
+            assert!(client.cell_dir("GB100001").join("GB100001.000").exists());
+            assert!(client.permits().get_permit("GB100001").is_some());
+
+            let mut sig = Vec::new();
+            signer.sign(&cancel).unwrap().write(&mut sig).unwrap();
+            let signed = crate::exchange::tests::write_set(
+                "client-sig-signed",
+                &[
+                    ("ENC_ROOT/GB/GB100001/0/1/GB100001.001", &cancel),
+                    ("ENC_ROOT/GB/GB100001/0/1/SB100001.001", &sig),
+                ],
+            );
+            let res = client.import_exchange_set(&signed, &ImportOptions::new());
+            fs::remove_dir_all(&signed)?;
+            assert_eq!(res?.cells[0].status(), ImportStatus::Removed);
+            assert!(!client.cell_dir("GB100001").exists());
+            assert!(client.permits().get_permit("GB100001").is_none());
+            Ok(())
+        })();
+        for d in [&dir, &set] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res
+    }
+}
@@ -0,0 +1,99 @@
+//! Source of the current time, so date dependent logic can be tested
+//!
+//! The functions that depend on the date come in pairs, e.g. `PermitRecord::sse_code` and
+//! `sse_code_at` with a clock, and `DataClient` and `PermitDatabase` take a clock that is
+//! used for everything they do.
+
+use chrono::prelude::*;
+use std::sync::{Arc, Mutex};
+
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+
+    fn today(&self) -> NaiveDate {
+        self.now().date()
+    }
+}
+
+/// the system clock in UTC
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+/// a clock that always returns the same time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedClock(pub NaiveDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> NaiveDateTime {
+        self.0
+    }
+}
+
+/// a clock that is set by hand, shared by its clones, e.g. to simulate days passing or to
+/// replay a log
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<NaiveDateTime>>);
+
+impl ManualClock {
+    pub fn new(now: NaiveDateTime) -> ManualClock {
+        ManualClock(Arc::new(Mutex::new(now)))
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, d: chrono::Duration) {
+        let mut now = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        *now += d;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> NaiveDateTime {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> NaiveDateTime {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> NaiveDateTime {
+        (**self).now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual() {
+        let start = NaiveDate::from_ymd_opt(2020, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let clock = ManualClock::new(start);
+        let shared: Arc<dyn Clock + Send + Sync> = Arc::new(clock.clone());
+        clock.advance(chrono::Duration::days(31));
+        assert_eq!(shared.today(), NaiveDate::from_ymd_opt(2020, 7, 2).unwrap());
+        clock.set(start);
+        assert_eq!(shared.now(), start);
+    }
+}
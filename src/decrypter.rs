@@ -1,34 +1,143 @@
+use crate::audit::{AuditEvent, AuditSink};
+use crate::cipher::{BlockCipher, Blowfish};
+use crate::errors;
 use crate::permit;
-use crypto::blowfish::Blowfish;
-use crypto::symmetriccipher::BlockDecryptor;
+use crate::registry::PermitRegistry;
+use crate::s57::Dsid;
+#[cfg(feature = "signature")]
+use crate::signature::{self, SignatureFile, TrustStore};
+use crc::{crc32, Hasher32};
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, Cursor};
-use zip::read::ZipArchive;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zip::read::{ZipArchive, ZipFile};
+use zip::result::ZipError;
 
-pub struct S63Decrypter<P: permit::GetPermit> {
+// the signature every ZIP archive starts with
+pub(crate) const LOCAL_FILE_HEADER: &[u8] = b"PK\x03\x04";
+
+pub struct S63Decrypter<P: permit::GetPermit, C: BlockCipher = Blowfish> {
     pub permit: P,
+    pub(crate) options: Options,
+    audit: Option<Arc<dyn AuditSink>>,
+    cipher: PhantomData<fn() -> C>,
+}
+
+// the tunables of a decrypter, see `S63DecrypterBuilder`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Options {
+    pub(crate) first_key: CellKey,
+    pub(crate) strict_padding: bool,
+    pub(crate) max_output: Option<u64>,
+    pub(crate) validate_crc: bool,
+    pub(crate) unzipped: bool,
+    pub(crate) check_edition: bool,
+    #[cfg(feature = "signature")]
+    pub(crate) signature_check: SignatureCheck,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            first_key: CellKey::Key1,
+            strict_padding: false,
+            max_output: None,
+            validate_crc: true,
+            unzipped: false,
+            check_edition: false,
+            #[cfg(feature = "signature")]
+            signature_check: SignatureCheck::BeforeDecryption,
+        }
+    }
 }
 
+/// decryption errors, by the stage that failed
 #[derive(Debug)]
 pub enum E {
-    DecryptionFailed,
+    /// none of the keys of the permit decrypted the cell, with the error of the last key
+    DecryptionFailed(Box<E>),
     PermitIsNone,
-    Io(io::Error),
     NoPermit(String),
+    InvalidCellName(String),
+    /// the encrypted data is not a whole number of blocks, it is likely truncated
     NonEightRead,
-    ZipErr(zip::result::ZipError),
+    /// the padding of the last block is inconsistent, only with strict padding
+    InvalidPadding,
+    /// the output would be larger than the maximum size of the decrypter
+    TooLarge(u64),
+    /// the CRC of the data does not match the expected CRC, from the catalogue or the archive
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// reading the encrypted data failed
+    Read(io::Error),
+    /// the decrypted data is not a ZIP archive, the key does not belong to the cell
+    WrongKey,
+    /// the decrypted data is a damaged ZIP archive, the media is likely corrupt
+    CorruptArchive(ZipError),
+    /// reading a file from the archive failed
+    Extract(ZipError),
+    /// writing the decrypted data failed
+    Write(io::Error),
+    /// the decrypted cell is of another edition than the permit is for, only when the
+    /// edition is checked
+    EditionNotPermitted {
+        permitted: u8,
+        found: u32,
+    },
+    /// the signature file doesn't match the encrypted cell or its key isn't certified by
+    /// a trusted SA
+    #[cfg(feature = "signature")]
+    SignatureInvalid(signature::E),
 }
 
-impl From<io::Error> for E {
-    fn from(e: io::Error) -> E {
-        E::Io(e)
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::DecryptionFailed(e) => write!(f, "no key of the permit decrypts the cell: {}", e),
+            E::PermitIsNone => write!(f, "no permit"),
+            E::NoPermit(cell) => write!(f, "no permit for cell {}", cell),
+            E::InvalidCellName(cell) => write!(f, "invalid cell name {}", cell),
+            E::NonEightRead => write!(f, "encrypted data is not a multiple of 8 bytes"),
+            E::InvalidPadding => write!(f, "invalid padding"),
+            E::TooLarge(max) => write!(f, "output larger than {} bytes", max),
+            E::CrcMismatch { expected, actual } => write!(
+                f,
+                "CRC mismatch, expected {:08X} but was {:08X}",
+                expected, actual
+            ),
+            E::Read(e) => write!(f, "reading encrypted data failed: {}", e),
+            E::WrongKey => write!(f, "the key does not decrypt the data"),
+            E::CorruptArchive(e) => write!(f, "corrupt archive: {}", e),
+            E::Extract(e) => write!(f, "extracting from archive failed: {}", e),
+            E::Write(e) => write!(f, "writing decrypted data failed: {}", e),
+            E::EditionNotPermitted { permitted, found } => write!(
+                f,
+                "the permit is for edition {} but the cell is edition {}",
+                permitted, found
+            ),
+            #[cfg(feature = "signature")]
+            E::SignatureInvalid(e) => write!(f, "invalid signature: {}", e),
+        }
     }
 }
 
-impl From<zip::result::ZipError> for E {
-    fn from(e: zip::result::ZipError) -> E {
-        E::ZipErr(e)
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::DecryptionFailed(e) => Some(e.as_ref()),
+            E::Read(e) | E::Write(e) => Some(e),
+            E::CorruptArchive(e) | E::Extract(e) => Some(e),
+            #[cfg(feature = "signature")]
+            E::SignatureInvalid(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
@@ -36,6 +145,18 @@ impl S63Decrypter<permit::EmptyPermit> {
     pub fn new() -> S63Decrypter<permit::EmptyPermit> {
         S63Decrypter {
             permit: permit::EmptyPermit(),
+            options: Options::default(),
+            audit: None,
+            cipher: PhantomData,
+        }
+    }
+
+    pub fn builder() -> S63DecrypterBuilder<permit::EmptyPermit> {
+        S63DecrypterBuilder {
+            permit: permit::EmptyPermit(),
+            options: Options::default(),
+            audit: None,
+            cipher: PhantomData,
         }
     }
 }
@@ -46,45 +167,381 @@ impl Default for S63Decrypter<permit::EmptyPermit> {
     }
 }
 
+impl S63Decrypter<PermitRegistry> {
+    /// creates a decrypter using the permits of a registry saved at `path`
+    pub fn from_registry<F: AsRef<Path>>(path: F, hwid: &str) -> Result<Self, errors::E> {
+        Ok(S63Decrypter::new_with_permit(PermitRegistry::load(
+            path, hwid,
+        )?))
+    }
+}
+
 impl<P: permit::GetPermit> S63Decrypter<P> {
     pub fn new_with_permit(permit: P) -> S63Decrypter<P> {
-        S63Decrypter { permit }
+        S63Decrypter {
+            permit,
+            options: Options::default(),
+            audit: None,
+            cipher: PhantomData,
+        }
     }
+}
 
+impl<P: permit::GetPermit, C: BlockCipher> S63Decrypter<P, C> {
+    /// decrypts the cell with the keys of its permit and writes the first file of the
+    /// archive to `wtr`
     pub fn with_cell<R: Read + Seek, W: Write>(
         &self,
         cell: &str,
         rdr: R,
         mut wtr: W,
-    ) -> Result<(), E> {
-        let mut rdr = BufReader::new(rdr);
+    ) -> Result<DecryptionInfo, E> {
+        if self.options.check_edition {
+            let mut res = Vec::new();
+            let info = self.with_cell_unchecked(cell, rdr, &mut res)?;
+            self.check_permit_edition(cell, &res)?;
+            wtr.write_all(&res).map_err(E::Write)?;
+            return Ok(info);
+        }
+        self.with_cell_unchecked(cell, rdr, wtr)
+    }
+
+    fn with_cell_unchecked<R: Read + Seek, W: Write>(
+        &self,
+        cell: &str,
+        rdr: R,
+        mut wtr: W,
+    ) -> Result<DecryptionInfo, E> {
+        let ((decrypted_len, extracted_name), key_used, attempts) =
+            self.try_keys(cell, BufReader::new(rdr), |key, rdr| {
+                extract_first::<C, _, _>(key, rdr, &mut wtr, &self.options)
+            })?;
+        Ok(DecryptionInfo {
+            key_used,
+            attempts,
+            decrypted_len,
+            extracted_name,
+        })
+    }
+
+    /// checks that the permit of `cell` licenses the edition in the DSID of `decrypted`, a
+    /// decrypted base cell or update. Data that isn't an S-57 file isn't checked. Done by
+    /// `with_cell` when enabled with `S63DecrypterBuilder::check_edition`
+    pub fn check_permit_edition(&self, cell: &str, decrypted: &[u8]) -> Result<(), E> {
         let permit = match self.permit.get_permit(cell) {
-            Some(val) => val,
-            None => return Err(E::NoPermit(String::from(cell))),
+            Some(permit) => permit,
+            None => return Err(E::NoPermit(cell.to_owned())),
+        };
+        match (permit.edition, Dsid::from_bytes(decrypted)) {
+            (Some(permitted), Ok(dsid)) if !permit.permits_edition(dsid.edition) => {
+                Err(E::EditionNotPermitted {
+                    permitted,
+                    found: dsid.edition,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// like `with_cell` but the CRC of the data is checked against `expected`, and nothing
+    /// is written on a mismatch
+    pub fn with_cell_verified<R: Read, W: Write>(
+        &self,
+        cell: &str,
+        mut rdr: R,
+        mut wtr: W,
+        expected: Crc,
+    ) -> Result<DecryptionInfo, E> {
+        let mut enc = Vec::new();
+        rdr.read_to_end(&mut enc).map_err(E::Read)?;
+        if let Crc::Encrypted(crc) = expected {
+            check_crc(crc, &enc)?;
+        }
+        let mut res = Vec::new();
+        let info = self.with_cell(cell, Cursor::new(enc), &mut res)?;
+        if let Crc::Decrypted(crc) = expected {
+            check_crc(crc, &res)?;
+        }
+        wtr.write_all(&res).map_err(E::Write)?;
+        Ok(info)
+    }
+
+    /// like `with_cell` but the signature of the encrypted cell is checked with `signature`,
+    /// whose data server key must be certified by one of the SA keys of `sa`. Fails with
+    /// `SignatureInvalid` and writes nothing if it doesn't match. Whether it is checked
+    /// before or after decrypting is set with `S63DecrypterBuilder::signature_check`
+    #[cfg(feature = "signature")]
+    pub fn with_cell_signed<R, W, T>(
+        &self,
+        cell: &str,
+        mut rdr: R,
+        mut wtr: W,
+        signature: &SignatureFile,
+        sa: &T,
+    ) -> Result<DecryptionInfo, E>
+    where
+        R: Read,
+        W: Write,
+        T: TrustStore + ?Sized,
+    {
+        let mut enc = Vec::new();
+        rdr.read_to_end(&mut enc).map_err(E::Read)?;
+        let check = || {
+            let res = signature.verify(sa, &enc);
+            if let Some(audit) = &self.audit {
+                audit.record(&AuditEvent::Signature {
+                    cell,
+                    error: res.as_ref().err(),
+                });
+            }
+            res.map_err(E::SignatureInvalid)
         };
-        for (i, key) in permit.cell_permit.keys().enumerate() {
+        let before = self.options.signature_check == SignatureCheck::BeforeDecryption;
+        if before {
+            check()?;
+        }
+        let mut res = Vec::new();
+        let info = self.with_cell(cell, Cursor::new(&enc), &mut res)?;
+        if !before {
+            check()?;
+        }
+        wtr.write_all(&res).map_err(E::Write)?;
+        Ok(info)
+    }
+
+    /// like `with_cell` but the cell is read from a memory mapping of the file at `path`.
+    /// The file must not be changed while it is decrypted
+    #[cfg(feature = "mmap")]
+    pub fn with_cell_path<F: AsRef<Path>, W: Write>(
+        &self,
+        cell: &str,
+        path: F,
+        wtr: W,
+    ) -> Result<DecryptionInfo, E> {
+        let file = std::fs::File::open(path).map_err(E::Read)?;
+        // safety: the mapping is only read, and the caller keeps the file unchanged
+        let map = unsafe { memmap2::Mmap::map(&file) }.map_err(E::Read)?;
+        self.with_cell(cell, Cursor::new(&map[..]), wtr)
+    }
+
+    /// like `with_cell` but every file in the archive is returned
+    pub fn with_cell_entries<R: Read + Seek>(
+        &self,
+        cell: &str,
+        rdr: R,
+    ) -> Result<Vec<ArchiveEntry>, E> {
+        self.try_keys(cell, BufReader::new(rdr), |key, rdr| {
+            self.with_key_entries(key, rdr)
+        })
+        .map(|(res, _, _)| res)
+    }
+
+    /// like `with_cell` but every file in the archive is passed to `sink` with its name
+    pub fn with_cell_each<R, F>(&self, cell: &str, rdr: R, mut sink: F) -> Result<usize, E>
+    where
+        R: Read + Seek,
+        F: FnMut(&str, &mut dyn Read) -> io::Result<()>,
+    {
+        self.try_keys(cell, BufReader::new(rdr), |key, rdr| {
+            self.with_key_each(key, rdr, &mut sink)
+        })
+        .map(|(res, _, _)| res)
+    }
+
+    /// like `with_cell` but every file in the archive is written under its name in `dir`,
+    /// see `with_key_to_dir`
+    pub fn with_cell_to_dir<R: Read + Seek, D: AsRef<Path>>(
+        &self,
+        cell: &str,
+        rdr: R,
+        dir: D,
+    ) -> Result<Vec<PathBuf>, E> {
+        self.try_keys(cell, BufReader::new(rdr), |key, rdr| {
+            self.with_key_to_dir(key, rdr, dir.as_ref())
+        })
+        .map(|(res, _, _)| res)
+    }
+
+    // calls `f` with each key of the cell's permit until one succeeds, returns the result,
+    // the key and the number of keys tried
+    fn try_keys<R, T, F>(&self, cell: &str, rdr: R, f: F) -> Result<(T, CellKey, usize), E>
+    where
+        R: Read + Seek,
+        F: FnMut(&[u8], &mut R) -> Result<T, E>,
+    {
+        let res = self.try_keys_unaudited(cell, rdr, f);
+        self.audit_decryption(cell, &res);
+        res
+    }
+
+    // records the outcome of decrypting `cell` with the audit sink
+    pub(crate) fn audit_decryption<T>(&self, cell: &str, res: &Result<(T, CellKey, usize), E>) {
+        if let Some(audit) = &self.audit {
+            audit.record(&AuditEvent::Decryption {
+                cell,
+                key: res.as_ref().ok().map(|(_, key, _)| *key),
+                error: res.as_ref().err(),
+            });
+        }
+    }
+
+    // the keys of the permit of `cell` in the order they are tried
+    pub(crate) fn cell_keys(&self, cell: &str) -> Result<Vec<(CellKey, &[u8])>, E> {
+        let permit = match self.permit.get_permit_checked(cell) {
+            Ok(Some(val)) => val,
+            Ok(None) => return Err(E::NoPermit(String::from(cell))),
+            Err(_) => return Err(E::InvalidCellName(String::from(cell))),
+        };
+        let mut keys: Vec<_> = [CellKey::Key1, CellKey::Key2]
+            .iter()
+            .copied()
+            .zip(permit.cell_permit.keys().map(|k| &k[..]))
+            .collect();
+        if self.options.first_key == CellKey::Key2 {
+            keys.reverse();
+        }
+        Ok(keys)
+    }
+
+    fn try_keys_unaudited<R, T, F>(
+        &self,
+        cell: &str,
+        mut rdr: R,
+        mut f: F,
+    ) -> Result<(T, CellKey, usize), E>
+    where
+        R: Read + Seek,
+        F: FnMut(&[u8], &mut R) -> Result<T, E>,
+    {
+        let mut err = E::WrongKey;
+        for (i, (which, key)) in self.cell_keys(cell)?.into_iter().enumerate() {
             if i != 0 {
-                rdr.seek(std::io::SeekFrom::Start(0))?;
+                rdr.seek(std::io::SeekFrom::Start(0)).map_err(E::Read)?;
             }
-            match self.with_key(key, &mut rdr, &mut wtr) {
-                Ok(_) => return Ok(()),
-                Err(_) => continue,
+            match f(key, &mut rdr) {
+                Ok(res) => return Ok((res, which, i + 1)),
+                // only a wrong key is worth trying the other key for
+                Err(e @ E::WrongKey) => err = e,
+                Err(e) => return Err(e),
             }
         }
 
-        Err(E::DecryptionFailed)
+        Err(E::DecryptionFailed(Box::new(err)))
     }
 
-    pub fn with_key<R: Read, W: Write>(&self, key: &[u8], mut rdr: R, mut wtr: W) -> Result<(), E> {
-        let mut zipfile = Vec::new();
-        decrypt_into(key, &mut rdr, &mut zipfile)?;
-        let mut archive = match ZipArchive::new(Cursor::new(zipfile)) {
-            Ok(archive) => archive,
-            Err(_) => return Err(E::DecryptionFailed),
+    pub fn with_key<R: Read, W: Write>(&self, key: &[u8], rdr: R, wtr: W) -> Result<(), E> {
+        extract_first::<C, _, _>(key, rdr, wtr, &self.options).map(|_| ())
+    }
+
+    /// decrypts the archive and returns all files in it, in archive order
+    pub fn with_key_entries<R: Read>(&self, key: &[u8], rdr: R) -> Result<Vec<ArchiveEntry>, E> {
+        let mut archive = decrypt_archive::<C, _>(key, rdr, &self.options)?;
+        let mut res = Vec::new();
+        for i in 0..archive.len() {
+            let mut zf = archive.by_index(i).map_err(E::Extract)?;
+            if zf.is_dir() {
+                continue;
+            }
+            let mut data = Vec::new();
+            extract(&mut zf, &mut data, &self.options)?;
+            res.push(ArchiveEntry {
+                name: zf.name().to_owned(),
+                data,
+            });
+        }
+        Ok(res)
+    }
+
+    /// decrypts the archive and passes each file in it to `sink` with its name, directories
+    /// are skipped. Returns the number of files
+    pub fn with_key_each<R, F>(&self, key: &[u8], rdr: R, mut sink: F) -> Result<usize, E>
+    where
+        R: Read,
+        F: FnMut(&str, &mut dyn Read) -> io::Result<()>,
+    {
+        let mut archive = decrypt_archive::<C, _>(key, rdr, &self.options)?;
+        let mut n = 0;
+        for i in 0..archive.len() {
+            let mut zf = archive.by_index(i).map_err(E::Extract)?;
+            if zf.is_dir() {
+                continue;
+            }
+            // the sink reads the file itself, so only the size in the archive can be checked
+            check_size(zf.size(), self.options.max_output)?;
+            let name = zf.name().to_owned();
+            sink(&name, &mut Extracted::new(&mut zf, &self.options)).map_err(E::Write)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// decrypts the archive and writes every file in it under its name in `dir`, creating
+    /// directories as needed. Returns the paths of the written files. Names that would end
+    /// up outside of `dir` are an error
+    pub fn with_key_to_dir<R: Read, D: AsRef<Path>>(
+        &self,
+        key: &[u8],
+        rdr: R,
+        dir: D,
+    ) -> Result<Vec<PathBuf>, E> {
+        let mut archive = decrypt_archive::<C, _>(key, rdr, &self.options)?;
+        let mut res = Vec::new();
+        for i in 0..archive.len() {
+            let mut zf = archive.by_index(i).map_err(E::Extract)?;
+            let path = match zf.enclosed_name() {
+                Some(name) => dir.as_ref().join(name),
+                None => {
+                    return Err(E::Extract(ZipError::InvalidArchive(
+                        "file name outside of the archive",
+                    )))
+                }
+            };
+            if zf.is_dir() {
+                fs::create_dir_all(&path).map_err(E::Write)?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(E::Write)?;
+            }
+            let mut file = fs::File::create(&path).map_err(E::Write)?;
+            extract(&mut zf, &mut file, &self.options)?;
+            res.push(path);
+        }
+        Ok(res)
+    }
+
+    /// like `with_cell` but the cell is decrypted and unzipped while it is read, see
+    /// `with_key_streaming`
+    pub fn with_cell_streaming<R: Read + Seek, W: Write>(
+        &self,
+        cell: &str,
+        rdr: R,
+        mut wtr: W,
+    ) -> Result<(), E> {
+        self.try_keys(cell, rdr, |key, rdr| {
+            self.with_key_streaming(key, rdr, &mut wtr)
+        })
+        .map(|(res, _, _)| res)
+    }
+
+    /// like `with_key` but with memory use independent of the cell size. The ZIP is read
+    /// from its local headers, so archives whose entry sizes are only given in a trailing
+    /// data descriptor can't be read this way
+    pub fn with_key_streaming<R: Read, W: Write>(
+        &self,
+        key: &[u8],
+        rdr: R,
+        mut wtr: W,
+    ) -> Result<(), E> {
+        let mut dec = DecryptReader::with_cipher(C::new(key), rdr)
+            .strict_padding(self.options.strict_padding);
+        let mut zf = match zip::read::read_zipfile_from_stream(&mut dec) {
+            Ok(Some(zf)) => zf,
+            Ok(None) => return Err(E::Extract(ZipError::FileNotFound)),
+            Err(ZipError::Io(e)) => return Err(decrypt_err(e)),
+            Err(_) => return Err(E::WrongKey),
         };
-        let mut zf = archive.by_index(0)?;
-        std::io::copy(&mut zf, &mut wtr)?;
-        Ok(())
+        extract(&mut zf, &mut wtr, &self.options).map(|_| ())
     }
 
     pub fn with_key_bytes<D: AsRef<[u8]>>(&self, key: &[u8], data: D) -> Result<Vec<u8>, E> {
@@ -101,32 +558,471 @@ impl<P: permit::GetPermit> S63Decrypter<P> {
         Ok(res)
     }
 
+    /// only decrypts and depads the data, returning the still compressed ZIP archive. The
+    /// key is not checked, a wrong key gives random bytes
+    pub fn decrypt_raw<R: Read>(&self, key: &[u8], rdr: R) -> Result<Vec<u8>, E> {
+        let mut res = Vec::new();
+        decrypt_into::<C, _, _>(key, rdr, &mut res, &self.options)?;
+        Ok(res)
+    }
+
+    /// whether `key` decrypts `data` into a ZIP archive. Only the first block is decrypted,
+    /// so this is cheap but doesn't find damage further into the data
     pub fn can_decrypt<D: AsRef<[u8]>>(&self, key: &[u8], data: D) -> bool {
-        self.with_key_bytes(key, data).is_ok()
+        self.can_decrypt_reader(key, data.as_ref()).unwrap_or(false)
+    }
+
+    /// like `can_decrypt` but only the first block is read from `rdr`
+    pub fn can_decrypt_reader<R: Read>(&self, key: &[u8], mut rdr: R) -> Result<bool, E> {
+        let mut enc = [0u8; 8];
+        match rdr.read_exact(&mut enc) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(E::Read(e)),
+        }
+        let mut dec = [0u8; 8];
+        C::new(key).decrypt_block(&enc, &mut dec);
+        Ok(dec.starts_with(LOCAL_FILE_HEADER))
+    }
+}
+
+/// which of the two cell keys of a permit decrypted a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellKey {
+    Key1,
+    Key2,
+}
+
+/// the expected CRC32 of a cell, for `S63Decrypter::with_cell_verified`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crc {
+    /// of the encrypted file as delivered, as listed in the catalogue of an exchange set
+    Encrypted(u32),
+    /// of the cell file after it is decrypted and unzipped
+    Decrypted(u32),
+}
+
+/// when `S63Decrypter::with_cell_signed` checks the signature
+#[cfg(feature = "signature")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureCheck {
+    /// nothing is decrypted from a cell with an invalid signature
+    BeforeDecryption,
+    /// decryption errors, e.g. a missing permit, are reported before an invalid signature
+    AfterDecryption,
+}
+
+fn check_crc(expected: u32, data: &[u8]) -> Result<(), E> {
+    let actual = crc::crc32::checksum_ieee(data);
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(E::CrcMismatch { expected, actual })
     }
 }
 
-fn decrypt_into<R: Read, W: Write>(key: &[u8], rdr: &mut R, wtr: &mut W) -> Result<(), E> {
-    let crypto = Blowfish::new(&key);
-    let mut enc = [0u8; 8];
-    let mut dec = [0u8; 8];
-    let mut first = true;
+/// configures a `S63Decrypter`, the defaults are those of `S63Decrypter::new`
+pub struct S63DecrypterBuilder<P: permit::GetPermit, C: BlockCipher = Blowfish> {
+    permit: P,
+    options: Options,
+    audit: Option<Arc<dyn AuditSink>>,
+    cipher: PhantomData<fn() -> C>,
+}
+
+impl<P: permit::GetPermit, C: BlockCipher> S63DecrypterBuilder<P, C> {
+    pub fn permit<Q: permit::GetPermit>(self, permit: Q) -> S63DecrypterBuilder<Q, C> {
+        S63DecrypterBuilder {
+            permit,
+            options: self.options,
+            audit: self.audit,
+            cipher: PhantomData,
+        }
+    }
+
+    /// decrypt with `D` instead of Blowfish
+    pub fn cipher<D: BlockCipher>(self) -> S63DecrypterBuilder<P, D> {
+        S63DecrypterBuilder {
+            permit: self.permit,
+            options: self.options,
+            audit: self.audit,
+            cipher: PhantomData,
+        }
+    }
+
+    /// the key tried first, defaults to key 1. Trying key 2 first saves a decryption
+    /// once the data server has moved on to new keys
+    pub fn first_key(mut self, key: CellKey) -> Self {
+        self.options.first_key = key;
+        self
+    }
+
+    /// reject data whose last block ends in 1 to 8 without being padded with that many
+    /// bytes of that value, instead of treating it as unpadded
+    pub fn strict_padding(mut self, strict: bool) -> Self {
+        self.options.strict_padding = strict;
+        self
+    }
+
+    /// limits the size of the decrypted archive and of each file extracted from it
+    pub fn max_output_size(mut self, max: u64) -> Self {
+        self.options.max_output = Some(max);
+        self
+    }
+
+    /// check the CRCs of the files in the archive when they are extracted, on by default. A
+    /// mismatch is a `CrcMismatch` error, with this off the file is written as is
+    pub fn validate_zip_crc(mut self, validate: bool) -> Self {
+        self.options.validate_crc = validate;
+        self
+    }
+
+    /// also accept encrypted files that are not zipped. `with_cell` and `with_key` then
+    /// write the depadded data as is when it doesn't start with a ZIP header. A wrong key
+    /// can't be told apart from such a file, so with this on the second key is never tried
+    pub fn allow_unzipped(mut self, allow: bool) -> Self {
+        self.options.unzipped = allow;
+        self
+    }
+
+    /// check that the edition of decrypted cells is the one their permit is for, see
+    /// `S63Decrypter::check_permit_edition`. Off by default, the output is then buffered
+    pub fn check_edition(mut self, check: bool) -> Self {
+        self.options.check_edition = check;
+        self
+    }
+
+    /// when `with_cell_signed` checks the signature, defaults to before decrypting
+    #[cfg(feature = "signature")]
+    pub fn signature_check(mut self, when: SignatureCheck) -> Self {
+        self.options.signature_check = when;
+        self
+    }
+
+    /// records every decryption and signature check in `sink`
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    pub fn build(self) -> S63Decrypter<P, C> {
+        S63Decrypter {
+            permit: self.permit,
+            options: self.options,
+            audit: self.audit,
+            cipher: PhantomData,
+        }
+    }
+}
+
+/// details of a successful `S63Decrypter::with_cell`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecryptionInfo {
+    /// a cell decrypted with key 2 means the data server has moved on to a new key
+    pub key_used: CellKey,
+    /// the number of keys tried, including the one that succeeded
+    pub attempts: usize,
+    /// the size of the decrypted archive, before unzipping
+    pub decrypted_len: u64,
+    /// the name of the file in the archive that was written, `None` for data that was not
+    /// zipped, see `S63DecrypterBuilder::allow_unzipped`
+    pub extracted_name: Option<String>,
+}
+
+/// a file in the archive of a decrypted cell
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// decrypts the whole input and opens it as a ZIP archive
+fn decrypt_archive<C: BlockCipher, R: Read>(
+    key: &[u8],
+    rdr: R,
+    opts: &Options,
+) -> Result<ZipArchive<Cursor<Vec<u8>>>, E> {
+    let mut zipfile = Vec::new();
+    decrypt_into::<C, _, _>(key, rdr, &mut zipfile, opts)?;
+    open_archive(zipfile)
+}
+
+// data decrypted with the wrong key is random and won't contain the end of central
+// directory record, data that does but can't be read is a damaged archive
+pub(crate) fn open_archive(zipfile: Vec<u8>) -> Result<ZipArchive<Cursor<Vec<u8>>>, E> {
+    const END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+    let has_end = zipfile
+        .windows(END_OF_CENTRAL_DIRECTORY.len())
+        .rev()
+        .take(u16::MAX as usize + 22)
+        .any(|w| w == END_OF_CENTRAL_DIRECTORY);
+    ZipArchive::new(Cursor::new(zipfile)).map_err(|e| match e {
+        _ if !has_end => E::WrongKey,
+        e => E::CorruptArchive(e),
+    })
+}
+
+// copies at most `max` bytes, telling read and write errors apart
+fn copy<R: Read, W: Write>(
+    rdr: &mut R,
+    wtr: &mut W,
+    max: Option<u64>,
+    read_err: fn(io::Error) -> E,
+) -> Result<u64, E> {
+    let mut buf = [0u8; 8192];
+    let mut n = 0;
     loop {
-        let b = rdr.read(&mut enc)?;
-        if b == 0 {
-            break;
+        let b = match rdr.read(&mut buf) {
+            Ok(0) => return Ok(n),
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_err(e)),
+        };
+        n += b as u64;
+        check_size(n, max)?;
+        wtr.write_all(&buf[..b]).map_err(E::Write)?;
+    }
+}
+
+pub(crate) fn check_size(n: u64, max: Option<u64>) -> Result<(), E> {
+    match max {
+        Some(max) if n > max => Err(E::TooLarge(max)),
+        _ => Ok(()),
+    }
+}
+
+fn extract_err(e: io::Error) -> E {
+    E::Extract(ZipError::Io(e))
+}
+
+// a file read from an archive with the CRC of what was read. zip fails a file whose CRC
+// doesn't match once it is read to the end, unless the options validate CRCs such a
+// mismatch then ends the file instead
+struct Extracted<'a, 'b> {
+    zf: &'a mut ZipFile<'b>,
+    crc: crc32::Digest,
+    len: u64,
+    validate_crc: bool,
+}
+
+impl<'a, 'b> Extracted<'a, 'b> {
+    fn new(zf: &'a mut ZipFile<'b>, opts: &Options) -> Extracted<'a, 'b> {
+        Extracted {
+            zf,
+            crc: crc32::Digest::new(crc32::IEEE),
+            len: 0,
+            validate_crc: opts.validate_crc,
         }
+    }
 
-        if !first {
-            first = false
+    // the CRC error of a file read to the end whose CRC doesn't match
+    fn crc_mismatch(&self) -> Option<E> {
+        let (expected, actual) = (self.zf.crc32(), self.crc.sum32());
+        if self.len == self.zf.size() && actual != expected {
+            Some(E::CrcMismatch { expected, actual })
         } else {
-            wtr.write_all(&dec)?;
+            None
         }
-        crypto.decrypt_block(&enc, &mut dec);
     }
-    wtr.write_all(depad(&dec))?;
+}
+
+impl Read for Extracted<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.zf.read(buf) {
+            Ok(n) => {
+                self.crc.write(&buf[..n]);
+                self.len += n as u64;
+                Ok(n)
+            }
+            Err(_) if !self.validate_crc && self.crc_mismatch().is_some() => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// copies a file read from an archive to `wtr`
+fn extract<W: Write>(zf: &mut ZipFile, wtr: &mut W, opts: &Options) -> Result<u64, E> {
+    let mut ex = Extracted::new(zf, opts);
+    copy(&mut ex, wtr, opts.max_output, extract_err).map_err(|e| ex.crc_mismatch().unwrap_or(e))
+}
+
+// errors of `DecryptReader` about the encrypted data get their own variants
+pub(crate) fn decrypt_err(e: io::Error) -> E {
+    match e.get_ref().and_then(|e| e.downcast_ref::<BlockErr>()) {
+        Some(BlockErr::Partial) => E::NonEightRead,
+        Some(BlockErr::Padding) => E::InvalidPadding,
+        None => E::Read(e),
+    }
+}
+
+// writes the first file of the archive, returns the archive size and the file name.
+// Data without a ZIP header is written as is if the options allow unzipped data
+fn extract_first<C: BlockCipher, R: Read, W: Write>(
+    key: &[u8],
+    rdr: R,
+    mut wtr: W,
+    opts: &Options,
+) -> Result<(u64, Option<String>), E> {
+    let mut zipfile = Vec::new();
+    let len = decrypt_into::<C, _, _>(key, rdr, &mut zipfile, opts)?;
+    if opts.unzipped && !zipfile.starts_with(LOCAL_FILE_HEADER) {
+        wtr.write_all(&zipfile).map_err(E::Write)?;
+        return Ok((len, None));
+    }
+    let mut archive = open_archive(zipfile)?;
+    let mut zf = archive.by_index(0).map_err(E::Extract)?;
+    extract(&mut zf, &mut wtr, opts)?;
+    Ok((len, Some(zf.name().to_owned())))
+}
+
+// decrypts and depads all of `rdr` into `wtr`, returns the decrypted size
+fn decrypt_into<C: BlockCipher, R: Read, W: Write>(
+    key: &[u8],
+    rdr: R,
+    wtr: &mut W,
+    opts: &Options,
+) -> Result<u64, E> {
+    let mut dec = DecryptReader::with_cipher(C::new(key), rdr).strict_padding(opts.strict_padding);
+    copy(&mut dec, wtr, opts.max_output, decrypt_err)
+}
+
+/// decrypts S-63 encrypted data while it is read, removing the padding at the end.
+/// Input that is not a whole number of blocks is an `InvalidData` error
+pub struct DecryptReader<R: Read, C: BlockCipher = Blowfish> {
+    rdr: R,
+    crypto: C,
+    // the decrypted block being returned, `pos..len` is left
+    block: [u8; 8],
+    pos: usize,
+    len: usize,
+    // the following block, held back until it's known if it is the last one
+    next: Option<[u8; 8]>,
+    started: bool,
+    strict: bool,
+}
+
+// why `DecryptReader` rejected the encrypted data
+#[derive(Debug)]
+enum BlockErr {
+    Partial,
+    Padding,
+}
+
+impl fmt::Display for BlockErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockErr::Partial => write!(f, "encrypted data is not a multiple of the block size"),
+            BlockErr::Padding => write!(f, "invalid padding"),
+        }
+    }
+}
+
+impl std::error::Error for BlockErr {}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(key: &[u8], rdr: R) -> DecryptReader<R> {
+        DecryptReader::with_cipher(Blowfish::new(key), rdr)
+    }
+}
+
+impl<R: Read, C: BlockCipher> DecryptReader<R, C> {
+    pub fn with_cipher(crypto: C, rdr: R) -> DecryptReader<R, C> {
+        DecryptReader {
+            rdr,
+            crypto,
+            block: [0; 8],
+            pos: 0,
+            len: 0,
+            next: None,
+            started: false,
+            strict: false,
+        }
+    }
+
+    /// reject a last block ending in 1 to 8 that isn't padded with that many bytes of that
+    /// value with an `InvalidData` error, instead of returning it as unpadded
+    pub fn strict_padding(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    // reads and decrypts one block, None at the end of the input
+    fn read_block(&mut self) -> io::Result<Option<[u8; 8]>> {
+        let mut enc = [0u8; 8];
+        match read_full(&mut self.rdr, &mut enc)? {
+            0 => Ok(None),
+            8 => {
+                let mut dec = [0u8; 8];
+                self.crypto.decrypt_block(&enc, &mut dec);
+                Ok(Some(dec))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BlockErr::Partial,
+            )),
+        }
+    }
+
+    // moves the next block into `block`, returns false at the end
+    fn fill(&mut self) -> io::Result<bool> {
+        if !self.started {
+            self.started = true;
+            self.next = self.read_block()?;
+        }
+        let current = match self.next.take() {
+            Some(b) => b,
+            None => return Ok(false),
+        };
+        self.next = self.read_block()?;
+        self.block = current;
+        self.pos = 0;
+        self.len = match self.next {
+            Some(_) => 8,
+            None => {
+                let len = depad(&current).len();
+                if self.strict && len == 8 && (1..=8).contains(&current[7]) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        BlockErr::Padding,
+                    ));
+                }
+                len
+            }
+        };
+        Ok(true)
+    }
+}
+
+impl<R: Read, C: BlockCipher> Read for DecryptReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.len {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
 
-    Ok(())
+// like `read_exact` but the end of the input is not an error, returns the number of bytes
+// read which is less than `buf.len()` only at the end of the input
+fn read_full<R: Read>(rdr: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match rdr.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(b) => n += b,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
 }
 
 fn depad(data: &[u8]) -> &[u8] {
@@ -145,8 +1041,558 @@ fn depad(data: &[u8]) -> &[u8] {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+
+    // zips `data` as a single entry and encrypts it as done by a data server
+    fn encrypt(key: &[u8], name: &str, data: &[u8]) -> Vec<u8> {
+        encrypt_entries(key, &[(name, data)])
+    }
+
+    fn encrypt_entries(key: &[u8], entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        encrypt_plain(key, zip.finish().unwrap().into_inner())
+    }
+
+    pub(crate) fn encrypt_plain(key: &[u8], mut plain: Vec<u8>) -> Vec<u8> {
+        let pad = 8 - plain.len() % 8;
+        plain.extend(std::iter::repeat_n(pad as u8, pad));
+        encrypt_blocks(key, &plain)
+    }
+
+    fn encrypt_blocks(key: &[u8], plain: &[u8]) -> Vec<u8> {
+        let crypto = Blowfish::new(key);
+        let mut res = vec![0u8; plain.len()];
+        for (p, e) in plain.chunks(8).zip(res.chunks_mut(8)) {
+            crypto.encrypt_block(p, e);
+        }
+        res
+    }
+
+    #[test]
+    fn zip_crc() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("GB100001.000", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"cell").unwrap();
+        let mut plain = zip.finish().unwrap().into_inner();
+        // the CRC is in the local header and in the central directory
+        let crc = crc::crc32::checksum_ieee(b"cell").to_le_bytes();
+        for i in 0..plain.len() - 3 {
+            if plain[i..i + 4] == crc {
+                plain[i] ^= 1;
+            }
+        }
+        let enc = encrypt_plain(&key, plain);
+
+        let d = S63Decrypter::new();
+        assert!(matches!(
+            d.with_key_bytes(&key, &enc),
+            Err(E::CrcMismatch { .. })
+        ));
+        let mut out = Vec::new();
+        assert!(matches!(
+            d.with_key_streaming(&key, &enc[..], &mut out),
+            Err(E::CrcMismatch { .. })
+        ));
+
+        let d = S63Decrypter::builder().validate_zip_crc(false).build();
+        assert_eq!(d.with_key_bytes(&key, &enc)?, b"cell");
+        let mut out = Vec::new();
+        d.with_key_streaming(&key, &enc[..], &mut out)?;
+        assert_eq!(out, b"cell");
+        Ok(())
+    }
+
+    // a reader returning at most 3 bytes per read
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn streaming() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let enc = encrypt(&key, "GB100001.000", &data);
+        let d = S63Decrypter::new();
+
+        let mut out = Vec::new();
+        d.with_key_streaming(&key, Trickle(enc.as_slice()), &mut out)?;
+        assert_eq!(out, data);
+        assert_eq!(d.with_key_bytes(&key, &enc)?, data);
+        assert!(matches!(
+            d.with_key_streaming(&[5, 4, 3, 2, 1], enc.as_slice(), Vec::new()),
+            Err(E::WrongKey)
+        ));
+        assert!(matches!(
+            d.with_key_streaming(&key, &enc[..51], Vec::new()),
+            Err(E::NonEightRead)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn all_entries() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let enc = encrypt_entries(
+            &key,
+            &[("GB100001.000", b"cell"), ("README.TXT", b"readme")],
+        );
+        let d = S63Decrypter::new();
+        let entries = d.with_key_entries(&key, enc.as_slice())?;
+        assert_eq!(
+            entries,
+            vec![
+                ArchiveEntry {
+                    name: String::from("GB100001.000"),
+                    data: b"cell".to_vec()
+                },
+                ArchiveEntry {
+                    name: String::from("README.TXT"),
+                    data: b"readme".to_vec()
+                },
+            ]
+        );
+
+        let mut names = Vec::new();
+        let n = d.with_key_each(&key, enc.as_slice(), |name, _| {
+            names.push(name.to_owned());
+            Ok(())
+        })?;
+        assert_eq!(n, 2);
+        assert_eq!(names, vec!["GB100001.000", "README.TXT"]);
+        Ok(())
+    }
+
+    #[test]
+    fn decryption_info() -> Result<(), E> {
+        let p = crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+        let d = S63Decrypter::new_with_permit(vec![p]);
+
+        let enc = encrypt(&[6, 7, 8, 9, 10], "GB100001.000", b"cell");
+        let mut out = Vec::new();
+        let info = d.with_cell("GB100001", Cursor::new(&enc), &mut out)?;
+        assert_eq!(out, b"cell");
+        assert_eq!(info.key_used, CellKey::Key2);
+        assert_eq!(info.attempts, 2);
+        assert_eq!(info.extracted_name.as_deref(), Some("GB100001.000"));
+        assert_eq!(
+            info.decrypted_len,
+            d.decrypt_raw(&[6, 7, 8, 9, 10], enc.as_slice())?.len() as u64
+        );
+
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", b"cell");
+        let info = d.with_cell("GB100001", Cursor::new(&enc), Vec::new())?;
+        assert_eq!(info.key_used, CellKey::Key1);
+        assert_eq!(info.attempts, 1);
+
+        let enc = encrypt(&[1, 1, 1, 1, 1], "GB100001.000", b"cell");
+        let err = d.with_cell("GB100001", Cursor::new(&enc), Vec::new());
+        assert!(matches!(err, Err(E::DecryptionFailed(e)) if matches!(*e, E::WrongKey)));
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_raw() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let d = S63Decrypter::new();
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+
+        let raw = d.decrypt_raw(&key, enc.as_slice())?;
+        assert!(raw.starts_with(b"PK\x03\x04"));
+        let mut archive = ZipArchive::new(Cursor::new(raw)).unwrap();
+        let mut data = Vec::new();
+        archive
+            .by_name("GB100001.000")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"cell");
+        Ok(())
+    }
+
+    #[test]
+    fn builder() -> Result<(), E> {
+        let p = crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+        let d = S63Decrypter::builder()
+            .permit(vec![p])
+            .first_key(CellKey::Key2)
+            .max_output_size(300)
+            .build();
+
+        let enc = encrypt(&[6, 7, 8, 9, 10], "GB100001.000", b"cell");
+        let info = d.with_cell("GB100001", Cursor::new(&enc), Vec::new())?;
+        assert_eq!(info.key_used, CellKey::Key2);
+        assert_eq!(info.attempts, 1);
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", b"cell");
+        let info = d.with_cell("GB100001", Cursor::new(&enc), Vec::new())?;
+        assert_eq!(info.key_used, CellKey::Key1);
+        assert_eq!(info.attempts, 2);
+
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", &[0; 1000]);
+        assert!(matches!(
+            d.with_cell("GB100001", Cursor::new(&enc), Vec::new()),
+            Err(E::TooLarge(300))
+        ));
+        assert!(matches!(
+            d.with_key_streaming(&[1, 2, 3, 4, 5], enc.as_slice(), Vec::new()),
+            Err(E::TooLarge(300))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn check_edition() -> Result<(), E> {
+        let permit = |edition: Option<u8>| {
+            let mut p = crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+            p.edition = edition;
+            p
+        };
+        let decrypter = |edition| {
+            S63Decrypter::builder()
+                .permit(vec![permit(edition)])
+                .check_edition(true)
+                .build()
+        };
+        let cell = crate::s57::tests::cell("GB100001.000", 4, 0);
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", &cell);
+
+        let mut out = Vec::new();
+        decrypter(Some(4)).with_cell("GB100001", Cursor::new(&enc), &mut out)?;
+        assert_eq!(out, cell);
+        decrypter(None).with_cell("GB100001", Cursor::new(&enc), Vec::new())?;
+        let mut out = Vec::new();
+        let res = decrypter(Some(3)).with_cell("GB100001", Cursor::new(&enc), &mut out);
+        assert!(matches!(
+            res,
+            Err(E::EditionNotPermitted {
+                permitted: 3,
+                found: 4
+            })
+        ));
+        assert!(out.is_empty());
+        let cancel = crate::s57::tests::cell("GB100001.005", 0, 5);
+        decrypter(Some(3)).check_permit_edition("GB100001", &cancel)?;
+        decrypter(Some(3)).check_permit_edition("GB100001", b"not a cell")?;
+        S63Decrypter::new_with_permit(vec![permit(Some(3))]).with_cell(
+            "GB100001",
+            Cursor::new(&enc),
+            Vec::new(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn audit() {
+        let events = Arc::new(crate::audit::tests::Events::default());
+        let permit = crate::permit::test_permit("GB100001", &[9, 9, 9, 9, 9], &[1, 2, 3, 4, 5]);
+        let decrypter = S63Decrypter::builder()
+            .permit(vec![permit])
+            .audit(events.clone())
+            .build();
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", b"cell");
+        let info = decrypter
+            .with_cell("GB100001", Cursor::new(&enc), Vec::new())
+            .unwrap();
+        assert_eq!(info.key_used, CellKey::Key2);
+        assert!(decrypter
+            .with_cell("GB100002", Cursor::new(&enc), Vec::new())
+            .is_err());
+        let events = events.take();
+        assert_eq!(events[0], "decryption GB100001 ok key 2");
+        assert!(events[1].starts_with("decryption GB100002 failed: "));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[cfg(feature = "signature")]
+    #[test]
+    fn with_cell_signed() -> Result<(), E> {
+        use crate::signature::{tests::key, CellSigner, PrivateKey};
+        let permit = || crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+        let private = |x: u32| PrivateKey::new(key(x).1, dsa::BigUint::from(x)).unwrap();
+        let (sa, ds) = (private(12345), private(67890));
+        let certificate = sa.certify(ds.public_key()).unwrap();
+        let signer = CellSigner::new(ds, certificate);
+
+        let enc = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", b"cell");
+        let sig = signer.sign(&enc).unwrap();
+        let d = S63Decrypter::new_with_permit(vec![permit()]);
+        let mut out = Vec::new();
+        d.with_cell_signed("GB100001", enc.as_slice(), &mut out, &sig, sa.public_key())?;
+        assert_eq!(out, b"cell");
+
+        let other = encrypt(&[1, 2, 3, 4, 5], "GB100001.000", b"other");
+        let mut out = Vec::new();
+        let res = d.with_cell_signed(
+            "GB100001",
+            other.as_slice(),
+            &mut out,
+            &sig,
+            &sig.public_key,
+        );
+        assert!(matches!(res, Err(E::SignatureInvalid(_))));
+        assert!(out.is_empty());
+        let sa_keys = vec![key(777).1];
+        let res = d.with_cell_signed("GB100002", enc.as_slice(), Vec::new(), &sig, &sa_keys);
+        assert!(matches!(res, Err(E::SignatureInvalid(_))));
+
+        let d = S63Decrypter::builder()
+            .permit(vec![permit()])
+            .signature_check(SignatureCheck::AfterDecryption)
+            .build();
+        let res = d.with_cell_signed("GB100002", enc.as_slice(), Vec::new(), &sig, &sa_keys);
+        assert!(matches!(res, Err(E::NoPermit(_))));
+        let res = d.with_cell_signed("GB100001", enc.as_slice(), Vec::new(), &sig, &sa_keys);
+        assert!(matches!(res, Err(E::SignatureInvalid(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn can_decrypt() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+        let d = S63Decrypter::new();
+        assert!(d.can_decrypt(&key, &enc));
+        assert!(!d.can_decrypt(&[5, 4, 3, 2, 1], &enc));
+        assert!(!d.can_decrypt(&key, &enc[..7]));
+        assert!(d.can_decrypt_reader(&key, Trickle(&enc[..8]))?);
+        assert!(
+            !d.can_decrypt_reader(&key, encrypt_plain(&key, b"not zipped".to_vec()).as_slice())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn with_cell_verified() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let p = crate::permit::test_permit("GB100001", &key, &key);
+        let d = S63Decrypter::new_with_permit(vec![p]);
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+        let enc_crc = crc::crc32::checksum_ieee(&enc);
+        let cell_crc = crc::crc32::checksum_ieee(b"cell");
+
+        let mut out = Vec::new();
+        d.with_cell_verified(
+            "GB100001",
+            enc.as_slice(),
+            &mut out,
+            Crc::Encrypted(enc_crc),
+        )?;
+        assert_eq!(out, b"cell");
+        let mut out = Vec::new();
+        d.with_cell_verified(
+            "GB100001",
+            enc.as_slice(),
+            &mut out,
+            Crc::Decrypted(cell_crc),
+        )?;
+        assert_eq!(out, b"cell");
+
+        let mut out = Vec::new();
+        let res = d.with_cell_verified(
+            "GB100001",
+            enc.as_slice(),
+            &mut out,
+            Crc::Decrypted(enc_crc),
+        );
+        assert!(matches!(
+            res,
+            Err(E::CrcMismatch { expected, actual }) if expected == enc_crc && actual == cell_crc
+        ));
+        assert!(out.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn to_dir() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let d = S63Decrypter::new();
+        let dir = std::env::temp_dir().join(format!("s63-to-dir-{}", std::process::id()));
+        let enc = encrypt_entries(
+            &key,
+            &[("GB100001.000", b"cell"), ("INFO/README.TXT", b"readme")],
+        );
+        let res = d.with_key_to_dir(&key, enc.as_slice(), &dir);
+        let files: Vec<_> = res.iter().flatten().map(|f| fs::read(f).unwrap()).collect();
+        let evil = encrypt(&key, "../GB100001.000", b"cell");
+        let evil = d.with_key_to_dir(&key, evil.as_slice(), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            res?,
+            [
+                dir.join("GB100001.000"),
+                dir.join("INFO").join("README.TXT")
+            ]
+        );
+        assert_eq!(files, [b"cell".to_vec(), b"readme".to_vec()]);
+        assert!(matches!(evil, Err(E::Extract(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn partial_block() {
+        let key = [1, 2, 3, 4, 5];
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+        let d = S63Decrypter::new();
+        for len in [1, 7, enc.len() - 1] {
+            assert!(matches!(
+                d.with_key_bytes(&key, &enc[..len]),
+                Err(E::NonEightRead)
+            ));
+            assert!(matches!(
+                d.decrypt_raw(&key, &enc[..len]),
+                Err(E::NonEightRead)
+            ));
+        }
+        let err = DecryptReader::new(&key, &enc[..9])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn strict_padding() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let enc = encrypt_blocks(&key, &[1, 2, 3, 4, 5, 6, 7, 3]);
+        let lenient = S63Decrypter::new();
+        assert_eq!(
+            lenient.decrypt_raw(&key, enc.as_slice())?,
+            [1, 2, 3, 4, 5, 6, 7, 3]
+        );
+        let strict = S63Decrypter::builder().strict_padding(true).build();
+        assert!(matches!(
+            strict.decrypt_raw(&key, enc.as_slice()),
+            Err(E::InvalidPadding)
+        ));
+
+        let enc = encrypt_blocks(&key, &[1, 2, 3, 4, 5, 3, 3, 3]);
+        assert_eq!(strict.decrypt_raw(&key, enc.as_slice())?, [1, 2, 3, 4, 5]);
+        let enc = encrypt_blocks(&key, &[1, 2, 3, 4, 5, 6, 7, 9]);
+        assert_eq!(strict.decrypt_raw(&key, enc.as_slice())?.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn unzipped() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let enc = encrypt_plain(&key, b"not zipped".to_vec());
+        let d = S63Decrypter::new();
+        assert!(matches!(d.with_key_bytes(&key, &enc), Err(E::WrongKey)));
+
+        let d = S63Decrypter::builder().allow_unzipped(true).build();
+        assert_eq!(d.with_key_bytes(&key, &enc)?, b"not zipped");
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+        assert_eq!(d.with_key_bytes(&key, &enc)?, b"cell");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn with_cell_path() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let p = crate::permit::test_permit("GB100001", &key, &key);
+        let d = S63Decrypter::new_with_permit(vec![p]);
+
+        let path = std::env::temp_dir().join(format!("s63-mmap-{}.000", std::process::id()));
+        std::fs::write(&path, encrypt(&key, "GB100001.000", b"cell")).unwrap();
+        let mut out = Vec::new();
+        let res = d.with_cell_path("GB100001", &path, &mut out);
+        std::fs::remove_file(&path).unwrap();
+        res?;
+        assert_eq!(out, b"cell");
+        Ok(())
+    }
+
+    #[test]
+    fn stages() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let d = S63Decrypter::new();
+        let enc = encrypt(&key, "GB100001.000", b"cell");
+        assert!(matches!(
+            d.with_key_bytes(&[1, 1, 1, 1, 1], &enc),
+            Err(E::WrongKey)
+        ));
+
+        // the file data is damaged but the central directory is intact
+        let mut damaged = enc.clone();
+        damaged[44] ^= 0xff;
+        assert!(matches!(
+            d.with_key_bytes(&key, &damaged),
+            Err(E::Extract(_))
+        ));
+
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = d.with_key(&key, enc.as_slice(), Full).unwrap_err();
+        assert!(matches!(err, E::Write(_)));
+        assert_eq!(err.to_string(), "writing decrypted data failed: disk full");
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_reader() -> io::Result<()> {
+        let key = [1, 2, 3, 4, 5];
+        let crypto = Blowfish::new(&key);
+        let mut enc = [0u8; 16];
+        crypto.encrypt_block(&[1, 2, 3, 4, 5, 6, 7, 8], &mut enc[..8]);
+        crypto.encrypt_block(&[9, 10, 11, 12, 13, 3, 3, 3], &mut enc[8..]);
+        let mut out = Vec::new();
+        DecryptReader::new(&key, Trickle(&enc[..])).read_to_end(&mut out)?;
+        assert_eq!(out, (1..=13).collect::<Vec<u8>>());
+
+        let mut out = Vec::new();
+        DecryptReader::new(&key, &[][..]).read_to_end(&mut out)?;
+        assert!(out.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_into_blocks() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let opts = Options::default();
+        let decrypt = |enc: &[u8]| -> Result<Vec<u8>, E> {
+            let mut out = Vec::new();
+            let n = decrypt_into::<Blowfish, _, _>(&key, Trickle(enc), &mut out, &opts)?;
+            assert_eq!(n, out.len() as u64);
+            Ok(out)
+        };
+
+        // no blocks
+        assert!(decrypt(&[])?.is_empty());
+        // one block, padded, unpadded and all padding
+        assert_eq!(decrypt(&encrypt_plain(&key, vec![1, 2, 3]))?, [1, 2, 3]);
+        assert_eq!(
+            decrypt(&encrypt_blocks(&key, &[1, 2, 3, 4, 5, 6, 7, 9]))?,
+            [1, 2, 3, 4, 5, 6, 7, 9]
+        );
+        assert!(decrypt(&encrypt_blocks(&key, &[8; 8]))?.is_empty());
+        // many blocks, the padding is only removed from the last one
+        let data: Vec<u8> = (0..100).map(|i| if i % 9 == 0 { 3 } else { i }).collect();
+        assert_eq!(decrypt(&encrypt_plain(&key, data.clone()))?, data);
+        let data = vec![4u8; 64];
+        assert_eq!(decrypt(&encrypt_plain(&key, data.clone()))?, data);
+        Ok(())
+    }
+
     #[test]
     fn test_depad() {
         let mut data = depad(&[1, 2, 3, 4, 5, 6, 7, 8]);
@@ -165,6 +1611,19 @@ mod tests {
         assert_eq!(data, [1]);
 
         data = depad(&[8, 8, 8, 8, 8, 8, 8, 8]);
-        assert_eq!(data, []);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn invalid_cell_name() {
+        let d = S63Decrypter::new();
+        assert!(matches!(
+            d.with_cell_bytes("gb10001", []),
+            Err(E::InvalidCellName(_))
+        ));
+        assert!(matches!(
+            d.with_cell_bytes("GB100001", []),
+            Err(E::NoPermit(_))
+        ));
     }
 }
@@ -0,0 +1,209 @@
+use crate::cipher::{BlockCipher, Blowfish};
+use crate::decrypter::CellKey;
+use crate::permit;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::marker::PhantomData;
+use zip::result::ZipError;
+use zip::write::{FileOptions, ZipWriter};
+
+/// zips and encrypts cells so that `S63Decrypter` can decrypt them, for the data server side
+pub struct S63Encrypter<P: permit::GetPermit, C: BlockCipher = Blowfish> {
+    pub permit: P,
+    cipher: PhantomData<fn() -> C>,
+}
+
+/// encryption errors, by the stage that failed
+#[derive(Debug)]
+pub enum E {
+    NoPermit(String),
+    InvalidCellName(String),
+    /// reading the plain data failed
+    Read(io::Error),
+    /// zipping the data failed
+    Zip(ZipError),
+    /// writing the encrypted data failed
+    Write(io::Error),
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::NoPermit(cell) => write!(f, "no permit for cell {}", cell),
+            E::InvalidCellName(cell) => write!(f, "invalid cell name {}", cell),
+            E::Read(e) => write!(f, "reading plain data failed: {}", e),
+            E::Zip(e) => write!(f, "zipping failed: {}", e),
+            E::Write(e) => write!(f, "writing encrypted data failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::Read(e) | E::Write(e) => Some(e),
+            E::Zip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl S63Encrypter<permit::EmptyPermit> {
+    pub fn new() -> S63Encrypter<permit::EmptyPermit> {
+        S63Encrypter {
+            permit: permit::EmptyPermit(),
+            cipher: PhantomData,
+        }
+    }
+}
+
+impl Default for S63Encrypter<permit::EmptyPermit> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: permit::GetPermit> S63Encrypter<P> {
+    pub fn new_with_permit(permit: P) -> S63Encrypter<P> {
+        S63Encrypter {
+            permit,
+            cipher: PhantomData,
+        }
+    }
+}
+
+impl<P: permit::GetPermit, C: BlockCipher> S63Encrypter<P, C> {
+    /// encrypt with `D` instead of Blowfish
+    pub fn cipher<D: BlockCipher>(self) -> S63Encrypter<P, D> {
+        S63Encrypter {
+            permit: self.permit,
+            cipher: PhantomData,
+        }
+    }
+
+    /// encrypts the file `name`, e.g. GB100001.000, with a key from the permit of its cell,
+    /// the part of the name before the dot
+    pub fn with_cell<R: Read, W: Write>(
+        &self,
+        name: &str,
+        key: CellKey,
+        rdr: R,
+        wtr: W,
+    ) -> Result<(), E> {
+        let cell = name.split('.').next().unwrap_or(name);
+        let permit = match self.permit.get_permit_checked(cell) {
+            Ok(Some(val)) => val,
+            Ok(None) => return Err(E::NoPermit(String::from(cell))),
+            Err(_) => return Err(E::InvalidCellName(String::from(cell))),
+        };
+        let key = match key {
+            CellKey::Key1 => &permit.cell_permit.key1,
+            CellKey::Key2 => &permit.cell_permit.key2,
+        };
+        self.with_key(key, name, rdr, wtr)
+    }
+
+    /// zips the data as the file `name` and encrypts the archive with `key`
+    pub fn with_key<R: Read, W: Write>(
+        &self,
+        key: &[u8],
+        name: &str,
+        mut rdr: R,
+        wtr: W,
+    ) -> Result<(), E> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(name, FileOptions::default())
+            .map_err(E::Zip)?;
+        io::copy(&mut rdr, &mut zip).map_err(E::Read)?;
+        let zipfile = zip.finish().map_err(E::Zip)?.into_inner();
+        encrypt_into(&C::new(key), &zipfile, wtr)
+    }
+
+    pub fn with_key_bytes<D: AsRef<[u8]>>(
+        &self,
+        key: &[u8],
+        name: &str,
+        data: D,
+    ) -> Result<Vec<u8>, E> {
+        let mut res = Vec::new();
+        self.with_key(key, name, data.as_ref(), &mut res)?;
+        Ok(res)
+    }
+
+    pub fn with_cell_bytes<D: AsRef<[u8]>>(
+        &self,
+        name: &str,
+        key: CellKey,
+        data: D,
+    ) -> Result<Vec<u8>, E> {
+        let mut res = Vec::new();
+        self.with_cell(name, key, data.as_ref(), &mut res)?;
+        Ok(res)
+    }
+}
+
+// encrypts `data` in blocks of 8, the last block is padded with the number of padding
+// bytes when `data` is not a multiple of 8
+fn encrypt_into<C: BlockCipher, W: Write>(crypto: &C, data: &[u8], mut wtr: W) -> Result<(), E> {
+    let mut enc = [0u8; 8];
+    for chunk in data.chunks(8) {
+        let mut block = [(8 - chunk.len()) as u8; 8];
+        block[..chunk.len()].copy_from_slice(chunk);
+        crypto.encrypt_block(&block, &mut enc);
+        wtr.write_all(&enc).map_err(E::Write)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypter::S63Decrypter;
+
+    #[test]
+    fn round_trip() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let enc = S63Encrypter::new().with_key_bytes(&key, "GB100001.000", &data)?;
+        assert_eq!(enc.len() % 8, 0);
+
+        let d = S63Decrypter::new();
+        assert_eq!(d.with_key_bytes(&key, &enc).unwrap(), data);
+        let mut out = Vec::new();
+        d.with_key_streaming(&key, enc.as_slice(), &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        Ok(())
+    }
+
+    fn permit() -> crate::permit::PermitRecord {
+        crate::permit::test_permit("GB100001", &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10])
+    }
+
+    #[test]
+    fn with_cell() -> Result<(), E> {
+        let e = S63Encrypter::new_with_permit(vec![permit()]);
+        let enc = e.with_cell_bytes("GB100001.001", CellKey::Key2, b"update")?;
+
+        let d = S63Decrypter::new_with_permit(vec![permit()]);
+        let mut out = Vec::new();
+        let info = d
+            .with_cell("GB100001", Cursor::new(&enc), &mut out)
+            .unwrap();
+        assert_eq!(out, b"update");
+        assert_eq!(info.key_used, CellKey::Key2);
+        assert_eq!(info.extracted_name.as_deref(), Some("GB100001.001"));
+
+        assert!(matches!(
+            e.with_cell_bytes("GB100002.000", CellKey::Key1, b""),
+            Err(E::NoPermit(_))
+        ));
+        assert!(matches!(
+            e.with_cell_bytes("gb1.000", CellKey::Key1, b""),
+            Err(E::InvalidCellName(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,17 +1,25 @@
+// the `failure` derive expands to impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 use chrono::ParseError;
 use failure::Fail;
+use std::fmt;
 use std::io;
 use std::num::ParseIntError;
 #[derive(Debug, Fail)]
 pub enum E {
     #[fail(display = "ParseError: {}", _0)]
     InvalidDate(#[cause] ParseError),
-    #[fail(display = "Invalid DATE field: {}", _0)]
+    #[fail(display = "Invalid cell permit: {}", _0)]
     ParseCellPermit(CPReason),
+    #[fail(display = "{}", _0)]
+    Parse(Box<ParseContext>),
     #[fail(display = "Invalid DATE field: {}", _0)]
     ParseDateError(String),
     #[fail(display = "Invalid VERSION field: {}", _0)]
     ParseVersionError(String),
+    #[fail(display = "Unsupported permit file version: {}", _0)]
+    UnsupportedVersion(u8),
     #[fail(display = "IO Error: {}", _0)]
     IoErr(#[cause] io::Error),
     #[fail(display = "ParseIntError: {}", _0)]
@@ -22,16 +30,122 @@ pub enum E {
     InvalidSli,
     #[fail(display = "Invalid Checksum")]
     InvalidChksum,
+    #[fail(display = "Invalid cell name: {}", _0)]
+    InvalidCellName(String),
+    #[fail(display = "Invalid HW_ID: {}", _0)]
+    InvalidHwid(String),
+    #[fail(display = "Invalid key length {}, expects length 5", _0)]
+    InvalidKeyLength(usize),
+    #[fail(display = "Missing field: {}", _0)]
+    MissingField(&'static str),
+    #[fail(display = "Invalid field: {}", _0)]
+    InvalidField(String),
+    #[fail(display = "Permit store error: {}", _0)]
+    Store(String),
     #[fail(display = "HexError: {}", _0)]
     FromHex(hex::FromHexError),
 }
 
+/// a field of a permit row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    CellPermit,
+    Cell,
+    Date,
+    Eck1,
+    Eck2,
+    Checksum,
+    Sli,
+    Edition,
+    DataServerId,
+    Comment,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Field::CellPermit => "cell permit",
+            Field::Cell => "cell name",
+            Field::Date => "expiry date",
+            Field::Eck1 => "ECK1",
+            Field::Eck2 => "ECK2",
+            Field::Checksum => "checksum",
+            Field::Sli => "service level indicator",
+            Field::Edition => "edition",
+            Field::DataServerId => "data server id",
+            Field::Comment => "comment",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// where in a permit file parsing failed
+#[derive(Debug)]
+pub struct ParseContext {
+    /// 1-based line number, if parsed from a file
+    pub line: Option<usize>,
+    /// the raw line, if parsed from a file
+    pub raw: Option<String>,
+    pub field: Field,
+    pub cause: E,
+}
+
+impl fmt::Display for ParseContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "invalid {}: {}", self.field, self.cause)?;
+        if let Some(raw) = &self.raw {
+            write!(f, " in {:?}", raw)?;
+        }
+        Ok(())
+    }
+}
+
+impl E {
+    /// adds the field that failed to parse
+    pub(crate) fn in_field(self, field: Field) -> E {
+        match self {
+            E::Parse(_) => self,
+            cause => E::Parse(Box::new(ParseContext {
+                line: None,
+                raw: None,
+                field,
+                cause,
+            })),
+        }
+    }
+
+    /// adds the line number and raw line to a parse error
+    pub(crate) fn at_line(self, line: usize, raw: &str) -> E {
+        match self {
+            E::Parse(mut ctx) => {
+                ctx.line = Some(line);
+                ctx.raw = Some(raw.trim_end().to_owned());
+                E::Parse(ctx)
+            }
+            e => e,
+        }
+    }
+
+    /// the underlying error without the parse context
+    pub fn root(&self) -> &E {
+        match self {
+            E::Parse(ctx) => ctx.cause.root(),
+            e => e,
+        }
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum CPReason {
     #[fail(display = "Invalid Date format {}", _0)]
     Date(#[cause] ParseError),
     #[fail(display = "Invalid length {}, expects length 64", _0)]
     Length(usize),
+    #[fail(display = "Non-ASCII characters")]
+    NonAscii,
 }
 
 impl From<ParseError> for E {
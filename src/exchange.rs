@@ -0,0 +1,509 @@
+//! S-63 exchange sets, the media layout with ENC_ROOT that cells are delivered in
+
+use crate::cell::CellName;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use zip::read::ZipArchive;
+use zip::result::ZipError;
+
+mod builder;
+mod catalog;
+mod coverage;
+mod decrypt;
+mod diff;
+#[cfg(feature = "iso")]
+mod iso;
+mod media;
+mod products;
+mod sequence;
+mod serial;
+mod status;
+mod verify;
+pub use self::builder::*;
+pub use self::catalog::*;
+pub use self::coverage::*;
+pub use self::decrypt::*;
+pub use self::diff::*;
+pub use self::media::*;
+pub use self::products::*;
+pub use self::sequence::*;
+pub use self::serial::*;
+pub use self::status::*;
+pub use self::verify::*;
+
+const ENC_ROOT: &str = "ENC_ROOT";
+const SERIAL: &str = "SERIAL.ENC";
+const PRODUCTS: &str = "INFO/PRODUCTS.TXT";
+const CATALOG: &str = "ENC_ROOT/CATALOG.031";
+// the first line of an S-63 signature file
+const SIGNATURE_HEADER: &[u8] = b"// Signature part R:";
+
+#[derive(Debug)]
+pub enum E {
+    Io(io::Error),
+    /// there is no ENC_ROOT directory
+    NotAnExchangeSet(String),
+    /// the file is not in the exchange set
+    NoFile(String),
+    /// the exchange set archive can't be read
+    Zip(ZipError),
+    /// a file of the exchange set or media doesn't follow its format
+    Invalid {
+        file: &'static str,
+        reason: String,
+    },
+}
+
+impl From<io::Error> for E {
+    fn from(e: io::Error) -> E {
+        E::Io(e)
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::Io(e) => write!(f, "IO error: {}", e),
+            E::NotAnExchangeSet(s) => write!(f, "no ENC_ROOT in {}", s),
+            E::NoFile(s) => write!(f, "no file {} in the exchange set", s),
+            E::Zip(e) => write!(f, "invalid exchange set archive: {}", e),
+            E::Invalid { file, reason } => write!(f, "invalid {}: {}", file, reason),
+        }
+    }
+}
+
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::Io(e) => Some(e),
+            E::Zip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// where the files of an exchange set are read from. Paths are relative to the directory
+// containing ENC_ROOT and separated by '/'
+trait Source {
+    fn files(&self) -> io::Result<Vec<String>>;
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+struct DirSource(PathBuf);
+
+impl Source for DirSource {
+    fn files(&self) -> io::Result<Vec<String>> {
+        let mut res = Vec::new();
+        let mut dirs = vec![(self.0.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => prefix.clone() + &name,
+                    Err(_) => continue,
+                };
+                if entry.file_type()?.is_dir() {
+                    dirs.push((entry.path(), name + "/"));
+                } else {
+                    res.push(name);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.0.join(path))
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.0.join(path))
+    }
+}
+
+// an exchange set in a ZIP archive, possibly in a directory of the archive
+struct ZipSource<R> {
+    archive: RefCell<ZipArchive<R>>,
+    // the directory containing ENC_ROOT, with a trailing '/'
+    prefix: String,
+}
+
+impl<R: Read + Seek> ZipSource<R> {
+    fn new(rdr: R) -> Result<ZipSource<R>, E> {
+        let archive = ZipArchive::new(rdr).map_err(E::Zip)?;
+        let prefix = archive
+            .file_names()
+            .filter_map(|name| {
+                let upper = name.to_ascii_uppercase();
+                let i = upper.find("ENC_ROOT/")?;
+                if i == 0 || upper[..i].ends_with('/') {
+                    Some(name[..i].to_owned())
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|prefix| prefix.len())
+            .unwrap_or_default();
+        Ok(ZipSource {
+            archive: RefCell::new(archive),
+            prefix,
+        })
+    }
+}
+
+impl<R: Read + Seek> Source for ZipSource<R> {
+    fn files(&self) -> io::Result<Vec<String>> {
+        Ok(self
+            .archive
+            .borrow()
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .filter_map(|name| name.strip_prefix(self.prefix.as_str()))
+            .map(String::from)
+            .collect())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut archive = self.archive.borrow_mut();
+        let mut zf = archive.by_name(&(self.prefix.clone() + path))?;
+        let mut res = Vec::new();
+        zf.read_to_end(&mut res)?;
+        Ok(res)
+    }
+}
+
+fn in_enc_root(path: &str) -> bool {
+    path.get(..ENC_ROOT.len() + 1)
+        .is_some_and(|p| p.eq_ignore_ascii_case("ENC_ROOT/"))
+}
+
+/// an encrypted cell file of an exchange set, a base cell or an update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellFile {
+    pub cell: String,
+    /// from the directory layout ENC_ROOT/<producer>/<cell>/<edition>/<update>, when the
+    /// exchange set follows it
+    pub edition: Option<u32>,
+    /// the file extension, 0 for a base cell
+    pub update: u32,
+    /// the path in the exchange set
+    pub path: String,
+}
+
+impl CellFile {
+    // a file named like GB100001.000 under ENC_ROOT
+    fn from_path(path: &str) -> Option<CellFile> {
+        let mut parts = path.rsplit('/');
+        let name = parts.next()?;
+        let (cell, ext) = name.split_at(name.find('.')?);
+        let ext = &ext[1..];
+        if ext.len() != 3 || !ext.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let cell = CellName::new(&cell.to_ascii_uppercase()).ok()?;
+        let edition = parts.nth(1).and_then(|e| e.parse().ok());
+        Some(CellFile {
+            cell: cell.into(),
+            edition,
+            update: ext.parse().ok()?,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn is_base(&self) -> bool {
+        self.update == 0
+    }
+
+    /// a cancellation update, an update of edition 0. The cell is withdrawn and is to be
+    /// removed with its permit
+    pub fn is_cancellation(&self) -> bool {
+        self.edition == Some(0) && self.update > 0
+    }
+
+    /// the path of the signature file of the cell file, the file name with its first letter
+    /// replaced by S, e.g. ENC_ROOT/GB/GB100001/4/0/SB100001.000
+    pub fn signature_path(&self) -> String {
+        let i = self.path.rfind('/').map_or(0, |i| i + 1);
+        format!("{}S{}", &self.path[..i], &self.path[i + 1..])
+    }
+}
+
+/// an exchange set with SERIAL.ENC, INFO/PRODUCTS.TXT, ENC_ROOT/CATALOG.031 and the cells
+/// under ENC_ROOT. Names are matched regardless of case
+pub struct ExchangeSet {
+    source: Box<dyn Source>,
+    files: Vec<String>,
+    cells: Vec<CellFile>,
+    // the base cells in PRODUCTS.TXT by cell, read when first needed
+    listed_bases: OnceCell<HashMap<String, Product>>,
+}
+
+impl ExchangeSet {
+    /// opens the exchange set in `dir`, either the directory containing ENC_ROOT or
+    /// ENC_ROOT itself
+    pub fn open<D: AsRef<Path>>(dir: D) -> Result<ExchangeSet, E> {
+        let dir = dir.as_ref();
+        let is_root = |d: &Path| {
+            d.file_name()
+                .is_some_and(|n| n.to_string_lossy().eq_ignore_ascii_case(ENC_ROOT))
+        };
+        let root = if is_root(dir) {
+            dir.parent().unwrap_or(dir).to_owned()
+        } else {
+            dir.to_owned()
+        };
+        ExchangeSet::from_source(Box::new(DirSource(root)), &dir.display().to_string())
+    }
+
+    /// opens an exchange set delivered as a ZIP archive, ENC_ROOT may be in a directory of
+    /// the archive. Files are read from the archive when needed
+    pub fn from_zip<R: Read + Seek + 'static>(rdr: R) -> Result<ExchangeSet, E> {
+        ExchangeSet::from_source(Box::new(ZipSource::new(rdr)?), "archive")
+    }
+
+    fn from_source(source: Box<dyn Source>, name: &str) -> Result<ExchangeSet, E> {
+        let mut files = source.files()?;
+        files.sort();
+        if !files.iter().any(|f| in_enc_root(f)) {
+            return Err(E::NotAnExchangeSet(name.to_owned()));
+        }
+        let candidates: Vec<_> = files
+            .iter()
+            .filter(|f| in_enc_root(f))
+            .filter_map(|f| CellFile::from_path(f))
+            .collect();
+        // signature files are named like cells and the signature path of an S-prefixed cell
+        // such as SE3AI6X1 is its own, so they are told apart by content
+        let signatures: HashSet<_> = candidates.iter().map(CellFile::signature_path).collect();
+        let mut cells = Vec::with_capacity(candidates.len());
+        for c in candidates {
+            if !signatures.contains(&c.path) || !source.read(&c.path)?.starts_with(SIGNATURE_HEADER)
+            {
+                cells.push(c);
+            }
+        }
+        cells.sort_by(|a, b| (&a.cell, a.edition, a.update).cmp(&(&b.cell, b.edition, b.update)));
+        Ok(ExchangeSet {
+            source,
+            files,
+            cells,
+            listed_bases: OnceCell::new(),
+        })
+    }
+
+    /// all files of the exchange set, sorted
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// the cell files, sorted on cell, edition and update
+    pub fn cells(&self) -> impl Iterator<Item = &CellFile> {
+        self.cells.iter()
+    }
+
+    /// the files of `cell`
+    pub fn cell<'a>(&'a self, cell: &'a str) -> impl Iterator<Item = &'a CellFile> + 'a {
+        self.cells.iter().filter(move |c| c.cell == cell)
+    }
+
+    /// the cancellation updates of the exchange set, see `CellFile::is_cancellation`
+    pub fn cancellations(&self) -> impl Iterator<Item = &CellFile> {
+        self.cells.iter().filter(|c| c.is_cancellation())
+    }
+
+    fn find(&self, path: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|f| f.eq_ignore_ascii_case(path))
+            .map(|f| f.as_str())
+    }
+
+    pub fn serial_enc(&self) -> Option<&str> {
+        self.find(SERIAL)
+    }
+
+    pub fn products_txt(&self) -> Option<&str> {
+        self.find(PRODUCTS)
+    }
+
+    pub fn catalog(&self) -> Option<&str> {
+        self.find(CATALOG)
+    }
+
+    /// reads a file of the exchange set, `path` as given by `files` or a `CellFile`
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, E> {
+        let path = self.find(path).ok_or_else(|| E::NoFile(path.to_owned()))?;
+        Ok(self.source.read(path)?)
+    }
+
+    /// where a file is on disk, for exchange sets opened from a directory and `None` for
+    /// other exchange sets
+    pub fn local_path(&self, path: &str) -> Option<PathBuf> {
+        self.source.local_path(self.find(path)?)
+    }
+}
+
+impl fmt::Debug for ExchangeSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExchangeSet")
+            .field("files", &self.files.len())
+            .field("cells", &self.cells.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // writes a small exchange set to a new directory, remove it when done
+    pub(crate) fn write_set(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s63-{}-{}", name, std::process::id()));
+        for (path, data) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn open_dir() -> Result<(), E> {
+        let dir = write_set(
+            "exchange-dir",
+            &[
+                ("SERIAL.ENC", b"serial"),
+                ("INFO/PRODUCTS.TXT", b"products"),
+                ("ENC_ROOT/CATALOG.031", b"catalog"),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"update"),
+                ("ENC_ROOT/GB/GB100002/1/0/gb100002.000", b"lower"),
+                ("ENC_ROOT/GB/README.TXT", b"text"),
+            ],
+        );
+        let set = ExchangeSet::open(&dir);
+        let from_enc_root = ExchangeSet::open(dir.join("ENC_ROOT")).map(|s| s.files().len());
+        let not_a_set = ExchangeSet::open(dir.join("INFO"));
+        let res = set.and_then(|set| {
+            let cells: Vec<_> = set.cells().cloned().collect();
+            let serial = set.read(set.serial_enc().unwrap())?;
+            Ok((set, cells, serial))
+        });
+        fs::remove_dir_all(&dir).unwrap();
+        let (set, cells, serial) = res?;
+
+        assert_eq!(set.files().len(), 7);
+        assert_eq!(from_enc_root?, 7);
+        assert!(matches!(not_a_set, Err(E::NotAnExchangeSet(_))));
+        assert_eq!(set.products_txt(), Some("INFO/PRODUCTS.TXT"));
+        assert_eq!(set.catalog(), Some("ENC_ROOT/CATALOG.031"));
+        assert_eq!(serial, b"serial");
+        assert_eq!(
+            cells[..2],
+            [
+                CellFile {
+                    cell: "GB100001".into(),
+                    edition: Some(4),
+                    update: 0,
+                    path: "ENC_ROOT/GB/GB100001/4/0/GB100001.000".into(),
+                },
+                CellFile {
+                    cell: "GB100001".into(),
+                    edition: Some(4),
+                    update: 1,
+                    path: "ENC_ROOT/GB/GB100001/4/1/GB100001.001".into(),
+                },
+            ]
+        );
+        assert_eq!(cells[2].cell, "GB100002");
+        assert_eq!(
+            cells[0].signature_path(),
+            "ENC_ROOT/GB/GB100001/4/0/SB100001.000"
+        );
+        assert_eq!(set.cell("GB100001").count(), 2);
+        assert_eq!(
+            set.local_path(&cells[0].path),
+            Some(dir.join("ENC_ROOT/GB/GB100001/4/0/GB100001.000"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn s_prefixed_cells() -> Result<(), E> {
+        let dir = write_set(
+            "s-prefixed-cells",
+            &[
+                ("ENC_ROOT/SE/SE3AI6X1/1/0/SE3AI6X1.000", b"cell"),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"cell"),
+                (
+                    "ENC_ROOT/GB/GB100001/4/0/SB100001.000",
+                    b"// Signature part R:\r\n",
+                ),
+            ],
+        );
+        let set = ExchangeSet::open(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cells: Vec<_> = set?.cells().map(|c| c.path.clone()).collect();
+        assert_eq!(
+            cells,
+            [
+                "ENC_ROOT/GB/GB100001/4/0/GB100001.000",
+                "ENC_ROOT/SE/SE3AI6X1/1/0/SE3AI6X1.000",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn other_products() -> Result<(), E> {
+        let dir = write_set(
+            "other-products",
+            &[("ENC_ROOT/GB/GB800001/1/0/GB800001.000", b"aio")],
+        );
+        let set = ExchangeSet::open(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let cells: Vec<_> = set?.cells().map(|c| c.cell.clone()).collect();
+        assert_eq!(cells, ["GB800001"]);
+        Ok(())
+    }
+
+    #[test]
+    fn open_zip() -> Result<(), E> {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in [
+            ("AVCS/SERIAL.ENC", &b"serial"[..]),
+            ("AVCS/ENC_ROOT/CATALOG.031", b"catalog"),
+            ("AVCS/ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+            ("AVCS/ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"update"),
+        ] {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let archive = zip.finish().unwrap();
+
+        let set = ExchangeSet::from_zip(archive)?;
+        assert_eq!(set.files().len(), 4);
+        assert_eq!(set.serial_enc(), Some("SERIAL.ENC"));
+        assert_eq!(set.cells().count(), 2);
+        let update = set.cell("GB100001").nth(1).unwrap();
+        assert_eq!(update.update, 1);
+        assert_eq!(set.read(&update.path)?, b"update");
+        assert_eq!(set.local_path(&update.path), None);
+        assert!(matches!(
+            ExchangeSet::from_zip(io::Cursor::new(b"not a zip".to_vec())),
+            Err(E::Zip(_))
+        ));
+        Ok(())
+    }
+}
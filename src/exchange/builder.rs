@@ -0,0 +1,480 @@
+//! Building exchange sets from plain S-57 cells, the data server side of `ExchangeSet`
+
+use super::{Catalog, CatalogEntry, Coverage, Product, Products, Serial, SetKind, E};
+use crate::clock::{Clock, SystemClock};
+use crate::encrypter::{self, S63Encrypter};
+use crate::s57::{self, Dsid};
+use crate::server::CellKeyStore;
+#[cfg(feature = "signature")]
+use crate::signature::{self, CellSigner};
+use chrono::prelude::*;
+use crc::crc32;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+use zip::result::ZipError;
+use zip::write::{FileOptions, ZipWriter};
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// a cell isn't an S-57 cell file
+    Cell(s57::E),
+    /// there are no keys for the edition of the cell
+    NoKeys {
+        cell: String,
+        edition: u32,
+    },
+    Encrypt(encrypter::E),
+    /// the files can't be written
+    Exchange(E),
+    Zip(ZipError),
+    #[cfg(feature = "signature")]
+    Signature(signature::E),
+}
+
+impl From<E> for BuildError {
+    fn from(e: E) -> BuildError {
+        BuildError::Exchange(e)
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> BuildError {
+        BuildError::Exchange(E::Io(e))
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Cell(e) => write!(f, "invalid cell: {}", e),
+            BuildError::NoKeys { cell, edition } => {
+                write!(f, "no keys for edition {} of {}", edition, cell)
+            }
+            BuildError::Encrypt(e) => write!(f, "encryption failed: {}", e),
+            BuildError::Exchange(e) => write!(f, "{}", e),
+            BuildError::Zip(e) => write!(f, "zip error: {}", e),
+            #[cfg(feature = "signature")]
+            BuildError::Signature(e) => write!(f, "signing failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Cell(e) => Some(e),
+            BuildError::Encrypt(e) => Some(e),
+            BuildError::Exchange(e) => Some(e),
+            BuildError::Zip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// an encrypted cell file and what is listed about it
+struct Built {
+    dsid: Dsid,
+    cell: String,
+    // the file extension, UPDN except for re-issues
+    file_update: u32,
+    path: String,
+    data: Vec<u8>,
+    coverage: Option<Coverage>,
+}
+
+/// builds an exchange set from plain S-57 base cells and updates. Each file is zipped and
+/// encrypted with CK1 of its edition, the edition and update are read from its DSID.
+/// SERIAL.ENC, INFO/PRODUCTS.TXT and ENC_ROOT/CATALOG.031 are generated, and cells are
+/// laid out as ENC_ROOT/<producer>/<cell>/<edition>/<update>/<file>
+pub struct ExchangeSetBuilder<K: CellKeyStore> {
+    data_server: String,
+    keys: K,
+    cells: Vec<(Vec<u8>, Option<Coverage>)>,
+    kind: Option<SetKind>,
+    volume: String,
+    clock: Arc<dyn Clock + Send + Sync>,
+    #[cfg(feature = "signature")]
+    signer: Option<CellSigner>,
+}
+
+impl<K: CellKeyStore> ExchangeSetBuilder<K> {
+    /// an exchange set of `data_server`, e.g. "GB", with the cell keys of `keys`
+    pub fn new(data_server: &str, keys: K) -> ExchangeSetBuilder<K> {
+        ExchangeSetBuilder {
+            data_server: data_server.to_owned(),
+            keys,
+            cells: Vec::new(),
+            kind: None,
+            volume: String::from("V01X01"),
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "signature")]
+            signer: None,
+        }
+    }
+
+    /// adds a base cell or update, the plain S-57 file
+    pub fn cell(mut self, data: Vec<u8>) -> Self {
+        self.cells.push((data, None));
+        self
+    }
+
+    /// adds a cell with the limits listed in the catalogue and product list
+    pub fn cell_with_coverage(mut self, data: Vec<u8>, coverage: Coverage) -> Self {
+        self.cells.push((data, Some(coverage)));
+        self
+    }
+
+    /// the type in SERIAL.ENC, defaults to BASE when there are base cells
+    pub fn kind(mut self, kind: SetKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// the volume of the catalogue entries, defaults to V01X01
+    pub fn volume(mut self, volume: &str) -> Self {
+        self.volume = volume.to_owned();
+        self
+    }
+
+    /// the date of the exchange set, defaults to the system clock
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// signs the cell files, see `CellSigner`
+    #[cfg(feature = "signature")]
+    pub fn signer(mut self, signer: CellSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    fn encrypt(&self, data: &[u8], coverage: Option<Coverage>) -> Result<Built, BuildError> {
+        let dsid = Dsid::from_bytes(data).map_err(BuildError::Cell)?;
+        let (cell, ext) = dsid.name.split_once('.').unwrap_or((&dsid.name, ""));
+        let cell = cell.to_owned();
+        let file_update = ext
+            .parse()
+            .map_err(|_| BuildError::Cell(s57::E::Invalid(format!("file name {}", dsid.name))))?;
+        let no_keys = || BuildError::NoKeys {
+            cell: cell.clone(),
+            edition: dsid.edition,
+        };
+        // cancellations are of edition 0, they are encrypted with the current keys
+        let edition = match dsid.edition {
+            0 => None,
+            e => Some(u8::try_from(e).map_err(|_| no_keys())?),
+        };
+        let (key1, _) = self.keys.cell_keys(&cell, edition).ok_or_else(no_keys)?;
+        let data = S63Encrypter::new()
+            .with_key_bytes(key1.as_bytes(), &dsid.name, data)
+            .map_err(BuildError::Encrypt)?;
+        let path = format!(
+            "{}/{}/{}/{}/{}/{}",
+            super::ENC_ROOT,
+            cell.get(..2).unwrap_or_default(),
+            cell,
+            dsid.edition,
+            file_update,
+            dsid.name
+        );
+        Ok(Built {
+            dsid,
+            cell,
+            file_update,
+            path,
+            data,
+            coverage,
+        })
+    }
+
+    // the product line of `b`, with the base cell and latest update of the set
+    fn product(b: &Built, built: &[Built]) -> Product {
+        let same_edition = || {
+            built
+                .iter()
+                .filter(|o| o.cell == b.cell && o.dsid.edition == b.dsid.edition)
+        };
+        let base = same_edition().find(|o| o.file_update == 0);
+        let latest = same_edition().max_by_key(|o| o.dsid.update);
+        Product {
+            section: String::from("ENC"),
+            cell: b.cell.clone(),
+            file_update: b.file_update,
+            compressed: true,
+            encrypted: true,
+            base_issue_date: base.and_then(|o| o.dsid.issue_date),
+            edition: b.dsid.edition,
+            update_issue_date: latest
+                .filter(|o| o.file_update > 0)
+                .and_then(|o| o.dsid.issue_date),
+            update: latest.map_or(b.dsid.update, |o| o.dsid.update),
+            file_size: Some(b.data.len() as u64),
+            limits: b.coverage,
+            coverage: String::new(),
+            crc: Some(crc32::checksum_ieee(&b.data)),
+        }
+    }
+
+    // the path and contents of the signature file of `b`, when signing
+    #[cfg(feature = "signature")]
+    fn signature(&self, b: &Built) -> Result<Option<(String, Vec<u8>)>, BuildError> {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return Ok(None),
+        };
+        let path = super::CellFile {
+            cell: b.cell.clone(),
+            edition: Some(b.dsid.edition),
+            update: b.file_update,
+            path: b.path.clone(),
+        }
+        .signature_path();
+        let mut sig = Vec::new();
+        signer
+            .sign(&b.data)
+            .and_then(|s| s.write(&mut sig))
+            .map_err(BuildError::Signature)?;
+        Ok(Some((path, sig)))
+    }
+
+    #[cfg(not(feature = "signature"))]
+    fn signature(&self, _: &Built) -> Result<Option<(String, Vec<u8>)>, BuildError> {
+        Ok(None)
+    }
+
+    fn catalog_entry(&self, path: &str, data: Option<&[u8]>, impl_: &str) -> CatalogEntry {
+        CatalogEntry {
+            file: path
+                .trim_start_matches(super::ENC_ROOT)
+                .trim_start_matches('/')
+                .replace('/', "\\"),
+            long_name: String::new(),
+            volume: self.volume.clone(),
+            implementation: impl_.to_owned(),
+            coverage: None,
+            crc: data.map(crc32::checksum_ieee),
+            comment: String::new(),
+        }
+    }
+
+    /// the files of the exchange set, paths relative to the directory with ENC_ROOT
+    pub fn files(&self) -> Result<Vec<(String, Vec<u8>)>, BuildError> {
+        let now = self.clock.now();
+        let mut built = self
+            .cells
+            .iter()
+            .map(|(data, coverage)| self.encrypt(data, *coverage))
+            .collect::<Result<Vec<_>, _>>()?;
+        built.sort_by(|a, b| {
+            (&a.cell, a.dsid.edition, a.file_update).cmp(&(&b.cell, b.dsid.edition, b.file_update))
+        });
+
+        let mut catalog = Catalog {
+            entries: vec![self.catalog_entry(super::CATALOG, None, "ASC")],
+        };
+        let mut products = Products {
+            date: now.with_second(0).and_then(|d| d.with_nanosecond(0)),
+            version: Some(2),
+            products: Vec::new(),
+        };
+        let mut cells = Vec::new();
+        for b in &built {
+            let mut entry = self.catalog_entry(&b.path, Some(&b.data), "BIN");
+            entry.coverage = b.coverage;
+            catalog.entries.push(entry);
+            products.products.push(Self::product(b, &built));
+            if let Some((path, sig)) = self.signature(b)? {
+                catalog
+                    .entries
+                    .push(self.catalog_entry(&path, Some(&sig), "ASC"));
+                cells.push((path, sig));
+            }
+        }
+
+        let kind = self.kind.unwrap_or_else(|| {
+            if built.iter().any(|b| b.file_update == 0) {
+                SetKind::Base
+            } else {
+                SetKind::Update
+            }
+        });
+        let mut serial = Vec::new();
+        Serial::new(&self.data_server, now.date(), kind).write(&mut serial)?;
+        let mut products_txt = Vec::new();
+        products.write(&mut products_txt)?;
+        let mut catalog_031 = Vec::new();
+        catalog.write(&mut catalog_031)?;
+
+        let mut res = vec![
+            (super::SERIAL.to_owned(), serial),
+            (super::PRODUCTS.to_owned(), products_txt),
+            (super::CATALOG.to_owned(), catalog_031),
+        ];
+        res.extend(built.into_iter().map(|b| (b.path, b.data)));
+        res.extend(cells);
+        Ok(res)
+    }
+
+    /// writes the exchange set to the directory `dir`, it will contain ENC_ROOT
+    pub fn write_dir<D: AsRef<Path>>(&self, dir: D) -> Result<(), BuildError> {
+        for (path, data) in self.files()? {
+            let path = dir.as_ref().join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+
+    /// writes the exchange set as a ZIP archive with ENC_ROOT at the top
+    pub fn write_zip<W: Write + Seek>(&self, wtr: W) -> Result<W, BuildError> {
+        let mut zip = ZipWriter::new(wtr);
+        for (path, data) in self.files()? {
+            zip.start_file(path, FileOptions::default())
+                .map_err(BuildError::Zip)?;
+            zip.write_all(&data)?;
+        }
+        zip.finish().map_err(BuildError::Zip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DataClient;
+    use crate::clock::FixedClock;
+    use crate::exchange::ExchangeSet;
+    use crate::s57::tests::cell;
+    use crate::secret::SecretKey;
+    use crate::server::{DataServer, Expiry, KeyDatabase, PermitRequest};
+    use crate::up::{MId, MKeyStore};
+
+    fn builder() -> ExchangeSetBuilder<KeyDatabase> {
+        let key = |k: u8| SecretKey::new([k; 5]);
+        let mut keys = KeyDatabase::new();
+        keys.insert("GB100001", 3, key(1), key(2));
+        keys.insert("GB100001", 4, key(2), key(3));
+        keys.insert("GB100002", 1, key(4), key(5));
+        let now = NaiveDate::from_ymd_opt(2020, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        ExchangeSetBuilder::new("GB", keys)
+            .clock(FixedClock(now))
+            .cell(cell("GB100002.000", 1, 0))
+            .cell(cell("GB100001.001", 4, 1))
+            .cell_with_coverage(
+                cell("GB100001.000", 4, 0),
+                Coverage {
+                    south: 50.0,
+                    west: -2.0,
+                    north: 51.0,
+                    east: -1.0,
+                },
+            )
+    }
+
+    #[test]
+    fn build() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("s63-builder-{}", std::process::id()));
+        let cells = std::env::temp_dir().join(format!("s63-builder-cells-{}", std::process::id()));
+        let builder = builder();
+        let res = (|| -> Result<(), Box<dyn std::error::Error>> {
+            builder.write_dir(&dir)?;
+            let set = ExchangeSet::open(&dir)?;
+            assert!(set.verify()?.is_ok());
+            let serial = set.serial()?;
+            assert_eq!(
+                (serial.data_server.as_str(), serial.kind),
+                ("GB", SetKind::Base)
+            );
+            assert_eq!(serial.week, (23, 2020));
+            let products = set.products()?;
+            assert_eq!(products.products.len(), 3);
+            let base = &products.products[0];
+            assert_eq!((base.cell.as_str(), base.file_update), ("GB100001", 0));
+            assert_eq!((base.edition, base.update), (4, 1));
+            assert_eq!(base.limits.map(|c| c.north), Some(51.0));
+            assert_eq!(
+                set.cells().map(|c| c.path.as_str()).collect::<Vec<_>>(),
+                [
+                    "ENC_ROOT/GB/GB100001/4/0/GB100001.000",
+                    "ENC_ROOT/GB/GB100001/4/1/GB100001.001",
+                    "ENC_ROOT/GB/GB100002/1/0/GB100002.000",
+                ]
+            );
+
+            // a subscriber decrypts the set with a permit file of the data server
+            let mut m_keys = MKeyStore::new();
+            m_keys.insert(MId::new("3130").unwrap(), "10121").unwrap();
+            let server = DataServer::new("GB", m_keys, &builder.keys);
+            let requests = [
+                PermitRequest::new("GB100001", Expiry::Months(12)),
+                PermitRequest::new("GB100002", Expiry::Months(12)),
+            ];
+            let txt = server.write_permit_file_at(
+                Vec::new(),
+                "66B5CBFDF7E4139D5B6086C23130",
+                &requests,
+                builder.clock.clone(),
+            )?;
+            let mut client = DataClient::new("12345", &cells);
+            client.install_permits(&txt[..])?;
+            let report = client.import_at(&set, builder.clock.clone())?;
+            assert!(report.is_ok());
+            assert_eq!(
+                fs::read(client.cell_dir("GB100001").join("GB100001.001"))?,
+                cell("GB100001.001", 4, 1)
+            );
+
+            let zip = builder.write_zip(io::Cursor::new(Vec::new()))?;
+            let zipped = ExchangeSet::from_zip(io::Cursor::new(zip.into_inner()))?;
+            assert_eq!(zipped.files(), set.files());
+            assert!(zipped.verify()?.is_ok());
+            Ok(())
+        })();
+        for d in [&dir, &cells] {
+            let _ = fs::remove_dir_all(d);
+        }
+        res?;
+
+        let missing =
+            ExchangeSetBuilder::new("GB", KeyDatabase::new()).cell(cell("GB100003.000", 1, 0));
+        assert!(matches!(
+            missing.files(),
+            Err(BuildError::NoKeys { cell, edition: 1 }) if cell == "GB100003"
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "signature")]
+    #[test]
+    fn signed() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::signature::{PrivateKey, SignatureFile};
+        use dsa::BigUint;
+
+        let (_, sa_pub) = crate::signature::tests::key(12345);
+        let (_, ds_pub) = crate::signature::tests::key(67890);
+        let sa = PrivateKey::new(sa_pub.clone(), BigUint::from(12345u32))?;
+        let ds = PrivateKey::new(ds_pub.clone(), BigUint::from(67890u32))?;
+        let signer = CellSigner::new(ds, sa.certify(&ds_pub)?);
+        let files = builder().signer(signer).files()?;
+        let file = |path: &str| &files.iter().find(|(p, _)| p == path).unwrap().1;
+        let sig = SignatureFile::from_rdr(&file("ENC_ROOT/GB/GB100001/4/1/SB100001.001")[..])?;
+        sig.verify(&sa_pub, file("ENC_ROOT/GB/GB100001/4/1/GB100001.001"))?;
+        assert_eq!(files.len(), 3 + 3 * 2);
+        let catalog = Catalog::from_rdr(&file("ENC_ROOT/CATALOG.031")[..])?;
+        let entry = catalog
+            .entry("ENC_ROOT/GB/GB100001/4/1/SB100001.001")
+            .unwrap();
+        assert_eq!(entry.implementation, "ASC");
+        Ok(())
+    }
+}
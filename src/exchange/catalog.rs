@@ -0,0 +1,218 @@
+//! ENC_ROOT/CATALOG.031, the ISO/IEC 8211 file listing every file of the exchange set
+
+use super::{Coverage, ExchangeSet, E};
+use crate::iso8211::{self, field_desc, write_record, UT};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::prelude::*;
+
+const CATALOG: &str = "CATALOG.031";
+
+/// the catalogue of an exchange set, a CATD record per file
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Catalog {
+    pub entries: Vec<CatalogEntry>,
+}
+
+/// a CATD record
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    /// the path relative to ENC_ROOT, separated by '\'
+    pub file: String,
+    /// the long name of the file
+    pub long_name: String,
+    /// the volume the file is on, e.g. V01X01
+    pub volume: String,
+    /// ASC for text, BIN for binary files
+    pub implementation: String,
+    pub coverage: Option<Coverage>,
+    pub crc: Option<u32>,
+    pub comment: String,
+}
+
+impl CatalogEntry {
+    /// the path in the exchange set, as given by `ExchangeSet::files`
+    pub fn path(&self) -> String {
+        format!("{}/{}", super::ENC_ROOT, self.file.replace('\\', "/"))
+    }
+}
+
+fn invalid(reason: &str) -> E {
+    E::Invalid {
+        file: CATALOG,
+        reason: reason.to_owned(),
+    }
+}
+
+impl Catalog {
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<Catalog, E> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        Catalog::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Catalog, E> {
+        let file = iso8211::File::from_bytes(data).map_err(|e| invalid(&e.0))?;
+        if file.field_desc("CATD").is_none() {
+            return Err(invalid("no CATD field"));
+        }
+        let mut entries = Vec::new();
+        for rec in file.records() {
+            for catd in rec.fields().filter(|f| f.tag == "CATD") {
+                let subfields: HashMap<_, _> = catd
+                    .subfields()
+                    .map_err(|e| invalid(&e.0))?
+                    .into_iter()
+                    .collect();
+                let text = |label: &str| {
+                    let v = subfields.get(label).copied().unwrap_or_default();
+                    String::from_utf8_lossy(v).trim().to_owned()
+                };
+                let float = |label: &str| text(label).parse::<f64>().ok();
+                let coverage = match [float("SLAT"), float("WLON"), float("NLAT"), float("ELON")] {
+                    [Some(south), Some(west), Some(north), Some(east)] => Some(Coverage {
+                        south,
+                        west,
+                        north,
+                        east,
+                    }),
+                    _ => None,
+                };
+                entries.push(CatalogEntry {
+                    file: text("FILE"),
+                    long_name: text("LFIL"),
+                    volume: text("VOLM"),
+                    implementation: text("IMPL"),
+                    coverage,
+                    crc: u32::from_str_radix(&text("CRCS"), 16).ok(),
+                    comment: text("COMT"),
+                });
+            }
+        }
+        Ok(Catalog { entries })
+    }
+
+    /// the entry of a path in the exchange set, e.g. ENC_ROOT/GB/GB100001/4/0/GB100001.000
+    pub fn entry(&self, path: &str) -> Option<&CatalogEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.path().eq_ignore_ascii_case(path))
+    }
+
+    /// writes the catalogue as ISO/IEC 8211
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        let ddr = [
+            ("0000", b"0000;&   CATALOG.031".to_vec()),
+            (
+                "0001",
+                field_desc("0100;&   ", "ISO/IEC 8211 Record Identifier", "", "(b12)"),
+            ),
+            (
+                "CATD",
+                field_desc(
+                    "1600;&   ",
+                    "Catalogue directory field",
+                    "RCNM!RCID!FILE!LFIL!VOLM!IMPL!SLAT!WLON!NLAT!ELON!CRCS!COMT",
+                    "(A(2),I(10),3A,A(3),4R,2A)",
+                ),
+            ),
+        ];
+        wtr.write_all(&write_record(b"3LE1 09", b" ! ", &ddr))?;
+        let coord = |c: Option<f64>| c.map(|c| c.to_string()).unwrap_or_default();
+        for (i, e) in self.entries.iter().enumerate() {
+            // the record number in field 0001 is a 16 bit integer
+            let id = u16::try_from(i + 1).map_err(|_| invalid("too many entries"))?;
+            let mut catd = format!("CD{:010}", id).into_bytes();
+            for s in [&e.file, &e.long_name, &e.volume] {
+                catd.extend_from_slice(s.as_bytes());
+                catd.push(UT);
+            }
+            catd.extend_from_slice(format!("{:3.3}", e.implementation).as_bytes());
+            let c = e.coverage;
+            for s in [
+                coord(c.map(|c| c.south)),
+                coord(c.map(|c| c.west)),
+                coord(c.map(|c| c.north)),
+                coord(c.map(|c| c.east)),
+                e.crc.map(|c| format!("{:08X}", c)).unwrap_or_default(),
+                e.comment.clone(),
+            ] {
+                catd.extend_from_slice(s.as_bytes());
+                catd.push(UT);
+            }
+            let fields = [("0001", id.to_le_bytes().to_vec()), ("CATD", catd)];
+            wtr.write_all(&write_record(b" D     ", b"   ", &fields))?;
+        }
+        Ok(())
+    }
+}
+
+impl ExchangeSet {
+    /// reads and parses ENC_ROOT/CATALOG.031
+    pub fn read_catalog(&self) -> Result<Catalog, E> {
+        let path = self
+            .catalog()
+            .ok_or_else(|| E::NoFile(super::CATALOG.to_owned()))?;
+        Catalog::from_bytes(&self.read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file: &str, crc: Option<u32>) -> CatalogEntry {
+        CatalogEntry {
+            file: file.into(),
+            long_name: String::new(),
+            volume: "V01X01".into(),
+            implementation: "BIN".into(),
+            coverage: None,
+            crc,
+            comment: String::new(),
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), E> {
+        let mut base = entry("GB\\GB100001\\4\\0\\GB100001.000", Some(0x8AF3E2C1));
+        base.coverage = Some(Coverage {
+            south: 49.5,
+            west: -7.0,
+            north: 50.0,
+            east: -6.5,
+        });
+        let mut readme = entry("README.TXT", None);
+        readme.implementation = "ASC".into();
+        readme.comment = "text".into();
+        let catalog = Catalog {
+            entries: vec![entry("CATALOG.031", None), base, readme],
+        };
+        let mut data = Vec::new();
+        catalog.write(&mut data)?;
+        assert_eq!(&data[5..9], b"3LE1");
+
+        let read = Catalog::from_rdr(&data[..])?;
+        assert_eq!(read, catalog);
+        assert_eq!(
+            read.entry("ENC_ROOT/GB/GB100001/4/0/GB100001.000")
+                .unwrap()
+                .crc,
+            Some(0x8AF3E2C1)
+        );
+        assert!(Catalog::from_bytes(&data[..data.len() - 3]).is_err());
+        assert!(Catalog::from_bytes(b"garbage").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let catalog = Catalog {
+            entries: vec![entry("GB100001.000", None); u16::MAX as usize + 1],
+        };
+        assert!(matches!(
+            catalog.write(std::io::sink()),
+            Err(E::Invalid { .. })
+        ));
+    }
+}
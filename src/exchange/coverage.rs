@@ -0,0 +1,308 @@
+//! The geographic coverage of the cells of an exchange set, from INFO/PRODUCTS.TXT or
+//! ENC_ROOT/CATALOG.031
+
+use super::{Catalog, CellFile, Coverage, ExchangeSet, Products, E};
+
+/// a polygon of latitude and longitude vertices in degrees, closed from the last vertex to
+/// the first
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Polygon {
+    pub vertices: Vec<(f64, f64)>,
+}
+
+impl From<Coverage> for Polygon {
+    fn from(c: Coverage) -> Polygon {
+        Polygon {
+            vertices: vec![
+                (c.south, c.west),
+                (c.south, c.east),
+                (c.north, c.east),
+                (c.north, c.west),
+            ],
+        }
+    }
+}
+
+// the orientation of `c` to the line from `a` to `b`, -1, 0 or 1
+fn orientation(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> i8 {
+    let d = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+    if d > 0.0 {
+        1
+    } else if d < 0.0 {
+        -1
+    } else {
+        0
+    }
+}
+
+// the edges of a polygon as pairs of vertices
+fn edges(v: &[(f64, f64)]) -> impl Iterator<Item = ((f64, f64), (f64, f64))> + '_ {
+    (0..v.len()).map(move |i| (v[i], v[(i + 1) % v.len()]))
+}
+
+fn crosses((a, b): ((f64, f64), (f64, f64)), (c, d): ((f64, f64), (f64, f64))) -> bool {
+    orientation(a, b, c) * orientation(a, b, d) < 0
+        && orientation(c, d, a) * orientation(c, d, b) < 0
+}
+
+impl Polygon {
+    /// the smallest box containing the polygon, None without vertices
+    pub fn bounds(&self) -> Option<Coverage> {
+        let (&(lat, lon), rest) = self.vertices.split_first()?;
+        let init = Coverage {
+            south: lat,
+            west: lon,
+            north: lat,
+            east: lon,
+        };
+        Some(rest.iter().fold(init, |c, &(lat, lon)| Coverage {
+            south: c.south.min(lat),
+            west: c.west.min(lon),
+            north: c.north.max(lat),
+            east: c.east.max(lon),
+        }))
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        let mut inside = false;
+        for ((lat_a, lon_a), (lat_b, lon_b)) in edges(&self.vertices) {
+            if (lat_a > lat) != (lat_b > lat)
+                && lon < (lon_b - lon_a) * (lat - lat_a) / (lat_b - lat_a) + lon_a
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// whether the polygon and `area` overlap or touch, an area crossing the antimeridian
+    /// is taken in its parts on either side of it
+    pub fn intersects(&self, area: &Coverage) -> bool {
+        area.split().any(|a| self.intersects_part(&a))
+    }
+
+    fn intersects_part(&self, area: &Coverage) -> bool {
+        if !self.bounds().is_some_and(|b| b.intersects(area)) {
+            return false;
+        }
+        let corners = Polygon::from(*area).vertices;
+        self.vertices
+            .iter()
+            .any(|&(lat, lon)| area.contains(lat, lon))
+            || corners.iter().any(|&(lat, lon)| self.contains(lat, lon))
+            || edges(&self.vertices).any(|e| edges(&corners).any(|c| crosses(e, c)))
+    }
+}
+
+/// the polygons of a data coverage field, polygons separated by ':' and the latitudes and
+/// longitudes of the vertices separated by ';' or spaces, e.g. `49.5;-7.0;50;-7;50;-6.5`.
+/// None if the field can't be read
+pub(super) fn polygons(s: &str) -> Option<Vec<Polygon>> {
+    let mut res = Vec::new();
+    for p in s.split(':').map(str::trim).filter(|p| !p.is_empty()) {
+        let coords = p
+            .split(|c: char| c == ';' || c.is_whitespace())
+            .filter(|c| !c.is_empty())
+            .map(|c| c.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if coords.len() < 6 || coords.len() % 2 != 0 {
+            return None;
+        }
+        res.push(Polygon {
+            vertices: coords.chunks(2).map(|c| (c[0], c[1])).collect(),
+        });
+    }
+    Some(res)
+}
+
+// the coverage of `cell` from the products or, when they don't give it, the catalogue
+fn coverage(
+    set: &ExchangeSet,
+    cell: &str,
+    products: Option<&Products>,
+    catalog: Option<&Catalog>,
+) -> Result<Vec<Polygon>, E> {
+    for p in products.iter().flat_map(|p| p.cell(cell)) {
+        let polygons = p.data_coverage()?;
+        if !polygons.is_empty() {
+            return Ok(polygons);
+        }
+    }
+    Ok(set
+        .cell(cell)
+        .filter_map(|f| catalog?.entry(&f.path)?.coverage)
+        .take(1)
+        .flat_map(Coverage::split)
+        .map(Polygon::from)
+        .collect())
+}
+
+impl ExchangeSet {
+    fn coverage_sources(&self) -> Result<(Option<Products>, Option<Catalog>), E> {
+        let products = match self.products_txt() {
+            Some(_) => Some(self.products()?),
+            None => None,
+        };
+        let catalog = match self.catalog() {
+            Some(_) => Some(self.read_catalog()?),
+            None => None,
+        };
+        Ok((products, catalog))
+    }
+
+    /// the coverage of `cell`, the data coverage or limits in INFO/PRODUCTS.TXT or else the
+    /// limits of its files in ENC_ROOT/CATALOG.031. Empty when neither gives it
+    pub fn cell_coverage(&self, cell: &str) -> Result<Vec<Polygon>, E> {
+        let (products, catalog) = self.coverage_sources()?;
+        coverage(self, cell, products.as_ref(), catalog.as_ref())
+    }
+
+    /// the files of the cells whose coverage intersects `area`, see `cell_coverage`. Cells
+    /// without a known coverage are included as they may be in the area
+    pub fn cells_intersecting(&self, area: &Coverage) -> Result<Vec<&CellFile>, E> {
+        let (products, catalog) = self.coverage_sources()?;
+        let mut res = Vec::new();
+        let mut last: Option<(&str, bool)> = None;
+        for file in self.cells() {
+            let inside = match last {
+                Some((cell, inside)) if cell == file.cell => inside,
+                _ => {
+                    let polygons = coverage(self, &file.cell, products.as_ref(), catalog.as_ref())?;
+                    polygons.is_empty() || polygons.iter().any(|p| p.intersects(area))
+                }
+            };
+            last = Some((&file.cell, inside));
+            if inside {
+                res.push(file);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::CatalogEntry;
+
+    fn area(south: f64, west: f64, north: f64, east: f64) -> Coverage {
+        Coverage {
+            south,
+            west,
+            north,
+            east,
+        }
+    }
+
+    #[test]
+    fn polygon() {
+        let triangle = &polygons("0 0;10 0;0 10").unwrap()[0];
+        assert_eq!(triangle.bounds(), Some(area(0.0, 0.0, 10.0, 10.0)));
+        assert!(triangle.contains(2.0, 2.0));
+        assert!(!triangle.contains(8.0, 8.0));
+        assert!(triangle.intersects(&area(1.0, 1.0, 2.0, 2.0)));
+        assert!(triangle.intersects(&area(-1.0, -1.0, 20.0, 20.0)));
+        assert!(triangle.intersects(&area(-1.0, 4.0, 11.0, 5.0)));
+        assert!(!triangle.intersects(&area(7.0, 7.0, 9.0, 9.0)));
+        assert!(!triangle.intersects(&area(20.0, 20.0, 21.0, 21.0)));
+        assert_eq!(polygons("1 2;3 4:5 6 7 8 9 10").map(|p| p.len()), None);
+        assert_eq!(
+            polygons("1 2 3 4 5 6:5 6 7 8 9 10").map(|p| p.len()),
+            Some(2)
+        );
+        assert_eq!(polygons("").map(|p| p.len()), Some(0));
+        assert_eq!(polygons("1 2 a 4 5 6"), None);
+    }
+
+    #[test]
+    fn antimeridian() {
+        let pacific = area(-20.0, 170.0, -10.0, -170.0);
+        assert!(pacific.crosses_antimeridian());
+        assert_eq!(
+            pacific.split().collect::<Vec<_>>(),
+            [
+                area(-20.0, 170.0, -10.0, 180.0),
+                area(-20.0, -180.0, -10.0, -170.0)
+            ]
+        );
+        assert!(pacific.contains(-15.0, 175.0));
+        assert!(pacific.contains(-15.0, -175.0));
+        assert!(!pacific.contains(-15.0, 0.0));
+        assert!(pacific.intersects(&area(-16.0, -175.0, -14.0, -172.0)));
+        assert!(area(-16.0, 172.0, -14.0, 175.0).intersects(&pacific));
+        assert!(pacific.intersects(&area(-30.0, 160.0, 0.0, -160.0)));
+        assert!(!pacific.intersects(&area(-16.0, -10.0, -14.0, 10.0)));
+        assert!(!area(-16.0, -10.0, -14.0, 10.0).intersects(&pacific));
+
+        let fiji = &polygons("-17 -179;-16 -179;-16 -178").unwrap()[0];
+        assert!(fiji.intersects(&pacific));
+        assert!(!fiji.intersects(&area(-20.0, 170.0, -10.0, 179.0)));
+        let west = Polygon::from(area(-16.0, 175.0, -14.0, 178.0));
+        assert!(west.intersects(&pacific));
+    }
+
+    #[test]
+    fn cells_intersecting() -> Result<(), E> {
+        let products = "\
+            :ENC\r\n\
+            GB100001.000,0,1,,4,,0,,49.5,-7.0,50.0,-6.5,49.5;-7;50;-7;49.5;-6.5,\r\n\
+            GB100002.000,0,1,,1,,0,,51.0,1.0,52.0,2.0,,\r\n";
+        let catalog = Catalog {
+            entries: vec![CatalogEntry {
+                file: "GB\\GB100003\\1\\0\\GB100003.000".into(),
+                long_name: String::new(),
+                volume: "V01X01".into(),
+                implementation: "BIN".into(),
+                coverage: Some(area(60.0, 10.0, 61.0, 11.0)),
+                crc: None,
+                comment: String::new(),
+            }],
+        };
+        let mut cat = Vec::new();
+        catalog.write(&mut cat)?;
+        let dir = super::super::tests::write_set(
+            "coverage",
+            &[
+                ("INFO/PRODUCTS.TXT", products.as_bytes()),
+                ("ENC_ROOT/CATALOG.031", &cat),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b""),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b""),
+                ("ENC_ROOT/GB/GB100002/1/0/GB100002.000", b""),
+                ("ENC_ROOT/GB/GB100003/1/0/GB100003.000", b""),
+                ("ENC_ROOT/GB/GB100004/1/0/GB100004.000", b""),
+            ],
+        );
+        let res = ExchangeSet::open(&dir).and_then(|set| check(&set));
+        std::fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+
+    fn check(set: &ExchangeSet) -> Result<(), E> {
+        let cells = |a: Coverage| -> Result<Vec<String>, E> {
+            let files = set.cells_intersecting(&a)?;
+            Ok(files.iter().map(|f| f.path.clone()).collect())
+        };
+
+        assert_eq!(set.cell_coverage("GB100001")?[0].vertices.len(), 3);
+        assert_eq!(
+            set.cell_coverage("GB100003")?,
+            [Polygon::from(area(60.0, 10.0, 61.0, 11.0))]
+        );
+        assert!(set.cell_coverage("GB100004")?.is_empty());
+        assert_eq!(
+            cells(area(49.6, -6.95, 49.7, -6.9))?,
+            [
+                "ENC_ROOT/GB/GB100001/4/0/GB100001.000",
+                "ENC_ROOT/GB/GB100001/4/1/GB100001.001",
+                "ENC_ROOT/GB/GB100004/1/0/GB100004.000",
+            ]
+        );
+        // in the limits of GB100001 but not in its data coverage
+        assert_eq!(
+            cells(area(49.9, -6.6, 50.0, -6.5))?,
+            ["ENC_ROOT/GB/GB100004/1/0/GB100004.000"]
+        );
+        assert_eq!(cells(area(51.5, 1.5, 60.5, 10.5))?.len(), 3);
+        Ok(())
+    }
+}
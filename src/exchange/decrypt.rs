@@ -0,0 +1,250 @@
+//! Decrypting every cell of an exchange set into an S-57 exchange set
+
+use super::{ExchangeSet, E};
+use crate::cipher::BlockCipher;
+use crate::decrypter::{self, S63Decrypter};
+use crate::permit::{GetPermit, PermitRecord};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+
+/// what was decrypted of a cell by `decrypt_exchange_set`
+#[derive(Debug)]
+pub struct CellResult {
+    pub cell: String,
+    /// the decrypted base cell and updates, in order
+    pub files: Vec<PathBuf>,
+    /// why the cell or one of its updates couldn't be decrypted, the following updates are
+    /// not decrypted then
+    pub error: Option<decrypter::E>,
+}
+
+impl CellResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+// `path` of the exchange set in `out_dir`, None for paths that would end up outside it
+fn out_path(out_dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(out_dir.join(path))
+    } else {
+        None
+    }
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), E> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(fs::write(path, data)?)
+}
+
+impl<P: GetPermit, C: BlockCipher> S63Decrypter<P, C> {
+    /// decrypts the base cells and updates of `set` into `out_dir`, keeping the layout and
+    /// names of ENC_ROOT. The other files under ENC_ROOT, e.g. CATALOG.031 and text files,
+    /// are copied. Cells without a permit get a `NoPermit` result
+    pub fn with_exchange_set<D: AsRef<Path>>(
+        &self,
+        set: &ExchangeSet,
+        out_dir: D,
+    ) -> Result<Vec<CellResult>, E> {
+        let out_dir = out_dir.as_ref();
+        let cells: HashSet<_> = set.cells.iter().map(|c| c.path.as_str()).collect();
+        for f in set
+            .files
+            .iter()
+            .filter(|f| super::in_enc_root(f) && !cells.contains(f.as_str()))
+        {
+            if let Some(path) = out_path(out_dir, f) {
+                write(&path, &set.read(f)?)?;
+            }
+        }
+
+        let mut res = Vec::new();
+        for file in set.cells() {
+            if res.last().is_none_or(|r: &CellResult| r.cell != file.cell) {
+                res.push(self.with_exchange_set_cell(set, &file.cell, out_dir)?);
+            }
+        }
+        Ok(res)
+    }
+
+    /// decrypts the base cell and the updates of `cell` in `set` into `out_dir`, in order
+    /// of edition and update, see `with_exchange_set`. The updates are encrypted with the
+    /// keys of the base cell permit. Decryption stops at the first file that fails
+    pub fn with_exchange_set_cell<D: AsRef<Path>>(
+        &self,
+        set: &ExchangeSet,
+        cell: &str,
+        out_dir: D,
+    ) -> Result<CellResult, E> {
+        let mut result = CellResult {
+            cell: cell.to_owned(),
+            files: Vec::new(),
+            error: None,
+        };
+        if self.permit.get_permit(cell).is_none() {
+            result.error = Some(decrypter::E::NoPermit(cell.to_owned()));
+            return Ok(result);
+        }
+        for file in set.cell(cell) {
+            let path = match out_path(out_dir.as_ref(), &file.path) {
+                Some(path) => path,
+                None => continue,
+            };
+            let mut data = Vec::new();
+            match self.with_cell(cell, Cursor::new(set.read(&file.path)?), &mut data) {
+                Ok(_) => {
+                    write(&path, &data)?;
+                    result.files.push(path);
+                }
+                Err(e) => {
+                    result.error = Some(e);
+                    break;
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// decrypts every cell of `set` with `permits` into `out_dir`, see
+/// `S63Decrypter::with_exchange_set`
+pub fn decrypt_exchange_set<P: GetPermit, D: AsRef<Path>>(
+    set: &ExchangeSet,
+    permits: P,
+    out_dir: D,
+) -> Result<Vec<CellResult>, E> {
+    S63Decrypter::new_with_permit(permits).with_exchange_set(set, out_dir)
+}
+
+/// decrypts the base cell and updates of the cell of `permit` from `set` into `out_dir`,
+/// see `S63Decrypter::with_exchange_set_cell`
+pub fn decrypt_cell<D: AsRef<Path>>(
+    set: &ExchangeSet,
+    permit: &PermitRecord,
+    out_dir: D,
+) -> Result<CellResult, E> {
+    S63Decrypter::new_with_permit(std::slice::from_ref(permit)).with_exchange_set_cell(
+        set,
+        &permit.cell_permit.cell,
+        out_dir,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decrypter::CellKey;
+    use crate::encrypter::S63Encrypter;
+    use crate::permit::{test_permit, PermitRecord};
+
+    fn permit(cell: &str, key: &[u8]) -> PermitRecord {
+        test_permit(cell, key, key)
+    }
+
+    #[test]
+    fn decrypt() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let e = S63Encrypter::new_with_permit(vec![permit("GB100001", &key)]);
+        let enc = |name: &str, data: &[u8]| {
+            let mut res = Vec::new();
+            e.with_cell(name, CellKey::Key1, data, &mut res).unwrap();
+            res
+        };
+        let dir = super::super::tests::write_set(
+            "decrypt-set",
+            &[
+                ("SERIAL.ENC", b"serial"),
+                ("ENC_ROOT/CATALOG.031", b"catalog"),
+                (
+                    "ENC_ROOT/GB/GB100001/4/0/GB100001.000",
+                    &enc("GB100001.000", b"base"),
+                ),
+                (
+                    "ENC_ROOT/GB/GB100001/4/1/GB100001.001",
+                    &enc("GB100001.001", b"upd1"),
+                ),
+                ("ENC_ROOT/GB/GB100001/4/2/GB100001.002", b"corrupt"),
+                (
+                    "ENC_ROOT/GB/GB100001/4/3/GB100001.003",
+                    &enc("GB100001.003", b"upd3"),
+                ),
+                ("ENC_ROOT/GB/GB100002/1/0/GB100002.000", b"no permit"),
+                ("ENC_ROOT/GB/GB100001/README.TXT", b"text"),
+            ],
+        );
+        let out = dir.join("out");
+        let res = ExchangeSet::open(&dir)
+            .and_then(|set| decrypt_exchange_set(&set, vec![permit("GB100001", &key)], &out));
+        let read = |p: &str| fs::read(out.join(p)).ok();
+        let (base, upd1, upd3, readme, catalog, serial) = (
+            read("ENC_ROOT/GB/GB100001/4/0/GB100001.000"),
+            read("ENC_ROOT/GB/GB100001/4/1/GB100001.001"),
+            read("ENC_ROOT/GB/GB100001/4/3/GB100001.003"),
+            read("ENC_ROOT/GB/GB100001/README.TXT"),
+            read("ENC_ROOT/CATALOG.031"),
+            read("SERIAL.ENC"),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        let res = res?;
+
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].cell, "GB100001");
+        assert_eq!(res[0].files.len(), 2);
+        assert!(res[0].error.is_some());
+        assert!(matches!(res[1].error, Some(decrypter::E::NoPermit(_))));
+        assert_eq!(base.as_deref(), Some(&b"base"[..]));
+        assert_eq!(upd1.as_deref(), Some(&b"upd1"[..]));
+        assert_eq!(upd3, None);
+        assert_eq!(readme.as_deref(), Some(&b"text"[..]));
+        assert_eq!(catalog.as_deref(), Some(&b"catalog"[..]));
+        assert_eq!(serial, None);
+        assert_eq!(out_path(&out, "ENC_ROOT/../../x"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn updates() -> Result<(), E> {
+        let key = [1, 2, 3, 4, 5];
+        let e = S63Encrypter::new_with_permit(vec![permit("GB100001", &key)]);
+        let enc = |data: &[u8]| {
+            let mut res = Vec::new();
+            e.with_cell("GB100001.000", CellKey::Key1, data, &mut res)
+                .unwrap();
+            res
+        };
+        let dir = super::super::tests::write_set(
+            "decrypt-updates",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/2/GB100001.002", &enc(b"upd2")),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", &enc(b"base")),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", &enc(b"upd1")),
+                ("ENC_ROOT/GB/GB100002/1/0/GB100002.000", &enc(b"other")),
+            ],
+        );
+        let out = dir.join("out");
+        let res = ExchangeSet::open(&dir)
+            .and_then(|set| decrypt_cell(&set, &permit("GB100001", &key), &out));
+        let names: Vec<_> = ["0/GB100001.000", "1/GB100001.001", "2/GB100001.002"]
+            .iter()
+            .map(|p| out.join("ENC_ROOT/GB/GB100001/4").join(p))
+            .collect();
+        let data: Vec<_> = names.iter().map(|p| fs::read(p).ok()).collect();
+        let other = out.join("ENC_ROOT/GB/GB100002/1/0/GB100002.000").exists();
+        fs::remove_dir_all(&dir).unwrap();
+        let res = res?;
+
+        assert!(res.is_ok());
+        assert_eq!(res.files, names);
+        assert_eq!(data[0].as_deref(), Some(&b"base"[..]));
+        assert_eq!(data[1].as_deref(), Some(&b"upd1"[..]));
+        assert_eq!(data[2].as_deref(), Some(&b"upd2"[..]));
+        assert!(!other);
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! What importing an exchange set would change, from the cell files alone and before
+//! anything is decrypted
+
+use super::{BaseCell, CellVersion, ExchangeSet, UpdateCheck};
+use std::collections::{BTreeMap, HashMap};
+
+/// the installed version of cells, e.g. a map of cell name to version
+pub trait InstalledCells {
+    fn installed_version(&self, cell: &str) -> Option<CellVersion>;
+}
+
+impl InstalledCells for HashMap<String, CellVersion> {
+    fn installed_version(&self, cell: &str) -> Option<CellVersion> {
+        self.get(cell).copied()
+    }
+}
+
+impl InstalledCells for BTreeMap<String, CellVersion> {
+    fn installed_version(&self, cell: &str) -> Option<CellVersion> {
+        self.get(cell).copied()
+    }
+}
+
+impl<T: InstalledCells + ?Sized> InstalledCells for &T {
+    fn installed_version(&self, cell: &str) -> Option<CellVersion> {
+        (**self).installed_version(cell)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellChange {
+    /// a base cell of a cell that isn't installed
+    New,
+    /// updates following the installed ones
+    Updated,
+    /// a base cell of a later edition than the installed one
+    NewEdition,
+    /// a base cell of the installed edition, see `BaseCell::ReIssue`
+    ReIssue,
+    /// a cancellation update, the cell and its permit are to be removed
+    Cancelled,
+    /// nothing newer than the installed cell
+    Unchanged,
+}
+
+/// a cell of an exchange set compared to the installed cell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellDiff {
+    pub cell: String,
+    pub installed: Option<CellVersion>,
+    /// the version after importing, None when nothing can be imported
+    pub available: Option<CellVersion>,
+    pub change: CellChange,
+    /// whether the files can be applied, see `ExchangeSet::check_updates`
+    pub check: UpdateCheck,
+}
+
+impl ExchangeSet {
+    /// compares every cell of the exchange set with the `installed` cells, in the order of
+    /// `cells`
+    pub fn diff<I: InstalledCells + ?Sized>(&self, installed: &I) -> Vec<CellDiff> {
+        let mut res: Vec<CellDiff> = Vec::new();
+        for file in self.cells() {
+            if res.last().is_some_and(|d| d.cell == file.cell) {
+                continue;
+            }
+            res.push(self.cell_diff(&file.cell, installed.installed_version(&file.cell)));
+        }
+        res
+    }
+
+    /// compares the files of `cell` with the `installed` version, see `diff`
+    pub fn cell_diff(&self, cell: &str, installed: Option<CellVersion>) -> CellDiff {
+        let check = self.check_updates(cell, installed);
+        let base = self.base_cell(cell, installed);
+        let edition = match (base, installed) {
+            (Some(_), _) => self
+                .cell(cell)
+                .filter(|f| f.is_base())
+                .last()
+                .and_then(|f| f.edition)
+                .or(installed.map(|i| i.edition)),
+            (None, Some(i)) => Some(i.edition),
+            (None, None) => None,
+        };
+        // the update of a base cell is the one it includes
+        let base_update = self.base_update(cell, edition);
+        let last = match base {
+            Some(_) => Some(base_update),
+            None => installed.map(|i| i.update),
+        };
+        let update = self
+            .cell(cell)
+            .filter(|f| f.edition.is_none() || f.edition == edition)
+            .filter(|f| !f.is_base() || base.is_some())
+            .map(|f| if f.is_base() { base_update } else { f.update })
+            .filter(|&u| last.is_some_and(|l| u >= l))
+            .max();
+        let available = match check {
+            UpdateCheck::Ok => edition
+                .zip(update)
+                .map(|(edition, update)| CellVersion { edition, update })
+                .filter(|&v| base.is_some() || installed.is_none_or(|i| v > i)),
+            _ => None,
+        };
+        let change = match (&check, base) {
+            (UpdateCheck::Cancelled { .. }, _) => CellChange::Cancelled,
+            (_, Some(BaseCell::New)) => CellChange::New,
+            (_, Some(BaseCell::NewEdition)) => CellChange::NewEdition,
+            (_, Some(BaseCell::ReIssue { .. })) => CellChange::ReIssue,
+            (UpdateCheck::Ok, None) if available.is_none() => CellChange::Unchanged,
+            (UpdateCheck::WrongEdition { .. }, None) if installed.is_some() => {
+                CellChange::Unchanged
+            }
+            _ => CellChange::Updated,
+        };
+        CellDiff {
+            cell: cell.to_owned(),
+            installed,
+            available,
+            change,
+            check,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() -> Result<(), super::super::E> {
+        let dir = super::super::tests::write_set(
+            "diff",
+            &[
+                (
+                    "INFO/PRODUCTS.TXT",
+                    b":DATE 20150525 03:13\r\n:VERSION 2\r\n:ENC\r\n\
+                    GB100003.000,0,1,,2,,8,\r\n",
+                ),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b""),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b""),
+                ("ENC_ROOT/GB/GB100002/2/3/GB100002.003", b""),
+                ("ENC_ROOT/GB/GB100002/2/4/GB100002.004", b""),
+                ("ENC_ROOT/GB/GB100003/2/0/GB100003.000", b""),
+                ("ENC_ROOT/GB/GB100004/0/5/GB100004.005", b""),
+                ("ENC_ROOT/GB/GB100005/1/2/GB100005.002", b""),
+                ("ENC_ROOT/GB/GB100006/3/0/GB100006.000", b""),
+                ("ENC_ROOT/GB/GB100007/1/4/GB100007.004", b""),
+            ],
+        );
+        let v = |edition, update| CellVersion { edition, update };
+        let installed: HashMap<String, CellVersion> = [
+            ("GB100002", v(2, 2)),
+            ("GB100003", v(2, 7)),
+            ("GB100004", v(1, 4)),
+            ("GB100005", v(1, 2)),
+            ("GB100006", v(2, 1)),
+            ("GB100007", v(1, 2)),
+        ]
+        .iter()
+        .map(|(c, v)| (c.to_string(), *v))
+        .collect();
+
+        let diff = super::super::ExchangeSet::open(&dir).map(|set| set.diff(&installed));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let diff = diff?;
+        let changes: Vec<_> = diff.iter().map(|d| (d.cell.as_str(), d.change)).collect();
+        assert_eq!(
+            changes,
+            [
+                ("GB100001", CellChange::New),
+                ("GB100002", CellChange::Updated),
+                ("GB100003", CellChange::ReIssue),
+                ("GB100004", CellChange::Cancelled),
+                ("GB100005", CellChange::Unchanged),
+                ("GB100006", CellChange::NewEdition),
+                ("GB100007", CellChange::Updated),
+            ]
+        );
+        assert_eq!(diff[0].installed, None);
+        assert_eq!(diff[0].available, Some(v(4, 1)));
+        assert_eq!(diff[1].available, Some(v(2, 4)));
+        assert_eq!(diff[2].available, Some(v(2, 8)));
+        assert_eq!(diff[3].available, None);
+        assert_eq!(diff[4].available, None);
+        assert!(diff[4].check.is_ok());
+        assert_eq!(diff[5].available, Some(v(3, 0)));
+        assert_eq!(diff[6].check, UpdateCheck::MissingUpdates(vec![3]));
+        assert_eq!(diff[6].available, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Reads exchange sets from ISO 9660 images of the media, enabled with the `iso` feature
+
+use super::{ExchangeSet, Source, E};
+use std::cell::RefCell;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+const SECTOR: u64 = 2048;
+// directories nested deeper than this are not read, a guard against loops in bad images
+const MAX_DEPTH: usize = 16;
+
+// a file of the image, its name without the ";1" version
+struct IsoFile {
+    path: String,
+    sector: u32,
+    len: u32,
+}
+
+struct IsoSource<R> {
+    rdr: RefCell<R>,
+    files: Vec<IsoFile>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid ISO 9660 image: {}", msg),
+    )
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+impl<R: Read + Seek> IsoSource<R> {
+    fn new(mut rdr: R) -> io::Result<IsoSource<R>> {
+        let root = primary_root(&mut rdr)?;
+        let mut files = Vec::new();
+        let mut dirs = vec![(root, String::new(), 0)];
+        while let Some(((sector, len), prefix, depth)) = dirs.pop() {
+            if depth > MAX_DEPTH {
+                return Err(invalid("directories nested too deep"));
+            }
+            let data = read_extent(&mut rdr, sector, len)?;
+            for rec in records(&data)? {
+                let name = prefix.clone() + &rec.name;
+                if rec.dir {
+                    dirs.push(((rec.sector, rec.len), name + "/", depth + 1));
+                } else {
+                    files.push(IsoFile {
+                        path: name,
+                        sector: rec.sector,
+                        len: rec.len,
+                    });
+                }
+            }
+        }
+        Ok(IsoSource {
+            rdr: RefCell::new(rdr),
+            files,
+        })
+    }
+}
+
+impl<R: Read + Seek> Source for IsoSource<R> {
+    fn files(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.iter().map(|f| f.path.clone()).collect())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let f = self
+            .files
+            .iter()
+            .find(|f| f.path == path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_owned()))?;
+        read_extent(&mut *self.rdr.borrow_mut(), f.sector, f.len)
+    }
+}
+
+// the extent of the root directory from the primary volume descriptor
+fn primary_root<R: Read + Seek>(rdr: &mut R) -> io::Result<(u32, u32)> {
+    // the volume descriptors start at sector 16 and end with a terminator, type 255
+    for i in 16.. {
+        let mut vd = [0u8; SECTOR as usize];
+        rdr.seek(SeekFrom::Start(i * SECTOR))?;
+        rdr.read_exact(&mut vd)?;
+        if &vd[1..6] != b"CD001" {
+            return Err(invalid("no volume descriptor"));
+        }
+        match vd[0] {
+            1 => {
+                let root = &vd[156..190];
+                return Ok((u32_le(&root[2..]), u32_le(&root[10..])));
+            }
+            255 => break,
+            _ => (),
+        }
+    }
+    Err(invalid("no primary volume descriptor"))
+}
+
+// the extent is checked against the size of the image before it is read, a corrupt record
+// could give a length of up to 4 GiB
+fn read_extent<R: Read + Seek>(rdr: &mut R, sector: u32, len: u32) -> io::Result<Vec<u8>> {
+    let size = rdr.seek(SeekFrom::End(0))?;
+    let start = u64::from(sector) * SECTOR;
+    if start + u64::from(len) > size {
+        return Err(invalid("extent beyond the end of the image"));
+    }
+    rdr.seek(SeekFrom::Start(start))?;
+    let mut res = vec![0u8; len as usize];
+    rdr.read_exact(&mut res)?;
+    Ok(res)
+}
+
+struct Record {
+    name: String,
+    sector: u32,
+    len: u32,
+    dir: bool,
+}
+
+// the directory records of a directory extent, without "." and ".."
+fn records(data: &[u8]) -> io::Result<Vec<Record>> {
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        if len == 0 {
+            // records don't span sectors, the rest of the sector is padding
+            pos = (pos / SECTOR as usize + 1) * SECTOR as usize;
+            continue;
+        }
+        let rec = data
+            .get(pos..pos + len)
+            .filter(|r| r.len() >= 33 && r.len() >= 33 + r[32] as usize)
+            .ok_or_else(|| invalid("truncated directory record"))?;
+        pos += len;
+        let name = &rec[33..33 + rec[32] as usize];
+        if name == [0] || name == [1] {
+            continue;
+        }
+        let name = String::from_utf8_lossy(name);
+        let name = name.split(';').next().unwrap_or_default();
+        res.push(Record {
+            name: name.strip_suffix('.').unwrap_or(name).to_owned(),
+            sector: u32_le(&rec[2..]),
+            len: u32_le(&rec[10..]),
+            dir: rec[25] & 2 != 0,
+        });
+    }
+    Ok(res)
+}
+
+impl ExchangeSet {
+    /// opens an exchange set from an ISO 9660 image of the media, ENC_ROOT must be in the
+    /// root of the image. Only the ISO 9660 names are read, not Joliet or Rock Ridge ones
+    pub fn from_iso<R: Read + Seek + 'static>(rdr: R) -> Result<ExchangeSet, E> {
+        ExchangeSet::from_source(Box::new(IsoSource::new(rdr)?), "image")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn dir_record(name: &[u8], sector: u32, len: u32, dir: bool) -> Vec<u8> {
+        let mut rec = vec![0u8; 33];
+        rec[2..6].copy_from_slice(&sector.to_le_bytes());
+        rec[6..10].copy_from_slice(&sector.to_be_bytes());
+        rec[10..14].copy_from_slice(&len.to_le_bytes());
+        rec[14..18].copy_from_slice(&len.to_be_bytes());
+        rec[25] = if dir { 2 } else { 0 };
+        rec[32] = name.len() as u8;
+        rec.extend_from_slice(name);
+        if rec.len() % 2 == 1 {
+            rec.push(0);
+        }
+        rec[0] = rec.len() as u8;
+        rec
+    }
+
+    // a minimal image, every directory fits in one sector
+    fn build_iso(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut dirs = BTreeSet::new();
+        dirs.insert(String::new());
+        for (path, _) in files {
+            let mut prefix = String::new();
+            for part in path
+                .split('/')
+                .rev()
+                .skip(1)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                prefix = prefix + part + "/";
+                dirs.insert(prefix.clone());
+            }
+        }
+        let dirs: Vec<String> = dirs.into_iter().collect();
+        let dir_sector = |d: &str| 18 + dirs.iter().position(|x| x == d).unwrap() as u32;
+        let mut next = 18 + dirs.len() as u32;
+        let mut file_sectors = Vec::new();
+        for (_, data) in files {
+            file_sectors.push(next);
+            next += (data.len() as u32).div_ceil(SECTOR as u32).max(1);
+        }
+
+        let mut img = vec![0u8; next as usize * SECTOR as usize];
+        let pvd = 16 * SECTOR as usize;
+        img[pvd] = 1;
+        img[pvd + 1..pvd + 6].copy_from_slice(b"CD001");
+        let root = dir_record(&[0], 18, SECTOR as u32, true);
+        img[pvd + 156..pvd + 156 + root.len()].copy_from_slice(&root);
+        let term = 17 * SECTOR as usize;
+        img[term] = 255;
+        img[term + 1..term + 6].copy_from_slice(b"CD001");
+
+        for d in &dirs {
+            let mut recs = dir_record(&[0], dir_sector(d), SECTOR as u32, true);
+            recs.extend(dir_record(&[1], 18, SECTOR as u32, true));
+            for sub in dirs.iter().filter(|s| {
+                s.len() > d.len()
+                    && s.starts_with(d.as_str())
+                    && !s[d.len()..s.len() - 1].contains('/')
+            }) {
+                let name = &sub[d.len()..sub.len() - 1];
+                recs.extend(dir_record(
+                    name.as_bytes(),
+                    dir_sector(sub),
+                    SECTOR as u32,
+                    true,
+                ));
+            }
+            for (i, (path, data)) in files.iter().enumerate() {
+                if let Some(name) = path.strip_prefix(d.as_str()).filter(|n| !n.contains('/')) {
+                    let name = format!("{};1", name);
+                    recs.extend(dir_record(
+                        name.as_bytes(),
+                        file_sectors[i],
+                        data.len() as u32,
+                        false,
+                    ));
+                }
+            }
+            let at = dir_sector(d) as usize * SECTOR as usize;
+            img[at..at + recs.len()].copy_from_slice(&recs);
+        }
+        for (i, (_, data)) in files.iter().enumerate() {
+            let at = file_sectors[i] as usize * SECTOR as usize;
+            img[at..at + data.len()].copy_from_slice(data);
+        }
+        img
+    }
+
+    #[test]
+    fn open_iso() -> Result<(), E> {
+        let cell: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let img = build_iso(&[
+            ("SERIAL.ENC", b"serial"),
+            ("INFO/PRODUCTS.TXT", b"products"),
+            ("ENC_ROOT/CATALOG.031", b"catalog"),
+            ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", &cell),
+        ]);
+        let set = ExchangeSet::from_iso(io::Cursor::new(img))?;
+        assert_eq!(set.files().len(), 4);
+        assert_eq!(set.products_txt(), Some("INFO/PRODUCTS.TXT"));
+        let base = set.cells().next().unwrap();
+        assert_eq!(base.edition, Some(4));
+        assert_eq!(set.read(&base.path)?, cell);
+        assert_eq!(set.read("SERIAL.ENC")?, b"serial");
+
+        assert!(matches!(
+            ExchangeSet::from_iso(io::Cursor::new(vec![0u8; 40000])),
+            Err(E::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn extent_beyond_image() {
+        let mut img = build_iso(&[("ENC_ROOT/CATALOG.031", b"catalog")]);
+        // the length of the root directory in the primary volume descriptor
+        let at = 16 * SECTOR as usize + 156 + 10;
+        img[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ExchangeSet::from_iso(io::Cursor::new(img)),
+            Err(E::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! MEDIA.TXT, the index of large media deliveries split over several media
+
+use super::E;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+const MEDIA: &str = "MEDIA.TXT";
+
+/// MEDIA.TXT from the root of a medium of a large media delivery, e.g.
+///
+/// ```text
+/// UKHO AVCS Service
+/// M01X02
+/// M1;B1,B2,INFO;AVCS Base Media
+/// M2;U1;AVCS Update Media
+/// ```
+///
+/// Lines before the volumes are kept in `header`, the identification line MnnXnn gives
+/// `medium` and `media_count`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Media {
+    pub header: Vec<String>,
+    /// the number of this medium
+    pub medium: Option<u32>,
+    /// the number of media in the delivery
+    pub media_count: Option<u32>,
+    pub volumes: Vec<MediaVolume>,
+}
+
+/// a medium of the delivery and the exchange set directories on it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaVolume {
+    /// e.g. M1
+    pub id: String,
+    pub number: u32,
+    /// the directories in the root of the medium, each containing an ENC_ROOT except
+    /// for INFO
+    pub exchange_sets: Vec<String>,
+    pub description: String,
+}
+
+impl Media {
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<Media, E> {
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    /// reads MEDIA.TXT in `dir`, the root of a medium
+    pub fn open<D: AsRef<Path>>(dir: D) -> Result<Media, E> {
+        let dir = dir.as_ref();
+        let file = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().eq_ignore_ascii_case(MEDIA))
+            .ok_or_else(|| E::NoFile(dir.join(MEDIA).display().to_string()))?;
+        Media::from_rdr(fs::File::open(file.path())?)
+    }
+
+    pub fn volume(&self, id: &str) -> Option<&MediaVolume> {
+        self.volumes.iter().find(|v| v.id.eq_ignore_ascii_case(id))
+    }
+
+    /// the medium with the exchange set directory `set`
+    pub fn volume_of(&self, set: &str) -> Option<&MediaVolume> {
+        self.volumes
+            .iter()
+            .find(|v| v.exchange_sets.iter().any(|s| s.eq_ignore_ascii_case(set)))
+    }
+}
+
+// MnnXnn, medium nn of nn
+fn identification(line: &str) -> Option<(u32, u32)> {
+    let (n, count) = line.strip_prefix('M')?.split_once('X')?;
+    Some((n.parse().ok()?, count.parse().ok()?))
+}
+
+impl std::str::FromStr for Media {
+    type Err = E;
+
+    fn from_str(s: &str) -> Result<Media, E> {
+        let mut res = Media::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((n, count)) = identification(line) {
+                res.medium = Some(n);
+                res.media_count = Some(count);
+                continue;
+            }
+            let mut fields = line.splitn(3, ';');
+            let id = fields.next().unwrap_or_default().trim();
+            let number = id.strip_prefix('M').and_then(|n| n.parse().ok());
+            let (number, sets) = match (number, fields.next()) {
+                (Some(n), Some(sets)) => (n, sets),
+                _ if res.volumes.is_empty() => {
+                    res.header.push(line.to_owned());
+                    continue;
+                }
+                _ => {
+                    return Err(E::Invalid {
+                        file: MEDIA,
+                        reason: format!("line {} is not a volume", i + 1),
+                    })
+                }
+            };
+            res.volumes.push(MediaVolume {
+                id: id.to_owned(),
+                number,
+                exchange_sets: sets
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect(),
+                description: fields.next().unwrap_or_default().trim().to_owned(),
+            });
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<(), E> {
+        let media = Media::from_rdr(
+            &b"UKHO AVCS Service\r\nWeek 23\r\nM01X02\r\n\
+               M1;B1,B2,INFO;AVCS Base Media\r\nM2;U1\r\n"[..],
+        )?;
+        assert_eq!(media.header, ["UKHO AVCS Service", "Week 23"]);
+        assert_eq!((media.medium, media.media_count), (Some(1), Some(2)));
+        assert_eq!(media.volumes.len(), 2);
+        assert_eq!(media.volumes[0].exchange_sets, ["B1", "B2", "INFO"]);
+        assert_eq!(media.volumes[0].description, "AVCS Base Media");
+        assert_eq!(media.volume("m2").unwrap().number, 2);
+        assert_eq!(media.volume("M2").unwrap().description, "");
+        assert_eq!(media.volume_of("b2").unwrap().id, "M1");
+        assert!(media.volume_of("U2").is_none());
+
+        assert!(matches!(
+            "M1;B1\nnot a volume".parse::<Media>(),
+            Err(E::Invalid {
+                file: "MEDIA.TXT",
+                ..
+            })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! INFO/PRODUCTS.TXT, the list of the cells in the exchange set
+
+use super::{CellVersion, ExchangeSet, Polygon, E};
+use chrono::prelude::*;
+use std::fmt;
+use std::io::prelude::*;
+
+const PRODUCTS: &str = "PRODUCTS.TXT";
+
+/// INFO/PRODUCTS.TXT, e.g.
+///
+/// ```text
+/// :DATE 20150525 03:13
+/// :VERSION 2
+/// :ENC
+/// GB100001.000,0,1,20141201,4,20150505,12,10342,49.5,-7.0,50.0,-6.5,,8AF3E2C1
+/// ```
+///
+/// A product line is the file name, compression and encryption flags, the issue date and
+/// edition of the base cell, the issue date and number of the latest update, the file
+/// size, the south, west, north and east limits of the cell, the data coverage and the
+/// CRC of the file. Fields after the name may be empty
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Products {
+    pub date: Option<NaiveDateTime>,
+    pub version: Option<u32>,
+    pub products: Vec<Product>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Product {
+    /// the section the product is listed in, e.g. ENC
+    pub section: String,
+    pub cell: String,
+    /// the file extension, 0 for a base cell
+    pub file_update: u32,
+    pub compressed: bool,
+    pub encrypted: bool,
+    pub base_issue_date: Option<NaiveDate>,
+    pub edition: u32,
+    pub update_issue_date: Option<NaiveDate>,
+    /// the latest update
+    pub update: u32,
+    pub file_size: Option<u64>,
+    pub limits: Option<Coverage>,
+    /// the data coverage, as written in the file
+    pub coverage: String,
+    pub crc: Option<u32>,
+}
+
+/// a latitude and longitude box in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coverage {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl Product {
+    /// a cancellation, listed with edition 0
+    pub fn is_cancellation(&self) -> bool {
+        self.edition == 0 && self.file_update > 0
+    }
+
+    /// a base cell of the `installed` edition, a re-issue replacing the installed cell and
+    /// its updates
+    pub fn is_reissue_of(&self, installed: &CellVersion) -> bool {
+        self.file_update == 0 && self.edition == installed.edition
+    }
+
+    /// the polygons of the data coverage, or the limits when the data coverage is empty
+    pub fn data_coverage(&self) -> Result<Vec<Polygon>, E> {
+        let polygons = super::coverage::polygons(&self.coverage).ok_or_else(|| E::Invalid {
+            file: PRODUCTS,
+            reason: format!("invalid data coverage of {}: {}", self.cell, self.coverage),
+        })?;
+        if polygons.is_empty() {
+            Ok(self
+                .limits
+                .into_iter()
+                .flat_map(Coverage::split)
+                .map(Polygon::from)
+                .collect())
+        } else {
+            Ok(polygons)
+        }
+    }
+}
+
+impl Coverage {
+    /// whether the box crosses the antimeridian, given with its west limit east of its
+    /// east limit
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west > self.east
+    }
+
+    /// the box split at the antimeridian into the parts west and east of it, the box
+    /// itself when it doesn't cross it
+    pub fn split(self) -> impl Iterator<Item = Coverage> {
+        let crosses = self.crosses_antimeridian();
+        let west = Coverage {
+            east: if crosses { 180.0 } else { self.east },
+            ..self
+        };
+        let east = Coverage {
+            west: -180.0,
+            ..self
+        };
+        std::iter::once(west).chain(Some(east).filter(|_| crosses))
+    }
+
+    pub fn intersects(&self, other: &Coverage) -> bool {
+        self.split().any(|a| {
+            other.split().any(|b| {
+                a.south <= b.north && b.south <= a.north && a.west <= b.east && b.west <= a.east
+            })
+        })
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.split()
+            .any(|c| (c.south..=c.north).contains(&lat) && (c.west..=c.east).contains(&lon))
+    }
+}
+
+impl Products {
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<Products, E> {
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    /// the products of `cell`, the base cell first
+    pub fn cell<'a>(&'a self, cell: &'a str) -> impl Iterator<Item = &'a Product> + 'a {
+        self.products.iter().filter(move |p| p.cell == cell)
+    }
+
+    /// writes the product list with CRLF line ends, a section line before the first
+    /// product of each section
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        if let Some(date) = self.date {
+            write!(wtr, ":DATE {}\r\n", date.format("%Y%m%d %H:%M"))?;
+        }
+        if let Some(version) = self.version {
+            write!(wtr, ":VERSION {}\r\n", version)?;
+        }
+        let mut section = None;
+        for p in &self.products {
+            if section != Some(&p.section) {
+                write!(wtr, ":{}\r\n", p.section)?;
+                section = Some(&p.section);
+            }
+            write!(wtr, "{}\r\n", p)?;
+        }
+        Ok(())
+    }
+}
+
+/// the line of the product in PRODUCTS.TXT
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let date = |d: Option<NaiveDate>| d.map(|d| d.format("%Y%m%d").to_string());
+        let opt = |s: Option<String>| s.unwrap_or_default();
+        let limit = |l: fn(&Coverage) -> f64| self.limits.as_ref().map(|c| l(c).to_string());
+        write!(
+            f,
+            "{}.{:03},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.cell,
+            self.file_update,
+            u8::from(self.compressed),
+            u8::from(self.encrypted),
+            opt(date(self.base_issue_date)),
+            self.edition,
+            opt(date(self.update_issue_date)),
+            self.update,
+            opt(self.file_size.map(|s| s.to_string())),
+            opt(limit(|c| c.south)),
+            opt(limit(|c| c.west)),
+            opt(limit(|c| c.north)),
+            opt(limit(|c| c.east)),
+            self.coverage,
+            opt(self.crc.map(|c| format!("{:08X}", c))),
+        )
+    }
+}
+
+fn invalid(line: usize, reason: &str) -> E {
+    E::Invalid {
+        file: PRODUCTS,
+        reason: format!("{} on line {}", reason, line),
+    }
+}
+
+fn opt<T: std::str::FromStr>(s: Option<&str>) -> Result<Option<T>, ()> {
+    match s.map(str::trim) {
+        None | Some("") | Some("-") => Ok(None),
+        Some(s) => s.parse().map(Some).map_err(|_| ()),
+    }
+}
+
+fn date(s: Option<&str>) -> Result<Option<NaiveDate>, ()> {
+    match s.map(str::trim) {
+        None | Some("") | Some("-") => Ok(None),
+        Some(s) => NaiveDate::parse_from_str(s, "%Y%m%d")
+            .map(Some)
+            .map_err(|_| ()),
+    }
+}
+
+fn product(section: &str, line: &str) -> Result<Product, ()> {
+    let mut fields = line.split(',');
+    let name = fields.next().unwrap_or_default().trim();
+    let (cell, ext) = name.split_once('.').ok_or(())?;
+    let flag = |s: Option<&str>| opt::<u8>(s).map(|f| f == Some(1));
+    let compressed = flag(fields.next())?;
+    let encrypted = flag(fields.next())?;
+    let base_issue_date = date(fields.next())?;
+    let edition = opt(fields.next())?.unwrap_or(0);
+    let update_issue_date = date(fields.next())?;
+    let update = opt(fields.next())?.unwrap_or(0);
+    let file_size = opt(fields.next())?;
+    let limits = [
+        opt(fields.next())?,
+        opt(fields.next())?,
+        opt(fields.next())?,
+        opt(fields.next())?,
+    ];
+    let coverage = fields.next().unwrap_or_default().trim().to_owned();
+    let crc = match fields.next().map(str::trim) {
+        None | Some("") => None,
+        Some(s) => Some(u32::from_str_radix(s, 16).map_err(|_| ())?),
+    };
+    Ok(Product {
+        section: section.to_owned(),
+        cell: cell.to_owned(),
+        file_update: ext.parse().map_err(|_| ())?,
+        compressed,
+        encrypted,
+        base_issue_date,
+        edition,
+        update_issue_date,
+        update,
+        file_size,
+        limits: match limits {
+            [Some(south), Some(west), Some(north), Some(east)] => Some(Coverage {
+                south,
+                west,
+                north,
+                east,
+            }),
+            _ => None,
+        },
+        coverage,
+        crc,
+    })
+}
+
+impl std::str::FromStr for Products {
+    type Err = E;
+
+    fn from_str(s: &str) -> Result<Products, E> {
+        let mut res = Products::default();
+        let mut section = String::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix(':') {
+                let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+                match key {
+                    "DATE" => {
+                        let date = NaiveDateTime::parse_from_str(value.trim(), "%Y%m%d %H:%M")
+                            .map_err(|_| invalid(i + 1, "invalid date"))?;
+                        res.date = Some(date);
+                    }
+                    "VERSION" => {
+                        let version = value.trim().parse();
+                        res.version = Some(version.map_err(|_| invalid(i + 1, "invalid version"))?);
+                    }
+                    _ => section = key.to_owned(),
+                }
+                continue;
+            }
+            let p = product(&section, line).map_err(|_| invalid(i + 1, "invalid product"))?;
+            res.products.push(p);
+        }
+        Ok(res)
+    }
+}
+
+impl ExchangeSet {
+    /// reads and parses INFO/PRODUCTS.TXT
+    pub fn products(&self) -> Result<Products, E> {
+        let path = self
+            .products_txt()
+            .ok_or_else(|| E::NoFile(super::PRODUCTS.to_owned()))?;
+        Products::from_rdr(&self.read(path)?[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXT: &str = ":DATE 20150525 03:13\r\n:VERSION 2\r\n:ENC\r\n\
+        GB100001.000,0,1,20141201,4,20150505,12,10342,49.5,-7.0,50.0,-6.5,,8AF3E2C1\r\n\
+        GB100001.012,0,1,20141201,4,20150505,12,1024,,,,\r\n\
+        :ECS\r\nGB200001.000,0,0,,1,,0\r\n";
+
+    #[test]
+    fn parse() -> Result<(), E> {
+        let products: Products = TXT.parse()?;
+        assert_eq!(
+            products.date,
+            NaiveDate::from_ymd_opt(2015, 5, 25).and_then(|d| d.and_hms_opt(3, 13, 0))
+        );
+        assert_eq!(products.version, Some(2));
+        assert_eq!(products.products.len(), 3);
+        let base = &products.products[0];
+        assert_eq!(base.section, "ENC");
+        assert_eq!((base.cell.as_str(), base.file_update), ("GB100001", 0));
+        assert!(!base.compressed && base.encrypted);
+        assert_eq!(base.base_issue_date, NaiveDate::from_ymd_opt(2014, 12, 1));
+        assert_eq!((base.edition, base.update), (4, 12));
+        assert_eq!(base.file_size, Some(10342));
+        let limits = base.limits.unwrap();
+        assert!(limits.contains(49.7, -6.8));
+        assert!(!limits.contains(51.0, -6.8));
+        assert_eq!(base.crc, Some(0x8AF3E2C1));
+        assert_eq!(products.products[1].limits, None);
+        assert_eq!(products.products[2].section, "ECS");
+        assert_eq!(products.cell("GB100001").count(), 2);
+        assert!(!products.products.iter().any(Product::is_cancellation));
+        let installed = CellVersion {
+            edition: 4,
+            update: 9,
+        };
+        assert!(base.is_reissue_of(&installed));
+        assert!(!products.products[1].is_reissue_of(&installed));
+        let mut written = Vec::new();
+        products.write(&mut written)?;
+        assert_eq!(Products::from_rdr(&written[..])?, products);
+        assert!(String::from_utf8(written).unwrap().starts_with(
+            ":DATE 20150525 03:13\r\n:VERSION 2\r\n:ENC\r\n\
+             GB100001.000,0,1,20141201,4,20150505,12,10342,49.5,-7,50,-6.5,,8AF3E2C1\r\n"
+        ));
+        let cancel: Products = ":ENC\nGB100001.013,0,1,20141201,0,20150601,13".parse()?;
+        assert!(cancel.products[0].is_cancellation());
+
+        assert!(matches!(
+            ":ENC\nGB100001,0".parse::<Products>(),
+            Err(E::Invalid {
+                file: "PRODUCTS.TXT",
+                ..
+            })
+        ));
+        assert!(":ENC\nGB100001.000,0,1,2014".parse::<Products>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn exchange_set() -> Result<(), E> {
+        let dir = super::super::tests::write_set(
+            "products",
+            &[
+                ("INFO/PRODUCTS.TXT", TXT.as_bytes()),
+                ("ENC_ROOT/CATALOG.031", b"catalog"),
+            ],
+        );
+        let products = ExchangeSet::open(&dir).and_then(|s| s.products());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(products?.products.len(), 3);
+        Ok(())
+    }
+}
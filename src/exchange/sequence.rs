@@ -0,0 +1,246 @@
+//! Checks that the files of a cell in an exchange set continue the installed cell, as
+//! required when importing updates
+
+use super::{ExchangeSet, Product};
+use std::collections::HashMap;
+
+/// the edition and last applied update of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CellVersion {
+    pub edition: u32,
+    pub update: u32,
+}
+
+/// the result of `ExchangeSet::check_updates`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateCheck {
+    /// the files can be applied in order, or there is nothing new for the cell
+    Ok,
+    /// the update numbers that are neither installed nor in the exchange set, 0 when there
+    /// is no base cell to apply the updates to
+    MissingUpdates(Vec<u32>),
+    /// the updates are for another edition than the installed one, and the exchange set
+    /// has no base cell of their edition
+    WrongEdition { installed: u32, found: u32 },
+    /// the exchange set cancels the cell with the cancellation update `update`
+    Cancelled { update: u32 },
+}
+
+impl UpdateCheck {
+    pub fn is_ok(&self) -> bool {
+        *self == UpdateCheck::Ok
+    }
+}
+
+/// what a base cell in an exchange set is to the installed cell, see `ExchangeSet::base_cell`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseCell {
+    /// the cell isn't installed
+    New,
+    /// a later edition than the installed one
+    NewEdition,
+    /// the installed edition issued again with the updates up to `update` included. It
+    /// replaces the installed cell and its updates rather than being applied to them
+    ReIssue { update: u32 },
+}
+
+impl ExchangeSet {
+    /// checks that the files of `cell` follow `installed` without a gap in the update
+    /// numbers. A base cell in the exchange set, a new edition or a re-issue, replaces the
+    /// installed cell and the updates must follow it instead, see `base_cell`. Updates that
+    /// are already installed are ignored. Files without an edition in their path are taken
+    /// to be of the installed edition. A cancellation update gives `Cancelled` whatever the
+    /// other files
+    pub fn check_updates(&self, cell: &str, installed: Option<CellVersion>) -> UpdateCheck {
+        let files: Vec<_> = self.cell(cell).collect();
+        if let Some(c) = files.iter().find(|f| f.is_cancellation()) {
+            return UpdateCheck::Cancelled { update: c.update };
+        }
+        let base = files.iter().rev().find(|f| f.is_base());
+        if let (Some(b), Some(i)) = (base, installed) {
+            if b.edition.is_some_and(|e| e < i.edition) {
+                return UpdateCheck::WrongEdition {
+                    installed: i.edition,
+                    found: b.edition.unwrap_or_default(),
+                };
+            }
+        }
+        // a base cell of the installed edition without later updates is ignored
+        let base = base.filter(|_| self.base_cell(cell, installed).is_some());
+        let (edition, last) = match (base, installed) {
+            (Some(b), _) => (b.edition, self.base_update(cell, b.edition)),
+            (None, Some(i)) => (Some(i.edition), i.update),
+            (None, None) if files.is_empty() => return UpdateCheck::Ok,
+            (None, None) => return UpdateCheck::MissingUpdates(vec![0]),
+        };
+        let mut updates: Vec<u32> = Vec::new();
+        for f in files.iter().filter(|f| !f.is_base() && f.update > last) {
+            match (f.edition, edition) {
+                (Some(found), Some(installed)) if found != installed && base.is_none() => {
+                    return UpdateCheck::WrongEdition { installed, found }
+                }
+                (Some(found), Some(e)) if found != e => (),
+                _ => updates.push(f.update),
+            }
+        }
+        let missing: Vec<u32> = match updates.iter().max() {
+            Some(&max) => (last + 1..max).filter(|u| !updates.contains(u)).collect(),
+            None => Vec::new(),
+        };
+        if missing.is_empty() {
+            UpdateCheck::Ok
+        } else {
+            UpdateCheck::MissingUpdates(missing)
+        }
+    }
+
+    /// the kind of the last base cell of `cell` in the exchange set, see `check_updates`.
+    /// None when there is no base cell, it is of an older edition than `installed`, or it
+    /// is of the installed edition and includes no later update. The update a base cell
+    /// includes is the latest update PRODUCTS.TXT lists with it, a base cell that isn't
+    /// listed includes none
+    pub fn base_cell(&self, cell: &str, installed: Option<CellVersion>) -> Option<BaseCell> {
+        let base = self.cell(cell).filter(|f| f.is_base()).last()?;
+        let i = match installed {
+            Some(i) => i,
+            None => return Some(BaseCell::New),
+        };
+        let listed = self.listed_bases().get(cell);
+        match base.edition.or(listed.map(|p| p.edition)) {
+            Some(e) if e > i.edition => Some(BaseCell::NewEdition),
+            Some(e) if e < i.edition => None,
+            _ => listed
+                .filter(|p| p.is_reissue_of(&i) && p.update > i.update)
+                .map(|p| BaseCell::ReIssue { update: p.update }),
+        }
+    }
+
+    /// the update the base cell of `cell` of `edition` includes, see `base_cell`
+    pub(crate) fn base_update(&self, cell: &str, edition: Option<u32>) -> u32 {
+        self.listed_bases()
+            .get(cell)
+            .filter(|p| edition.is_none_or(|e| e == p.edition))
+            .map_or(0, |p| p.update)
+    }
+
+    // the base cells in PRODUCTS.TXT by cell, none when there is no readable product list
+    fn listed_bases(&self) -> &HashMap<String, Product> {
+        self.listed_bases.get_or_init(|| {
+            self.products()
+                .into_iter()
+                .flat_map(|p| p.products)
+                .filter(|p| p.file_update == 0)
+                .map(|p| (p.cell.clone(), p))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_updates() -> Result<(), super::super::E> {
+        let dir = super::super::tests::write_set(
+            "sequence",
+            &[
+                ("ENC_ROOT/GB/GB100001/4/3/GB100001.003", b""),
+                ("ENC_ROOT/GB/GB100001/4/4/GB100001.004", b""),
+                ("ENC_ROOT/GB/GB100001/4/6/GB100001.006", b""),
+                ("ENC_ROOT/GB/GB100002/2/0/GB100002.000", b""),
+                ("ENC_ROOT/GB/GB100002/2/1/GB100002.001", b""),
+                ("ENC_ROOT/GB/GB100002/2/3/GB100002.003", b""),
+                ("ENC_ROOT/GB/GB100004/0/7/GB100004.007", b""),
+            ],
+        );
+        let set = super::super::ExchangeSet::open(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let set = set?;
+        let v = |edition, update| Some(CellVersion { edition, update });
+
+        assert!(set.check_updates("GB100001", v(4, 5)).is_ok());
+        assert!(set.check_updates("GB100001", v(4, 6)).is_ok());
+        assert_eq!(
+            set.check_updates("GB100001", v(4, 2)),
+            UpdateCheck::MissingUpdates(vec![5])
+        );
+        assert_eq!(
+            set.check_updates("GB100001", v(4, 1)),
+            UpdateCheck::MissingUpdates(vec![2, 5])
+        );
+        assert_eq!(
+            set.check_updates("GB100001", v(3, 2)),
+            UpdateCheck::WrongEdition {
+                installed: 3,
+                found: 4
+            }
+        );
+        assert_eq!(
+            set.check_updates("GB100001", None),
+            UpdateCheck::MissingUpdates(vec![0])
+        );
+        assert_eq!(
+            set.check_updates("GB100002", None),
+            UpdateCheck::MissingUpdates(vec![2])
+        );
+        assert_eq!(
+            set.check_updates("GB100002", v(1, 9)),
+            UpdateCheck::MissingUpdates(vec![2])
+        );
+        assert_eq!(
+            set.check_updates("GB100002", v(3, 0)),
+            UpdateCheck::WrongEdition {
+                installed: 3,
+                found: 2
+            }
+        );
+        assert!(set.check_updates("GB100003", None).is_ok());
+        assert_eq!(
+            set.check_updates("GB100004", v(2, 6)),
+            UpdateCheck::Cancelled { update: 7 }
+        );
+        let cancelled: Vec<_> = set.cancellations().map(|c| c.cell.as_str()).collect();
+        assert_eq!(cancelled, ["GB100004"]);
+
+        assert_eq!(set.base_cell("GB100001", v(4, 2)), None);
+        assert_eq!(set.base_cell("GB100002", None), Some(BaseCell::New));
+        assert_eq!(
+            set.base_cell("GB100002", v(1, 9)),
+            Some(BaseCell::NewEdition)
+        );
+        assert_eq!(set.base_cell("GB100002", v(2, 4)), None);
+        assert_eq!(set.base_cell("GB100002", v(3, 0)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn reissue() -> Result<(), super::super::E> {
+        let dir = super::super::tests::write_set(
+            "reissue",
+            &[
+                (
+                    "INFO/PRODUCTS.TXT",
+                    b":DATE 20150525 03:13\r\n:VERSION 2\r\n:ENC\r\n\
+                    GB100001.000,0,1,,2,,5,\r\nGB100001.006,0,1,,2,,6,\r\n",
+                ),
+                ("ENC_ROOT/GB/GB100001/2/0/GB100001.000", b""),
+                ("ENC_ROOT/GB/GB100001/2/6/GB100001.006", b""),
+            ],
+        );
+        let set = super::super::ExchangeSet::open(&dir);
+        let res = set.map(|set| {
+            let v = |edition, update| Some(CellVersion { edition, update });
+            assert_eq!(
+                set.base_cell("GB100001", v(2, 3)),
+                Some(BaseCell::ReIssue { update: 5 })
+            );
+            assert_eq!(set.base_cell("GB100001", v(2, 5)), None);
+            assert!(set.check_updates("GB100001", v(2, 3)).is_ok());
+            assert!(set.check_updates("GB100001", v(2, 5)).is_ok());
+            assert!(set.check_updates("GB100001", None).is_ok());
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+}
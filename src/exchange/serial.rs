@@ -0,0 +1,158 @@
+//! SERIAL.ENC, the identification of an exchange set
+
+use super::{ExchangeSet, E};
+use chrono::prelude::*;
+use std::fmt;
+use std::io::prelude::*;
+
+const SERIAL: &str = "SERIAL.ENC";
+
+/// whether the exchange set has base cells or updates only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetKind {
+    Base,
+    Update,
+}
+
+impl SetKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SetKind::Base => "BASE",
+            SetKind::Update => "UPDATE",
+        }
+    }
+}
+
+/// SERIAL.ENC, a line of fixed width fields, e.g.
+///
+/// ```text
+/// GBWK21-14   20140522BASE      02.00
+/// ```
+///
+/// The data server, 2 characters, the week of the exchange set, 10, the date, 8, BASE or
+/// UPDATE, 10, and the format version, 5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Serial {
+    pub data_server: String,
+    /// the ISO week and its year
+    pub week: (u32, i32),
+    pub date: NaiveDate,
+    pub kind: SetKind,
+    pub version: String,
+}
+
+fn invalid(reason: &str) -> E {
+    E::Invalid {
+        file: SERIAL,
+        reason: reason.to_owned(),
+    }
+}
+
+impl Serial {
+    /// the serial of the exchange set of `data_server` on `date`, in its ISO week
+    pub fn new(data_server: &str, date: NaiveDate, kind: SetKind) -> Serial {
+        let week = date.iso_week();
+        Serial {
+            data_server: data_server.to_owned(),
+            week: (week.week(), week.year()),
+            date,
+            kind,
+            version: String::from("02.00"),
+        }
+    }
+
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<Serial, E> {
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        write!(wtr, "{}\r\n", self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let week = format!("WK{:02}-{:02}", self.week.0, self.week.1.rem_euclid(100));
+        write!(
+            f,
+            "{:2.2}{:10}{}{:10}{:5.5}",
+            self.data_server,
+            week,
+            self.date.format("%Y%m%d"),
+            self.kind.as_str(),
+            self.version
+        )
+    }
+}
+
+impl std::str::FromStr for Serial {
+    type Err = E;
+
+    fn from_str(s: &str) -> Result<Serial, E> {
+        let s = s.trim_end();
+        let field = |from: usize, to: usize| s.get(from..to.min(s.len())).map(str::trim);
+        let data_server = field(0, 2).ok_or_else(|| invalid("no data server"))?;
+        let week = field(2, 12)
+            .and_then(|w| w.strip_prefix("WK"))
+            .and_then(|w| w.split_once('-'))
+            .ok_or_else(|| invalid("invalid week"))?;
+        let date = field(12, 20)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .ok_or_else(|| invalid("invalid date"))?;
+        let kind = match field(20, 30) {
+            Some("BASE") => SetKind::Base,
+            Some("UPDATE") => SetKind::Update,
+            _ => return Err(invalid("invalid exchange set type")),
+        };
+        let number = |n: &str| n.parse::<u32>().map_err(|_| invalid("invalid week"));
+        // the week is given with a two digit year, in the century of the date
+        let year = date.year() / 100 * 100 + number(week.1)? as i32;
+        Ok(Serial {
+            data_server: data_server.to_owned(),
+            week: (number(week.0)?, year),
+            date,
+            kind,
+            version: field(30, 35).unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+impl ExchangeSet {
+    /// reads and parses SERIAL.ENC
+    pub fn serial(&self) -> Result<Serial, E> {
+        let path = self
+            .serial_enc()
+            .ok_or_else(|| E::NoFile(super::SERIAL.to_owned()))?;
+        Serial::from_rdr(&self.read(path)?[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<(), E> {
+        let serial: Serial = "GBWK21-14   20140522BASE      02.00\r\n".parse()?;
+        let date = NaiveDate::from_ymd_opt(2014, 5, 22).unwrap();
+        assert_eq!(serial, Serial::new("GB", date, SetKind::Base));
+        let mut written = Vec::new();
+        serial.write(&mut written)?;
+        assert_eq!(written, b"GBWK21-14   20140522BASE      02.00\r\n");
+        let update = Serial::new(
+            "FR",
+            NaiveDate::from_ymd_opt(2021, 1, 2).unwrap(),
+            SetKind::Update,
+        );
+        assert_eq!(update.week, (53, 2020));
+        assert_eq!(update.to_string(), "FRWK53-20   20210102UPDATE    02.00");
+        assert!("GBWK21-14   2014052BASE".parse::<Serial>().is_err());
+        assert!("GBWK21-14   20140522OTHER     02.00"
+            .parse::<Serial>()
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! STATUS.LST, the status of the exchange sets on a medium
+
+use super::E;
+use chrono::NaiveDate;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+const STATUS: &str = "STATUS.LST";
+
+/// STATUS.LST from the root of a medium, a line per exchange set directory with its
+/// status, the date of the status and an optional comment, e.g.
+///
+/// ```text
+/// B1;CURRENT;20150525
+/// U1;SUPERSEDED;20150601;replaced by U2
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StatusList {
+    pub entries: Vec<SetStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetStatus {
+    /// the exchange set directory, e.g. B1
+    pub exchange_set: String,
+    pub status: Status,
+    pub date: Option<NaiveDate>,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Current,
+    /// a newer exchange set replaces this one
+    Superseded,
+    /// the exchange set must not be used
+    Withdrawn,
+    Other(String),
+}
+
+impl Status {
+    fn parse(s: &str) -> Status {
+        match s.to_ascii_uppercase().as_str() {
+            "CURRENT" => Status::Current,
+            "SUPERSEDED" => Status::Superseded,
+            "WITHDRAWN" => Status::Withdrawn,
+            _ => Status::Other(s.to_owned()),
+        }
+    }
+}
+
+impl StatusList {
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<StatusList, E> {
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    /// reads STATUS.LST in `dir`, the root of a medium. The file is optional, an empty
+    /// list is returned when there is none
+    pub fn open<D: AsRef<Path>>(dir: D) -> Result<StatusList, E> {
+        let file = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().eq_ignore_ascii_case(STATUS));
+        match file {
+            Some(f) => StatusList::from_rdr(fs::File::open(f.path())?),
+            None => Ok(StatusList::default()),
+        }
+    }
+
+    pub fn status(&self, set: &str) -> Option<&SetStatus> {
+        self.entries
+            .iter()
+            .find(|e| e.exchange_set.eq_ignore_ascii_case(set))
+    }
+
+    /// false for superseded and withdrawn exchange sets, sets that aren't listed are
+    /// usable
+    pub fn is_usable(&self, set: &str) -> bool {
+        !matches!(
+            self.status(set).map(|s| &s.status),
+            Some(Status::Superseded) | Some(Status::Withdrawn)
+        )
+    }
+}
+
+impl std::str::FromStr for StatusList {
+    type Err = E;
+
+    fn from_str(s: &str) -> Result<StatusList, E> {
+        let mut res = StatusList::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || E::Invalid {
+                file: STATUS,
+                reason: format!("invalid status on line {}", i + 1),
+            };
+            let mut fields = line.splitn(4, ';').map(str::trim);
+            let set = fields.next().unwrap_or_default();
+            let status = fields.next().filter(|s| !s.is_empty() && !set.is_empty());
+            let status = status.ok_or_else(invalid)?;
+            let date = match fields.next() {
+                None | Some("") => None,
+                Some(d) => Some(NaiveDate::parse_from_str(d, "%Y%m%d").map_err(|_| invalid())?),
+            };
+            res.entries.push(SetStatus {
+                exchange_set: set.to_owned(),
+                status: Status::parse(status),
+                date,
+                comment: fields.next().unwrap_or_default().to_owned(),
+            });
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() -> Result<(), E> {
+        let list = StatusList::from_rdr(
+            &b"B1;CURRENT;20150525\r\nU1;superseded;20150601;replaced by U2\r\n\
+               U0;WITHDRAWN\r\nU2;PENDING;;\r\n"[..],
+        )?;
+        assert_eq!(list.entries.len(), 4);
+        assert_eq!(list.entries[0].status, Status::Current);
+        assert_eq!(list.entries[0].date, NaiveDate::from_ymd_opt(2015, 5, 25));
+        assert_eq!(list.status("u1").unwrap().comment, "replaced by U2");
+        assert_eq!(
+            list.status("U2").unwrap().status,
+            Status::Other("PENDING".into())
+        );
+        assert!(list.is_usable("B1"));
+        assert!(!list.is_usable("U1"));
+        assert!(!list.is_usable("U0"));
+        assert!(list.is_usable("U2"));
+        assert!(list.is_usable("B9"));
+
+        assert!("B1".parse::<StatusList>().is_err());
+        assert!("B1;CURRENT;2015".parse::<StatusList>().is_err());
+        Ok(())
+    }
+}
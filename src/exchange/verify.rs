@@ -0,0 +1,197 @@
+//! Checks the files of an exchange set against CATALOG.031 and PRODUCTS.TXT
+
+use super::{ExchangeSet, E};
+use crc::crc32;
+use std::collections::{HashMap, HashSet};
+
+/// the result of `ExchangeSet::verify`, paths as given by `ExchangeSet::files`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    /// listed but not in the exchange set
+    pub missing: Vec<String>,
+    /// under ENC_ROOT but not listed in the catalogue
+    pub extra: Vec<String>,
+    pub corrupt: Vec<Corrupt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corrupt {
+    pub path: String,
+    pub mismatch: Mismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Crc { expected: u32, actual: u32 },
+    Size { expected: u64, actual: u64 },
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupt.is_empty()
+    }
+
+    fn missing(&mut self, path: String) {
+        if !self.missing.contains(&path) {
+            self.missing.push(path);
+        }
+    }
+
+    fn check(&mut self, path: &str, data: &[u8], crc: Option<u32>, size: Option<u64>) {
+        let mut mismatches = Vec::new();
+        if let Some(expected) = size.filter(|&s| s != data.len() as u64) {
+            mismatches.push(Mismatch::Size {
+                expected,
+                actual: data.len() as u64,
+            });
+        }
+        if let Some(expected) = crc {
+            let actual = crc32::checksum_ieee(data);
+            if actual != expected {
+                mismatches.push(Mismatch::Crc { expected, actual });
+            }
+        }
+        for mismatch in mismatches {
+            let c = Corrupt {
+                path: path.to_owned(),
+                mismatch,
+            };
+            if !self.corrupt.contains(&c) {
+                self.corrupt.push(c);
+            }
+        }
+    }
+}
+
+impl ExchangeSet {
+    /// checks that the files listed in CATALOG.031 and the cells listed in PRODUCTS.TXT
+    /// are in the exchange set with the listed sizes and CRCs, and that there are no
+    /// files under ENC_ROOT that the catalogue doesn't list. Every listed file is read.
+    /// Fails with `NoFile` when there is neither a catalogue nor a product list
+    pub fn verify(&self) -> Result<VerifyReport, E> {
+        if self.catalog().is_none() && self.products_txt().is_none() {
+            return Err(E::NoFile(super::CATALOG.to_owned()));
+        }
+        let mut report = VerifyReport::default();
+        if let Some(catalog_path) = self.catalog() {
+            let catalog = self.read_catalog()?;
+            // names are matched regardless of case, by their uppercase form
+            let mut files = HashMap::new();
+            for f in &self.files {
+                files.entry(f.to_ascii_uppercase()).or_insert(f.as_str());
+            }
+            let mut listed = HashSet::new();
+            for e in &catalog.entries {
+                let path = e.path();
+                let upper = path.to_ascii_uppercase();
+                match files.get(&upper) {
+                    Some(path) if path.eq_ignore_ascii_case(catalog_path) => (),
+                    Some(path) => report.check(path, &self.read(path)?, e.crc, None),
+                    None => report.missing(path),
+                }
+                listed.insert(upper);
+            }
+            for f in self.files.iter().filter(|f| super::in_enc_root(f)) {
+                if !f.eq_ignore_ascii_case(catalog_path)
+                    && !listed.contains(&f.to_ascii_uppercase())
+                {
+                    report.extra.push(f.clone());
+                }
+            }
+        }
+        if self.products_txt().is_some() {
+            for p in self.products()?.products {
+                let file = self.cell(&p.cell).find(|c| {
+                    c.update == p.file_update && c.edition.is_none_or(|e| e == p.edition)
+                });
+                match file {
+                    Some(f) => report.check(&f.path, &self.read(&f.path)?, p.crc, p.file_size),
+                    None => report.missing(format!("{}.{:03}", p.cell, p.file_update)),
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::{Catalog, CatalogEntry};
+
+    fn entry(file: &str, crc: u32) -> CatalogEntry {
+        CatalogEntry {
+            file: file.into(),
+            long_name: String::new(),
+            volume: "V01X01".into(),
+            implementation: "BIN".into(),
+            coverage: None,
+            crc: Some(crc),
+            comment: String::new(),
+        }
+    }
+
+    #[test]
+    fn verify() -> Result<(), E> {
+        let catalog = Catalog {
+            entries: vec![
+                entry("CATALOG.031", 0),
+                entry(
+                    "GB\\GB100001\\4\\0\\GB100001.000",
+                    crc32::checksum_ieee(b"base"),
+                ),
+                entry("GB\\GB100001\\4\\1\\GB100001.001", 1),
+                entry("GB\\GB100002\\1\\0\\GB100002.000", 2),
+            ],
+        };
+        let mut cat = Vec::new();
+        catalog.write(&mut cat)?;
+        let products = ":ENC\nGB100001.000,0,1,,4,,1,4\nGB100001.001,0,1,,4,,1,10\n\
+                        GB100003.000,0,1,,1,,0,4\n";
+        let dir = super::super::tests::write_set(
+            "verify",
+            &[
+                ("INFO/PRODUCTS.TXT", products.as_bytes()),
+                ("ENC_ROOT/CATALOG.031", &cat),
+                ("ENC_ROOT/GB/GB100001/4/0/GB100001.000", b"base"),
+                ("ENC_ROOT/GB/GB100001/4/1/GB100001.001", b"update"),
+                ("ENC_ROOT/GB/README.TXT", b"text"),
+            ],
+        );
+        let report = ExchangeSet::open(&dir).and_then(|s| s.verify());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dir = super::super::tests::write_set("verify-none", &[("ENC_ROOT/A.TXT", b"")]);
+        let unlisted = ExchangeSet::open(&dir).and_then(|s| s.verify());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = report?;
+
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.missing,
+            ["ENC_ROOT/GB/GB100002/1/0/GB100002.000", "GB100003.000"]
+        );
+        assert_eq!(report.extra, ["ENC_ROOT/GB/README.TXT"]);
+        let update = "ENC_ROOT/GB/GB100001/4/1/GB100001.001";
+        assert_eq!(
+            report.corrupt,
+            [
+                Corrupt {
+                    path: update.into(),
+                    mismatch: Mismatch::Crc {
+                        expected: 1,
+                        actual: crc32::checksum_ieee(b"update")
+                    }
+                },
+                Corrupt {
+                    path: update.into(),
+                    mismatch: Mismatch::Size {
+                        expected: 10,
+                        actual: 6
+                    }
+                },
+            ]
+        );
+        assert!(matches!(unlisted, Err(E::NoFile(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Warnings for permits about to expire and errors for expired permits, as ECDIS show them
+//! before the subscription runs out
+
+use crate::clock::Clock;
+use crate::permit::PermitRecord;
+use crate::sse::{SseCode, EXPIRY_WARNING_DAYS};
+use chrono::NaiveDate;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// the permit expires soon
+    Warning,
+    /// the permit has expired
+    Error,
+}
+
+/// an expired or expiring permit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiryNotice {
+    pub cell: String,
+    /// the last day the permit is valid
+    pub expiry: NaiveDate,
+    /// the days from the check to the expiry date, negative for expired permits
+    pub days_left: i64,
+    /// `PermitExpired` or `SubscriptionExpiring`
+    pub code: SseCode,
+}
+
+impl ExpiryNotice {
+    pub fn severity(&self) -> Severity {
+        if self.days_left < 0 {
+            Severity::Error
+        } else {
+            Severity::Warning
+        }
+    }
+}
+
+/// e.g. "SSE 20 - Subscription service will expire in less than 30 days. ...: GB100001
+/// expires 2020-06-30"
+impl fmt::Display for ExpiryNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match self.severity() {
+            Severity::Error => "expired",
+            Severity::Warning => "expires",
+        };
+        write!(f, "{}: {} {} {}", self.code, self.cell, verb, self.expiry)
+    }
+}
+
+/// the notices of `ExpiryCheck::report`, each sorted on expiry date then cell
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpiryReport {
+    pub errors: Vec<ExpiryNotice>,
+    pub warnings: Vec<ExpiryNotice>,
+}
+
+impl ExpiryReport {
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.warnings.is_empty()
+    }
+
+    /// the errors, then the warnings
+    pub fn iter(&self) -> impl Iterator<Item = &ExpiryNotice> {
+        self.errors.iter().chain(&self.warnings)
+    }
+}
+
+/// checks permits for expiry, warning `warning_days` days ahead. Works on any permits, e.g.
+/// those of a `PermitStore` with `PermitStore::iter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpiryCheck {
+    warning_days: i64,
+}
+
+impl Default for ExpiryCheck {
+    fn default() -> Self {
+        ExpiryCheck {
+            warning_days: EXPIRY_WARNING_DAYS,
+        }
+    }
+}
+
+impl ExpiryCheck {
+    /// warns `EXPIRY_WARNING_DAYS` ahead, the standard 30 days
+    pub fn new() -> ExpiryCheck {
+        ExpiryCheck::default()
+    }
+
+    pub fn warning_days(mut self, days: i64) -> Self {
+        self.warning_days = days;
+        self
+    }
+
+    /// the notice for `permit` on `today`, None for a permit that is neither expired nor
+    /// expiring
+    pub fn check(&self, permit: &PermitRecord, today: NaiveDate) -> Option<ExpiryNotice> {
+        let code = if permit.is_expired(today) {
+            SseCode::PermitExpired
+        } else if permit.expires_within(self.warning_days, today) {
+            SseCode::SubscriptionExpiring
+        } else {
+            return None;
+        };
+        Some(ExpiryNotice {
+            cell: permit.cell_permit.cell.clone(),
+            expiry: permit.cell_permit.date,
+            days_left: (permit.cell_permit.date - today).num_days(),
+            code,
+        })
+    }
+
+    /// the notices of `permits` on `today`, in the order of the permits
+    pub fn notices<'a, I>(
+        &self,
+        permits: I,
+        today: NaiveDate,
+    ) -> impl Iterator<Item = ExpiryNotice> + 'a
+    where
+        I: IntoIterator<Item = &'a PermitRecord>,
+        I::IntoIter: 'a,
+    {
+        let check = *self;
+        permits
+            .into_iter()
+            .filter_map(move |p| check.check(p, today))
+    }
+
+    pub fn notices_at<'a, I, C>(
+        &self,
+        permits: I,
+        clock: C,
+    ) -> impl Iterator<Item = ExpiryNotice> + 'a
+    where
+        I: IntoIterator<Item = &'a PermitRecord>,
+        I::IntoIter: 'a,
+        C: Clock,
+    {
+        self.notices(permits, clock.today())
+    }
+
+    /// the notices of `permits` on `today` by severity
+    pub fn report<'a, I>(&self, permits: I, today: NaiveDate) -> ExpiryReport
+    where
+        I: IntoIterator<Item = &'a PermitRecord>,
+    {
+        let mut res = ExpiryReport::default();
+        for n in permits.into_iter().filter_map(|p| self.check(p, today)) {
+            match n.severity() {
+                Severity::Error => res.errors.push(n),
+                Severity::Warning => res.warnings.push(n),
+            }
+        }
+        for notices in [&mut res.errors, &mut res.warnings] {
+            notices.sort_by(|a, b| (a.expiry, &a.cell).cmp(&(b.expiry, &b.cell)));
+        }
+        res
+    }
+
+    pub fn report_at<'a, I, C>(&self, permits: I, clock: C) -> ExpiryReport
+    where
+        I: IntoIterator<Item = &'a PermitRecord>,
+        C: Clock,
+    {
+        self.report(permits, clock.today())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permit::test_permit;
+    use crate::store::{MemoryStore, PermitStore};
+
+    fn permit(cell: &str, date: NaiveDate) -> PermitRecord {
+        let mut p = test_permit(cell, &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+        p.cell_permit.date = date;
+        p
+    }
+
+    #[test]
+    fn report() -> Result<(), crate::errors::E> {
+        let day = |m, d| NaiveDate::from_ymd_opt(2020, m, d).unwrap();
+        let mut store = MemoryStore::new();
+        store.insert(permit("GB100001", day(6, 30)))?;
+        store.insert(permit("GB100002", day(5, 31)))?;
+        store.insert(permit("GB100003", day(12, 31)))?;
+        store.insert(permit("GB100004", day(6, 10)))?;
+        store.insert(permit("GB100005", day(5, 1)))?;
+
+        let report = ExpiryCheck::new().report(store.iter(), day(6, 1));
+        let cells = |n: &[ExpiryNotice]| n.iter().map(|n| n.cell.clone()).collect::<Vec<_>>();
+        assert_eq!(cells(&report.errors), ["GB100005", "GB100002"]);
+        assert_eq!(cells(&report.warnings), ["GB100004", "GB100001"]);
+        assert_eq!(report.errors[1].days_left, -1);
+        assert_eq!(report.errors[1].code, SseCode::PermitExpired);
+        assert_eq!(report.warnings[1].days_left, 29);
+        assert_eq!(report.warnings[1].code, SseCode::SubscriptionExpiring);
+        assert_eq!(report.iter().count(), 4);
+        assert!(report.warnings[0].to_string().starts_with("SSE 20 - "));
+        assert!(report.errors[0]
+            .to_string()
+            .ends_with("GB100005 expired 2020-05-01"));
+
+        let short = ExpiryCheck::new().warning_days(10);
+        let warnings: Vec<_> = short
+            .notices(store.iter(), day(6, 1))
+            .filter(|n| n.severity() == Severity::Warning)
+            .collect();
+        assert_eq!(cells(&warnings), ["GB100004"]);
+        assert!(ExpiryCheck::new()
+            .report(store.iter(), day(1, 1))
+            .is_empty());
+        Ok(())
+    }
+}
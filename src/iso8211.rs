@@ -0,0 +1,455 @@
+//! ISO/IEC 8211, the record format of CATALOG.031 and S-57 cell files
+//!
+//! ```no_run
+//! use rust_s63::iso8211::File;
+//!
+//! let data = std::fs::read("ENC_ROOT/CATALOG.031")?;
+//! let file = File::from_bytes(&data)?;
+//! for rec in file.records() {
+//!     if let Some(catd) = rec.field("CATD") {
+//!         for (label, value) in catd.subfields()? {
+//!             println!("{}: {}", label, String::from_utf8_lossy(value));
+//!         }
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+// field and unit terminators
+pub(crate) const FT: u8 = 0x1e;
+pub(crate) const UT: u8 = 0x1f;
+
+/// why an ISO/IEC 8211 file can't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct E(pub String);
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid ISO/IEC 8211 file: {}", self.0)
+    }
+}
+
+impl std::error::Error for E {}
+
+fn invalid(reason: &str) -> E {
+    E(reason.to_owned())
+}
+
+pub(crate) fn num(b: &[u8]) -> Result<usize, E> {
+    std::str::from_utf8(b)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| invalid("invalid number in record leader or directory"))
+}
+
+/// the format of a subfield, the width is None for subfields ended by a unit terminator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text(Option<usize>),
+    Binary(usize),
+}
+
+// more subfields than any S-57 field has, so a bogus repeat count can't allocate without bound
+const MAX_FORMATS: usize = 4096;
+
+// "(A(2),I(10),3A,A(3),4R,2A)" as one format per subfield
+pub(crate) fn formats(s: &str) -> Result<Vec<Format>, E> {
+    let err = || invalid("invalid format controls");
+    let s = s.trim();
+    let s = s
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or_else(err)?;
+    let mut res = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut tokens = Vec::new();
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                tokens.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    tokens.push(&s[start..]);
+    for t in tokens.into_iter().map(str::trim).filter(|t| !t.is_empty()) {
+        let digits = t.bytes().take_while(u8::is_ascii_digit).count();
+        let count = if digits == 0 {
+            1
+        } else {
+            num(&t.as_bytes()[..digits])?
+        };
+        let t = &t[digits..];
+        let one = if t.starts_with('(') {
+            formats(t)?
+        } else if let Some(bits) = t.strip_prefix('b') {
+            // b1w, b2w: unsigned and signed integers of w bytes
+            vec![Format::Binary(num(bits
+                .as_bytes()
+                .get(1..)
+                .ok_or_else(err)?)?)]
+        } else {
+            let width = match t.get(1..).filter(|w| !w.is_empty()) {
+                None => None,
+                Some(w) => {
+                    let w = w.strip_prefix('(').and_then(|w| w.strip_suffix(')'));
+                    Some(num(w.ok_or_else(err)?.as_bytes())?)
+                }
+            };
+            match *t.as_bytes().first().ok_or_else(err)? {
+                b'A' | b'I' | b'R' | b'S' | b'C' | b'X' => vec![Format::Text(width)],
+                // bit strings, the width is in bits
+                b'B' => vec![Format::Binary(width.ok_or_else(err)? / 8)],
+                _ => return Err(err()),
+            }
+        };
+        let total = count
+            .checked_mul(one.len())
+            .and_then(|n| n.checked_add(res.len()))
+            .filter(|n| *n <= MAX_FORMATS);
+        if total.is_none() {
+            return Err(invalid("too many subfields in format controls"));
+        }
+        for _ in 0..count {
+            res.extend_from_slice(&one);
+        }
+    }
+    Ok(res)
+}
+
+/// the description of a field from the data descriptive record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDesc {
+    pub name: String,
+    /// the subfield labels, without the leading '*' of repeating fields
+    pub labels: Vec<String>,
+    pub formats: Vec<Format>,
+}
+
+impl FieldDesc {
+    /// the subfields of a field as label and raw value
+    pub fn subfields<'a>(&'a self, mut data: &'a [u8]) -> Result<Vec<(&'a str, &'a [u8])>, E> {
+        let mut res = Vec::new();
+        for (label, format) in self.labels.iter().zip(&self.formats) {
+            if data.is_empty() || data == [FT] {
+                break;
+            }
+            let (value, len) = subfield(*format, data)?;
+            data = &data[len..];
+            res.push((label.as_str(), value));
+        }
+        Ok(res)
+    }
+
+    /// the repetitions of the subfields of a repeating field such as the coordinates of
+    /// SG2D, each with its unit terminators
+    pub fn groups<'a>(&self, mut data: &'a [u8]) -> Result<Vec<&'a [u8]>, E> {
+        data = data.strip_suffix(&[FT]).unwrap_or(data);
+        let mut res = Vec::new();
+        while !data.is_empty() {
+            let mut len = 0;
+            for format in &self.formats {
+                len += subfield(*format, &data[len.min(data.len())..])?.1;
+            }
+            if len == 0 || len > data.len() {
+                return Err(invalid("truncated subfield"));
+            }
+            res.push(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(res)
+    }
+}
+
+// the value of a subfield at the start of `data` and the number of bytes it takes,
+// including the unit terminator
+fn subfield(format: Format, data: &[u8]) -> Result<(&[u8], usize), E> {
+    match format {
+        Format::Text(Some(w)) | Format::Binary(w) => {
+            let value = data.get(..w).ok_or_else(|| invalid("truncated subfield"))?;
+            Ok((value, w))
+        }
+        Format::Text(None) => {
+            let end = data.iter().position(|&b| b == UT || b == FT);
+            let end = end.unwrap_or(data.len());
+            Ok((&data[..end], (end + 1).min(data.len())))
+        }
+    }
+}
+
+// the fields of a record, tag and data without the field terminator
+pub(crate) type Record<'a> = Vec<(&'a str, &'a [u8])>;
+
+/// the subfields of a field as label and raw value
+pub type Subfields<'a> = Vec<(&'a str, &'a [u8])>;
+
+/// an ISO/IEC 8211 file read from memory, the field descriptions of its data descriptive
+/// record and its data records
+#[derive(Debug)]
+pub struct File<'a> {
+    descs: HashMap<String, FieldDesc>,
+    records: Vec<Record<'a>>,
+}
+
+/// a data record of a `File`
+#[derive(Debug, Clone, Copy)]
+pub struct DataRecord<'a> {
+    descs: &'a HashMap<String, FieldDesc>,
+    fields: &'a [(&'a str, &'a [u8])],
+}
+
+/// a field of a data record
+#[derive(Debug, Clone, Copy)]
+pub struct Field<'a> {
+    pub tag: &'a str,
+    /// the field data without the field terminator
+    pub data: &'a [u8],
+    /// None when the data descriptive record doesn't describe the field
+    pub desc: Option<&'a FieldDesc>,
+}
+
+impl<'a> File<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Result<File<'a>, E> {
+        let (descs, records) = parse(data)?;
+        Ok(File { descs, records })
+    }
+
+    pub fn field_desc(&self, tag: &str) -> Option<&FieldDesc> {
+        self.descs.get(tag)
+    }
+
+    /// the data records in file order
+    pub fn records(&self) -> impl Iterator<Item = DataRecord<'_>> {
+        self.records.iter().map(move |r| DataRecord {
+            descs: &self.descs,
+            fields: r,
+        })
+    }
+}
+
+impl<'a> DataRecord<'a> {
+    /// the fields in record order, starting with the record identifier 0001
+    pub fn fields(&self) -> impl Iterator<Item = Field<'a>> + 'a {
+        let descs = self.descs;
+        self.fields.iter().map(move |&(tag, data)| Field {
+            tag,
+            data,
+            desc: descs.get(tag),
+        })
+    }
+
+    /// the first field `tag`
+    pub fn field(&self, tag: &str) -> Option<Field<'a>> {
+        self.fields().find(|f| f.tag == tag)
+    }
+}
+
+impl<'a> Field<'a> {
+    /// the subfields as label and raw value, empty for undescribed fields
+    pub fn subfields(&self) -> Result<Subfields<'a>, E> {
+        match self.desc {
+            Some(desc) => desc.subfields(self.data),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// the subfields of every repetition of a repeating field
+    pub fn groups(&self) -> Result<Vec<Subfields<'a>>, E> {
+        let desc = match self.desc {
+            Some(desc) => desc,
+            None => return Ok(Vec::new()),
+        };
+        desc.groups(self.data)?
+            .into_iter()
+            .map(|g| desc.subfields(g))
+            .collect()
+    }
+}
+
+pub(crate) fn record(rec: &[u8]) -> Result<Record<'_>, E> {
+    let leader = rec.get(..24).ok_or_else(|| invalid("truncated record"))?;
+    let base = num(&leader[12..17])?;
+    let (size_len, size_pos, size_tag) = (
+        num(&leader[20..21])?,
+        num(&leader[21..22])?,
+        num(&leader[23..24])?,
+    );
+    let entry = size_len + size_pos + size_tag;
+    let mut dir = &rec[24..];
+    let mut res = Vec::new();
+    while dir.first().is_some_and(|&b| b != FT) {
+        let e = dir
+            .get(..entry)
+            .ok_or_else(|| invalid("truncated directory"))?;
+        let tag = std::str::from_utf8(&e[..size_tag]).map_err(|_| invalid("invalid tag"))?;
+        let len = num(&e[size_tag..size_tag + size_len])?;
+        let pos = num(&e[size_tag + size_len..])?;
+        let field = rec
+            .get(base + pos..base + pos + len)
+            .ok_or_else(|| invalid("field outside of record"))?;
+        res.push((tag, field.strip_suffix(&[FT]).unwrap_or(field)));
+        dir = &dir[entry..];
+    }
+    Ok(res)
+}
+
+// the records of a file
+pub(crate) fn records(mut data: &[u8]) -> Result<Vec<Record<'_>>, E> {
+    let mut res = Vec::new();
+    while !data.is_empty() {
+        let len = num(data.get(..5).ok_or_else(|| invalid("truncated record"))?)?;
+        let rec = data.get(..len).ok_or_else(|| invalid("truncated record"))?;
+        res.push(record(rec)?);
+        data = &data[len..];
+    }
+    Ok(res)
+}
+
+// the field descriptions of the data descriptive record, the first record
+pub(crate) fn field_descs(
+    ddr: &[(&str, &[u8])],
+    controls: usize,
+) -> Result<HashMap<String, FieldDesc>, E> {
+    let mut res = HashMap::new();
+    for (tag, data) in ddr.iter().filter(|(tag, _)| *tag != "0000") {
+        let mut parts = data.get(controls..).unwrap_or_default().split(|&b| b == UT);
+        let name = String::from_utf8_lossy(parts.next().unwrap_or_default());
+        let labels = String::from_utf8_lossy(parts.next().unwrap_or_default());
+        let format = String::from_utf8_lossy(parts.next().unwrap_or_default());
+        let desc = FieldDesc {
+            name: name.trim().to_owned(),
+            labels: labels
+                .trim_start_matches('*')
+                .split('!')
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect(),
+            formats: if format.is_empty() {
+                Vec::new()
+            } else {
+                formats(&format)?
+            },
+        };
+        res.insert(tag.to_string(), desc);
+    }
+    Ok(res)
+}
+
+// the field descriptions of the data descriptive record and the data records of a file
+pub(crate) fn parse(data: &[u8]) -> Result<(HashMap<String, FieldDesc>, Vec<Record<'_>>), E> {
+    let mut recs = records(data)?;
+    if recs.is_empty() {
+        return Err(invalid("no records"));
+    }
+    let ddr = recs.remove(0);
+    let controls = num(&data[10..12])?;
+    Ok((field_descs(&ddr, controls)?, recs))
+}
+
+pub(crate) fn field_desc(controls: &str, name: &str, labels: &str, format: &str) -> Vec<u8> {
+    format!("{}{}\x1f{}\x1f{}", controls, name, labels, format).into_bytes()
+}
+
+// a record with `leader` as leader positions 5 to 11 and `ext` as 17 to 19
+pub(crate) fn write_record(leader: &[u8], ext: &[u8], fields: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut area = Vec::new();
+    let mut dir = Vec::new();
+    for (tag, data) in fields {
+        dir.push((tag, data.len() + 1, area.len()));
+        area.extend_from_slice(data);
+        area.push(FT);
+    }
+    let digits = |n: usize| n.to_string().len();
+    let size_len = dir.iter().map(|d| digits(d.1)).max().unwrap_or(1);
+    let size_pos = dir.iter().map(|d| digits(d.2)).max().unwrap_or(1);
+    let base = 24 + dir.len() * (4 + size_len + size_pos) + 1;
+    let mut res = format!("{:05}", base + area.len()).into_bytes();
+    res.extend_from_slice(leader);
+    res.extend_from_slice(format!("{:05}", base).as_bytes());
+    res.extend_from_slice(ext);
+    res.extend_from_slice(format!("{}{}04", size_len, size_pos).as_bytes());
+    for (tag, len, pos) in dir {
+        res.extend_from_slice(tag.as_bytes());
+        res.extend_from_slice(format!("{:0w$}", len, w = size_len).as_bytes());
+        res.extend_from_slice(format!("{:0w$}", pos, w = size_pos).as_bytes());
+    }
+    res.push(FT);
+    res.extend_from_slice(&area);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_controls() -> Result<(), E> {
+        assert_eq!(
+            formats("(A(2),I(10),2A,(b11,B(40)))")?,
+            [
+                Format::Text(Some(2)),
+                Format::Text(Some(10)),
+                Format::Text(None),
+                Format::Text(None),
+                Format::Binary(1),
+                Format::Binary(5),
+            ]
+        );
+        assert!(formats("A(2)").is_err());
+        assert!(formats("(A,2)").is_err());
+        assert!(formats("(99999999A)").is_err());
+        assert!(formats("(99999(99999A))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn file() -> Result<(), E> {
+        let ddr = [
+            ("0000", b"0000;&   TEST".to_vec()),
+            (
+                "0001",
+                field_desc("0100;&   ", "Record identifier", "", "(b12)"),
+            ),
+            (
+                "PNTS",
+                field_desc("2600;&   ", "Points field", "*NAME!X!Y", "(A,2b11)"),
+            ),
+        ];
+        let mut data = write_record(b"3LE1 09", b" ! ", &ddr);
+        let fields = [
+            ("0001", 1u16.to_le_bytes().to_vec()),
+            ("PNTS", b"a\x1f\x01\x02b\x1f\x03\x04".to_vec()),
+            ("XTRA", b"raw".to_vec()),
+        ];
+        data.extend_from_slice(&write_record(b" D     ", b"   ", &fields));
+
+        let file = File::from_bytes(&data)?;
+        assert_eq!(file.field_desc("PNTS").unwrap().name, "Points field");
+        assert_eq!(file.field_desc("PNTS").unwrap().labels, ["NAME", "X", "Y"]);
+        let recs: Vec<_> = file.records().collect();
+        assert_eq!(recs.len(), 1);
+        let tags: Vec<_> = recs[0].fields().map(|f| f.tag).collect();
+        assert_eq!(tags, ["0001", "PNTS", "XTRA"]);
+        let pnts = recs[0].field("PNTS").unwrap();
+        assert_eq!(
+            pnts.groups()?,
+            [
+                vec![("NAME", &b"a"[..]), ("X", &[1][..]), ("Y", &[2][..])],
+                vec![("NAME", &b"b"[..]), ("X", &[3][..]), ("Y", &[4][..])],
+            ]
+        );
+        assert_eq!(pnts.subfields()?.len(), 3);
+        let xtra = recs[0].field("XTRA").unwrap();
+        assert!(xtra.desc.is_none() && xtra.subfields()?.is_empty());
+        assert_eq!(xtra.data, b"raw");
+        assert!(File::from_bytes(&data[..data.len() - 1]).is_err());
+        Ok(())
+    }
+}
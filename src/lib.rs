@@ -2,6 +2,46 @@ pub mod up;
 
 pub mod permit;
 
+pub mod cache;
+
+pub mod cipher;
+
 pub mod decrypter;
 
+pub mod encrypter;
+
 pub mod errors;
+
+pub mod exchange;
+
+pub mod iso8211;
+
+pub mod clock;
+
+pub mod cell;
+
+pub mod secret;
+
+pub mod store;
+
+pub mod registry;
+
+pub mod s57;
+
+#[cfg(feature = "signature")]
+pub mod signature;
+
+pub mod permit_index;
+
+pub mod sse;
+
+pub mod expiry;
+
+pub mod audit;
+
+pub mod client;
+
+pub mod server;
+
+#[cfg(feature = "tokio")]
+pub mod async_io;
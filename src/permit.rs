@@ -1,17 +1,35 @@
-use crate::errors::E;
+use crate::cell::CellName;
+use crate::cipher::{BlockCipher, Blowfish};
+use crate::clock::{Clock, SystemClock};
+use crate::errors::{Field, E};
+use crate::secret::{SecretKey, Zeroizing};
+use crate::up::UserPermit;
 use chrono::prelude::*;
 use crc::crc32;
-use crypto::blowfish::Blowfish;
-use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
-use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+mod export;
+pub use self::export::*;
+
 const PERMIT_RECORD_LENGTH: usize = 8 + 8 + 16 + 16 + 16;
+const LINE_END: &str = "\r\n";
 
 pub trait GetPermit {
     fn get_permit(&self, cell: &str) -> Option<&PermitRecord>;
+
+    /// like `get_permit` but rejects names that are not valid S-57 cell names
+    fn get_permit_checked(&self, cell: &str) -> Result<Option<&PermitRecord>, E> {
+        let cell = CellName::new(cell)?;
+        Ok(self.get_permit(&cell))
+    }
 }
 
 pub struct EmptyPermit();
@@ -28,6 +46,103 @@ impl<S: ::std::hash::BuildHasher> GetPermit for HashMap<String, PermitRecord, S>
     }
 }
 
+impl GetPermit for BTreeMap<String, PermitRecord> {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.get(cell)
+    }
+}
+
+impl GetPermit for [PermitRecord] {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.iter().find(|p| p.cell_permit.cell == cell)
+    }
+}
+
+impl GetPermit for Vec<PermitRecord> {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.as_slice().get_permit(cell)
+    }
+}
+
+impl<T: GetPermit + ?Sized> GetPermit for &T {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        (**self).get_permit(cell)
+    }
+}
+
+/// lookup of permits on ranges of cell names, for permit sets sorted on cell name
+pub trait GetPermitRange: GetPermit {
+    /// the permits with cell names in `from..to`, in order
+    fn permits_in_range<'a>(&'a self, from: &str, to: &str) -> Vec<&'a PermitRecord>;
+
+    /// the permits with cell names starting with `prefix`, e.g. a producer code, in order
+    fn permits_with_prefix<'a>(&'a self, prefix: &str) -> Vec<&'a PermitRecord>;
+}
+
+impl GetPermitRange for BTreeMap<String, PermitRecord> {
+    fn permits_in_range<'a>(&'a self, from: &str, to: &str) -> Vec<&'a PermitRecord> {
+        if from >= to {
+            return Vec::new();
+        }
+        self.range::<str, _>((Bound::Included(from), Bound::Excluded(to)))
+            .map(|(_, p)| p)
+            .collect()
+    }
+
+    fn permits_with_prefix<'a>(&'a self, prefix: &str) -> Vec<&'a PermitRecord> {
+        self.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(c, _)| c.starts_with(prefix))
+            .map(|(_, p)| p)
+            .collect()
+    }
+}
+
+/// permits kept in a vector sorted on cell name, looked up with binary search
+#[derive(Debug, Default)]
+pub struct SortedPermits(Vec<PermitRecord>);
+
+impl SortedPermits {
+    /// sorts the permits, for duplicate cells the last permit is kept
+    pub fn new(mut permits: Vec<PermitRecord>) -> SortedPermits {
+        permits.reverse();
+        permits.sort_by(|a, b| a.cell_permit.cell.cmp(&b.cell_permit.cell));
+        permits.dedup_by(|a, b| a.cell_permit.cell == b.cell_permit.cell);
+        SortedPermits(permits)
+    }
+
+    pub fn as_slice(&self) -> &[PermitRecord] {
+        &self.0
+    }
+
+    // index of the first permit with a cell name not less than `cell`
+    fn lower_bound(&self, cell: &str) -> usize {
+        self.0
+            .partition_point(|p| p.cell_permit.cell.as_str() < cell)
+    }
+}
+
+impl GetPermit for SortedPermits {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.0
+            .get(self.lower_bound(cell))
+            .filter(|p| p.cell_permit.cell == cell)
+    }
+}
+
+impl GetPermitRange for SortedPermits {
+    fn permits_in_range<'a>(&'a self, from: &str, to: &str) -> Vec<&'a PermitRecord> {
+        let (start, end) = (self.lower_bound(from), self.lower_bound(to));
+        self.0[start..end.max(start)].iter().collect()
+    }
+
+    fn permits_with_prefix<'a>(&'a self, prefix: &str) -> Vec<&'a PermitRecord> {
+        self.0[self.lower_bound(prefix)..]
+            .iter()
+            .take_while(|p| p.cell_permit.cell.starts_with(prefix))
+            .collect()
+    }
+}
+
 /// convinience method to get a GetPermit from a reader
 pub fn permit_from_rdr<R: Read>(rdr: R, key: &str) -> Result<impl GetPermit, E> {
     let mut res = HashMap::new();
@@ -39,27 +154,360 @@ pub fn permit_from_rdr<R: Read>(rdr: R, key: &str) -> Result<impl GetPermit, E>
     Ok(res)
 }
 
+/// permits loaded with several candidate HW_IDs, see `permit_from_rdr_multi`
+#[derive(Debug, Default)]
+pub struct MultiHwidPermits {
+    permits: HashMap<String, PermitRecord>,
+    hwids: HashMap<String, String>,
+}
+
+impl MultiHwidPermits {
+    /// the HW_ID that validated the permit for `cell`
+    pub fn matched_hwid(&self, cell: &str) -> Option<&str> {
+        self.hwids.get(cell).map(String::as_str)
+    }
+}
+
+impl GetPermit for MultiHwidPermits {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.permits.get(cell)
+    }
+}
+
+/// like `permit_from_rdr` but each permit is decrypted with whichever of the candidate
+/// HW_IDs validates its checksum
+pub fn permit_from_rdr_multi<R: Read>(rdr: R, keys: &[&str]) -> Result<MultiHwidPermits, E> {
+    let mut res = MultiHwidPermits::default();
+    let (_, f) = PermitFile::new(rdr)?;
+    for permit in f.raw_permits() {
+        let (p, key) = permit?.decrypt_keys_any(keys)?;
+        res.hwids
+            .insert(p.cell_permit.cell.clone(), String::from(key));
+        res.permits.insert(p.cell_permit.cell.clone(), p);
+    }
+    Ok(res)
+}
+
+/// how to resolve two permits for the same cell when merging permit sources
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    /// keep the permit with the latest expiry date, then the highest edition
+    NewestExpiry,
+    /// keep the permit with the highest edition, then the latest expiry date
+    HighestEdition,
+}
+
+impl MergePolicy {
+    /// whether `new` replaces `old` for the same cell, on ties it does
+    pub fn prefers(self, new: &PermitRecord, old: &PermitRecord) -> bool {
+        match self {
+            MergePolicy::NewestExpiry => {
+                (new.cell_permit.date, new.edition) >= (old.cell_permit.date, old.edition)
+            }
+            MergePolicy::HighestEdition => {
+                (new.edition, new.cell_permit.date) >= (old.edition, old.cell_permit.date)
+            }
+        }
+    }
+}
+
+/// merges several sources of permits, e.g. base, update and AIO permit files, into one GetPermit.
+/// On ties the permit from the later source wins.
+pub fn merge<I, P>(sources: I, policy: MergePolicy) -> Result<HashMap<String, PermitRecord>, E>
+where
+    I: IntoIterator<Item = P>,
+    P: IntoIterator<Item = Result<PermitRecord, E>>,
+{
+    let mut res = HashMap::new();
+    for source in sources {
+        for permit in source {
+            merge_permit(&mut res, permit?, policy);
+        }
+    }
+    Ok(res)
+}
+
+fn merge_permit(map: &mut HashMap<String, PermitRecord>, p: PermitRecord, policy: MergePolicy) {
+    let replace = match map.get(&p.cell_permit.cell) {
+        Some(old) => policy.prefers(&p, old),
+        None => true,
+    };
+    if replace {
+        map.insert(p.cell_permit.cell.clone(), p);
+    }
+}
+
+/// the difference between two permit sets, see `diff`
+#[derive(Debug, Default, PartialEq)]
+pub struct PermitDiff<'a> {
+    pub added: Vec<&'a PermitRecord>,
+    pub removed: Vec<&'a PermitRecord>,
+    /// (old, new) pairs where the expiry date changed
+    pub renewed: Vec<(&'a PermitRecord, &'a PermitRecord)>,
+    /// (old, new) pairs where the edition changed
+    pub edition_changed: Vec<(&'a PermitRecord, &'a PermitRecord)>,
+}
+
+/// computes what changed between two permit sets, all lists are sorted on cell name
+pub fn diff<'a, S: ::std::hash::BuildHasher>(
+    old: &'a HashMap<String, PermitRecord, S>,
+    new: &'a HashMap<String, PermitRecord, S>,
+) -> PermitDiff<'a> {
+    let mut res = PermitDiff::default();
+    for (cell, n) in new {
+        match old.get(cell) {
+            Some(o) => {
+                if o.cell_permit.date != n.cell_permit.date {
+                    res.renewed.push((o, n));
+                }
+                if o.edition != n.edition {
+                    res.edition_changed.push((o, n));
+                }
+            }
+            None => res.added.push(n),
+        }
+    }
+    res.removed = old
+        .iter()
+        .filter(|(c, _)| !new.contains_key(*c))
+        .map(|(_, o)| o)
+        .collect();
+
+    res.added
+        .sort_by(|a, b| a.cell_permit.cell.cmp(&b.cell_permit.cell));
+    res.removed
+        .sort_by(|a, b| a.cell_permit.cell.cmp(&b.cell_permit.cell));
+    res.renewed
+        .sort_by(|a, b| a.0.cell_permit.cell.cmp(&b.0.cell_permit.cell));
+    res.edition_changed
+        .sort_by(|a, b| a.0.cell_permit.cell.cmp(&b.0.cell_permit.cell));
+    res
+}
+
+/// generates subscription permit records for the given cells, as done on the data server side.
+/// The records are checked to be encryptable with the HW_ID of `up` and can be written
+/// with a `PermitFileWriter` using that HW_ID
+pub fn generate_permits<I: IntoIterator<Item = CellPermit>>(
+    up: &UserPermit,
+    data_server_id: &str,
+    cells: I,
+) -> Result<Vec<PermitRecord>, E> {
+    cells
+        .into_iter()
+        .map(|cell_permit| {
+            cell_permit.encrypt(up.hwid())?;
+            Ok(PermitRecord {
+                cell_permit,
+                sli: SericeLevelIndicator::SubscriptionPermit,
+                edition: None,
+                data_server_id: data_server_id.to_owned(),
+                comment: String::new(),
+                section: Section::Enc,
+                extras: Vec::new(),
+                original: None,
+            })
+        })
+        .collect()
+}
+
+/// copies the permit file read from `rdr` to `wtr`, re-encrypting every permit issued for
+/// `from` for the HW_ID `to`. The header, sections, editions and comments are kept.
+/// Returns the number of permits written
+pub fn reencrypt_permits<R: Read, W: Write>(
+    rdr: R,
+    wtr: W,
+    from: &str,
+    to: &str,
+) -> Result<usize, E> {
+    let (md, pf) = PermitFile::new(rdr)?;
+    let mut w = PermitFileWriter::new(wtr, &md, to)?;
+    let mut n = 0;
+    for p in pf.permits(from) {
+        w.write_permit(&p?)?;
+        n += 1;
+    }
+    w.finish()?;
+    Ok(n)
+}
+
+/// the result of `renew_permits`, in the order of the permits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Renewal {
+    /// subscription permits with the new expiry date
+    pub renewed: Vec<PermitRecord>,
+    /// single purchase permits and permits valid beyond the new date, unchanged
+    pub kept: Vec<PermitRecord>,
+}
+
+// renews `p` to `expiry`, false if it isn't renewed
+fn renew(p: &mut PermitRecord, expiry: NaiveDate) -> bool {
+    if p.sli != SericeLevelIndicator::SubscriptionPermit || p.cell_permit.date >= expiry {
+        return false;
+    }
+    p.cell_permit.date = expiry;
+    p.original = None;
+    true
+}
+
+impl Renewal {
+    fn push(&mut self, mut p: PermitRecord, expiry: NaiveDate) -> &PermitRecord {
+        let list = if renew(&mut p, expiry) {
+            &mut self.renewed
+        } else {
+            &mut self.kept
+        };
+        list.push(p);
+        &list[list.len() - 1]
+    }
+}
+
+/// renews the subscription permits of `permits` to expire on `expiry`, keeping the keys,
+/// edition and the other fields. The checksum of a renewed permit changes with the date
+/// when it is written. Single purchase permits aren't part of a subscription and permits
+/// are never shortened, both are kept as they are
+pub fn renew_permits<I: IntoIterator<Item = PermitRecord>>(
+    permits: I,
+    expiry: NaiveDate,
+) -> Renewal {
+    let mut res = Renewal::default();
+    for p in permits {
+        res.push(p, expiry);
+    }
+    res
+}
+
+/// copies the permit file of `hwid` read from `rdr` to `wtr` with the subscription permits
+/// renewed to `expiry` and the date of the file now, see `renew_permit_file_at`
+pub fn renew_permit_file<R: Read, W: Write>(
+    rdr: R,
+    wtr: W,
+    hwid: &str,
+    expiry: NaiveDate,
+) -> Result<Renewal, E> {
+    renew_permit_file_at(rdr, wtr, hwid, expiry, SystemClock)
+}
+
+/// copies the permit file of `hwid` read from `rdr` to `wtr` with the subscription permits
+/// renewed to `expiry`, see `renew_permits`. The file is dated at the time of `clock`,
+/// the other permits are written unchanged and in the same order
+pub fn renew_permit_file_at<R: Read, W: Write, C: Clock>(
+    rdr: R,
+    wtr: W,
+    hwid: &str,
+    expiry: NaiveDate,
+    clock: C,
+) -> Result<Renewal, E> {
+    let (md, pf) = PermitFile::new(rdr)?;
+    let md = MetaData {
+        date: clock.now(),
+        version: md.version,
+    };
+    let mut w = PermitFileWriter::new(wtr, &md, hwid)?;
+    let mut res = Renewal::default();
+    for p in pf.permits(hwid) {
+        w.write_permit(res.push(p?, expiry))?;
+    }
+    w.finish()?;
+    Ok(res)
+}
+
 /// convinience method to get a GetPermit from a file
 pub fn permit_from_file<R: AsRef<std::path::Path>>(
     path: R,
     key: &str,
 ) -> Result<impl GetPermit, E> {
-    Ok(permit_from_rdr(std::fs::File::open(path)?, key)?)
+    permit_from_rdr(std::fs::File::open(path)?, key)
 }
 
-#[derive(Debug, PartialEq)]
+/// permits merged from all PERMIT.TXT files in a directory tree, see `permit_from_dir`
+#[derive(Debug, Default)]
+pub struct DirPermits {
+    pub permits: HashMap<String, PermitRecord>,
+    /// the files that could not be read or parsed
+    pub errors: Vec<(PathBuf, E)>,
+}
+
+impl GetPermit for DirPermits {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.permits.get(cell)
+    }
+}
+
+/// walks the directory tree at `path`, e.g. an AVCS media folder, and merges the permits of
+/// every PERMIT.TXT found keeping the newest expiry. Files that fail to parse are reported
+/// in `DirPermits::errors`
+pub fn permit_from_dir<P: AsRef<Path>>(path: P, key: &str) -> Result<DirPermits, E> {
+    let mut files = Vec::new();
+    find_permit_files(path.as_ref(), &mut files)?;
+    let mut res = DirPermits::default();
+    for file in files {
+        let permits = std::fs::File::open(&file)
+            .map_err(E::from)
+            .and_then(PermitFile::new)
+            .and_then(|(_, pf)| pf.permits(key).collect::<Result<Vec<_>, _>>());
+        match permits {
+            Ok(permits) => {
+                for p in permits {
+                    merge_permit(&mut res.permits, p, MergePolicy::NewestExpiry);
+                }
+            }
+            Err(e) => res.errors.push((file, e)),
+        }
+    }
+    Ok(res)
+}
+
+fn find_permit_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), E> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_permit_files(&path, files)?;
+        } else if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.eq_ignore_ascii_case("PERMIT.TXT"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CellPermit {
     pub cell: String,
     pub date: NaiveDate,
-    pub key1: [u8; 5],
-    pub key2: [u8; 5],
+    pub key1: SecretKey,
+    pub key2: SecretKey,
 }
 
 impl CellPermit {
-    pub(crate) fn keys(&self) -> Keys {
+    pub fn builder() -> CellPermitBuilder {
+        CellPermitBuilder::default()
+    }
+
+    /// encrypts the cell keys with `hwid` and returns the 64 character cell permit string
+    pub fn encrypt(&self, hwid: &str) -> Result<String, E> {
+        self.encrypt_with_cipher::<Blowfish>(hwid)
+    }
+
+    /// like `encrypt` but with the cipher `C` instead of Blowfish
+    pub fn encrypt_with_cipher<C: BlockCipher>(&self, hwid: &str) -> Result<String, E> {
+        CellName::new(&self.cell)?;
+        if hwid.len() != 5 || !hwid.is_ascii() {
+            return Err(E::InvalidHwid(hwid.to_owned()));
+        }
+        Ok(encrypt_cell_permit::<C>(self, hwid))
+    }
+
+    pub(crate) fn keys(&self) -> Keys<'_> {
         Keys {
-            k1: &self.key1,
-            k2: &self.key2,
+            k1: self.key1.as_bytes(),
+            k2: self.key2.as_bytes(),
             i: 0,
         }
     }
@@ -90,7 +538,8 @@ impl<'a> Iterator for Keys<'a> {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum SericeLevelIndicator {
     SubscriptionPermit,
     SinglePurchasePermit,
@@ -107,92 +556,707 @@ impl FromStr for SericeLevelIndicator {
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for SericeLevelIndicator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SericeLevelIndicator::SubscriptionPermit => write!(f, "0"),
+            SericeLevelIndicator::SinglePurchasePermit => write!(f, "1"),
+        }
+    }
+}
+
+/// the section of the permit file a record belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Section {
+    /// ENC cell permits, the `:ENC` section
+    Enc,
+    /// permits for ECS and other products, the `:ECS` section
+    Ecs,
+}
+
+impl Section {
+    pub(crate) fn marker(self) -> &'static str {
+        match self {
+            Section::Enc => ":ENC",
+            Section::Ecs => ":ECS",
+        }
+    }
+
+    pub(crate) fn from_marker(l: &str) -> Option<Section> {
+        let l = l.trim_start();
+        if l.starts_with(":ENC") {
+            Some(Section::Enc)
+        } else if l.starts_with(":ECS") {
+            Some(Section::Ecs)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PermitRecord {
     pub cell_permit: CellPermit,
     pub sli: SericeLevelIndicator,
     pub edition: Option<u8>,
     pub data_server_id: String,
     pub comment: String,
+    pub section: Section,
+    /// proprietary columns after the comment, see `PermitFile::extra_columns`
+    pub extras: Vec<String>,
+    /// the permit as it was read from a file, `None` for permits created in code
+    pub original: Option<Original>,
 }
 
+/// a permit exactly as it appeared in a permit file
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Original {
+    /// the 64 character cell permit with the keys still encrypted
+    pub cell_permit: String,
+    /// the whole line, without the line ending
+    pub line: String,
+}
+
+/// records are compared by content, where they were read from is ignored
+impl PartialEq for PermitRecord {
+    fn eq(&self, other: &PermitRecord) -> bool {
+        self.cell_permit == other.cell_permit
+            && self.sli == other.sli
+            && self.edition == other.edition
+            && self.data_server_id == other.data_server_id
+            && self.comment == other.comment
+            && self.section == other.section
+            && self.extras == other.extras
+    }
+}
+
+impl PermitRecord {
+    pub fn builder() -> PermitRecordBuilder {
+        PermitRecordBuilder::default()
+    }
+
+    /// a permit is valid up to and including its expiry date
+    pub fn is_expired(&self, now: NaiveDate) -> bool {
+        now > self.cell_permit.date
+    }
+
+    /// true if the permit is still valid but expires within `days` days of `now`
+    pub fn expires_within(&self, days: i64, now: NaiveDate) -> bool {
+        !self.is_expired(now) && (self.cell_permit.date - now).num_days() <= days
+    }
+
+    pub fn is_expired_at<C: Clock>(&self, clock: C) -> bool {
+        self.is_expired(clock.today())
+    }
+
+    /// whether the permit licenses cells of `edition`. A permit without an edition is for
+    /// every edition, and cancellations, edition 0, need no license
+    pub fn permits_edition(&self, edition: u32) -> bool {
+        edition == 0 || self.edition.is_none_or(|e| u32::from(e) == edition)
+    }
+
+    /// formats the record as one row of a PERMIT.TXT file, encrypting the cell keys with `key`
+    pub fn serialize(&self, key: &str) -> Result<String, E> {
+        self.serialize_with_cipher::<Blowfish>(key)
+    }
+
+    /// like `serialize` but with the cipher `C` instead of Blowfish
+    pub fn serialize_with_cipher<C: BlockCipher>(&self, key: &str) -> Result<String, E> {
+        let mut s = format!(
+            "{},{},{},{},{}",
+            self.cell_permit.encrypt_with_cipher::<C>(key)?,
+            self.sli,
+            self.edition.map(|e| e.to_string()).unwrap_or_default(),
+            self.data_server_id,
+            self.comment
+        );
+        for extra in &self.extras {
+            s.push(',');
+            s.push_str(extra);
+        }
+        Ok(s)
+    }
+}
+
+#[derive(Default)]
+pub struct CellPermitBuilder {
+    cell: Option<String>,
+    date: Option<NaiveDate>,
+    key1: Option<Zeroizing<Vec<u8>>>,
+    key2: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl CellPermitBuilder {
+    pub fn cell(mut self, cell: &str) -> Self {
+        self.cell = Some(cell.to_owned());
+        self
+    }
+
+    pub fn date(mut self, date: NaiveDate) -> Self {
+        self.date = Some(date);
+        self
+    }
+
+    pub fn key1(mut self, key: &[u8]) -> Self {
+        self.key1 = Some(Zeroizing::new(key.to_vec()));
+        self
+    }
+
+    /// defaults to key1 if not set
+    pub fn key2(mut self, key: &[u8]) -> Self {
+        self.key2 = Some(Zeroizing::new(key.to_vec()));
+        self
+    }
+
+    pub fn build(self) -> Result<CellPermit, E> {
+        let cell = CellName::new(&self.cell.ok_or(E::MissingField("cell"))?)?.into();
+        let date = self.date.ok_or(E::MissingField("date"))?;
+        let key1 = to_key(&self.key1.ok_or(E::MissingField("key1"))?)?;
+        let key2 = match self.key2 {
+            Some(k) => to_key(&k)?,
+            None => key1.clone(),
+        };
+        Ok(CellPermit {
+            cell,
+            date,
+            key1,
+            key2,
+        })
+    }
+}
+
+fn to_key(k: &[u8]) -> Result<SecretKey, E> {
+    let mut key = [0u8; 5];
+    if k.len() != key.len() {
+        return Err(E::InvalidKeyLength(k.len()));
+    }
+    key.copy_from_slice(k);
+    Ok(SecretKey::new(key))
+}
+
+pub struct PermitRecordBuilder {
+    cell_permit: Option<CellPermit>,
+    sli: SericeLevelIndicator,
+    edition: Option<u8>,
+    data_server_id: Option<String>,
+    comment: String,
+    section: Section,
+    extras: Vec<String>,
+}
+
+impl Default for PermitRecordBuilder {
+    fn default() -> Self {
+        PermitRecordBuilder {
+            cell_permit: None,
+            sli: SericeLevelIndicator::SubscriptionPermit,
+            edition: None,
+            data_server_id: None,
+            comment: String::new(),
+            section: Section::Enc,
+            extras: Vec::new(),
+        }
+    }
+}
+
+impl PermitRecordBuilder {
+    pub fn cell_permit(mut self, cell_permit: CellPermit) -> Self {
+        self.cell_permit = Some(cell_permit);
+        self
+    }
+
+    pub fn sli(mut self, sli: SericeLevelIndicator) -> Self {
+        self.sli = sli;
+        self
+    }
+
+    pub fn edition(mut self, edition: u8) -> Self {
+        self.edition = Some(edition);
+        self
+    }
+
+    pub fn data_server_id(mut self, id: &str) -> Self {
+        self.data_server_id = Some(id.to_owned());
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = comment.to_owned();
+        self
+    }
+
+    pub fn section(mut self, section: Section) -> Self {
+        self.section = section;
+        self
+    }
+
+    /// proprietary columns written after the comment
+    pub fn extras<S: AsRef<str>>(mut self, extras: &[S]) -> Self {
+        self.extras = extras.iter().map(|e| e.as_ref().to_owned()).collect();
+        self
+    }
+
+    pub fn build(self) -> Result<PermitRecord, E> {
+        let data_server_id = self
+            .data_server_id
+            .ok_or(E::MissingField("data_server_id"))?;
+        if data_server_id.contains(',') {
+            return Err(E::InvalidField(String::from("data server id contains ','")));
+        }
+        if self.extras.iter().any(|e| e.contains(',')) {
+            return Err(E::InvalidField(String::from("extra column contains ','")));
+        }
+        Ok(PermitRecord {
+            cell_permit: self.cell_permit.ok_or(E::MissingField("cell_permit"))?,
+            sli: self.sli,
+            edition: self.edition,
+            data_server_id,
+            comment: self.comment,
+            section: self.section,
+            extras: self.extras,
+            original: None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetaData {
     pub date: NaiveDateTime,
     pub version: u8,
 }
 
+impl MetaData {
+    /// writes the `:DATE` and `:VERSION` header lines
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        write!(
+            wtr,
+            ":DATE {}{}",
+            self.date.format("%Y%m%d %H:%M"),
+            LINE_END
+        )?;
+        write!(wtr, ":VERSION {}{}", self.version, LINE_END)?;
+        Ok(())
+    }
+}
+
 pub struct PermitFile<R: Read> {
     file: BufReader<R>,
+    extra_columns: usize,
+}
+
+/// how to handle permits whose checksum does not match the HW_ID
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumPolicy {
+    /// checksum failures are errors
+    Strict,
+    /// checksum failures are collected as warnings, see `Permits::warnings`
+    Warn,
+    /// checksums are not validated
+    Skip,
 }
 
-pub struct Permits<'a, R: Read>(BufReader<R>, &'a str);
+/// iterates the permits of a file, decrypting the cell keys with the HW_ID
+pub struct Permits<'a, R: Read> {
+    raw: RawPermits<R>,
+    key: &'a str,
+    policy: ChecksumPolicy,
+    warnings: Vec<LineError>,
+}
+
+impl<'a, R: Read> Permits<'a, R> {
+    /// the checksum failures encountered so far with `ChecksumPolicy::Warn`
+    pub fn warnings(&self) -> &[LineError] {
+        &self.warnings
+    }
+}
 
 impl<'a, R: Read> Iterator for Permits<'a, R> {
     type Item = Result<PermitRecord, E>;
 
     fn next(&mut self) -> Option<Result<PermitRecord, E>> {
-        let mut s = String::new();
-        let res = match self.0.read_line(&mut s) {
-            Ok(r) => match r {
-                0 => None,
-                _ => Some(parse_permit(&s, self.1)),
-            },
-            Err(e) => Some(Err(e.into())),
-        };
+        let raw = self.raw.next()?;
+        let (key, line) = (self.key, self.raw.line);
+        let p = raw.and_then(|p| match self.policy {
+            ChecksumPolicy::Strict => p.decrypt_keys(key),
+            ChecksumPolicy::Warn => {
+                if let Err(e) = p.cell_permit.verify_checksum(key) {
+                    self.warnings.push(LineError {
+                        line,
+                        reason: e.at_line(line, &self.raw.raw),
+                    });
+                }
+                p.decrypt_keys_unchecked(key)
+            }
+            ChecksumPolicy::Skip => p.decrypt_keys_unchecked(key),
+        });
+        Some(p.map_err(|e| e.at_line(line, &self.raw.raw)))
+    }
+}
 
-        if s.starts_with(":ENC") || s.starts_with(":ECS") {
-            self.next()
-        } else {
-            res
+/// the result of `PermitFile::validate`, problems are identified by 1-based line number
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// the number of permit lines in the file, including unparsable ones
+    pub total: usize,
+    /// permits not issued for the HW_ID
+    pub checksum_failures: Vec<LineError>,
+    /// line and cell of permits that have expired
+    pub expired: Vec<(usize, String)>,
+    pub unparsable: Vec<LineError>,
+    /// line and cell of permits for a cell already seen in the same section
+    pub duplicates: Vec<(usize, String)>,
+}
+
+impl ValidationReport {
+    /// true if every permit in the file is usable with the HW_ID
+    pub fn is_ok(&self) -> bool {
+        self.checksum_failures.is_empty()
+            && self.expired.is_empty()
+            && self.unparsable.is_empty()
+            && self.duplicates.is_empty()
+    }
+
+    /// the number of permits without problems
+    pub fn valid(&self) -> usize {
+        let mut bad: Vec<usize> = self
+            .checksum_failures
+            .iter()
+            .chain(&self.unparsable)
+            .map(|e| e.line)
+            .chain(self.expired.iter().map(|(l, _)| *l))
+            .chain(self.duplicates.iter().map(|(l, _)| *l))
+            .collect();
+        bad.sort_unstable();
+        bad.dedup();
+        self.total - bad.len()
+    }
+}
+
+/// iterates the permits of a file, skipping malformed lines and collecting their errors
+pub struct LenientPermits<'a, R: Read> {
+    permits: Permits<'a, R>,
+    errors: Vec<LineError>,
+    // after a read error, a reader that fails keeps failing
+    done: bool,
+}
+
+/// an error on a specific line of a permit file
+#[derive(Debug)]
+pub struct LineError {
+    /// 1-based line number in the file
+    pub line: usize,
+    pub reason: E,
+}
+
+impl<'a, R: Read> LenientPermits<'a, R> {
+    /// the errors encountered so far
+    pub fn errors(&self) -> &[LineError] {
+        &self.errors
+    }
+
+    pub fn into_errors(self) -> Vec<LineError> {
+        self.errors
+    }
+}
+
+impl<'a, R: Read> Iterator for LenientPermits<'a, R> {
+    type Item = PermitRecord;
+
+    fn next(&mut self) -> Option<PermitRecord> {
+        while !self.done {
+            match self.permits.next()? {
+                Ok(p) => return Some(p),
+                Err(reason) => {
+                    // only malformed lines are skipped, the iteration stops at a read error
+                    self.done = matches!(reason.root(), E::IoErr(_));
+                    self.errors.push(LineError {
+                        line: self.permits.raw.line,
+                        reason,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// iterates the permits of a file without decrypting the cell keys
+pub struct RawPermits<R: Read> {
+    rdr: BufReader<R>,
+    // the number and content of the last line read
+    line: usize,
+    raw: String,
+    section: Section,
+    extra_columns: usize,
+}
+
+impl<R: Read> Iterator for RawPermits<R> {
+    type Item = Result<RawPermitRecord, E>;
+
+    fn next(&mut self) -> Option<Result<RawPermitRecord, E>> {
+        loop {
+            let mut buf = Vec::new();
+            self.line += 1;
+            let res = self.rdr.read_until(b'\n', &mut buf);
+            self.raw = decode_line(buf);
+            match res {
+                Ok(0) => return None,
+                Ok(_) if self.raw.trim().is_empty() => continue,
+                Ok(_) => match Section::from_marker(&self.raw) {
+                    Some(section) => self.section = section,
+                    None => {
+                        return Some(
+                            parse_raw_permit_with_extras(
+                                &self.raw,
+                                self.section,
+                                self.extra_columns,
+                            )
+                            .map_err(|e| e.at_line(self.line, &self.raw)),
+                        )
+                    }
+                },
+                Err(e) => return Some(Err(e.into())),
+            }
         }
     }
 }
 
-// parses one ECS row in the PERMIT.TXT file
+/// a cell permit where the cell keys are still encrypted
+#[derive(Debug, PartialEq, Clone)]
+pub struct RawCellPermit {
+    pub cell: String,
+    pub date: NaiveDate,
+    /// hex encoded encrypted cell key 1
+    pub eck1: String,
+    /// hex encoded encrypted cell key 2
+    pub eck2: String,
+    /// hex encoded encrypted checksum
+    pub chksum: String,
+}
+
+impl RawCellPermit {
+    /// validates the checksum and decrypts the cell keys with `hwid`
+    pub fn decrypt_keys(&self, hwid: &str) -> Result<CellPermit, E> {
+        self.decrypt_keys_with_cipher::<Blowfish>(hwid)
+    }
+
+    /// like `decrypt_keys` but with the cipher `C` instead of Blowfish
+    pub fn decrypt_keys_with_cipher<C: BlockCipher>(&self, hwid: &str) -> Result<CellPermit, E> {
+        self.verify_checksum_with_cipher::<C>(hwid)?;
+        self.decrypt_keys_unchecked_with_cipher::<C>(hwid)
+    }
+
+    /// checks that the permit was issued for `hwid`
+    pub fn verify_checksum(&self, hwid: &str) -> Result<(), E> {
+        self.verify_checksum_with_cipher::<Blowfish>(hwid)
+    }
+
+    /// like `verify_checksum` but with the cipher `C` instead of Blowfish
+    pub fn verify_checksum_with_cipher<C: BlockCipher>(&self, hwid: &str) -> Result<(), E> {
+        let s = format!(
+            "{}{}{}{}{}",
+            self.cell,
+            self.date.format("%Y%m%d"),
+            self.eck1,
+            self.eck2,
+            self.chksum
+        );
+        permit_chksum::<C>(&s, hwid).map_err(|e| e.in_field(Field::Checksum))
+    }
+
+    /// the 64 character cell permit for the HW_ID `to`, the permit has to be issued for `from`
+    pub fn reencrypt(&self, from: &str, to: &str) -> Result<String, E> {
+        self.decrypt_keys(from)?.encrypt(to)
+    }
+
+    /// decrypts the keys without validating the checksum, the keys are garbage if the
+    /// permit was issued for another HW_ID
+    pub fn decrypt_keys_unchecked(&self, hwid: &str) -> Result<CellPermit, E> {
+        self.decrypt_keys_unchecked_with_cipher::<Blowfish>(hwid)
+    }
+
+    /// like `decrypt_keys_unchecked` but with the cipher `C` instead of Blowfish
+    pub fn decrypt_keys_unchecked_with_cipher<C: BlockCipher>(
+        &self,
+        hwid: &str,
+    ) -> Result<CellPermit, E> {
+        Ok(CellPermit {
+            cell: self.cell.clone(),
+            date: self.date,
+            key1: decrypt_key_with::<C>(&self.eck1, hwid).map_err(|e| e.in_field(Field::Eck1))?,
+            key2: decrypt_key_with::<C>(&self.eck2, hwid).map_err(|e| e.in_field(Field::Eck2))?,
+        })
+    }
+}
+
+/// a permit record parsed without a HW_ID, see `PermitFile::raw_permits`
+#[derive(Debug, PartialEq)]
+pub struct RawPermitRecord {
+    pub cell_permit: RawCellPermit,
+    pub sli: SericeLevelIndicator,
+    pub edition: Option<u8>,
+    pub data_server_id: String,
+    pub comment: String,
+    pub section: Section,
+    pub extras: Vec<String>,
+    pub original: Original,
+}
+
+impl RawPermitRecord {
+    /// decrypts the keys with the first of `hwids` that validates the checksum
+    pub fn decrypt_keys_any<'k>(self, hwids: &[&'k str]) -> Result<(PermitRecord, &'k str), E> {
+        let mut err = E::InvalidChksum;
+        for hwid in hwids {
+            match self.cell_permit.decrypt_keys(hwid) {
+                Ok(cell_permit) => return Ok((self.with_cell_permit(cell_permit), hwid)),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    pub fn decrypt_keys(self, hwid: &str) -> Result<PermitRecord, E> {
+        self.decrypt_keys_any(&[hwid]).map(|(p, _)| p)
+    }
+
+    /// like `decrypt_keys` but with the cipher `C` instead of Blowfish
+    pub fn decrypt_keys_with_cipher<C: BlockCipher>(self, hwid: &str) -> Result<PermitRecord, E> {
+        let cell_permit = self.cell_permit.decrypt_keys_with_cipher::<C>(hwid)?;
+        Ok(self.with_cell_permit(cell_permit))
+    }
+
+    /// decrypts the keys without validating the checksum
+    pub fn decrypt_keys_unchecked(self, hwid: &str) -> Result<PermitRecord, E> {
+        let cell_permit = self.cell_permit.decrypt_keys_unchecked(hwid)?;
+        Ok(self.with_cell_permit(cell_permit))
+    }
+
+    fn with_cell_permit(self, cell_permit: CellPermit) -> PermitRecord {
+        PermitRecord {
+            cell_permit,
+            sli: self.sli,
+            edition: self.edition,
+            data_server_id: self.data_server_id,
+            comment: self.comment,
+            section: self.section,
+            extras: self.extras,
+            original: Some(self.original),
+        }
+    }
+}
+
+/// decodes a line of a permit file as UTF-8, falling back to Latin-1
+pub(crate) fn decode_line(buf: Vec<u8>) -> String {
+    match String::from_utf8(buf) {
+        Ok(s) => s,
+        Err(e) => e.into_bytes().into_iter().map(char::from).collect(),
+    }
+}
+
+#[cfg(test)]
 fn parse_permit(s: &str, key: &str) -> Result<PermitRecord, E> {
-    let mut ss = s.split(',');
-    let cell_permit = parse_cell_permit(ss.next().ok_or(E::CellPermitTooShort)?, key)?;
-    let sli = ss.next().ok_or(E::CellPermitTooShort)?.parse()?;
-    let edition = match ss.next().ok_or(E::CellPermitTooShort)? {
+    parse_raw_permit(s, Section::Enc)?.decrypt_keys(key)
+}
+
+// parses one ECS row in the PERMIT.TXT file, leaving the cell keys encrypted
+pub(crate) fn parse_raw_permit(s: &str, section: Section) -> Result<RawPermitRecord, E> {
+    parse_raw_permit_with_extras(s, section, 0)
+}
+
+// like parse_raw_permit but up to `extra_columns` fields at the end of the row are split
+// off from the comment
+fn parse_raw_permit_with_extras(
+    s: &str,
+    section: Section,
+    extra_columns: usize,
+) -> Result<RawPermitRecord, E> {
+    // the comment is the last field and may itself contain commas
+    let mut ss = s.splitn(5, ',').map(str::trim);
+    let mut next = |field: Field| {
+        ss.next()
+            .ok_or_else(|| E::CellPermitTooShort.in_field(field))
+    };
+    let raw_cell_permit = next(Field::CellPermit)?;
+    let cell_permit = parse_raw_cell_permit(raw_cell_permit)?;
+    let sli = next(Field::Sli)?
+        .parse()
+        .map_err(|e: E| e.in_field(Field::Sli))?;
+    let edition = match next(Field::Edition)? {
         "" => None,
-        a => Some(a.parse()?),
+        a => Some(a.parse().map_err(|e| E::from(e).in_field(Field::Edition))?),
+    };
+    let data_server_id = next(Field::DataServerId).map(String::from)?;
+    let mut rest: Vec<_> = next(Field::Comment)?
+        .rsplitn(extra_columns + 1, ',')
+        .map(|f| f.trim().to_owned())
+        .collect();
+    let comment = rest.pop().unwrap_or_default();
+    rest.reverse();
+    let original = Original {
+        cell_permit: raw_cell_permit.to_owned(),
+        line: s.trim_end_matches(['\r', '\n']).to_owned(),
     };
-    let data_server_id = ss.next().ok_or(E::CellPermitTooShort).map(String::from)?;
-    let comment = ss.next().ok_or(E::CellPermitTooShort)?.trim().to_string();
 
-    Ok(PermitRecord {
+    Ok(RawPermitRecord {
         cell_permit,
         sli,
         edition,
         data_server_id,
         comment,
+        section,
+        extras: rest,
+        original,
     })
 }
 
+#[cfg(test)]
 fn parse_cell_permit(s: &str, key: &str) -> Result<CellPermit, E> {
+    parse_raw_cell_permit(s)?.decrypt_keys(key)
+}
+
+fn parse_raw_cell_permit(s: &str) -> Result<RawCellPermit, E> {
     if s.len() != PERMIT_RECORD_LENGTH {
-        return Err(E::ParseCellPermit(crate::errors::CPReason::Length(s.len())));
+        return Err(E::ParseCellPermit(crate::errors::CPReason::Length(s.len()))
+            .in_field(Field::CellPermit));
+    }
+    if !s.is_ascii() {
+        return Err(
+            E::ParseCellPermit(crate::errors::CPReason::NonAscii).in_field(Field::CellPermit)
+        );
     }
-    permit_chksum(s, key)?;
-    let cell = String::from(&s[0..8]);
+    let cell = CellName::new(&s[0..8])
+        .map_err(|e| e.in_field(Field::Cell))?
+        .into();
     let date = NaiveDate::parse_from_str(&s[8..16], "%Y%m%d")
-        .map_err(|e| E::ParseCellPermit(crate::errors::CPReason::Date(e)))?;
-    let key1 = decrypt_key(&s[16..32], key)?;
-    let key2 = decrypt_key(&s[32..48], key)?;
-    Ok(CellPermit {
+        .map_err(|e| E::ParseCellPermit(crate::errors::CPReason::Date(e)).in_field(Field::Date))?;
+    Ok(RawCellPermit {
         cell,
         date,
-        key1,
-        key2,
+        eck1: String::from(&s[16..32]),
+        eck2: String::from(&s[32..48]),
+        chksum: String::from(&s[48..64]),
     })
 }
 
-fn permit_chksum(s: &str, key: &str) -> Result<(), E> {
+fn permit_chksum<C: BlockCipher>(s: &str, key: &str) -> Result<(), E> {
     let (rest, chksum) = (&s[0..48], &s[48..]);
-    let chksum = hex::decode(&chksum)?;
+    let chksum = hex::decode(chksum)?;
+
+    if chksum == encrypted_chksum::<C>(rest, key) {
+        Ok(())
+    } else {
+        Err(E::InvalidChksum)
+    }
+}
+
+// the blowfish encrypted crc32 of the first 48 characters of a cell permit
+fn encrypted_chksum<C: BlockCipher>(rest: &str, key: &str) -> [u8; 8] {
     let crc32_arr = crc32(rest.as_bytes());
     let mut enc = [0u8; 8];
-    let crypto = Blowfish::new(hwid6(key).as_bytes());
+    let crypto = C::new(hwid6(key).as_bytes());
     crypto.encrypt_block(
         crc32_arr
             .iter()
@@ -202,27 +1266,50 @@ fn permit_chksum(s: &str, key: &str) -> Result<(), E> {
             .as_slice(),
         &mut enc,
     );
+    enc
+}
 
-    if chksum == enc {
-        Ok(())
-    } else {
-        Err(E::InvalidChksum)
-    }
+// builds the 64 character cell permit string, the inverse of parse_cell_permit
+fn encrypt_cell_permit<C: BlockCipher>(cp: &CellPermit, key: &str) -> String {
+    let mut s = format!(
+        "{}{}{}{}",
+        cp.cell,
+        cp.date.format("%Y%m%d"),
+        encrypt_key::<C>(&cp.key1, key),
+        encrypt_key::<C>(&cp.key2, key)
+    );
+    let chksum = hex::encode_upper(encrypted_chksum::<C>(&s, key));
+    s.push_str(&chksum);
+    s
 }
 
 fn crc32(data: &[u8]) -> [u8; 4] {
     crc32::checksum_ieee(data).to_be_bytes()
 }
 
-fn hwid6(hwid: &str) -> String {
-    hwid.chars().chain(hwid[0..1].chars()).collect()
+fn hwid6(hwid: &str) -> Zeroizing<String> {
+    Zeroizing::new(hwid.chars().chain(hwid[0..1].chars()).collect())
+}
+
+#[cfg(test)]
+fn decrypt_key(s: &str, hwid: &str) -> Result<SecretKey, E> {
+    decrypt_key_with::<Blowfish>(s, hwid)
 }
 
-fn decrypt_key(s: &str, hwid: &str) -> Result<[u8; 5], E> {
-    let crypto = Blowfish::new(hwid6(hwid).as_bytes());
-    let mut dec = [0u8; 8];
-    crypto.decrypt_block(hex::decode(s)?.as_slice(), &mut dec);
-    Ok([dec[0], dec[1], dec[2], dec[3], dec[4]])
+fn decrypt_key_with<C: BlockCipher>(s: &str, hwid: &str) -> Result<SecretKey, E> {
+    let crypto = C::new(hwid6(hwid).as_bytes());
+    let mut dec = Zeroizing::new([0u8; 8]);
+    crypto.decrypt_block(hex::decode(s)?.as_slice(), &mut *dec);
+    Ok(SecretKey::new([dec[0], dec[1], dec[2], dec[3], dec[4]]))
+}
+
+fn encrypt_key<C: BlockCipher>(k: &SecretKey, hwid: &str) -> String {
+    let crypto = C::new(hwid6(hwid).as_bytes());
+    let mut dec = Zeroizing::new([3u8; 8]);
+    dec[0..5].copy_from_slice(k);
+    let mut enc = [0u8; 8];
+    crypto.encrypt_block(&*dec, &mut enc);
+    hex::encode_upper(enc)
 }
 
 impl<'a, R: Read> PermitFile<R> {
@@ -232,42 +1319,209 @@ impl<'a, R: Read> PermitFile<R> {
         rdr.read_line(&mut date_str)?;
         let date = get_date(&date_str)?;
         rdr.read_line(&mut version_str)?;
-        let version = get_version(&version_str)?;
+        let version = check_version(get_version(&version_str)?)?;
 
-        Ok((MetaData { date, version }, PermitFile { file: rdr }))
+        Ok((
+            MetaData { date, version },
+            PermitFile {
+                file: rdr,
+                extra_columns: 0,
+            },
+        ))
+    }
+
+    /// the number of proprietary columns the RTE appends after the comment, they are parsed
+    /// into `PermitRecord::extras`. By default everything after the data server id is the
+    /// comment, commas included
+    pub fn extra_columns(mut self, n: usize) -> Self {
+        self.extra_columns = n;
+        self
     }
 
     pub fn permits(self, key: &'a str) -> Permits<'a, R> {
-        Permits(self.file, key)
+        self.permits_with_policy(key, ChecksumPolicy::Strict)
+    }
+
+    /// like `permits` but with a choice of how to handle checksum failures
+    pub fn permits_with_policy(self, key: &'a str, policy: ChecksumPolicy) -> Permits<'a, R> {
+        Permits {
+            raw: self.raw_permits(),
+            key,
+            policy,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// parses the permits without a HW_ID, keys can be decrypted later with `decrypt_keys`
+    pub fn raw_permits(self) -> RawPermits<R> {
+        RawPermits {
+            rdr: self.file,
+            line: 2,
+            raw: String::new(),
+            section: Section::Enc,
+            extra_columns: self.extra_columns,
+        }
+    }
+
+    /// checks every permit in the file against `key`, using the system clock for expiry
+    pub fn validate(self, key: &str) -> Result<ValidationReport, E> {
+        self.validate_at(key, SystemClock)
+    }
+
+    /// like `validate` but permits are checked for expiry at the time of `clock`.
+    /// Only I/O errors are returned, all other problems are collected in the report
+    pub fn validate_at<C: Clock>(self, key: &str, clock: C) -> Result<ValidationReport, E> {
+        let today = clock.today();
+        let mut report = ValidationReport::default();
+        let mut seen = HashSet::new();
+        let mut raw = self.raw_permits();
+        while let Some(p) = raw.next() {
+            report.total += 1;
+            let line = raw.line;
+            let p = match p {
+                Ok(p) => p,
+                Err(E::IoErr(e)) => return Err(E::IoErr(e)),
+                Err(reason) => {
+                    report.unparsable.push(LineError { line, reason });
+                    continue;
+                }
+            };
+            let cell = &p.cell_permit.cell;
+            if let Err(e) = p.cell_permit.verify_checksum(key) {
+                report.checksum_failures.push(LineError {
+                    line,
+                    reason: e.at_line(line, &raw.raw),
+                });
+            }
+            if today > p.cell_permit.date {
+                report.expired.push((line, cell.clone()));
+            }
+            if !seen.insert((cell.clone(), p.section)) {
+                report.duplicates.push((line, cell.clone()));
+            }
+        }
+        Ok(report)
+    }
+
+    /// like `permits` but malformed lines are skipped, their errors are available from the iterator
+    pub fn permits_lenient(self, key: &'a str) -> LenientPermits<'a, R> {
+        LenientPermits {
+            permits: self.permits(key),
+            errors: Vec::new(),
+            done: false,
+        }
     }
 }
 
-fn get_date(l: &str) -> Result<NaiveDateTime, E> {
-    let l = l.trim();
-    let l = if l.starts_with(":DATE ") {
-        &l[6..]
-    } else {
-        return Err(E::ParseDateError(l.to_owned()));
+pub(crate) fn get_date(l: &str) -> Result<NaiveDateTime, E> {
+    // the first line of the file may start with a UTF-8 BOM
+    let l = l.trim_start_matches('\u{feff}').trim();
+    let l = match l.strip_prefix(":DATE ") {
+        Some(l) => l,
+        None => return Err(E::ParseDateError(l.to_owned())),
     };
 
-    Ok(NaiveDateTime::parse_from_str(l, "%Y%m%d %H:%M")
-        .or_else(|_| NaiveDate::parse_from_str(l, "%Y%m%d").map(|x| x.and_hms(0, 0, 0)))?)
+    Ok(
+        NaiveDateTime::parse_from_str(l, "%Y%m%d %H:%M").or_else(|_| {
+            NaiveDate::parse_from_str(l, "%Y%m%d").map(|x| x.and_hms_opt(0, 0, 0).unwrap())
+        })?,
+    )
 }
 
-fn get_version(l: &str) -> Result<u8, E> {
-    let l = l.trim();
-    let l = if l.starts_with(":VERSION ") {
-        &l[9..]
+/// the permit file versions this crate can read and write, the last is written by default.
+/// Version 2 is the layout defined by S-63 edition 1.1 and later, version 1 files of earlier
+/// editions have the same record layout
+pub const SUPPORTED_VERSIONS: &[u8] = &[1, 2];
+
+pub(crate) fn check_version(version: u8) -> Result<u8, E> {
+    if SUPPORTED_VERSIONS.contains(&version) {
+        Ok(version)
     } else {
-        return Err(E::ParseVersionError(l.to_owned()));
+        Err(E::UnsupportedVersion(version))
+    }
+}
+
+pub(crate) fn get_version(l: &str) -> Result<u8, E> {
+    let l = l.trim();
+    let l = match l.strip_prefix(":VERSION ") {
+        Some(l) => l,
+        None => return Err(E::ParseVersionError(l.to_owned())),
     };
     Ok(l.parse()?)
 }
 
+/// writes a PERMIT.TXT file, encrypting the cell keys with the given HW_ID.
+/// Permits have to be written in section order, ENC permits before ECS permits
+pub struct PermitFileWriter<'a, W: Write> {
+    wtr: W,
+    key: &'a str,
+    section: Section,
+}
+
+impl<'a, W: Write> PermitFileWriter<'a, W> {
+    /// writes the header and opens the `:ENC` section
+    pub fn new(mut wtr: W, md: &MetaData, key: &'a str) -> Result<PermitFileWriter<'a, W>, E> {
+        check_version(md.version)?;
+        md.write(&mut wtr)?;
+        write!(wtr, "{}{}", Section::Enc.marker(), LINE_END)?;
+        Ok(PermitFileWriter {
+            wtr,
+            key,
+            section: Section::Enc,
+        })
+    }
+
+    pub fn write_permit(&mut self, p: &PermitRecord) -> Result<(), E> {
+        if p.section < self.section {
+            return Err(E::InvalidField(format!(
+                "{} permit written after {} section",
+                p.section.marker(),
+                self.section.marker()
+            )));
+        }
+        self.open_section(p.section)?;
+        write!(self.wtr, "{}{}", p.serialize(self.key)?, LINE_END)?;
+        Ok(())
+    }
+
+    /// writes the `:ECS` section if not written yet and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, E> {
+        self.open_section(Section::Ecs)?;
+        Ok(self.wtr)
+    }
+
+    fn open_section(&mut self, section: Section) -> Result<(), E> {
+        if section != self.section {
+            write!(self.wtr, "{}{}", section.marker(), LINE_END)?;
+            self.section = section;
+        }
+        Ok(())
+    }
+}
+
+/// a subscription permit of data server GB for every edition of `cell`, expiring on
+/// 2030-01-01, for tests
+#[cfg(test)]
+pub(crate) fn test_permit(cell: &str, key1: &[u8], key2: &[u8]) -> PermitRecord {
+    let cp = CellPermit::builder()
+        .cell(cell)
+        .date(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap())
+        .key1(key1)
+        .key2(key2)
+        .build()
+        .unwrap();
+    PermitRecord::builder()
+        .cell_permit(cp)
+        .data_server_id("GB")
+        .build()
+        .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    #[allow(deprecated, clippy::useless_vec)]
     fn read_date() -> Result<(), E> {
         let tests = vec![
             (
@@ -297,6 +1551,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn read_version() -> Result<(), E> {
         let tests = vec![(":VERSION 2", 2), (":VERSION 123", 123)];
 
@@ -308,6 +1563,36 @@ mod tests {
     }
 
     #[test]
+    fn unsupported_version() -> Result<(), E> {
+        let s = ":DATE 20071023 10:20\n:VERSION 3\n";
+        assert!(matches!(
+            PermitFile::new(s.as_bytes()),
+            Err(E::UnsupportedVersion(3))
+        ));
+        let mut md = MetaData {
+            date: NaiveDate::from_ymd_opt(2007, 10, 23)
+                .unwrap()
+                .and_hms_opt(10, 20, 0)
+                .unwrap(),
+            version: 0,
+        };
+        assert!(matches!(
+            PermitFileWriter::new(Vec::new(), &md, "12345"),
+            Err(E::UnsupportedVersion(0))
+        ));
+
+        let s = ":DATE 20071023 10:20\n:VERSION 1\n:ENC\n\
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,\n:ECS\n";
+        let (read, pf) = PermitFile::new(s.as_bytes())?;
+        assert_eq!(read.version, 1);
+        assert_eq!(pf.permits("12345").count(), 1);
+        md.version = 1;
+        PermitFileWriter::new(Vec::new(), &md, "12345")?;
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn parse_permit() -> Result<(), E> {
         let p_str = "GB61021A200711301F3EC4E525FFFCEC1F3EC4E525FFFCEC3E91E355E4E82D30,0,,GB,";
         let p = super::parse_permit(p_str, &String::from("12345"))?;
@@ -316,6 +1601,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn non_ascii_permit() {
+        let p_str = "GB61021A200711301F3EC4E525FFFCEC1F3EC4E525FFFCEC3E91E355E4E82D\u{e9},0,,GB,";
+        assert_eq!(p_str.find(',').unwrap(), 64);
+        assert!(matches!(
+            super::parse_permit(p_str, "12345").unwrap_err().root(),
+            E::ParseCellPermit(crate::errors::CPReason::NonAscii)
+        ));
+        assert!(matches!(
+            super::parse_permit(&p_str[1..], "12345")
+                .unwrap_err()
+                .root(),
+            E::ParseCellPermit(crate::errors::CPReason::Length(63))
+        ));
+    }
+
     #[test]
     fn decrypt_key() -> Result<(), E> {
         let hwid = "12348";
@@ -327,12 +1628,285 @@ mod tests {
     }
 
     #[test]
+    fn encrypt_cell_permit() -> Result<(), E> {
+        let p_str = "GB61021A200711301F3EC4E525FFFCEC1F3EC4E525FFFCEC3E91E355E4E82D30";
+        let cp = parse_cell_permit(p_str, "12345")?;
+        assert_eq!(cp.encrypt("12345")?, p_str);
+
+        let cp = CellPermit {
+            cell: String::from("NO4D0613"),
+            date: NaiveDate::from_ymd_opt(2021, 6, 30).unwrap(),
+            key1: [1, 2, 3, 4, 5].into(),
+            key2: [6, 7, 8, 9, 10].into(),
+        };
+        assert_eq!(parse_cell_permit(&cp.encrypt("ABCDE")?, "ABCDE")?, cp);
+        Ok(())
+    }
+
+    #[test]
+    fn encrypt_cell_permit_invalid() {
+        let mut cp = CellPermit {
+            cell: String::from("NO4D061"),
+            date: NaiveDate::from_ymd_opt(2021, 6, 30).unwrap(),
+            key1: [1, 2, 3, 4, 5].into(),
+            key2: [6, 7, 8, 9, 10].into(),
+        };
+        assert!(matches!(cp.encrypt("12345"), Err(E::InvalidCellName(_))));
+        cp.cell.push('3');
+        assert!(matches!(cp.encrypt("1234"), Err(E::InvalidHwid(_))));
+    }
+
+    #[test]
+    fn builders() -> Result<(), E> {
+        let cp = CellPermit::builder()
+            .cell("GB100001")
+            .date(NaiveDate::from_ymd_opt(2007, 12, 31).unwrap())
+            .key1(&[1, 2, 3, 4, 5])
+            .build()?;
+        assert_eq!(cp.key2, [1, 2, 3, 4, 5]);
+        let p = PermitRecord::builder()
+            .cell_permit(cp)
+            .data_server_id("GB")
+            .build()?;
+        assert_eq!(p.sli, SericeLevelIndicator::SubscriptionPermit);
+        assert_eq!(p.edition, None);
+        assert_eq!(p.comment, "");
+
+        let res = CellPermit::builder()
+            .cell("GB1000")
+            .date(NaiveDate::from_ymd_opt(2007, 12, 31).unwrap())
+            .key1(&[1, 2, 3, 4, 5])
+            .build();
+        assert!(matches!(res, Err(E::InvalidCellName(_))));
+        let res = CellPermit::builder()
+            .cell("GB100001")
+            .date(NaiveDate::from_ymd_opt(2007, 12, 31).unwrap())
+            .key1(&[1, 2, 3, 4])
+            .build();
+        assert!(matches!(res, Err(E::InvalidKeyLength(4))));
+        let res = PermitRecord::builder().data_server_id("GB").build();
+        assert!(matches!(res, Err(E::MissingField("cell_permit"))));
+        Ok(())
+    }
+
+    #[test]
+    fn expiry() -> Result<(), E> {
+        let p = PermitRecord::builder()
+            .cell_permit(
+                CellPermit::builder()
+                    .cell("GB100001")
+                    .date(NaiveDate::from_ymd_opt(2020, 1, 31).unwrap())
+                    .key1(&[1, 2, 3, 4, 5])
+                    .build()?,
+            )
+            .data_server_id("GB")
+            .build()?;
+        let day = |d| NaiveDate::from_ymd_opt(2020, 1, d).unwrap();
+        assert!(!p.is_expired(day(31)));
+        assert!(p.is_expired(NaiveDate::from_ymd_opt(2020, 2, 1).unwrap()));
+        assert!(p.expires_within(30, day(1)));
+        assert!(!p.expires_within(29, day(1)));
+        assert!(!p.expires_within(30, NaiveDate::from_ymd_opt(2020, 2, 1).unwrap()));
+
+        let clock = crate::clock::FixedClock(day(31).and_hms_opt(23, 59, 0).unwrap());
+        assert!(!p.is_expired_at(clock));
+        Ok(())
+    }
+
+    fn record(date: NaiveDate, edition: u8, comment: &str) -> Result<PermitRecord, E> {
+        cell_record("GB100001", date, edition, comment)
+    }
+
+    fn cell_record(
+        cell: &str,
+        date: NaiveDate,
+        edition: u8,
+        comment: &str,
+    ) -> Result<PermitRecord, E> {
+        let mut p = test_permit(cell, &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+        p.cell_permit.date = date;
+        p.edition = Some(edition);
+        p.comment = comment.to_owned();
+        Ok(p)
+    }
+
+    #[test]
+    fn renewal() -> Result<(), E> {
+        let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+        let renewed = NaiveDate::from_ymd_opt(2021, 6, 30).unwrap();
+        let mut single = cell_record("GB100002", date, 1, "single")?;
+        single.sli = SericeLevelIndicator::SinglePurchasePermit;
+        let permits = vec![
+            cell_record("GB100001", date, 4, "subscription")?,
+            single,
+            cell_record(
+                "GB100003",
+                NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
+                1,
+                "",
+            )?,
+        ];
+        let md = MetaData {
+            date: date.and_hms_opt(0, 0, 0).unwrap(),
+            version: 2,
+        };
+        let mut w = PermitFileWriter::new(Vec::new(), &md, "12345")?;
+        for p in &permits {
+            w.write_permit(p)?;
+        }
+        let txt = w.finish()?;
+
+        let now = NaiveDate::from_ymd_opt(2020, 6, 1)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap();
+        let clock = crate::clock::FixedClock(now);
+        let mut out = Vec::new();
+        let res = renew_permit_file_at(&txt[..], &mut out, "12345", renewed, clock)?;
+        assert_eq!(res, renew_permits(permits.clone(), renewed));
+        assert_eq!(res.renewed.len(), 1);
+        assert_eq!(res.kept.len(), 2);
+
+        let (md, pf) = PermitFile::new(&out[..])?;
+        assert_eq!(md.date, now);
+        let read = pf.permits("12345").collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].cell_permit.date, renewed);
+        assert_eq!(read[0].cell_permit.key1, permits[0].cell_permit.key1);
+        assert_eq!(
+            (read[0].edition, read[0].comment.as_str()),
+            (Some(4), "subscription")
+        );
+        assert_eq!(read[1].cell_permit.date, date);
+        assert_eq!(read[2].cell_permit.date.year(), 2022);
+        let old = &permits[0].serialize("12345")?;
+        let new = &read[0].serialize("12345")?;
+        assert_ne!(old[48..64], new[48..64]);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_policies() -> Result<(), E> {
+        let sources = || {
+            vec![
+                vec![record(
+                    NaiveDate::from_ymd_opt(2020, 6, 30).unwrap(),
+                    3,
+                    "base",
+                )],
+                vec![record(
+                    NaiveDate::from_ymd_opt(2020, 12, 31).unwrap(),
+                    2,
+                    "update",
+                )],
+            ]
+        };
+        let m = merge(sources(), MergePolicy::NewestExpiry)?;
+        assert_eq!(m.get_permit("GB100001").unwrap().comment, "update");
+        let m = merge(sources(), MergePolicy::HighestEdition)?;
+        assert_eq!(m.get_permit("GB100001").unwrap().comment, "base");
+        Ok(())
+    }
+
+    #[test]
+    fn diff_permits() -> Result<(), E> {
+        let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+        let later = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        let old = merge(
+            vec![vec![
+                cell_record("GB100001", date, 1, ""),
+                cell_record("GB100002", date, 1, ""),
+                cell_record("GB100003", date, 1, ""),
+            ]],
+            MergePolicy::NewestExpiry,
+        )?;
+        let new = merge(
+            vec![vec![
+                cell_record("GB100002", later, 1, ""),
+                cell_record("GB100003", date, 2, ""),
+                cell_record("GB100004", date, 1, ""),
+            ]],
+            MergePolicy::NewestExpiry,
+        )?;
+        let d = diff(&old, &new);
+        assert_eq!(d.added, vec![&new["GB100004"]]);
+        assert_eq!(d.removed, vec![&old["GB100001"]]);
+        assert_eq!(d.renewed, vec![(&old["GB100002"], &new["GB100002"])]);
+        assert_eq!(
+            d.edition_changed,
+            vec![(&old["GB100003"], &new["GB100003"])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_lookup() -> Result<(), E> {
+        let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+        let records = || {
+            ["NO100001", "GB100002", "GB200001", "GB100001", "FR100001"]
+                .iter()
+                .map(|c| cell_record(c, date, 1, ""))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let cells = |ps: Vec<&PermitRecord>| {
+            ps.iter()
+                .map(|p| p.cell_permit.cell.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let v = records()?;
+        assert!(v.get_permit("GB200001").is_some());
+        assert!(v.as_slice().get_permit("GB300001").is_none());
+
+        let sorted = SortedPermits::new(records()?);
+        let tree: BTreeMap<_, _> = records()?
+            .into_iter()
+            .map(|p| (p.cell_permit.cell.clone(), p))
+            .collect();
+        assert!(sorted.get_permit("FR100001").is_some());
+        assert!(sorted.get_permit("GB100003").is_none());
+        assert!(tree.get_permit("NO100001").is_some());
+
+        let gb = vec!["GB100001", "GB100002", "GB200001"];
+        assert_eq!(cells(sorted.permits_with_prefix("GB")), gb);
+        assert_eq!(cells(tree.permits_with_prefix("GB")), gb);
+        assert_eq!(
+            cells(sorted.permits_in_range("GB1", "GB2")),
+            gb[..2].to_vec()
+        );
+        assert_eq!(cells(tree.permits_in_range("GB1", "GB2")), gb[..2].to_vec());
+        assert!(tree.permits_in_range("GB2", "GB1").is_empty());
+        assert!(sorted.permits_in_range("GB2", "GB1").is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() -> Result<(), E> {
+        let p = record(NaiveDate::from_ymd_opt(2020, 6, 30).unwrap(), 3, "hej")?;
+        let json = serde_json::to_string(&p).unwrap();
+        assert_eq!(serde_json::from_str::<PermitRecord>(&json).unwrap(), p);
+
+        let md = MetaData {
+            date: NaiveDate::from_ymd_opt(2007, 10, 23)
+                .unwrap()
+                .and_hms_opt(10, 20, 0)
+                .unwrap(),
+            version: 2,
+        };
+        let json = serde_json::to_string(&md).unwrap();
+        assert_eq!(serde_json::from_str::<MetaData>(&json).unwrap(), md);
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn keys_iter() {
         let p = CellPermit {
             cell: String::from("abc"),
             date: NaiveDate::from_ymd(2012, 4, 22),
-            key1: [0, 0, 0, 0, 0],
-            key2: [0, 0, 0, 0, 0],
+            key1: [0, 0, 0, 0, 0].into(),
+            key2: [0, 0, 0, 0, 0].into(),
         };
         let mut iter = p.keys();
         assert_eq!(iter.next(), Some(&[0, 0, 0, 0, 0]));
@@ -341,8 +1915,8 @@ mod tests {
         let p = CellPermit {
             cell: String::from("abc"),
             date: NaiveDate::from_ymd(2012, 4, 22),
-            key1: [0, 0, 0, 0, 0],
-            key2: [0, 0, 0, 0, 1],
+            key1: [0, 0, 0, 0, 0].into(),
+            key2: [0, 0, 0, 0, 1].into(),
         };
         let mut iter = p.keys();
         assert_eq!(iter.next(), Some(&[0, 0, 0, 0, 0]));
@@ -0,0 +1,291 @@
+//! CSV and JSON import/export of decrypted permit inventories
+
+use super::{CellPermit, PermitRecord, Section, SericeLevelIndicator};
+use crate::errors::E;
+use chrono::NaiveDate;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+
+const CSV_HEADER: &str = "cell,expiry,sli,edition,data_server_id,comment,key1,key2,section";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// one permit in human readable form
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Row {
+    cell: String,
+    expiry: String,
+    sli: String,
+    edition: Option<u8>,
+    data_server_id: String,
+    comment: String,
+    key1: String,
+    key2: String,
+    section: String,
+    /// the proprietary columns of the permit, in the CSV after the section
+    #[cfg_attr(feature = "serde", serde(default))]
+    extras: Vec<String>,
+}
+
+impl Row {
+    fn new(p: &PermitRecord) -> Row {
+        Row {
+            cell: p.cell_permit.cell.clone(),
+            expiry: p.cell_permit.date.format(DATE_FORMAT).to_string(),
+            sli: String::from(match p.sli {
+                SericeLevelIndicator::SubscriptionPermit => "subscription",
+                SericeLevelIndicator::SinglePurchasePermit => "single purchase",
+            }),
+            edition: p.edition,
+            data_server_id: p.data_server_id.clone(),
+            comment: p.comment.clone(),
+            key1: hex::encode_upper(&p.cell_permit.key1),
+            key2: hex::encode_upper(&p.cell_permit.key2),
+            section: String::from(&p.section.marker()[1..]),
+            extras: p.extras.clone(),
+        }
+    }
+
+    fn into_permit(self) -> Result<PermitRecord, E> {
+        let sli = match self.sli.as_str() {
+            "subscription" => SericeLevelIndicator::SubscriptionPermit,
+            "single purchase" => SericeLevelIndicator::SinglePurchasePermit,
+            _ => return Err(E::InvalidSli),
+        };
+        let section = Section::from_marker(&format!(":{}", self.section))
+            .ok_or_else(|| E::InvalidField(format!("invalid section {}", self.section)))?;
+        let cell_permit = CellPermit::builder()
+            .cell(&self.cell)
+            .date(NaiveDate::parse_from_str(&self.expiry, DATE_FORMAT)?)
+            .key1(&hex::decode(&self.key1)?)
+            .key2(&hex::decode(&self.key2)?)
+            .build()?;
+        Ok(PermitRecord {
+            cell_permit,
+            sli,
+            edition: self.edition,
+            data_server_id: self.data_server_id,
+            comment: self.comment,
+            section,
+            extras: self.extras,
+            original: None,
+        })
+    }
+}
+
+/// writes the permits as CSV with a header row, keys are hex encoded. The proprietary
+/// columns of a permit follow its section
+pub fn export_csv<'a, W, I>(mut wtr: W, permits: I) -> Result<(), E>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a PermitRecord>,
+{
+    writeln!(wtr, "{}", CSV_HEADER)?;
+    for p in permits {
+        let r = Row::new(p);
+        let edition = r.edition.map(|e| e.to_string()).unwrap_or_default();
+        let fields = [
+            &r.cell,
+            &r.expiry,
+            &r.sli,
+            &edition,
+            &r.data_server_id,
+            &r.comment,
+            &r.key1,
+            &r.key2,
+            &r.section,
+        ];
+        let fields: Vec<_> = fields
+            .iter()
+            .copied()
+            .chain(&r.extras)
+            .map(|f| csv_quote(f))
+            .collect();
+        writeln!(wtr, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// reads permits written by `export_csv`
+pub fn import_csv<R: Read>(mut rdr: R) -> Result<Vec<PermitRecord>, E> {
+    let mut s = String::new();
+    rdr.read_to_string(&mut s)?;
+    let mut records = csv_records(&s).into_iter();
+    let header = records.next().unwrap_or_default();
+    if header.trim() != CSV_HEADER {
+        return Err(E::InvalidField(format!("invalid CSV header: {}", header)));
+    }
+    let mut res = Vec::new();
+    for l in records {
+        if l.trim().is_empty() {
+            continue;
+        }
+        let mut f = csv_fields(l.trim_end_matches('\r')).into_iter();
+        let mut next = || f.next().ok_or_else(|| E::InvalidField(l.to_owned()));
+        let mut row = Row {
+            cell: next()?,
+            expiry: next()?,
+            sli: next()?,
+            edition: match next()?.as_str() {
+                "" => None,
+                e => Some(e.parse()?),
+            },
+            data_server_id: next()?,
+            comment: next()?,
+            key1: next()?,
+            key2: next()?,
+            section: next()?,
+            extras: Vec::new(),
+        };
+        row.extras = f.collect();
+        res.push(row.into_permit()?);
+    }
+    Ok(res)
+}
+
+/// writes the permits as a JSON array
+#[cfg(feature = "serde")]
+pub fn export_json<'a, W, I>(wtr: W, permits: I) -> Result<(), E>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a PermitRecord>,
+{
+    let rows: Vec<_> = permits.into_iter().map(Row::new).collect();
+    serde_json::to_writer_pretty(wtr, &rows).map_err(|e| E::InvalidField(e.to_string()))
+}
+
+/// reads permits written by `export_json`
+#[cfg(feature = "serde")]
+pub fn import_json<R: Read>(rdr: R) -> Result<Vec<PermitRecord>, E> {
+    let rows: Vec<Row> =
+        serde_json::from_reader(rdr).map_err(|e| E::InvalidField(e.to_string()))?;
+    rows.into_iter().map(Row::into_permit).collect()
+}
+
+fn csv_quote(f: &str) -> String {
+    if f.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", f.replace('"', "\"\""))
+    } else {
+        f.to_owned()
+    }
+}
+
+// splits CSV text into records at the line breaks outside quoted fields
+fn csv_records(s: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            // an escaped quote toggles twice
+            '"' => quoted = !quoted,
+            '\n' if !quoted => {
+                res.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    if start < s.len() {
+        res.push(&s[start..]);
+    }
+    res
+}
+
+// splits one CSV record into fields, handling quoted fields
+fn csv_fields(l: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = l.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => res.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    res.push(field);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permits() -> Vec<PermitRecord> {
+        vec![
+            PermitRecord::builder()
+                .cell_permit(
+                    CellPermit::builder()
+                        .cell("GB100001")
+                        .date(NaiveDate::from_ymd_opt(2020, 6, 30).unwrap())
+                        .key1(&[1, 2, 3, 4, 5])
+                        .key2(&[6, 7, 8, 9, 10])
+                        .build()
+                        .unwrap(),
+                )
+                .edition(3)
+                .data_server_id("GB")
+                .build()
+                .unwrap(),
+            PermitRecord::builder()
+                .cell_permit(
+                    CellPermit::builder()
+                        .cell("GB100002")
+                        .date(NaiveDate::from_ymd_opt(2021, 1, 1).unwrap())
+                        .key1(&[1, 2, 3, 4, 5])
+                        .build()
+                        .unwrap(),
+                )
+                .sli(SericeLevelIndicator::SinglePurchasePermit)
+                .data_server_id("GB")
+                .comment("says \"hej\"")
+                .section(Section::Ecs)
+                .build()
+                .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn csv_roundtrip() -> Result<(), E> {
+        let mut buf = Vec::new();
+        export_csv(&mut buf, &permits())?;
+        let s = String::from_utf8(buf).unwrap();
+        assert!(s.contains("GB100001,2020-06-30,subscription,3,GB,,0102030405,060708090A,ENC"));
+        assert_eq!(import_csv(s.as_bytes())?, permits());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_multiline() -> Result<(), E> {
+        let mut permits = permits();
+        permits[0].comment = String::from("two\r\nlines, \"quoted\"");
+        permits[1].extras = vec![String::from("x"), String::from("y\nz")];
+        let mut buf = Vec::new();
+        export_csv(&mut buf, &permits)?;
+        assert_eq!(import_csv(buf.as_slice())?, permits);
+        Ok(())
+    }
+
+    #[test]
+    fn csv_split() {
+        assert_eq!(
+            csv_fields("a,\"b,c\",\"d\"\"e\","),
+            vec!["a", "b,c", "d\"e", ""]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_roundtrip() -> Result<(), E> {
+        let mut buf = Vec::new();
+        export_json(&mut buf, &permits())?;
+        assert_eq!(import_json(buf.as_slice())?, permits());
+        Ok(())
+    }
+}
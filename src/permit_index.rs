@@ -0,0 +1,148 @@
+//! Lazy permit lookup for large permit files
+//!
+//! The file is scanned once to index the byte offset of the row of every cell, rows are then
+//! parsed and decrypted on demand.
+
+use crate::errors::E;
+use crate::permit::{self, GetPermit, MetaData, PermitRecord, Section};
+use crate::secret::Zeroizing;
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+
+struct Entry {
+    offset: u64,
+    len: usize,
+    section: Section,
+    permit: OnceCell<PermitRecord>,
+}
+
+pub struct IndexedPermits<R: Read + Seek> {
+    rdr: RefCell<R>,
+    key: Zeroizing<String>,
+    index: HashMap<String, Entry>,
+}
+
+impl<R: Read + Seek> IndexedPermits<R> {
+    /// scans the permit file and indexes the rows, the keys are decrypted with `key` on lookup
+    pub fn new(mut rdr: R, key: &str) -> Result<(MetaData, IndexedPermits<R>), E> {
+        rdr.seek(SeekFrom::Start(0))?;
+        let mut index = HashMap::new();
+        let md = {
+            let mut buf = BufReader::new(&mut rdr);
+            let mut s = String::new();
+            let mut offset = buf.read_line(&mut s)? as u64;
+            let date = permit::get_date(&s)?;
+            s.clear();
+            offset += buf.read_line(&mut s)? as u64;
+            let version = permit::check_version(permit::get_version(&s)?)?;
+
+            let mut section = Section::Enc;
+            loop {
+                let mut line = Vec::new();
+                let len = buf.read_until(b'\n', &mut line)?;
+                if len == 0 {
+                    break;
+                }
+                let s = permit::decode_line(line);
+                if let Some(sec) = Section::from_marker(&s) {
+                    section = sec;
+                } else if let Some(cell) = s.trim_start().get(0..8) {
+                    index.insert(
+                        cell.to_owned(),
+                        Entry {
+                            offset,
+                            len,
+                            section,
+                            permit: OnceCell::new(),
+                        },
+                    );
+                }
+                offset += len as u64;
+            }
+            MetaData { date, version }
+        };
+        Ok((
+            md,
+            IndexedPermits {
+                rdr: RefCell::new(rdr),
+                key: Zeroizing::new(key.to_owned()),
+                index,
+            },
+        ))
+    }
+
+    /// the cells in the file
+    pub fn cells(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// parses and decrypts the permit for `cell` if not done already
+    pub fn load(&self, cell: &str) -> Result<Option<&PermitRecord>, E> {
+        let entry = match self.index.get(cell) {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        if let Some(p) = entry.permit.get() {
+            return Ok(Some(p));
+        }
+        let mut buf = vec![0u8; entry.len];
+        {
+            let mut rdr = self.rdr.borrow_mut();
+            rdr.seek(SeekFrom::Start(entry.offset))?;
+            rdr.read_exact(&mut buf)?;
+        }
+        let line = permit::decode_line(buf);
+        let p = permit::parse_raw_permit(&line, entry.section)?.decrypt_keys(&self.key)?;
+        Ok(Some(entry.permit.get_or_init(|| p)))
+    }
+}
+
+/// permits that fail to parse or decrypt are treated as missing, use `load` to get the error
+impl<R: Read + Seek> GetPermit for IndexedPermits<R> {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.load(cell).ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn lazy_lookup() -> Result<(), E> {
+        let s = ":DATE 20071023 10:20\r
+:VERSION 2\r
+:ENC\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej\r
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FD,1,0,GB,\r
+:ECS\r
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,\r
+";
+        let (md, permits) = IndexedPermits::new(Cursor::new(s), "12345")?;
+        assert_eq!(md.version, 2);
+        assert_eq!(permits.len(), 3);
+        let p = permits.get_permit("GB100001").unwrap();
+        assert_eq!(p.comment, "hej");
+        assert_eq!(p.cell_permit.key1, [54, 62, 171, 50, 198]);
+        assert_eq!(
+            permits.get_permit("GB100004").unwrap().section,
+            Section::Ecs
+        );
+        let err = permits.load("GB100002").unwrap_err();
+        assert!(matches!(err.root(), E::InvalidChksum));
+        assert!(permits.get_permit("GB100002").is_none());
+        assert!(permits.load("GB100003")?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+//! Registry of installed permits, persisted to disk
+//!
+//! The registry file starts with a `:REGISTRY 1` line followed by one tab separated line per
+//! permit: installation time, source file, section marker and the PERMIT.TXT row with the
+//! cell keys encrypted with the HW_ID. Backslashes, tabs and line breaks in the source are
+//! escaped with a backslash.
+
+use crate::errors::E;
+use crate::exchange::ExchangeSet;
+use crate::permit::{self, GetPermit, MergePolicy, PermitFile, PermitRecord, Section};
+use crate::secret::Zeroizing;
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+mod cells;
+mod database;
+pub use self::cells::*;
+pub use self::database::*;
+
+const HEADER: &str = ":REGISTRY 1";
+const DATE_FORMAT: &str = "%Y%m%d %H:%M:%S";
+
+// `path` with `ext` appended to the file name
+fn with_ext(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(ext);
+    path.with_file_name(name)
+}
+
+// writes `data` to a temporary file next to `path` and renames it over `path`, so a crash
+// leaves either the old or the new file
+pub(crate) fn write_replacing(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = with_ext(path, ".tmp");
+    let mut f = std::fs::File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InstalledPermit {
+    pub permit: PermitRecord,
+    pub installed: NaiveDateTime,
+    /// the PERMIT.TXT the permit was installed from
+    pub source: String,
+}
+
+/// what `PermitRegistry::install_with` did with the permits of a PERMIT.TXT, by cell name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstallSummary {
+    /// permits for cells without an installed permit
+    pub added: Vec<String>,
+    /// permits that replaced the installed permit
+    pub replaced: Vec<String>,
+    /// permits not installed as the installed permit is preferred by the merge policy
+    pub kept: Vec<String>,
+}
+
+pub struct PermitRegistry {
+    hwid: Zeroizing<String>,
+    permits: HashMap<String, InstalledPermit>,
+}
+
+impl PermitRegistry {
+    pub fn new(hwid: &str) -> PermitRegistry {
+        PermitRegistry {
+            hwid: Zeroizing::new(hwid.to_owned()),
+            permits: HashMap::new(),
+        }
+    }
+
+    /// installs all permits in the PERMIT.TXT read from `rdr`, replacing previously installed
+    /// permits for the same cells. Returns the number of installed permits
+    pub fn install<R: Read>(
+        &mut self,
+        rdr: R,
+        source: &str,
+        now: NaiveDateTime,
+    ) -> Result<usize, E> {
+        let (_, pf) = PermitFile::new(rdr)?;
+        let permits = pf.permits(&self.hwid).collect::<Result<Vec<_>, _>>()?;
+        let n = permits.len();
+        for permit in permits {
+            self.insert(InstalledPermit {
+                permit,
+                installed: now,
+                source: source.to_owned(),
+            });
+        }
+        Ok(n)
+    }
+
+    /// like `install` but a permit only replaces the installed permit for its cell if
+    /// `policy` prefers it. Nothing is installed if the file can't be read
+    pub fn install_with<R: Read>(
+        &mut self,
+        rdr: R,
+        source: &str,
+        now: NaiveDateTime,
+        policy: MergePolicy,
+    ) -> Result<InstallSummary, E> {
+        let (_, pf) = PermitFile::new(rdr)?;
+        let permits = pf.permits(&self.hwid).collect::<Result<Vec<_>, _>>()?;
+        let mut res = InstallSummary::default();
+        for permit in permits {
+            let cell = permit.cell_permit.cell.clone();
+            match self.permits.get(&cell) {
+                Some(old) if !policy.prefers(&permit, &old.permit) => {
+                    res.kept.push(cell);
+                    continue;
+                }
+                Some(_) => res.replaced.push(cell),
+                None => res.added.push(cell),
+            }
+            self.insert(InstalledPermit {
+                permit,
+                installed: now,
+                source: source.to_owned(),
+            });
+        }
+        for cells in [&mut res.added, &mut res.replaced, &mut res.kept] {
+            cells.sort();
+            cells.dedup();
+        }
+        Ok(res)
+    }
+
+    pub fn insert(&mut self, p: InstalledPermit) -> Option<InstalledPermit> {
+        self.permits.insert(p.permit.cell_permit.cell.clone(), p)
+    }
+
+    pub fn remove(&mut self, cell: &str) -> Option<InstalledPermit> {
+        self.permits.remove(cell)
+    }
+
+    /// removes the permits of the cells cancelled by `set`, see
+    /// `ExchangeSet::cancellations`. Returns the removed permits
+    pub fn remove_cancelled(&mut self, set: &ExchangeSet) -> Vec<InstalledPermit> {
+        set.cancellations()
+            .filter_map(|c| self.remove(&c.cell))
+            .collect()
+    }
+
+    pub fn installed(&self, cell: &str) -> Option<&InstalledPermit> {
+        self.permits.get(cell)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InstalledPermit> {
+        self.permits.values()
+    }
+
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        writeln!(wtr, "{}", HEADER)?;
+        let mut cells: Vec<_> = self.permits.keys().collect();
+        cells.sort();
+        for cell in cells {
+            let p = &self.permits[cell];
+            // installed permits are kept exactly as they were received, unless changed since
+            let row = match &p.permit.original {
+                Some(original) if self.is_original(&p.permit, &original.line) => {
+                    original.line.clone()
+                }
+                _ => p.permit.serialize(&self.hwid)?,
+            };
+            writeln!(
+                wtr,
+                "{}\t{}\t{}\t{}",
+                p.installed.format(DATE_FORMAT),
+                escape(&p.source),
+                p.permit.section.marker(),
+                row
+            )?;
+        }
+        Ok(())
+    }
+
+    // whether the permit file row `line` still reads as `p`
+    fn is_original(&self, p: &PermitRecord, line: &str) -> bool {
+        permit::parse_raw_permit(line, p.section)
+            .and_then(|r| r.decrypt_keys(&self.hwid))
+            .is_ok_and(|r| r == *p)
+    }
+
+    pub fn read<R: Read>(rdr: R, hwid: &str) -> Result<PermitRegistry, E> {
+        let mut res = PermitRegistry::new(hwid);
+        let mut lines = BufReader::new(rdr).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header.trim() != HEADER {
+            return Err(E::InvalidField(String::from("missing registry header")));
+        }
+        for l in lines {
+            let l = l?;
+            if l.trim().is_empty() {
+                continue;
+            }
+            res.insert(parse_line(&l, hwid)?);
+        }
+        Ok(res)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), E> {
+        let mut buf = Vec::new();
+        self.write(&mut buf)?;
+        write_replacing(path.as_ref(), &buf)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, hwid: &str) -> Result<PermitRegistry, E> {
+        PermitRegistry::read(std::fs::File::open(path)?, hwid)
+    }
+}
+
+fn parse_line(l: &str, hwid: &str) -> Result<InstalledPermit, E> {
+    let invalid = || E::InvalidField(format!("invalid registry line: {}", l));
+    let mut ss = l.splitn(4, '\t');
+    let installed = NaiveDateTime::parse_from_str(ss.next().ok_or_else(invalid)?, DATE_FORMAT)?;
+    let source = unescape(ss.next().ok_or_else(invalid)?).ok_or_else(invalid)?;
+    let section = Section::from_marker(ss.next().ok_or_else(invalid)?).ok_or_else(invalid)?;
+    let permit =
+        permit::parse_raw_permit(ss.next().ok_or_else(invalid)?, section)?.decrypt_keys(hwid)?;
+    Ok(InstalledPermit {
+        permit,
+        installed,
+        source,
+    })
+}
+
+// escapes the characters that would break the line of a source
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\t' => res.push_str("\\t"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+// the inverse of escape, None for an invalid escape
+fn unescape(s: &str) -> Option<String> {
+    let mut res = String::with_capacity(s.len());
+    let mut cs = s.chars();
+    while let Some(c) = cs.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        res.push(match cs.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(res)
+}
+
+/// the registry can be used directly as the permits of a `S63Decrypter`
+impl GetPermit for PermitRegistry {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.permits.get(cell).map(|p| &p.permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERMIT_TXT: &str = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+:ECS";
+
+    #[test]
+    fn install_and_persist() -> Result<(), E> {
+        let now = NaiveDate::from_ymd_opt(2007, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let mut reg = PermitRegistry::new("12345");
+        assert_eq!(reg.install(PERMIT_TXT.as_bytes(), "PERMIT.TXT", now)?, 2);
+        assert_eq!(reg.installed("GB100001").unwrap().source, "PERMIT.TXT");
+
+        let mut buf = Vec::new();
+        reg.write(&mut buf)?;
+        let read = PermitRegistry::read(buf.as_slice(), "12345")?;
+        assert_eq!(read.installed("GB100001"), reg.installed("GB100001"));
+        assert_eq!(read.installed("GB100002"), reg.installed("GB100002"));
+        assert_eq!(read.get_permit("GB100002").unwrap().edition, Some(0));
+        assert!(PermitRegistry::read(buf.as_slice(), "54321").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn escaped_source() -> Result<(), E> {
+        let now = NaiveDate::from_ymd_opt(2007, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let source = "D:\\PERMIT\tTXT\r\nGB100003";
+        let mut reg = PermitRegistry::new("12345");
+        reg.install(PERMIT_TXT.as_bytes(), source, now)?;
+        let mut buf = Vec::new();
+        reg.write(&mut buf)?;
+        assert_eq!(String::from_utf8(buf.clone()).unwrap().lines().count(), 3);
+        let read = PermitRegistry::read(buf.as_slice(), "12345")?;
+        assert_eq!(read.installed("GB100001").unwrap().source, source);
+        assert_eq!(read.installed("GB100002"), reg.installed("GB100002"));
+        assert_eq!(unescape("PERMIT\\x"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn changed_permits() -> Result<(), E> {
+        let now = NaiveDate::from_ymd_opt(2007, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let mut reg = PermitRegistry::new("12345");
+        reg.install(PERMIT_TXT.as_bytes(), "PERMIT.TXT", now)?;
+        let mut buf = Vec::new();
+        reg.write(&mut buf)?;
+        let mut read = PermitRegistry::read(buf.as_slice(), "12345")?;
+        let mut p = read.remove("GB100001").unwrap();
+        assert!(p.permit.original.is_some());
+        p.permit.cell_permit.date = NaiveDate::from_ymd_opt(2008, 12, 31).unwrap();
+        read.insert(p);
+
+        let mut buf = Vec::new();
+        read.write(&mut buf)?;
+        let read = PermitRegistry::read(buf.as_slice(), "12345")?;
+        let p = read.get_permit("GB100001").unwrap();
+        assert_eq!(
+            p.cell_permit.date,
+            NaiveDate::from_ymd_opt(2008, 12, 31).unwrap()
+        );
+        assert_eq!(p.comment, "hej");
+        assert_eq!(read.installed("GB100002"), reg.installed("GB100002"));
+        Ok(())
+    }
+
+    #[test]
+    fn save_replaces() -> Result<(), E> {
+        let now = NaiveDate::from_ymd_opt(2007, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("s63-registry-{}.reg", std::process::id()));
+        let mut reg = PermitRegistry::new("12345");
+        reg.save(&path)?;
+        reg.install(PERMIT_TXT.as_bytes(), "PERMIT.TXT", now)?;
+        reg.save(&path)?;
+        let read = PermitRegistry::load(&path, "12345");
+        let tmp = with_ext(&path, ".tmp");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read?.iter().count(), 2);
+        assert!(!tmp.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_cancelled() -> Result<(), E> {
+        let now = NaiveDate::from_ymd_opt(2007, 11, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let mut reg = PermitRegistry::new("12345");
+        reg.install(PERMIT_TXT.as_bytes(), "PERMIT.TXT", now)?;
+        let dir = crate::exchange::tests::write_set(
+            "cancelled",
+            &[
+                ("ENC_ROOT/GB/GB100001/0/3/GB100001.003", b""),
+                ("ENC_ROOT/GB/GB100002/1/1/GB100002.001", b""),
+            ],
+        );
+        let set = ExchangeSet::open(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let removed = reg.remove_cancelled(&set.unwrap());
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].permit.cell_permit.cell, "GB100001");
+        assert!(reg.installed("GB100001").is_none());
+        assert!(reg.installed("GB100002").is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Registry of installed cells, persisted to disk
+//!
+//! The registry file starts with a `:CELLS 1` line followed by one tab separated line per
+//! cell: cell name, edition, last applied update and import time.
+
+use crate::errors::E;
+use crate::exchange::{CellVersion, InstalledCells};
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+const HEADER: &str = ":CELLS 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledCell {
+    pub cell: String,
+    pub version: CellVersion,
+    /// when the last update was imported
+    pub imported: NaiveDateTime,
+}
+
+/// the installed edition and update of cells, consulted by imports to tell whether an
+/// exchange set is newer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellRegistry {
+    cells: BTreeMap<String, InstalledCell>,
+}
+
+impl CellRegistry {
+    pub fn new() -> CellRegistry {
+        CellRegistry::default()
+    }
+
+    /// records `version` of `cell` as imported at `imported`, returning the cell it replaced
+    pub fn insert(
+        &mut self,
+        cell: &str,
+        version: CellVersion,
+        imported: NaiveDateTime,
+    ) -> Option<InstalledCell> {
+        let c = InstalledCell {
+            cell: cell.to_owned(),
+            version,
+            imported,
+        };
+        self.cells.insert(cell.to_owned(), c)
+    }
+
+    pub fn remove(&mut self, cell: &str) -> Option<InstalledCell> {
+        self.cells.remove(cell)
+    }
+
+    pub fn get(&self, cell: &str) -> Option<&InstalledCell> {
+        self.cells.get(cell)
+    }
+
+    /// the installed cells by name
+    pub fn iter(&self) -> impl Iterator<Item = &InstalledCell> {
+        self.cells.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        writeln!(wtr, "{}", HEADER)?;
+        for c in self.cells.values() {
+            writeln!(
+                wtr,
+                "{}\t{}\t{}\t{}",
+                c.cell,
+                c.version.edition,
+                c.version.update,
+                c.imported.format(super::DATE_FORMAT)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(rdr: R) -> Result<CellRegistry, E> {
+        let mut res = CellRegistry::new();
+        let mut lines = BufReader::new(rdr).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header.trim() != HEADER {
+            return Err(E::InvalidField(String::from(
+                "missing cell registry header",
+            )));
+        }
+        for l in lines {
+            let l = l?;
+            if l.trim().is_empty() {
+                continue;
+            }
+            let c = parse_line(&l)?;
+            res.cells.insert(c.cell.clone(), c);
+        }
+        Ok(res)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), E> {
+        let mut buf = Vec::new();
+        self.write(&mut buf)?;
+        std::fs::write(path, buf)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<CellRegistry, E> {
+        CellRegistry::read(std::fs::File::open(path)?)
+    }
+}
+
+fn parse_line(l: &str) -> Result<InstalledCell, E> {
+    let invalid = || E::InvalidField(format!("invalid cell registry line: {}", l));
+    let mut ss = l.splitn(4, '\t');
+    let cell = ss.next().ok_or_else(invalid)?.to_owned();
+    let edition = ss
+        .next()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    let update = ss
+        .next()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    let imported =
+        NaiveDateTime::parse_from_str(ss.next().ok_or_else(invalid)?, super::DATE_FORMAT)?;
+    Ok(InstalledCell {
+        cell,
+        version: CellVersion { edition, update },
+        imported,
+    })
+}
+
+impl InstalledCells for CellRegistry {
+    fn installed_version(&self, cell: &str) -> Option<CellVersion> {
+        self.get(cell).map(|c| c.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn write_read() -> Result<(), E> {
+        let time = NaiveDate::from_ymd_opt(2020, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 30, 0)
+            .unwrap();
+        let v = |edition, update| CellVersion { edition, update };
+        let mut reg = CellRegistry::new();
+        reg.insert("GB100002", v(1, 0), time);
+        reg.insert("GB100001", v(4, 2), time);
+        assert_eq!(
+            reg.insert("GB100002", v(1, 3), time).unwrap().version,
+            v(1, 0)
+        );
+
+        let mut buf = Vec::new();
+        reg.write(&mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf.clone()).unwrap(),
+            ":CELLS 1\nGB100001\t4\t2\t20200601 12:30:00\nGB100002\t1\t3\t20200601 12:30:00\n"
+        );
+        let read = CellRegistry::read(&buf[..])?;
+        assert_eq!(read, reg);
+        assert_eq!(read.installed_version("GB100001"), Some(v(4, 2)));
+        assert_eq!(read.installed_version("GB100003"), None);
+
+        assert!(CellRegistry::read(&b"GB100001\t4\t2\t20200601 12:30:00\n"[..]).is_err());
+        assert!(CellRegistry::read(&b":CELLS 1\nGB100001\tx\t2\t20200601 12:30:00\n"[..]).is_err());
+        Ok(())
+    }
+}
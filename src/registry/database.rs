@@ -0,0 +1,236 @@
+//! A permit registry managed in a file, saved after every change with a backup of the
+//! state before it
+
+use super::{with_ext, InstallSummary, InstalledPermit, PermitRegistry};
+use crate::audit::{AuditEvent, AuditSink};
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::errors::E;
+use crate::permit::MergePolicy;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// a `PermitRegistry` in a file. Every change is saved at once, and the previous file is
+/// kept as a backup next to it so the last change can be undone
+pub struct PermitDatabase {
+    path: PathBuf,
+    registry: PermitRegistry,
+    policy: MergePolicy,
+    audit: Option<Arc<dyn AuditSink>>,
+    clock: Box<dyn Clock + Send + Sync>,
+}
+
+impl PermitDatabase {
+    /// opens the database in the file `path`, an empty database if there is no file yet.
+    /// Installed permits replace those with an earlier expiry date, see `policy`
+    pub fn open<P: Into<PathBuf>>(path: P, hwid: &str) -> Result<PermitDatabase, E> {
+        let path = path.into();
+        let registry = if path.exists() {
+            PermitRegistry::load(&path, hwid)?
+        } else {
+            PermitRegistry::new(hwid)
+        };
+        Ok(PermitDatabase {
+            path,
+            registry,
+            policy: MergePolicy::NewestExpiry,
+            audit: None,
+            clock: Box::new(SystemClock),
+        })
+    }
+
+    /// how a permit for an installed cell is resolved, defaults to the newest expiry
+    pub fn policy(mut self, policy: MergePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// records the permits added or replaced by installs in `sink`
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// the time installs are recorded at, defaults to the system clock
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    pub fn registry(&self) -> &PermitRegistry {
+        &self.registry
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the state before the last change, the file name with .bak appended
+    pub fn backup_path(&self) -> PathBuf {
+        with_ext(&self.path, ".bak")
+    }
+
+    /// installs the permits of the PERMIT.TXT read from `rdr` at the time of the database
+    /// clock, see `install_at`
+    pub fn install<R: Read>(&mut self, rdr: R, source: &str) -> Result<InstallSummary, E> {
+        let now = self.clock.now();
+        self.install_at(rdr, source, FixedClock(now))
+    }
+
+    /// installs the permits of the PERMIT.TXT read from `rdr` at the time of `clock` with
+    /// `PermitRegistry::install_with` and saves the database
+    pub fn install_at<R: Read, C: Clock>(
+        &mut self,
+        rdr: R,
+        source: &str,
+        clock: C,
+    ) -> Result<InstallSummary, E> {
+        let res = self
+            .registry
+            .install_with(rdr, source, clock.now(), self.policy)?;
+        if !res.added.is_empty() || !res.replaced.is_empty() {
+            self.commit()?;
+        }
+        if let Some(audit) = &self.audit {
+            for cell in res.added.iter().chain(&res.replaced) {
+                if let Some(p) = self.registry.installed(cell) {
+                    audit.record(&AuditEvent::permit_installed(&p.permit, source));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// removes the permits for `cells` and saves the database. Returns the removed permits,
+    /// cells without a permit are skipped
+    pub fn remove<S: AsRef<str>>(&mut self, cells: &[S]) -> Result<Vec<InstalledPermit>, E> {
+        let res: Vec<_> = cells
+            .iter()
+            .filter_map(|c| self.registry.remove(c.as_ref()))
+            .collect();
+        if !res.is_empty() {
+            self.commit()?;
+        }
+        Ok(res)
+    }
+
+    /// restores the state before the last change from the backup. Only one change can be
+    /// undone, returns false if there is no backup
+    pub fn undo(&mut self) -> Result<bool, E> {
+        let backup = self.backup_path();
+        if !backup.exists() {
+            return Ok(false);
+        }
+        let registry = PermitRegistry::load(&backup, &self.registry.hwid)?;
+        fs::rename(&backup, &self.path)?;
+        self.registry = registry;
+        Ok(true)
+    }
+
+    // saves the registry, the file is replaced only once the new one is written. On
+    // failure the registry is read back from the file
+    fn commit(&mut self) -> Result<(), E> {
+        let res = self.save();
+        if res.is_err() && self.path.exists() {
+            if let Ok(registry) = PermitRegistry::load(&self.path, &self.registry.hwid) {
+                self.registry = registry;
+            }
+        }
+        res
+    }
+
+    fn save(&self) -> Result<(), E> {
+        let tmp = with_ext(&self.path, ".tmp");
+        self.registry.save(&tmp)?;
+        if self.path.exists() {
+            fs::rename(&self.path, self.backup_path())?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permit::{test_permit, MetaData, PermitFileWriter};
+    use chrono::NaiveDate;
+
+    fn permit_txt(permits: &[(&str, u32)]) -> Vec<u8> {
+        let md = MetaData {
+            date: NaiveDate::from_ymd_opt(2020, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            version: 2,
+        };
+        let mut w = PermitFileWriter::new(Vec::new(), &md, "12345").unwrap();
+        for (cell, year) in permits {
+            let mut p = test_permit(cell, &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+            p.cell_permit.date = NaiveDate::from_ymd_opt(*year as i32, 1, 1).unwrap();
+            w.write_permit(&p).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    fn expiry(db: &PermitDatabase, cell: &str) -> Option<i32> {
+        use chrono::Datelike;
+        let p = db.registry().installed(cell)?;
+        Some(p.permit.cell_permit.date.year())
+    }
+
+    #[test]
+    fn database() -> Result<(), E> {
+        let dir = std::env::temp_dir().join(format!("s63-database-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("permits.reg");
+        let clock = FixedClock(
+            NaiveDate::from_ymd_opt(2020, 6, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+        );
+        let res = (|| {
+            let mut db = PermitDatabase::open(&path, "12345")?;
+            assert!(!db.undo()?);
+            let txt = permit_txt(&[("GB100001", 2030), ("GB100002", 2030)]);
+            let summary = db.install_at(&txt[..], "PERMIT.TXT", clock)?;
+            assert_eq!(summary.added, ["GB100001", "GB100002"]);
+            assert!(path.exists() && !db.backup_path().exists());
+
+            let txt = permit_txt(&[("GB100001", 2031), ("GB100002", 2029), ("GB100003", 2030)]);
+            let summary = db.install_at(&txt[..], "UPDATE.TXT", clock)?;
+            assert_eq!(
+                summary,
+                InstallSummary {
+                    added: vec!["GB100003".into()],
+                    replaced: vec!["GB100001".into()],
+                    kept: vec!["GB100002".into()],
+                }
+            );
+            assert_eq!(expiry(&db, "GB100001"), Some(2031));
+            assert_eq!(expiry(&db, "GB100002"), Some(2030));
+            assert!(db.backup_path().exists());
+
+            let removed = db.remove(&["GB100003", "GB100009"])?;
+            assert_eq!(removed.len(), 1);
+            let reopened = PermitDatabase::open(&path, "12345")?;
+            assert!(reopened.registry().installed("GB100003").is_none());
+            assert_eq!(expiry(&reopened, "GB100001"), Some(2031));
+
+            assert!(db.undo()?);
+            assert_eq!(expiry(&db, "GB100003"), Some(2030));
+            assert!(!db.undo()?);
+            let reopened = PermitDatabase::open(&path, "12345")?;
+            assert!(reopened.registry().installed("GB100003").is_some());
+
+            let mut db = reopened.policy(MergePolicy::HighestEdition);
+            assert!(db.install_at(&b"garbage"[..], "BAD.TXT", clock).is_err());
+            assert_eq!(db.registry().iter().count(), 3);
+            Ok(())
+        })();
+        fs::remove_dir_all(&dir)?;
+        res
+    }
+}
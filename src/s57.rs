@@ -0,0 +1,300 @@
+//! S-57 cell files, the decrypted base cells and updates
+
+use crate::iso8211::{self, FieldDesc};
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::prelude::*;
+
+mod update;
+pub use self::update::*;
+
+#[derive(Debug)]
+pub enum E {
+    Io(std::io::Error),
+    /// the file is not an S-57 cell file
+    Invalid(String),
+    /// the update can't be applied to the data set
+    Update(String),
+}
+
+impl From<std::io::Error> for E {
+    fn from(e: std::io::Error) -> E {
+        E::Io(e)
+    }
+}
+
+impl From<iso8211::E> for E {
+    fn from(e: iso8211::E) -> E {
+        E::Invalid(e.to_string())
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::Io(e) => write!(f, "IO error: {}", e),
+            E::Invalid(s) => write!(f, "invalid S-57 file: {}", s),
+            E::Update(s) => write!(f, "can't apply update: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// the data set identification of a cell file, from its DSID and DSSI fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dsid {
+    /// DSNM, the file name, e.g. GB100001.001
+    pub name: String,
+    /// EDTN
+    pub edition: u32,
+    /// UPDN, 0 for a base cell
+    pub update: u32,
+    /// UADT, the update application date
+    pub update_date: Option<NaiveDate>,
+    /// ISDT, the issue date
+    pub issue_date: Option<NaiveDate>,
+    /// AGEN, the IHO code of the producing agency
+    pub agency: u16,
+    /// COMT
+    pub comment: String,
+    /// AALL and NALL of DSSI, the lexical levels of the attribute values
+    pub lexical_levels: Option<(u8, u8)>,
+}
+
+// an unsigned little endian binary subfield
+fn unsigned(b: &[u8]) -> u32 {
+    b.iter().rev().fold(0, |n, &b| (n << 8) | u32::from(b))
+}
+
+// the subfields of the field `tag` of a record, by label
+fn subfields<'a>(
+    descs: &'a HashMap<String, FieldDesc>,
+    rec: &iso8211::Record<'a>,
+    tag: &str,
+) -> Result<Option<HashMap<&'a str, &'a [u8]>>, E> {
+    let (desc, data) = match (descs.get(tag), rec.iter().find(|(t, _)| *t == tag)) {
+        (Some(desc), Some((_, data))) => (desc, data),
+        _ => return Ok(None),
+    };
+    Ok(Some(desc.subfields(data)?.into_iter().collect()))
+}
+
+impl Dsid {
+    /// a cancellation update, EDTN is 0. The cell is withdrawn and is to be removed with its
+    /// permit
+    pub fn is_cancellation(&self) -> bool {
+        self.edition == 0 && self.update > 0
+    }
+
+    /// a re-issue, a base cell with the updates up to UPDN included. It replaces the cell
+    /// of the same edition and its updates
+    pub fn is_reissue(&self) -> bool {
+        self.name.ends_with(".000") && self.update > 0
+    }
+
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<Dsid, E> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        Dsid::from_bytes(&data)
+    }
+
+    /// reads the DSID of a cell file, the first data record
+    pub fn from_bytes(data: &[u8]) -> Result<Dsid, E> {
+        let (descs, recs) = iso8211::parse(data)?;
+        let rec = recs
+            .first()
+            .ok_or_else(|| E::Invalid("no data records".into()))?;
+        let dsid = subfields(&descs, rec, "DSID")?;
+        let dsid = dsid.ok_or_else(|| E::Invalid("no DSID field".into()))?;
+        let text = |label: &str| {
+            let v = dsid.get(label).copied().unwrap_or_default();
+            String::from_utf8_lossy(v).trim().to_owned()
+        };
+        let number = |label: &str| {
+            text(label)
+                .parse()
+                .map_err(|_| E::Invalid(format!("invalid {} {}", label, text(label))))
+        };
+        let date = |label: &str| NaiveDate::parse_from_str(&text(label), "%Y%m%d").ok();
+        let lexical_levels = subfields(&descs, rec, "DSSI")?.and_then(|dssi| {
+            let level = |label| dssi.get(label).map(|v| unsigned(v) as u8);
+            level("AALL").zip(level("NALL"))
+        });
+        Ok(Dsid {
+            name: text("DSNM"),
+            edition: number("EDTN")?,
+            update: number("UPDN")?,
+            update_date: date("UADT"),
+            issue_date: date("ISDT"),
+            agency: dsid
+                .get("AGEN")
+                .map(|v| unsigned(v) as u16)
+                .unwrap_or_default(),
+            comment: text("COMT"),
+            lexical_levels,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::iso8211::{field_desc, write_record, UT};
+
+    // a cell file with the DSID and DSSI fields of `name`, edition and update
+    pub(crate) fn cell(name: &str, edition: u32, update: u32) -> Vec<u8> {
+        cell_with(name, edition, update, &[])
+    }
+
+    fn desc(name: &str, labels: &str, format: &str) -> Vec<u8> {
+        field_desc("1600;&   ", name, labels, format)
+    }
+
+    fn repeating(name: &str, labels: &str, format: &str) -> Vec<u8> {
+        field_desc("2600;&   ", name, labels, format)
+    }
+
+    // like `cell` followed by the feature and vector `records`, 0001 is added to them
+    pub(crate) fn cell_with(
+        name: &str,
+        edition: u32,
+        update: u32,
+        records: &[Vec<(&str, Vec<u8>)>],
+    ) -> Vec<u8> {
+        let ddr = [
+            ("0000", b"0000;&   GB100001.000".to_vec()),
+            (
+                "0001",
+                field_desc("0100;&   ", "DS Identifier field", "", "(b12)"),
+            ),
+            (
+                "DSID",
+                field_desc(
+                    "1600;&   ",
+                    "Data set identification field",
+                    "RCNM!RCID!EXPP!INTU!DSNM!EDTN!UPDN!UADT!ISDT!STED!PRSP!PSDN!PRED!PROF!AGEN!COMT",
+                    "(b11,b14,2b11,3A,2A(8),R(4),b11,2A,b11,b12,A)",
+                ),
+            ),
+            (
+                "DSSI",
+                field_desc(
+                    "1600;&   ",
+                    "Data set structure information field",
+                    "DSTR!AALL!NALL!NOMR!NOCR!NOGR!NOLR!NOIN!NOCN!NOED!NOFA",
+                    "(3b11,8b14)",
+                ),
+            ),
+            (
+                "FRID",
+                desc(
+                    "Feature record identifier field",
+                    "RCNM!RCID!PRIM!GRUP!OBJL!RVER!RUIN",
+                    "(b11,b14,2b11,2b12,b11)",
+                ),
+            ),
+            (
+                "ATTF",
+                repeating("Feature record attribute field", "*ATTL!ATVL", "(b12,A)"),
+            ),
+            (
+                "FSPC",
+                desc(
+                    "Feature record to spatial record pointer control field",
+                    "FSUI!FSIX!NSPT",
+                    "(b11,2b12)",
+                ),
+            ),
+            (
+                "FSPT",
+                repeating(
+                    "Feature record to spatial record pointer field",
+                    "*NAME!ORNT!USAG!MASK",
+                    "(B(40),3b11)",
+                ),
+            ),
+            (
+                "VRID",
+                desc(
+                    "Vector record identifier field",
+                    "RCNM!RCID!RVER!RUIN",
+                    "(b11,b14,b12,b11)",
+                ),
+            ),
+            (
+                "SGCC",
+                desc(
+                    "Coordinate control field",
+                    "CCUI!CCIX!CCNC",
+                    "(b11,2b12)",
+                ),
+            ),
+            (
+                "SG2D",
+                repeating("2-D coordinate field", "*YCOO!XCOO", "(2b24)"),
+            ),
+        ];
+        let mut dsid = vec![10, 1, 0, 0, 0, 1, 5];
+        for s in [name, &edition.to_string(), &update.to_string()] {
+            dsid.extend_from_slice(s.as_bytes());
+            dsid.push(UT);
+        }
+        dsid.extend_from_slice(b"2020061520200601 3.1");
+        dsid.push(1);
+        dsid.extend_from_slice(b"2.0");
+        dsid.push(UT);
+        dsid.push(UT);
+        dsid.push(1);
+        dsid.extend_from_slice(&540u16.to_le_bytes());
+        dsid.extend_from_slice(b"comment");
+        dsid.push(UT);
+        let mut dssi = vec![2, 1, 2];
+        dssi.extend_from_slice(&[0; 32]);
+        let mut data = write_record(b"3LE1 09", b" ! ", &ddr);
+        let fields = [
+            ("0001", 1u16.to_le_bytes().to_vec()),
+            ("DSID", dsid),
+            ("DSSI", dssi),
+        ];
+        data.extend_from_slice(&write_record(b" D     ", b"   ", &fields));
+        for (i, rec) in records.iter().enumerate() {
+            let mut fields = vec![("0001", (i as u16 + 2).to_le_bytes().to_vec())];
+            fields.extend(rec.iter().cloned());
+            data.extend_from_slice(&write_record(b" D     ", b"   ", &fields));
+        }
+        data
+    }
+
+    #[test]
+    fn dsid() -> Result<(), E> {
+        let dsid = Dsid::from_rdr(&cell("GB100001.001", 4, 1)[..])?;
+        assert_eq!(
+            dsid,
+            Dsid {
+                name: "GB100001.001".into(),
+                edition: 4,
+                update: 1,
+                update_date: NaiveDate::from_ymd_opt(2020, 6, 15),
+                issue_date: NaiveDate::from_ymd_opt(2020, 6, 1),
+                agency: 540,
+                comment: "comment".into(),
+                lexical_levels: Some((1, 2)),
+            }
+        );
+        assert!(!dsid.is_cancellation() && !dsid.is_reissue());
+        assert!(Dsid::from_bytes(&cell("GB100001.000", 4, 3))?.is_reissue());
+        assert!(!Dsid::from_bytes(&cell("GB100001.000", 4, 0))?.is_reissue());
+        assert!(Dsid::from_bytes(&cell("GB100001.002", 0, 2))?.is_cancellation());
+        assert!(matches!(Dsid::from_bytes(b"garbage"), Err(E::Invalid(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+//! The S-57 update mechanism, applying update files (.001, .002, …) to a base cell
+
+use super::{Dsid, E};
+use crate::iso8211::{self, FieldDesc};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// the update instructions of RUIN and of the update control fields
+const INSERT: u8 = 1;
+const DELETE: u8 = 2;
+const MODIFY: u8 = 3;
+// the attribute value deleting an attribute
+const DELETE_VALUE: u8 = 0x7f;
+// the update control fields, the fields they control follow them
+const CONTROLS: [&str; 4] = ["FFPC", "FSPC", "VRPC", "SGCC"];
+const ATTRIBUTES: [&str; 3] = ["ATTF", "NATF", "ATTV"];
+// the order of the fields in feature and vector records
+const ORDER: [&str; 13] = [
+    "0001", "FRID", "FOID", "ATTF", "NATF", "FFPT", "FSPT", "VRID", "ATTV", "VRPT", "SG2D", "SG3D",
+    "ARCC",
+];
+
+type Field = (String, Vec<u8>);
+
+// a data set as records of owned fields, the data descriptive record is kept as is
+struct DataSet {
+    ddr: Vec<u8>,
+    descs: HashMap<String, FieldDesc>,
+    records: Vec<Vec<Field>>,
+}
+
+// the record identifier of a feature or vector record: RCNM, RCID, RVER and RUIN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Id {
+    rcnm: u8,
+    rcid: u32,
+    rver: u16,
+    ruin: u8,
+}
+
+fn le(b: &[u8]) -> u32 {
+    b.iter().rev().fold(0, |n, &b| (n << 8) | u32::from(b))
+}
+
+fn update_err(id: Id, reason: &str) -> E {
+    E::Update(format!("record {}/{}: {}", id.rcnm, id.rcid, reason))
+}
+
+// the FRID or VRID field and the offset of RVER in it
+fn id_field(rec: &[Field]) -> Option<(usize, usize)> {
+    rec.iter()
+        .enumerate()
+        .find_map(|(i, (tag, _))| match tag.as_str() {
+            "FRID" => Some((i, 9)),
+            "VRID" => Some((i, 5)),
+            _ => None,
+        })
+}
+
+fn record_id(rec: &[Field]) -> Option<Id> {
+    let (i, rver) = id_field(rec)?;
+    let data = &rec[i].1;
+    Some(Id {
+        rcnm: *data.first()?,
+        rcid: le(data.get(1..5)?),
+        rver: le(data.get(rver..rver + 2)?) as u16,
+        ruin: *data.get(rver + 2)?,
+    })
+}
+
+// where a field with `tag` goes in `rec`
+fn field_pos(rec: &[Field], tag: &str) -> usize {
+    let order = |t: &str| ORDER.iter().position(|o| *o == t).unwrap_or(ORDER.len());
+    rec.iter()
+        .position(|(t, _)| order(t) > order(tag))
+        .unwrap_or(rec.len())
+}
+
+fn set_field(rec: &mut Vec<Field>, tag: &str, data: Vec<u8>) {
+    match rec.iter_mut().find(|(t, _)| t == tag) {
+        Some(f) => f.1 = data,
+        None => {
+            let pos = field_pos(rec, tag);
+            rec.insert(pos, (tag.to_owned(), data));
+        }
+    }
+}
+
+impl DataSet {
+    fn read(data: &[u8]) -> Result<DataSet, E> {
+        let (descs, recs) = iso8211::parse(data)?;
+        let ddr_len = iso8211::num(&data[..5])?;
+        let records = recs
+            .into_iter()
+            .map(|r| {
+                r.into_iter()
+                    .map(|(tag, data)| (tag.to_owned(), data.to_vec()))
+                    .collect()
+            })
+            .collect();
+        Ok(DataSet {
+            ddr: data[..ddr_len].to_vec(),
+            descs,
+            records,
+        })
+    }
+
+    fn write(&self) -> Result<Vec<u8>, E> {
+        let mut res = self.ddr.clone();
+        for (i, rec) in self.records.iter().enumerate() {
+            // the record number in field 0001 is a 16 bit integer
+            let number = u16::try_from(i + 1)
+                .map_err(|_| E::Update("too many records for one data set".to_owned()))?;
+            let fields: Vec<(&str, Vec<u8>)> = rec
+                .iter()
+                .map(|(tag, data)| match tag.as_str() {
+                    "0001" => (tag.as_str(), number.to_le_bytes().to_vec()),
+                    _ => (tag.as_str(), data.clone()),
+                })
+                .collect();
+            res.extend_from_slice(&iso8211::write_record(b" D     ", b"   ", &fields));
+        }
+        Ok(res)
+    }
+
+    fn find(&self, id: Id) -> Result<usize, E> {
+        let pos = self
+            .records
+            .iter()
+            .position(|r| record_id(r).is_some_and(|i| i.rcnm == id.rcnm && i.rcid == id.rcid));
+        let pos = pos.ok_or_else(|| update_err(id, "not in the data set"))?;
+        let version = record_id(&self.records[pos]).map(|i| i.rver);
+        if version.is_some_and(|v| v.checked_add(1) != Some(id.rver)) {
+            return Err(update_err(id, "wrong record version"));
+        }
+        Ok(pos)
+    }
+
+    fn groups<'a>(&self, tag: &str, data: &'a [u8]) -> Result<Vec<&'a [u8]>, E> {
+        let desc = self
+            .descs
+            .get(tag)
+            .ok_or_else(|| E::Invalid(format!("no field description for {}", tag)))?;
+        Ok(desc.groups(data)?)
+    }
+
+    fn apply(&mut self, update: &DataSet) -> Result<(), E> {
+        let (dsid, recs) = update
+            .records
+            .split_first()
+            .ok_or_else(|| E::Invalid("no data records".into()))?;
+        if let (Some((_, data)), Some(first)) = (
+            dsid.iter().find(|(t, _)| t == "DSID"),
+            self.records.first_mut(),
+        ) {
+            let mut data = data.clone();
+            // EXPP, the data set is no longer an update
+            if let Some(expp) = data.get_mut(5) {
+                *expp = 1;
+            }
+            set_field(first, "DSID", data);
+        }
+        for rec in recs {
+            let id = match record_id(rec) {
+                Some(id) => id,
+                None => continue,
+            };
+            match id.ruin {
+                INSERT => self.insert(rec),
+                DELETE => {
+                    let pos = self.find(id)?;
+                    self.records.remove(pos);
+                }
+                MODIFY => {
+                    let pos = self.find(id)?;
+                    let mut target = std::mem::take(&mut self.records[pos]);
+                    let res = self.modify(&mut target, rec, id);
+                    self.records[pos] = target;
+                    res?;
+                }
+                _ => return Err(update_err(id, "invalid update instruction")),
+            }
+        }
+        Ok(())
+    }
+
+    // inserts a record after the last record of its kind
+    fn insert(&mut self, rec: &[Field]) {
+        let rec: Vec<Field> = rec
+            .iter()
+            .filter(|(t, _)| !CONTROLS.contains(&t.as_str()))
+            .cloned()
+            .collect();
+        let rcnm = record_id(&rec).map(|i| i.rcnm);
+        let pos = self
+            .records
+            .iter()
+            .rposition(|r| record_id(r).map(|i| i.rcnm) == rcnm)
+            .map_or(self.records.len(), |p| p + 1);
+        self.records.insert(pos, rec);
+    }
+
+    fn modify(&self, target: &mut Vec<Field>, update: &[Field], id: Id) -> Result<(), E> {
+        if let Some((i, rver)) = id_field(target) {
+            target[i].1[rver..rver + 2].copy_from_slice(&id.rver.to_le_bytes());
+        }
+        let mut fields = update.iter().peekable();
+        while let Some((tag, data)) = fields.next() {
+            let tag = tag.as_str();
+            if tag == "0001" || tag == "FRID" || tag == "VRID" {
+                continue;
+            }
+            if CONTROLS.contains(&tag) {
+                let control = data
+                    .get(..5)
+                    .ok_or_else(|| update_err(id, "truncated update control"))?;
+                // the controlled field follows, there is none for deletions
+                let tags: &[&str] = match tag {
+                    "SGCC" => &["SG2D", "SG3D"],
+                    "FFPC" => &["FFPT"],
+                    "FSPC" => &["FSPT"],
+                    _ => &["VRPT"],
+                };
+                let controlled = fields.next_if(|(t, _)| tags.contains(&t.as_str()));
+                let controlled_tag = match controlled {
+                    Some((t, _)) => t.as_str(),
+                    None => tags
+                        .iter()
+                        .copied()
+                        .find(|t| target.iter().any(|(f, _)| f == t))
+                        .unwrap_or(tags[0]),
+                };
+                let new = controlled.map(|(_, d)| d.as_slice()).unwrap_or_default();
+                self.update_pointers(target, controlled_tag, control, new, id)?;
+            } else if ATTRIBUTES.contains(&tag) {
+                self.update_attributes(target, tag, data)?;
+            } else {
+                set_field(target, tag, data.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // applies an update control, e.g. FSPC, to the controlled field `tag` of `target`
+    fn update_pointers(
+        &self,
+        target: &mut Vec<Field>,
+        tag: &str,
+        control: &[u8],
+        new: &[u8],
+        id: Id,
+    ) -> Result<(), E> {
+        let (instruction, index, count) = (
+            control[0],
+            le(&control[1..3]) as usize,
+            le(&control[3..5]) as usize,
+        );
+        let old = target
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, d)| d.clone())
+            .unwrap_or_default();
+        let mut groups = self.groups(tag, &old)?;
+        let new = self.groups(tag, new)?;
+        let start = index.saturating_sub(1);
+        match instruction {
+            INSERT if start <= groups.len() => {
+                groups.splice(start..start, new.into_iter().take(count));
+            }
+            DELETE | MODIFY if start + count <= groups.len() => {
+                let new = if instruction == DELETE {
+                    Vec::new()
+                } else {
+                    new
+                };
+                groups.splice(start..start + count, new.into_iter().take(count));
+            }
+            _ => return Err(update_err(id, &format!("invalid update of {}", tag))),
+        }
+        let data = groups.concat();
+        if data.is_empty() {
+            target.retain(|(t, _)| t != tag);
+        } else {
+            set_field(target, tag, data);
+        }
+        Ok(())
+    }
+
+    // replaces, adds and deletes the attributes of `update` in the field `tag` of `target`
+    fn update_attributes(
+        &self,
+        target: &mut Vec<Field>,
+        tag: &str,
+        update: &[u8],
+    ) -> Result<(), E> {
+        let old = target
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, d)| d.clone())
+            .unwrap_or_default();
+        let mut attrs = self.groups(tag, &old)?;
+        for attr in self.groups(tag, update)? {
+            let label = attr.get(..2);
+            let pos = attrs.iter().position(|a| a.get(..2) == label);
+            let delete = attr.get(2) == Some(&DELETE_VALUE);
+            match (pos, delete) {
+                (Some(pos), true) => {
+                    attrs.remove(pos);
+                }
+                (Some(pos), false) => attrs[pos] = attr,
+                (None, true) => (),
+                (None, false) => attrs.push(attr),
+            }
+        }
+        let data = attrs.concat();
+        if data.is_empty() {
+            target.retain(|(t, _)| t != tag);
+        } else {
+            set_field(target, tag, data);
+        }
+        Ok(())
+    }
+}
+
+/// applies the update files `updates` in order to the base cell `base` and returns the
+/// updated cell file. Each update must follow the previous file: the same edition and the
+/// next update number. Fails with `Update` if a record to delete or modify is not in the
+/// data set or has the wrong version
+pub fn apply_updates<U: AsRef<[u8]>>(base: &[u8], updates: &[U]) -> Result<Vec<u8>, E> {
+    let mut dsid = Dsid::from_bytes(base)?;
+    let mut set = DataSet::read(base)?;
+    for update in updates {
+        let update = update.as_ref();
+        let next = Dsid::from_bytes(update)?;
+        if next.edition != dsid.edition || Some(next.update) != dsid.update.checked_add(1) {
+            return Err(E::Update(format!(
+                "{} edition {} update {} doesn't follow edition {} update {}",
+                next.name, next.edition, next.update, dsid.edition, dsid.update
+            )));
+        }
+        set.apply(&DataSet::read(update)?)?;
+        dsid = next;
+    }
+    set.write()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iso8211::UT;
+    use crate::s57::tests::cell_with;
+
+    fn frid(rcid: u32, rver: u16, ruin: u8) -> (&'static str, Vec<u8>) {
+        let mut d = vec![100];
+        d.extend_from_slice(&rcid.to_le_bytes());
+        d.extend_from_slice(&[1, 2, 42, 0]);
+        d.extend_from_slice(&rver.to_le_bytes());
+        d.push(ruin);
+        ("FRID", d)
+    }
+
+    fn vrid(rcid: u32, rver: u16, ruin: u8) -> (&'static str, Vec<u8>) {
+        let mut d = vec![110];
+        d.extend_from_slice(&rcid.to_le_bytes());
+        d.extend_from_slice(&rver.to_le_bytes());
+        d.push(ruin);
+        ("VRID", d)
+    }
+
+    fn attf(attrs: &[(u16, &[u8])]) -> (&'static str, Vec<u8>) {
+        let mut d = Vec::new();
+        for (label, value) in attrs {
+            d.extend_from_slice(&label.to_le_bytes());
+            d.extend_from_slice(value);
+            d.push(UT);
+        }
+        ("ATTF", d)
+    }
+
+    // pointers to vector records, NAME is RCNM and RCID followed by ORNT, USAG and MASK
+    fn fspt(rcids: &[u32]) -> (&'static str, Vec<u8>) {
+        let mut d = Vec::new();
+        for rcid in rcids {
+            d.push(130);
+            d.extend_from_slice(&rcid.to_le_bytes());
+            d.extend_from_slice(&[1, 1, 2]);
+        }
+        ("FSPT", d)
+    }
+
+    fn sg2d(coords: &[(i32, i32)]) -> (&'static str, Vec<u8>) {
+        let mut d = Vec::new();
+        for (y, x) in coords {
+            d.extend_from_slice(&y.to_le_bytes());
+            d.extend_from_slice(&x.to_le_bytes());
+        }
+        ("SG2D", d)
+    }
+
+    fn control(
+        tag: &'static str,
+        instruction: u8,
+        index: u16,
+        count: u16,
+    ) -> (&'static str, Vec<u8>) {
+        let mut d = vec![instruction];
+        d.extend_from_slice(&index.to_le_bytes());
+        d.extend_from_slice(&count.to_le_bytes());
+        (tag, d)
+    }
+
+    fn field<'a>(set: &'a DataSet, rcnm: u8, rcid: u32, tag: &str) -> Option<&'a [u8]> {
+        let rec = set
+            .records
+            .iter()
+            .find(|r| record_id(r).is_some_and(|i| i.rcnm == rcnm && i.rcid == rcid))?;
+        rec.iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, d)| d.as_slice())
+    }
+
+    #[test]
+    fn apply() -> Result<(), E> {
+        let base = cell_with(
+            "GB100001.000",
+            4,
+            0,
+            &[
+                vec![vrid(1, 1, INSERT), sg2d(&[(1, 2), (3, 4)])],
+                vec![
+                    frid(1, 1, INSERT),
+                    attf(&[(1, b"a"), (2, b"b")]),
+                    fspt(&[1, 2]),
+                ],
+                vec![frid(2, 1, INSERT)],
+            ],
+        );
+        let update1 = cell_with(
+            "GB100001.001",
+            4,
+            1,
+            &[
+                vec![
+                    frid(1, 2, MODIFY),
+                    attf(&[(1, &[DELETE_VALUE]), (3, b"c")]),
+                    control("FSPC", INSERT, 2, 1),
+                    fspt(&[3]),
+                ],
+                vec![frid(2, 2, DELETE)],
+                vec![frid(3, 1, INSERT), attf(&[(4, b"d")])],
+                vec![vrid(2, 1, INSERT), sg2d(&[(0, 0)])],
+                vec![
+                    vrid(1, 2, MODIFY),
+                    control("SGCC", MODIFY, 1, 1),
+                    sg2d(&[(5, 6)]),
+                ],
+            ],
+        );
+        let update2 = cell_with(
+            "GB100001.002",
+            4,
+            2,
+            &[vec![frid(1, 3, MODIFY), control("FSPC", DELETE, 1, 2)]],
+        );
+
+        let res = apply_updates(&base, &[&update1, &update2])?;
+        let dsid = Dsid::from_bytes(&res)?;
+        assert_eq!((dsid.name.as_str(), dsid.update), ("GB100001.002", 2));
+        let set = DataSet::read(&res)?;
+        let ids: Vec<_> = set.records.iter().filter_map(|r| record_id(r)).collect();
+        let rcids: Vec<_> = ids.iter().map(|i| (i.rcnm, i.rcid, i.rver)).collect();
+        assert_eq!(rcids, [(110, 1, 2), (110, 2, 1), (100, 1, 3), (100, 3, 1)]);
+        assert_eq!(
+            field(&set, 100, 1, "ATTF"),
+            Some(&attf(&[(2, b"b"), (3, b"c")]).1[..])
+        );
+        assert_eq!(field(&set, 100, 1, "FSPT"), Some(&fspt(&[2]).1[..]));
+        assert_eq!(field(&set, 100, 1, "FSPC"), None);
+        assert_eq!(field(&set, 100, 3, "ATTF"), Some(&attf(&[(4, b"d")]).1[..]));
+        assert_eq!(
+            field(&set, 110, 1, "SG2D"),
+            Some(&sg2d(&[(5, 6), (3, 4)]).1[..])
+        );
+
+        assert!(matches!(
+            apply_updates(&base, &[&update2]),
+            Err(E::Update(_))
+        ));
+        assert!(matches!(
+            apply_updates(&base, &[&update1, &update1]),
+            Err(E::Update(_))
+        ));
+        let wrong_version = cell_with("GB100001.001", 4, 1, &[vec![frid(1, 3, DELETE)]]);
+        assert!(matches!(
+            apply_updates(&base, &[&wrong_version]),
+            Err(E::Update(_))
+        ));
+
+        // the version of the last record can't be followed
+        let last = cell_with("GB100001.000", 4, 0, &[vec![frid(1, u16::MAX, INSERT)]]);
+        let wrapped = cell_with("GB100001.001", 4, 1, &[vec![frid(1, 0, DELETE)]]);
+        assert!(matches!(
+            apply_updates(&last, &[&wrapped]),
+            Err(E::Update(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Key material that is wiped from memory when dropped
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroize;
+
+pub use zeroize::Zeroizing;
+
+/// a 5 byte cell key. The key is zeroized on drop and redacted in `Debug`
+#[derive(Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct SecretKey([u8; 5]);
+
+impl SecretKey {
+    pub fn new(key: [u8; 5]) -> SecretKey {
+        SecretKey(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 5] {
+        &self.0
+    }
+}
+
+impl From<[u8; 5]> for SecretKey {
+    fn from(key: [u8; 5]) -> SecretKey {
+        SecretKey(key)
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<[u8; 5]> for SecretKey {
+    fn eq(&self, other: &[u8; 5]) -> bool {
+        &self.0 == other
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+impl Zeroize for SecretKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let mut key = SecretKey::from([1, 2, 3, 4, 5]);
+        assert_eq!(format!("{:?}", key), "SecretKey(..)");
+        assert_eq!(key, [1, 2, 3, 4, 5]);
+        key.zeroize();
+        assert_eq!(key, [0; 5]);
+    }
+}
@@ -0,0 +1,356 @@
+//! The data server side of the scheme: issuing permit files to customers
+//!
+//! A `DataServer` holds the M_KEYs of the manufacturers and the cell keys of the cells it
+//! serves. Given the encrypted user permit of a customer it writes the PERMIT.TXT for the
+//! requested cells, encrypted with the HW_ID of the customer.
+//!
+//! `KeyDatabase` keeps the cell keys of every edition, encrypted on disk.
+
+use crate::clock::{Clock, SystemClock};
+use crate::errors;
+use crate::permit::{
+    renew_permit_file_at, CellPermit, MetaData, PermitFileWriter, PermitRecord, Renewal,
+    SericeLevelIndicator, SUPPORTED_VERSIONS,
+};
+use crate::secret::SecretKey;
+use crate::up::{MKeyStore, PermitErr, UserPermit};
+use chrono::{Days, Months, NaiveDate};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::prelude::*;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+mod keys;
+pub use self::keys::*;
+
+#[derive(Debug)]
+pub enum E {
+    /// the user permit can't be decrypted, e.g. the M_ID is unknown
+    UserPermit(PermitErr),
+    /// there are no keys for the cell, or not for the edition requested
+    UnknownCell(String),
+    /// the expiry date of a request is out of range
+    InvalidExpiry(String),
+    /// the permit file can't be written
+    Permit(errors::E),
+}
+
+impl From<PermitErr> for E {
+    fn from(e: PermitErr) -> E {
+        E::UserPermit(e)
+    }
+}
+
+impl From<errors::E> for E {
+    fn from(e: errors::E) -> E {
+        E::Permit(e)
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::UserPermit(e) => write!(f, "invalid user permit: {:?}", e),
+            E::UnknownCell(cell) => write!(f, "no cell keys for {}", cell),
+            E::InvalidExpiry(cell) => write!(f, "invalid expiry date for {}", cell),
+            E::Permit(e) => write!(f, "permit error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for E {}
+
+/// the cell keys a data server issues permits with
+pub trait CellKeyStore {
+    /// CK1 and CK2 of `edition` of `cell`, of the current edition for None
+    fn cell_keys(&self, cell: &str, edition: Option<u8>) -> Option<(SecretKey, SecretKey)>;
+}
+
+impl<S: ::std::hash::BuildHasher> CellKeyStore for HashMap<String, (SecretKey, SecretKey), S> {
+    fn cell_keys(&self, cell: &str, _: Option<u8>) -> Option<(SecretKey, SecretKey)> {
+        self.get(cell).cloned()
+    }
+}
+
+impl CellKeyStore for BTreeMap<String, (SecretKey, SecretKey)> {
+    fn cell_keys(&self, cell: &str, _: Option<u8>) -> Option<(SecretKey, SecretKey)> {
+        self.get(cell).cloned()
+    }
+}
+
+impl<T: CellKeyStore + ?Sized> CellKeyStore for &T {
+    fn cell_keys(&self, cell: &str, edition: Option<u8>) -> Option<(SecretKey, SecretKey)> {
+        (**self).cell_keys(cell, edition)
+    }
+}
+
+/// when a requested permit expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// on a date
+    Date(NaiveDate),
+    /// a number of days from the day the permit is issued
+    Days(u32),
+    /// a number of months from the day the permit is issued
+    Months(u32),
+}
+
+impl Expiry {
+    /// the expiry date of a permit issued on `today`
+    pub fn date(self, today: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Expiry::Date(date) => Some(date),
+            Expiry::Days(days) => today.checked_add_days(Days::new(u64::from(days))),
+            Expiry::Months(months) => today.checked_add_months(Months::new(months)),
+        }
+    }
+}
+
+/// a cell of a permit file, see `DataServer::permit_file`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermitRequest {
+    pub cell: String,
+    pub expiry: Expiry,
+    /// the edition the permit is limited to, None for every edition
+    pub edition: Option<u8>,
+    pub sli: SericeLevelIndicator,
+}
+
+impl PermitRequest {
+    /// a subscription permit for every edition of `cell`
+    pub fn new(cell: &str, expiry: Expiry) -> PermitRequest {
+        PermitRequest {
+            cell: cell.to_owned(),
+            expiry,
+            edition: None,
+            sli: SericeLevelIndicator::SubscriptionPermit,
+        }
+    }
+
+    pub fn edition(mut self, edition: u8) -> Self {
+        self.edition = Some(edition);
+        self
+    }
+
+    pub fn sli(mut self, sli: SericeLevelIndicator) -> Self {
+        self.sli = sli;
+        self
+    }
+}
+
+/// issues permit files for the user permits of customers
+pub struct DataServer<K: CellKeyStore> {
+    id: String,
+    m_keys: MKeyStore,
+    cell_keys: K,
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl<K: CellKeyStore> DataServer<K> {
+    /// the data server `id`, e.g. "GB", with the M_KEYs of the manufacturers it serves
+    pub fn new(id: &str, m_keys: MKeyStore, cell_keys: K) -> DataServer<K> {
+        DataServer {
+            id: id.to_owned(),
+            m_keys,
+            cell_keys,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// the time of the permit files and the day durations count from, defaults to the
+    /// system clock
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn m_keys(&self) -> &MKeyStore {
+        &self.m_keys
+    }
+
+    pub fn cell_keys(&self) -> &K {
+        &self.cell_keys
+    }
+
+    /// decrypts the user permit `up` with the M_KEY of its manufacturer
+    pub fn user_permit(&self, up: &str) -> Result<UserPermit, E> {
+        Ok(UserPermit::decrypt_with_store(up, &self.m_keys)?)
+    }
+
+    /// the permits of `requests` issued on `today`, in the order of the requests
+    pub fn permits(
+        &self,
+        requests: &[PermitRequest],
+        today: NaiveDate,
+    ) -> Result<Vec<PermitRecord>, E> {
+        requests
+            .iter()
+            .map(|r| {
+                let (key1, key2) = self
+                    .cell_keys
+                    .cell_keys(&r.cell, r.edition)
+                    .ok_or_else(|| E::UnknownCell(r.cell.clone()))?;
+                let date = r
+                    .expiry
+                    .date(today)
+                    .ok_or_else(|| E::InvalidExpiry(r.cell.clone()))?;
+                let cell_permit = CellPermit::builder()
+                    .cell(&r.cell)
+                    .date(date)
+                    .key1(key1.as_bytes())
+                    .key2(key2.as_bytes())
+                    .build()?;
+                let mut p = PermitRecord::builder()
+                    .cell_permit(cell_permit)
+                    .sli(r.sli)
+                    .data_server_id(&self.id);
+                if let Some(edition) = r.edition {
+                    p = p.edition(edition);
+                }
+                Ok(p.build()?)
+            })
+            .collect()
+    }
+
+    /// writes the PERMIT.TXT for the user permit `up` and `requests` at the time of the
+    /// server clock, see `write_permit_file_at`
+    pub fn write_permit_file<W: Write>(
+        &self,
+        wtr: W,
+        up: &str,
+        requests: &[PermitRequest],
+    ) -> Result<W, E> {
+        self.write_permit_file_at(wtr, up, requests, &*self.clock)
+    }
+
+    /// writes the PERMIT.TXT for the user permit `up` and `requests` at the time of
+    /// `clock`: the header, the `:ENC` section with a permit per request, and the empty
+    /// `:ECS` section. The cell keys are encrypted with the HW_ID of the user permit
+    pub fn write_permit_file_at<W: Write, C: Clock>(
+        &self,
+        wtr: W,
+        up: &str,
+        requests: &[PermitRequest],
+        clock: C,
+    ) -> Result<W, E> {
+        let up = self.user_permit(up)?;
+        let now = clock.now();
+        let permits = self.permits(requests, now.date())?;
+        let md = MetaData {
+            date: now,
+            version: SUPPORTED_VERSIONS[SUPPORTED_VERSIONS.len() - 1],
+        };
+        let hwid = Zeroizing::new(up.hwid().to_owned());
+        let mut w = PermitFileWriter::new(wtr, &md, &hwid)?;
+        for p in &permits {
+            w.write_permit(p)?;
+        }
+        Ok(w.finish()?)
+    }
+
+    /// copies the PERMIT.TXT of the user permit `up` read from `rdr` to `wtr` with the
+    /// subscription permits renewed to `expiry`, dated at the time of the server clock, see
+    /// `permit::renew_permits`
+    pub fn renew_permit_file<R: Read, W: Write>(
+        &self,
+        rdr: R,
+        wtr: W,
+        up: &str,
+        expiry: NaiveDate,
+    ) -> Result<Renewal, E> {
+        let up = self.user_permit(up)?;
+        let hwid = Zeroizing::new(up.hwid().to_owned());
+        Ok(renew_permit_file_at(rdr, wtr, &hwid, expiry, &*self.clock)?)
+    }
+
+    /// the PERMIT.TXT for the user permit `up` and `requests`, see `write_permit_file`
+    pub fn permit_file(&self, up: &str, requests: &[PermitRequest]) -> Result<Vec<u8>, E> {
+        self.write_permit_file(Vec::new(), up, requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::permit::PermitFile;
+    use crate::up::MId;
+
+    #[test]
+    fn permit_file() -> Result<(), E> {
+        let mut m_keys = MKeyStore::new();
+        m_keys.insert(MId::new("3130")?, "10121")?;
+        let mut cell_keys = HashMap::new();
+        cell_keys.insert(
+            "GB100001".to_owned(),
+            (
+                SecretKey::new([1, 2, 3, 4, 5]),
+                SecretKey::new([6, 7, 8, 9, 10]),
+            ),
+        );
+        cell_keys.insert(
+            "GB100002".to_owned(),
+            (
+                SecretKey::new([5, 4, 3, 2, 1]),
+                SecretKey::new([5, 4, 3, 2, 1]),
+            ),
+        );
+        let now = NaiveDate::from_ymd_opt(2020, 1, 31)
+            .unwrap()
+            .and_hms_opt(10, 30, 0)
+            .unwrap();
+        let server = DataServer::new("GB", m_keys, &cell_keys).clock(FixedClock(now));
+        let requests = [
+            PermitRequest::new("GB100001", Expiry::Months(1)),
+            PermitRequest::new("GB100002", Expiry::Days(30))
+                .edition(4)
+                .sli(SericeLevelIndicator::SinglePurchasePermit),
+        ];
+        // the user permit of HW_ID 12345 and M_ID 3130
+        let up = "66B5CBFDF7E4139D5B6086C23130";
+        let txt = server.permit_file(up, &requests)?;
+        let s = String::from_utf8(txt.clone()).unwrap();
+        assert!(s.starts_with(":DATE 20200131 10:30\r\n:VERSION 2\r\n:ENC\r\n"));
+        assert!(s.ends_with(":ECS\r\n"));
+
+        let (md, pf) = PermitFile::new(&txt[..])?;
+        assert_eq!(md.date, now);
+        let permits = pf.permits("12345").collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(permits.len(), 2);
+        let day = |m, d| NaiveDate::from_ymd_opt(2020, m, d).unwrap();
+        assert_eq!(permits[0].cell_permit.date, day(2, 29));
+        assert_eq!(permits[0].cell_permit.key2, [6, 7, 8, 9, 10]);
+        assert_eq!(permits[0].edition, None);
+        assert_eq!(permits[1].cell_permit.date, day(3, 1));
+        assert_eq!(permits[1].edition, Some(4));
+        assert_eq!(permits[1].sli, SericeLevelIndicator::SinglePurchasePermit);
+        assert_eq!(permits[1].data_server_id, "GB");
+
+        let renewed = day(12, 31);
+        let mut out = Vec::new();
+        let res = server.renew_permit_file(&txt[..], &mut out, up, renewed)?;
+        assert_eq!((res.renewed.len(), res.kept.len()), (1, 1));
+        let permits = PermitFile::new(&out[..])?
+            .1
+            .permits("12345")
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(permits[0].cell_permit.date, renewed);
+        assert_eq!(permits[1].cell_permit.date, day(3, 1));
+
+        let unknown = [PermitRequest::new("GB100003", Expiry::Days(1))];
+        assert!(matches!(
+            server.permit_file(up, &unknown),
+            Err(E::UnknownCell(c)) if c == "GB100003"
+        ));
+        assert!(matches!(
+            server.permit_file("66B5CBFDF7E4139D5B6086C23131", &requests),
+            Err(E::UserPermit(PermitErr::UnknownMId(_)))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+//! Cell keys of a data server, by cell and edition, stored encrypted
+//!
+//! A new edition of a cell is encrypted with the CK2 of the previous edition, so permits
+//! issued before it can read it, and gets a new CK2. The keys of earlier editions are kept
+//! to issue edition permits for them.
+//!
+//! The key file is `S63KEYS1`, the 8 byte nonce and the 16 byte Poly1305 tag followed by
+//! the ChaCha20 encrypted key list: a `:CELLKEYS 1` line and a tab separated line of cell,
+//! edition, CK1 and CK2 in hex per edition. The nonce is derived from the key list, so
+//! saving the same keys twice gives the same file.
+
+use super::CellKeyStore;
+use crate::errors::E;
+use crate::secret::{SecretKey, Zeroizing};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+#[cfg(feature = "rand")]
+use rand::{CryptoRng, RngCore};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::prelude::*;
+use std::path::Path;
+
+const MAGIC: &[u8] = b"S63KEYS1";
+const HEADER: &str = ":CELLKEYS 1";
+const NONCE_LENGTH: usize = 8;
+const TAG_LENGTH: usize = 16;
+
+/// a random cell key, `rng` should be a cryptographically secure generator such as
+/// `rand::rngs::OsRng`
+#[cfg(feature = "rand")]
+pub fn generate_cell_key<R: RngCore + CryptoRng + ?Sized>(rng: &mut R) -> SecretKey {
+    let mut key = [0u8; 5];
+    rng.fill_bytes(&mut key);
+    let res = SecretKey::new(key);
+    zeroize::Zeroize::zeroize(&mut key);
+    res
+}
+
+/// the 32 byte key the key file is encrypted with. Zeroized on drop and redacted in `Debug`
+#[derive(Clone)]
+pub struct StorageKey(Zeroizing<[u8; 32]>);
+
+impl StorageKey {
+    pub fn new(key: [u8; 32]) -> StorageKey {
+        StorageKey(Zeroizing::new(key))
+    }
+
+    #[cfg(feature = "rand")]
+    pub fn generate<R: RngCore + CryptoRng + ?Sized>(rng: &mut R) -> StorageKey {
+        let mut key = StorageKey::new([0; 32]);
+        rng.fill_bytes(&mut key.0[..]);
+        key
+    }
+
+    // a key for `purpose` derived from the storage key
+    fn derive(&self, purpose: &[u8]) -> Zeroizing<[u8; 32]> {
+        let mut mac = Hmac::new(Sha256::new(), &self.0[..]);
+        mac.input(purpose);
+        let mut res = Zeroizing::new([0u8; 32]);
+        mac.raw_result(&mut res[..]);
+        res
+    }
+}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageKey(..)")
+    }
+}
+
+/// CK1 and CK2 of every edition of the cells of a data server
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyDatabase {
+    cells: BTreeMap<String, BTreeMap<u8, (SecretKey, SecretKey)>>,
+}
+
+impl KeyDatabase {
+    pub fn new() -> KeyDatabase {
+        KeyDatabase::default()
+    }
+
+    /// sets the keys of `edition` of `cell`, returning the keys it replaced
+    pub fn insert(
+        &mut self,
+        cell: &str,
+        edition: u8,
+        key1: SecretKey,
+        key2: SecretKey,
+    ) -> Option<(SecretKey, SecretKey)> {
+        self.cells
+            .entry(cell.to_owned())
+            .or_default()
+            .insert(edition, (key1, key2))
+    }
+
+    /// new keys for `edition` of `cell`: CK1 is the CK2 of the latest edition, a new key
+    /// for the first edition, and CK2 is a new key. Returns the keys of the edition
+    #[cfg(feature = "rand")]
+    pub fn generate<R: RngCore + CryptoRng + ?Sized>(
+        &mut self,
+        cell: &str,
+        edition: u8,
+        rng: &mut R,
+    ) -> &(SecretKey, SecretKey) {
+        let key1 = match self.current(cell) {
+            Some((_, (_, key2))) => key2.clone(),
+            None => generate_cell_key(rng),
+        };
+        let key2 = generate_cell_key(rng);
+        let editions = self.cells.entry(cell.to_owned()).or_default();
+        editions.insert(edition, (key1, key2));
+        &editions[&edition]
+    }
+
+    /// removes the keys of every edition of `cell`
+    pub fn remove(&mut self, cell: &str) -> Option<BTreeMap<u8, (SecretKey, SecretKey)>> {
+        self.cells.remove(cell)
+    }
+
+    pub fn keys(&self, cell: &str, edition: u8) -> Option<&(SecretKey, SecretKey)> {
+        self.cells.get(cell)?.get(&edition)
+    }
+
+    /// the latest edition of `cell` and its keys
+    pub fn current(&self, cell: &str) -> Option<(u8, &(SecretKey, SecretKey))> {
+        let (edition, keys) = self.cells.get(cell)?.iter().next_back()?;
+        Some((*edition, keys))
+    }
+
+    /// the editions of `cell` and their keys, the earliest first
+    pub fn editions(&self, cell: &str) -> impl Iterator<Item = (u8, &(SecretKey, SecretKey))> {
+        self.cells
+            .get(cell)
+            .into_iter()
+            .flatten()
+            .map(|(e, keys)| (*e, keys))
+    }
+
+    /// the cells with keys, by name
+    pub fn cells(&self) -> impl Iterator<Item = &str> {
+        self.cells.keys().map(|c| c.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn write_encrypted<W: Write>(&self, mut wtr: W, key: &StorageKey) -> Result<(), E> {
+        let plain = self.to_text();
+        let mut nonce = [0u8; 32];
+        let mut mac = Hmac::new(Sha256::new(), &key.derive(b"nonce")[..]);
+        mac.input(&plain);
+        mac.raw_result(&mut nonce);
+        let nonce = &nonce[..NONCE_LENGTH];
+        let mut data = vec![0u8; plain.len()];
+        let mut tag = [0u8; TAG_LENGTH];
+        ChaCha20Poly1305::new(&key.derive(b"encryption")[..], nonce, MAGIC)
+            .encrypt(&plain, &mut data, &mut tag);
+        wtr.write_all(MAGIC)?;
+        wtr.write_all(nonce)?;
+        wtr.write_all(&tag)?;
+        wtr.write_all(&data)?;
+        Ok(())
+    }
+
+    /// reads a key file written with `write_encrypted`, a wrong key or a changed file is
+    /// an error
+    pub fn read_encrypted<R: Read>(mut rdr: R, key: &StorageKey) -> Result<KeyDatabase, E> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        let prefix = MAGIC.len() + NONCE_LENGTH + TAG_LENGTH;
+        if data.len() < prefix || !data.starts_with(MAGIC) {
+            return Err(E::InvalidField(String::from("not a cell key file")));
+        }
+        let nonce = &data[MAGIC.len()..MAGIC.len() + NONCE_LENGTH];
+        let tag = &data[MAGIC.len() + NONCE_LENGTH..prefix];
+        let mut plain = Zeroizing::new(vec![0u8; data.len() - prefix]);
+        let ok = ChaCha20Poly1305::new(&key.derive(b"encryption")[..], nonce, MAGIC).decrypt(
+            &data[prefix..],
+            &mut plain,
+            tag,
+        );
+        if !ok {
+            return Err(E::InvalidField(String::from(
+                "cell key file can't be decrypted, wrong key or corrupt file",
+            )));
+        }
+        KeyDatabase::from_text(&plain)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, key: &StorageKey) -> Result<(), E> {
+        let mut buf = Vec::new();
+        self.write_encrypted(&mut buf, key)?;
+        crate::registry::write_replacing(path.as_ref(), &buf)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, key: &StorageKey) -> Result<KeyDatabase, E> {
+        KeyDatabase::read_encrypted(std::fs::File::open(path)?, key)
+    }
+
+    fn to_text(&self) -> Zeroizing<Vec<u8>> {
+        let mut res = Zeroizing::new(Vec::new());
+        res.extend_from_slice(HEADER.as_bytes());
+        res.push(b'\n');
+        for (cell, editions) in &self.cells {
+            for (edition, (key1, key2)) in editions {
+                let line = Zeroizing::new(format!(
+                    "{}\t{}\t{}\t{}\n",
+                    cell,
+                    edition,
+                    hex::encode_upper(key1.as_bytes()),
+                    hex::encode_upper(key2.as_bytes())
+                ));
+                res.extend_from_slice(line.as_bytes());
+            }
+        }
+        res
+    }
+
+    fn from_text(text: &[u8]) -> Result<KeyDatabase, E> {
+        let invalid = || E::InvalidField(String::from("invalid cell key file"));
+        let text = std::str::from_utf8(text).map_err(|_| invalid())?;
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(HEADER) {
+            return Err(invalid());
+        }
+        let mut res = KeyDatabase::new();
+        for l in lines.filter(|l| !l.trim().is_empty()) {
+            let ss: Vec<_> = l.split('\t').collect();
+            if ss.len() != 4 {
+                return Err(invalid());
+            }
+            let edition = ss[1].parse().map_err(|_| invalid())?;
+            let key = |s: &str| -> Result<SecretKey, E> {
+                let mut key = [0u8; 5];
+                hex::decode_to_slice(s, &mut key).map_err(|_| invalid())?;
+                Ok(SecretKey::new(key))
+            };
+            res.insert(ss[0], edition, key(ss[2])?, key(ss[3])?);
+        }
+        Ok(res)
+    }
+}
+
+/// the keys of the edition, or of the latest edition for None
+impl CellKeyStore for KeyDatabase {
+    fn cell_keys(&self, cell: &str, edition: Option<u8>) -> Option<(SecretKey, SecretKey)> {
+        match edition {
+            Some(edition) => self.keys(cell, edition).cloned(),
+            None => self.current(cell).map(|(_, keys)| keys.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage() -> Result<(), E> {
+        let key = |k: u8| SecretKey::new([k; 5]);
+        let mut db = KeyDatabase::new();
+        db.insert("GB100001", 1, key(1), key(2));
+        db.insert("GB100001", 2, key(2), key(3));
+        db.insert("GB100002", 4, key(4), key(5));
+        assert_eq!(db.cell_keys("GB100001", None), Some((key(2), key(3))));
+        assert_eq!(db.cell_keys("GB100001", Some(1)), Some((key(1), key(2))));
+        assert_eq!(db.cell_keys("GB100001", Some(3)), None);
+        assert_eq!(db.editions("GB100001").count(), 2);
+        assert_eq!(db.cells().collect::<Vec<_>>(), ["GB100001", "GB100002"]);
+
+        let storage = StorageKey::new([7; 32]);
+        let mut buf = Vec::new();
+        db.write_encrypted(&mut buf, &storage)?;
+        assert!(buf.starts_with(MAGIC));
+        assert!(!buf.windows(8).any(|w| w == b"GB100001"));
+        let mut again = Vec::new();
+        db.write_encrypted(&mut again, &storage)?;
+        assert_eq!(again, buf);
+        assert_eq!(KeyDatabase::read_encrypted(&buf[..], &storage)?, db);
+
+        assert!(KeyDatabase::read_encrypted(&buf[..], &StorageKey::new([8; 32])).is_err());
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(KeyDatabase::read_encrypted(&buf[..], &storage).is_err());
+        assert!(KeyDatabase::read_encrypted(&b"S63KEYS1"[..], &storage).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn generate() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(63);
+        let mut db = KeyDatabase::new();
+        let (key1, key2) = db.generate("GB100001", 1, &mut rng).clone();
+        assert_ne!(key1, key2);
+        let (next1, next2) = db.generate("GB100001", 2, &mut rng).clone();
+        assert_eq!(next1, key2);
+        assert_ne!(next2, key2);
+        assert_eq!(db.keys("GB100001", 1), Some(&(key1, key2)));
+        assert_eq!(db.current("GB100001").map(|(e, _)| e), Some(2));
+    }
+}
@@ -0,0 +1,375 @@
+//! S-63 digital signatures, enabled with the `signature` feature. Cells are signed by the
+//! data server with DSA over the SHA-1 digest of the encrypted cell file, and the data
+//! server public key is signed by the Scheme Administrator (SA)
+
+use dsa::signature::DigestVerifier;
+use dsa::{BigUint, Components, VerifyingKey};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io::prelude::*;
+
+mod cert;
+mod sign;
+#[cfg(feature = "rand")]
+mod test_sa;
+mod trust;
+pub use self::cert::*;
+pub use self::sign::*;
+#[cfg(feature = "rand")]
+pub use self::test_sa::*;
+pub use self::trust::*;
+
+const R_HEADER: &str = "// Signature part R:";
+const S_HEADER: &str = "// Signature part S:";
+const KEY_HEADERS: [&str; 4] = ["// BIG p", "// BIG q", "// BIG g", "// BIG y"];
+const LINE_END: &str = "\r\n";
+
+#[derive(Debug)]
+pub enum E {
+    Io(std::io::Error),
+    /// the signature file doesn't follow the S-63 format
+    Parse(String),
+    /// the public key is not a valid DSA key
+    InvalidKey,
+    /// the X.509 certificate can't be read
+    Certificate(String),
+    /// the signature doesn't match the data
+    Mismatch,
+}
+
+impl From<std::io::Error> for E {
+    fn from(e: std::io::Error) -> E {
+        E::Io(e)
+    }
+}
+
+impl fmt::Display for E {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E::Io(e) => write!(f, "IO error: {}", e),
+            E::Parse(s) => write!(f, "invalid signature file: {}", s),
+            E::InvalidKey => write!(f, "invalid DSA public key"),
+            E::Certificate(s) => write!(f, "invalid certificate: {}", s),
+            E::Mismatch => write!(f, "signature doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for E {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            E::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// a DSA signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub r: BigUint,
+    pub s: BigUint,
+}
+
+/// a DSA public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub p: BigUint,
+    pub q: BigUint,
+    pub g: BigUint,
+    pub y: BigUint,
+}
+
+impl PublicKey {
+    /// parses a public key in the S-63 ASCII format, the p, q, g and y parts of a
+    /// signature file. A signature before the key, as in a self-signed SA certificate,
+    /// is skipped
+    pub fn from_ascii(s: &str) -> Result<PublicKey, E> {
+        let start = Parts::new(s)?.key_start.unwrap_or_default();
+        Parts::new(&s[start..])?.public_key()
+    }
+
+    /// the key in the S-63 ASCII format
+    pub fn to_ascii(&self) -> String {
+        let mut res = String::new();
+        for (header, n) in KEY_HEADERS.iter().zip([&self.p, &self.q, &self.g, &self.y]) {
+            write_part(&mut res, header, n);
+        }
+        res
+    }
+
+    fn from_dsa(key: &VerifyingKey) -> PublicKey {
+        let c = key.components();
+        PublicKey {
+            p: c.p().clone(),
+            q: c.q().clone(),
+            g: c.g().clone(),
+            y: key.y().clone(),
+        }
+    }
+
+    fn verifying_key(&self) -> Result<VerifyingKey, E> {
+        let c = Components::from_components(self.p.clone(), self.q.clone(), self.g.clone())
+            .map_err(|_| E::InvalidKey)?;
+        VerifyingKey::from_components(c, self.y.clone()).map_err(|_| E::InvalidKey)
+    }
+
+    /// checks `sig` against the SHA-1 digest of `data`
+    pub fn verify(&self, data: &[u8], sig: &Signature) -> Result<(), E> {
+        let sig = dsa::Signature::from_components(sig.r.clone(), sig.s.clone())
+            .map_err(|_| E::Mismatch)?;
+        self.verifying_key()?
+            .verify_digest(Sha1::new_with_prefix(data), &sig)
+            .map_err(|_| E::Mismatch)
+    }
+}
+
+/// the signature file of a cell: the data server signature of the encrypted cell, the SA
+/// signature of the data server public key and the public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureFile {
+    pub cell_signature: Signature,
+    pub key_signature: Signature,
+    pub public_key: PublicKey,
+    // the public key part as in the file, this is what the SA signed
+    key_text: String,
+}
+
+// the parts of the ASCII format, a "//" header line followed by a hex value
+struct Parts<'a> {
+    parts: std::vec::IntoIter<(&'a str, Vec<&'a str>)>,
+    // where the public key starts
+    key_start: Option<usize>,
+}
+
+fn write_part(out: &mut String, header: &str, n: &BigUint) {
+    let hex = hex::encode_upper(n.to_bytes_be());
+    let hex = format!("{:0>w$}", hex, w = hex.len().div_ceil(4) * 4);
+    let groups: Vec<&str> = (0..hex.len()).step_by(4).map(|i| &hex[i..i + 4]).collect();
+    out.push_str(header);
+    out.push_str(LINE_END);
+    out.push_str(&groups.join(" "));
+    out.push('.');
+    out.push_str(LINE_END);
+}
+
+impl<'a> Parts<'a> {
+    fn new(s: &'a str) -> Result<Parts<'a>, E> {
+        let mut parts: Vec<(&str, Vec<&str>)> = Vec::new();
+        let mut key_start = None;
+        let mut offset = 0;
+        for line in s.split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed.starts_with("//") {
+                if key_start.is_none() && trimmed.starts_with(KEY_HEADERS[0]) {
+                    key_start = Some(offset);
+                }
+                parts.push((trimmed, Vec::new()));
+            } else if !trimmed.is_empty() {
+                let part = parts.last_mut();
+                let part = part.ok_or_else(|| E::Parse("value before any header".into()))?;
+                part.1.push(trimmed);
+            }
+            offset += line.len();
+        }
+        Ok(Parts {
+            parts: parts.into_iter(),
+            key_start,
+        })
+    }
+
+    fn next(&mut self, header: &str) -> Result<BigUint, E> {
+        let (h, lines) = self
+            .parts
+            .next()
+            .ok_or_else(|| E::Parse(format!("missing {}", header)))?;
+        if !h.starts_with(header) {
+            return Err(E::Parse(format!("expected {}, found {}", header, h)));
+        }
+        hex_value(&lines)
+    }
+
+    fn public_key(&mut self) -> Result<PublicKey, E> {
+        Ok(PublicKey {
+            p: self.next(KEY_HEADERS[0])?,
+            q: self.next(KEY_HEADERS[1])?,
+            g: self.next(KEY_HEADERS[2])?,
+            y: self.next(KEY_HEADERS[3])?,
+        })
+    }
+}
+
+// a value as groups of 4 hex digits ended by '.'
+fn hex_value(lines: &[&str]) -> Result<BigUint, E> {
+    let mut hex: String = lines.iter().flat_map(|l| l.split_whitespace()).collect();
+    if hex.ends_with('.') {
+        hex.pop();
+    }
+    if hex.len() % 2 == 1 {
+        hex.insert(0, '0');
+    }
+    let bytes = hex::decode(&hex).map_err(|_| E::Parse(format!("invalid number {}", hex)))?;
+    Ok(BigUint::from_bytes_be(&bytes))
+}
+
+impl SignatureFile {
+    pub fn new(
+        cell_signature: Signature,
+        key_signature: Signature,
+        public_key: PublicKey,
+    ) -> SignatureFile {
+        SignatureFile {
+            cell_signature,
+            key_signature,
+            key_text: public_key.to_ascii(),
+            public_key,
+        }
+    }
+
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<SignatureFile, E> {
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)?;
+        s.parse()
+    }
+
+    pub fn write<W: Write>(&self, mut wtr: W) -> Result<(), E> {
+        Ok(wtr.write_all(self.to_string().as_bytes())?)
+    }
+
+    /// the part of the file with the public key, signed by the SA
+    pub fn key_text(&self) -> &str {
+        &self.key_text
+    }
+
+    /// checks the data server signature of `encrypted`, the encrypted cell file, with the
+    /// public key of the file. This doesn't tell if the key can be trusted, see
+    /// `verify_key`
+    pub fn verify_cell(&self, encrypted: &[u8]) -> Result<(), E> {
+        self.public_key.verify(encrypted, &self.cell_signature)
+    }
+
+    /// checks that the public key of the file is signed by one of the SA keys of `sa`
+    pub fn verify_key<T: TrustStore + ?Sized>(&self, sa: &T) -> Result<(), E> {
+        sa.verify(self.key_text.as_bytes(), &self.key_signature)
+            .map(|_| ())
+    }
+
+    /// `verify_key` and `verify_cell`
+    pub fn verify<T: TrustStore + ?Sized>(&self, sa: &T, encrypted: &[u8]) -> Result<(), E> {
+        self.verify_key(sa)?;
+        self.verify_cell(encrypted)
+    }
+}
+
+impl fmt::Display for SignatureFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut res = String::new();
+        for sig in [&self.cell_signature, &self.key_signature] {
+            write_part(&mut res, R_HEADER, &sig.r);
+            write_part(&mut res, S_HEADER, &sig.s);
+        }
+        write!(f, "{}{}", res, self.key_text)
+    }
+}
+
+impl std::str::FromStr for SignatureFile {
+    type Err = E;
+
+    fn from_str(s: &str) -> Result<SignatureFile, E> {
+        let mut parts = Parts::new(s)?;
+        let cell_signature = Signature {
+            r: parts.next(R_HEADER)?,
+            s: parts.next(S_HEADER)?,
+        };
+        let key_signature = Signature {
+            r: parts.next(R_HEADER)?,
+            s: parts.next(S_HEADER)?,
+        };
+        let public_key = parts.public_key()?;
+        Ok(SignatureFile {
+            cell_signature,
+            key_signature,
+            public_key,
+            key_text: s[parts.key_start.unwrap_or_default()..].to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dsa::signature::DigestSigner;
+    use dsa::SigningKey;
+
+    // a toy DSA key, far too small for real use
+    pub(crate) fn key(x: u32) -> (SigningKey, PublicKey) {
+        // p = 2q + 1 with q prime, g = 4 generates the subgroup of order q
+        let p = BigUint::from(2_147_483_783u64);
+        let q = BigUint::from(1_073_741_891u64);
+        let g = BigUint::from(4u32);
+        let y = g.modpow(&BigUint::from(x), &p);
+        let c = Components::from_components(p.clone(), q.clone(), g.clone()).unwrap();
+        let vk = VerifyingKey::from_components(c, y.clone()).unwrap();
+        let sk = SigningKey::from_components(vk, BigUint::from(x)).unwrap();
+        (sk, PublicKey { p, q, g, y })
+    }
+
+    fn sign(sk: &SigningKey, data: &[u8]) -> Signature {
+        let sig: dsa::Signature = sk.sign_digest(Sha1::new_with_prefix(data));
+        Signature {
+            r: sig.r().clone(),
+            s: sig.s().clone(),
+        }
+    }
+
+    #[test]
+    fn verify() -> Result<(), E> {
+        let (sa, sa_pub) = key(12345);
+        let (ds, ds_pub) = key(67890);
+        // as written by other tools, without the '.' and grouping
+        let key_text = format!(
+            "// BIG p\r\n{:X}\r\n// BIG q\r\n{:X}\r\n// BIG g\r\n{:X}\r\n// BIG y\r\n{:X}\r\n",
+            ds_pub.p, ds_pub.q, ds_pub.g, ds_pub.y,
+        );
+        let cell = b"encrypted cell";
+        let (cs, ks) = (sign(&ds, cell), sign(&sa, key_text.as_bytes()));
+        let file = format!(
+            "// Signature part R:\r\n{:X}\r\n// Signature part S:\r\n{:X}\r\n\
+             // Signature part R:\r\n{:X}\r\n// Signature part S:\r\n{:X}\r\n{}",
+            cs.r, cs.s, ks.r, ks.s, key_text
+        );
+        let sig = SignatureFile::from_rdr(file.as_bytes())?;
+        assert_eq!(sig.public_key, ds_pub);
+        assert_eq!(sig.key_text(), key_text);
+        sig.verify(&sa_pub, cell)?;
+        assert!(matches!(sig.verify_cell(b"other cell"), Err(E::Mismatch)));
+        assert!(matches!(sig.verify_key(&ds_pub), Err(E::Mismatch)));
+        assert_eq!(sig.check_key(&sa_pub), CertificateStatus::Valid);
+        assert_eq!(sig.check_key(&ds_pub), CertificateStatus::WrongSa);
+
+        assert!(matches!(
+            file.replace("BIG q", "BIG x").parse::<SignatureFile>(),
+            Err(E::Parse(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn write() -> Result<(), E> {
+        let (sa, sa_pub) = key(12345);
+        let (ds, ds_pub) = key(67890);
+        let ks = sign(&sa, ds_pub.to_ascii().as_bytes());
+        let sig = SignatureFile::new(sign(&ds, b"cell"), ks, ds_pub.clone());
+        let mut out = Vec::new();
+        sig.write(&mut out)?;
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("// Signature part R:\r\n"));
+        assert!(text.contains("// BIG g\r\n0004.\r\n// BIG y\r\n"));
+
+        let read: SignatureFile = text.parse()?;
+        assert_eq!(read, sig);
+        read.verify(&sa_pub, b"cell")?;
+        assert_eq!(PublicKey::from_ascii(&ds_pub.to_ascii())?, ds_pub);
+        Ok(())
+    }
+}
@@ -0,0 +1,430 @@
+//! The SA certificate shipped on media, IHO.CRT as an X.509 certificate or IHO.PUB with the
+//! public key in the S-63 ASCII format, the checks of data server certificates and the
+//! conversion between the two formats
+
+use super::{PrivateKey, PublicKey, Signature, SignatureFile, TrustStore, E};
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, NaiveDateTime};
+use dsa::pkcs8::{DecodePublicKey, EncodePublicKey};
+use dsa::VerifyingKey;
+use std::convert::TryFrom;
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use x509_cert::der::asn1::{BitString, ObjectIdentifier};
+use x509_cert::der::{Decode, DecodePem, Encode, EncodePem};
+use x509_cert::name::Name;
+use x509_cert::serial_number::SerialNumber;
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::time::{Time, Validity};
+use x509_cert::TbsCertificate;
+
+const DSA_WITH_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10040.4.3");
+
+/// an X.509 certificate with a DSA public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub public_key: PublicKey,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: NaiveDateTime,
+    pub not_after: NaiveDateTime,
+    // the whole certificate and the signature, None when it isn't DSA with SHA-1
+    der: Vec<u8>,
+    signature: Option<Signature>,
+}
+
+/// the result of checking a data server certificate against the SA key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Valid,
+    /// signed by the SA but not valid at the time of the check
+    Expired,
+    /// not signed by the SA key
+    WrongSa,
+    /// the certificate or its signature can't be read
+    Malformed,
+}
+
+fn invalid<T: std::fmt::Display>(e: T) -> E {
+    E::Certificate(e.to_string())
+}
+
+fn date(d: Duration) -> Result<NaiveDateTime, E> {
+    let secs = i64::try_from(d.as_secs()).map_err(invalid)?;
+    DateTime::from_timestamp(secs, 0)
+        .map(|d| d.naive_utc())
+        .ok_or_else(|| invalid("date out of range"))
+}
+
+fn time(date: NaiveDateTime) -> Result<Time, E> {
+    let secs = u64::try_from(date.and_utc().timestamp()).map_err(invalid)?;
+    Time::try_from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).map_err(invalid)
+}
+
+impl PublicKey {
+    /// parses a DER encoded SubjectPublicKeyInfo, the public key of X.509 certificates
+    pub fn from_der(der: &[u8]) -> Result<PublicKey, E> {
+        let key = VerifyingKey::from_public_key_der(der).map_err(|_| E::InvalidKey)?;
+        Ok(PublicKey::from_dsa(&key))
+    }
+
+    /// the key as a DER encoded SubjectPublicKeyInfo
+    pub fn to_der(&self) -> Result<Vec<u8>, E> {
+        let der = self.verifying_key()?.to_public_key_der();
+        Ok(der.map_err(invalid)?.into_vec())
+    }
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Certificate, E> {
+        Certificate::new(x509_cert::Certificate::from_der(der).map_err(invalid)?)
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Certificate, E> {
+        Certificate::new(x509_cert::Certificate::from_pem(pem).map_err(invalid)?)
+    }
+
+    fn new(cert: x509_cert::Certificate) -> Result<Certificate, E> {
+        let signature = cert
+            .signature
+            .as_bytes()
+            .filter(|_| cert.signature_algorithm.oid == DSA_WITH_SHA1)
+            .and_then(|der| dsa::Signature::from_der(der).ok())
+            .map(|s| Signature {
+                r: s.r().clone(),
+                s: s.s().clone(),
+            });
+        let der = cert.to_der().map_err(invalid)?;
+        let tbs = cert.tbs_certificate;
+        let spki = tbs.subject_public_key_info.to_der().map_err(invalid)?;
+        Ok(Certificate {
+            signature,
+            der,
+            public_key: PublicKey::from_der(&spki)?,
+            subject: tbs.subject.to_string(),
+            issuer: tbs.issuer.to_string(),
+            not_before: date(tbs.validity.not_before.to_unix_duration())?,
+            not_after: date(tbs.validity.not_after.to_unix_duration())?,
+        })
+    }
+
+    pub fn to_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn to_pem(&self) -> Result<String, E> {
+        let cert = x509_cert::Certificate::from_der(&self.der).map_err(invalid)?;
+        cert.to_pem(Default::default()).map_err(invalid)
+    }
+
+    /// the public key in the S-63 ASCII format
+    pub fn to_ascii(&self) -> String {
+        self.public_key.to_ascii()
+    }
+
+    pub fn is_valid_at(&self, now: NaiveDateTime) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+
+    /// checks that the certificate is signed by one of the SA keys of `sa` and valid now
+    pub fn verify<T: TrustStore + ?Sized>(&self, sa: &T) -> CertificateStatus {
+        self.verify_at(sa, SystemClock)
+    }
+
+    pub fn verify_at<T: TrustStore + ?Sized, C: Clock>(
+        &self,
+        sa: &T,
+        clock: C,
+    ) -> CertificateStatus {
+        let tbs =
+            x509_cert::Certificate::from_der(&self.der).and_then(|c| c.tbs_certificate.to_der());
+        let (tbs, signature) = match (tbs, &self.signature) {
+            (Ok(tbs), Some(s)) => (tbs, s),
+            _ => return CertificateStatus::Malformed,
+        };
+        match sa.verify(&tbs, signature) {
+            Ok(_) if self.is_valid_at(clock.now()) => CertificateStatus::Valid,
+            Ok(_) => CertificateStatus::Expired,
+            Err(E::InvalidKey) => CertificateStatus::Malformed,
+            Err(_) => CertificateStatus::WrongSa,
+        }
+    }
+}
+
+impl SignatureFile {
+    /// checks the data server certificate of the signature file, the SA signature of the
+    /// public key. These certificates don't expire
+    pub fn check_key<T: TrustStore + ?Sized>(&self, sa: &T) -> CertificateStatus {
+        match self.verify_key(sa) {
+            Ok(()) => CertificateStatus::Valid,
+            Err(E::InvalidKey) => CertificateStatus::Malformed,
+            Err(_) => CertificateStatus::WrongSa,
+        }
+    }
+}
+
+impl PrivateKey {
+    /// an X.509 certificate for `public_key`, signed by this key with DSA and SHA-1. This
+    /// turns a key in the S-63 ASCII format into a certificate. `subject` and `issuer`
+    /// are distinguished names such as "CN=IHO,O=IHO"
+    pub fn issue(
+        &self,
+        public_key: &PublicKey,
+        subject: &str,
+        issuer: &str,
+        not_before: NaiveDateTime,
+        not_after: NaiveDateTime,
+    ) -> Result<Certificate, E> {
+        let spki = SubjectPublicKeyInfoOwned::from_der(&public_key.to_der()?).map_err(invalid)?;
+        let algorithm = AlgorithmIdentifierOwned {
+            oid: DSA_WITH_SHA1,
+            parameters: None,
+        };
+        let tbs = TbsCertificate {
+            version: x509_cert::Version::V3,
+            serial_number: SerialNumber::from(1u32),
+            signature: algorithm.clone(),
+            issuer: Name::from_str(issuer).map_err(invalid)?,
+            validity: Validity {
+                not_before: time(not_before)?,
+                not_after: time(not_after)?,
+            },
+            subject: Name::from_str(subject).map_err(invalid)?,
+            subject_public_key_info: spki,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: None,
+        };
+        let sig = self.sign(&tbs.to_der().map_err(invalid)?)?;
+        let sig = dsa::Signature::from_components(sig.r, sig.s).map_err(|_| E::InvalidKey)?;
+        Certificate::new(x509_cert::Certificate {
+            tbs_certificate: tbs,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(&sig.to_der().map_err(invalid)?).map_err(invalid)?,
+        })
+    }
+}
+
+/// the SA public key as shipped on media
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaCertificate {
+    X509(Certificate),
+    Ascii(PublicKey),
+}
+
+impl SaCertificate {
+    /// reads IHO.CRT or IHO.PUB, an X.509 certificate in DER or PEM or a public key in the
+    /// S-63 ASCII format
+    pub fn from_bytes(data: &[u8]) -> Result<SaCertificate, E> {
+        let text = std::str::from_utf8(data).ok().map(str::trim_start);
+        match text {
+            Some(t) if t.starts_with("-----BEGIN") => {
+                Ok(SaCertificate::X509(Certificate::from_pem(t)?))
+            }
+            Some(t) if t.starts_with("//") => Ok(SaCertificate::Ascii(PublicKey::from_ascii(t)?)),
+            _ => Ok(SaCertificate::X509(Certificate::from_der(data)?)),
+        }
+    }
+
+    pub fn from_rdr<R: Read>(mut rdr: R) -> Result<SaCertificate, E> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        SaCertificate::from_bytes(&data)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        match self {
+            SaCertificate::X509(c) => &c.public_key,
+            SaCertificate::Ascii(k) => k,
+        }
+    }
+
+    /// the public key in the S-63 ASCII format, as in IHO.PUB
+    pub fn to_ascii(&self) -> String {
+        self.public_key().to_ascii()
+    }
+}
+
+impl From<Certificate> for SaCertificate {
+    fn from(c: Certificate) -> SaCertificate {
+        SaCertificate::X509(c)
+    }
+}
+
+impl From<PublicKey> for SaCertificate {
+    fn from(k: PublicKey) -> SaCertificate {
+        SaCertificate::Ascii(k)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dsa::signature::DigestSigner;
+    use dsa::{BigUint, SigningKey};
+    use sha1::{Digest, Sha1};
+    use x509_cert::der::asn1::UtcTime;
+
+    fn utc(d: Duration) -> Time {
+        Time::UtcTime(UtcTime::from_unix_duration(d).unwrap())
+    }
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(y, m, d)
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .unwrap()
+    }
+
+    // a certificate for `key` signed by `issuer_key`, valid for a year from 2020-01-01
+    pub(crate) fn x509(
+        key: &VerifyingKey,
+        subject: &str,
+        issuer: &str,
+        issuer_key: &SigningKey,
+    ) -> x509_cert::Certificate {
+        let spki = key.to_public_key_der().unwrap();
+        let spki = SubjectPublicKeyInfoOwned::from_der(spki.as_bytes()).unwrap();
+        let algorithm = AlgorithmIdentifierOwned {
+            oid: DSA_WITH_SHA1,
+            parameters: None,
+        };
+        let start = Duration::from_secs(1_577_836_800);
+        let tbs = TbsCertificate {
+            version: x509_cert::Version::V3,
+            serial_number: SerialNumber::from(1u32),
+            signature: algorithm.clone(),
+            issuer: Name::from_str(issuer).unwrap(),
+            validity: Validity {
+                not_before: utc(start),
+                not_after: utc(start + Duration::from_secs(366 * 86400)),
+            },
+            subject: Name::from_str(subject).unwrap(),
+            subject_public_key_info: spki,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: None,
+        };
+        let sig: dsa::Signature =
+            issuer_key.sign_digest(Sha1::new_with_prefix(tbs.to_der().unwrap()));
+        x509_cert::Certificate {
+            tbs_certificate: tbs,
+            signature_algorithm: algorithm,
+            signature: BitString::from_bytes(&sig.to_der().unwrap()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn sa_certificate() -> Result<(), E> {
+        let (sk, public) = super::super::tests::key(12345);
+        let vk = public.verifying_key()?;
+        let cert = x509(&vk, "CN=IHO", "CN=IHO", &sk);
+        let der = cert.to_der().unwrap();
+        let pem = cert.to_pem(Default::default()).unwrap();
+
+        let from_der = SaCertificate::from_bytes(&der)?;
+        assert_eq!(from_der.public_key(), &public);
+        let SaCertificate::X509(c) = SaCertificate::from_rdr(pem.as_bytes())? else {
+            panic!("not X.509")
+        };
+        assert_eq!(c.public_key, public);
+        assert_eq!(c.subject, "CN=IHO");
+        assert_eq!(c.not_before, day(2020, 1, 1));
+        assert!(c.is_valid_at(day(2020, 6, 1)));
+        assert!(!c.is_valid_at(day(2021, 6, 1)));
+
+        let ascii = format!(
+            "// BIG p\r\n{:X}\r\n// BIG q\r\n{:X}\r\n// BIG g\r\n{:X}\r\n// BIG y\r\n{:X}\r\n",
+            public.p, public.q, public.g, public.y
+        );
+        assert_eq!(
+            SaCertificate::from_bytes(ascii.as_bytes())?,
+            SaCertificate::Ascii(public)
+        );
+        assert!(matches!(
+            SaCertificate::from_bytes(b"garbage"),
+            Err(E::Certificate(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn convert() -> Result<(), E> {
+        let (_, sa_pub) = super::super::tests::key(12345);
+        let (_, ds_pub) = super::super::tests::key(67890);
+        let sa = PrivateKey::new(sa_pub.clone(), BigUint::from(12345u32))?;
+        assert_eq!(PublicKey::from_der(&ds_pub.to_der()?)?, ds_pub);
+
+        let ascii = ds_pub.to_ascii();
+        let cert = sa.issue(
+            &PublicKey::from_ascii(&ascii)?,
+            "CN=GB",
+            "CN=IHO",
+            day(2020, 1, 1),
+            day(2060, 1, 1),
+        )?;
+        assert_eq!(cert.to_ascii(), ascii);
+        let read = Certificate::from_pem(&cert.to_pem()?)?;
+        assert_eq!(read, cert);
+        assert_eq!(Certificate::from_der(cert.to_der())?, cert);
+        assert_eq!(
+            (read.not_before, read.not_after),
+            (day(2020, 1, 1), day(2060, 1, 1))
+        );
+        assert_eq!(read.verify(&sa_pub), CertificateStatus::Valid);
+        assert_eq!(read.verify(&ds_pub), CertificateStatus::WrongSa);
+
+        // the SA key in either format is trusted the same
+        let x509 = SaCertificate::from(sa.issue(
+            &sa_pub,
+            "CN=IHO",
+            "CN=IHO",
+            day(2020, 1, 1),
+            day(2060, 1, 1),
+        )?);
+        let ascii = SaCertificate::from_bytes(x509.to_ascii().as_bytes())?;
+        assert_eq!(ascii, SaCertificate::from(sa_pub));
+        assert_eq!(read.verify(&vec![x509, ascii]), CertificateStatus::Valid);
+        Ok(())
+    }
+
+    #[test]
+    fn data_server_certificate() -> Result<(), E> {
+        let (sa, sa_pub) = super::super::tests::key(12345);
+        let (other, _) = super::super::tests::key(777);
+        let (_, ds_pub) = super::super::tests::key(67890);
+        let vk = ds_pub.verifying_key()?;
+        let at = |y| {
+            crate::clock::FixedClock(
+                chrono::NaiveDate::from_ymd_opt(y, 6, 1)
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .unwrap(),
+            )
+        };
+        let cert = |issuer_key| {
+            let der = x509(&vk, "CN=GB", "CN=IHO", issuer_key).to_der().unwrap();
+            Certificate::from_der(&der)
+        };
+        assert_eq!(
+            cert(&sa)?.verify_at(&sa_pub, at(2020)),
+            CertificateStatus::Valid
+        );
+        assert_eq!(
+            cert(&sa)?.verify_at(&sa_pub, at(2022)),
+            CertificateStatus::Expired
+        );
+        assert_eq!(
+            cert(&other)?.verify_at(&sa_pub, at(2020)),
+            CertificateStatus::WrongSa
+        );
+
+        let mut unsigned = x509(&vk, "CN=GB", "CN=IHO", &sa);
+        unsigned.signature = BitString::from_bytes(&[0]).unwrap();
+        let unsigned = Certificate::from_der(&unsigned.to_der().unwrap())?;
+        assert_eq!(
+            unsigned.verify_at(&sa_pub, at(2020)),
+            CertificateStatus::Malformed
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Signing, the data server and SA side of the signatures
+
+use super::{PublicKey, Signature, SignatureFile, E};
+use dsa::signature::DigestSigner;
+#[cfg(feature = "rand")]
+use dsa::Components;
+use dsa::{BigUint, SigningKey};
+#[cfg(feature = "rand")]
+use rand::{CryptoRng, RngCore};
+use sha1::{Digest, Sha1};
+use std::fmt;
+
+/// a DSA private key
+#[derive(Clone)]
+pub struct PrivateKey {
+    key: SigningKey,
+    public_key: PublicKey,
+}
+
+impl PrivateKey {
+    /// the key with the private part `x` of `public_key`, fails with `InvalidKey` if `x`
+    /// doesn't belong to the public key
+    pub fn new(public_key: PublicKey, x: BigUint) -> Result<PrivateKey, E> {
+        if public_key.g.modpow(&x, &public_key.p) != public_key.y {
+            return Err(E::InvalidKey);
+        }
+        let key = SigningKey::from_components(public_key.verifying_key()?, x)
+            .map_err(|_| E::InvalidKey)?;
+        Ok(PrivateKey { key, public_key })
+    }
+
+    /// a new key with new domain parameters of the size used by S-63, 1024 bit p and
+    /// 160 bit q. Generating the parameters takes a few seconds
+    #[cfg(feature = "rand")]
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> PrivateKey {
+        #[allow(deprecated)]
+        let size = dsa::KeySize::DSA_1024_160;
+        let components = Components::generate(rng, size);
+        PrivateKey::from_dsa(SigningKey::generate(rng, components))
+    }
+
+    /// a new key with the domain parameters p, q and g of `like`, fails with `InvalidKey`
+    /// if they are not valid parameters
+    #[cfg(feature = "rand")]
+    pub fn generate_like<R: RngCore + CryptoRng>(
+        rng: &mut R,
+        like: &PublicKey,
+    ) -> Result<PrivateKey, E> {
+        let components = like.verifying_key()?.components().clone();
+        Ok(PrivateKey::from_dsa(SigningKey::generate(rng, components)))
+    }
+
+    #[cfg(feature = "rand")]
+    fn from_dsa(key: SigningKey) -> PrivateKey {
+        PrivateKey {
+            public_key: PublicKey::from_dsa(key.verifying_key()),
+            key,
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// signs the SHA-1 digest of `data`, the signature is deterministic (RFC 6979)
+    pub fn sign(&self, data: &[u8]) -> Result<Signature, E> {
+        let sig: dsa::Signature = self
+            .key
+            .try_sign_digest(Sha1::new_with_prefix(data))
+            .map_err(|_| E::InvalidKey)?;
+        Ok(Signature {
+            r: sig.r().clone(),
+            s: sig.s().clone(),
+        })
+    }
+
+    /// as the SA, signs the public key of a data server. This is the data server
+    /// certificate of the signature files
+    pub fn certify(&self, data_server: &PublicKey) -> Result<Signature, E> {
+        self.sign(data_server.to_ascii().as_bytes())
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivateKey")
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// creates the signature files of encrypted cells with the data server key and its
+/// certificate from the SA
+#[derive(Debug, Clone)]
+pub struct CellSigner {
+    key: PrivateKey,
+    certificate: Signature,
+}
+
+impl CellSigner {
+    /// `certificate` is the SA signature of the public key of `key`, see
+    /// `PrivateKey::certify`
+    pub fn new(key: PrivateKey, certificate: Signature) -> CellSigner {
+        CellSigner { key, certificate }
+    }
+
+    /// the signature file of `encrypted`, the encrypted cell file
+    pub fn sign(&self, encrypted: &[u8]) -> Result<SignatureFile, E> {
+        Ok(SignatureFile::new(
+            self.key.sign(encrypted)?,
+            self.certificate.clone(),
+            self.key.public_key.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign() -> Result<(), E> {
+        let (_, sa_pub) = super::super::tests::key(12345);
+        let (_, ds_pub) = super::super::tests::key(67890);
+        let sa = PrivateKey::new(sa_pub.clone(), BigUint::from(12345u32))?;
+        let ds = PrivateKey::new(ds_pub.clone(), BigUint::from(67890u32))?;
+        assert!(PrivateKey::new(ds_pub.clone(), BigUint::from(1u32)).is_err());
+        assert!(!format!("{:?}", ds).contains("67890"));
+
+        let signer = CellSigner::new(ds, sa.certify(&ds_pub)?);
+        let file = signer.sign(b"encrypted cell")?;
+        assert_eq!(file.public_key, ds_pub);
+        file.verify(&sa_pub, b"encrypted cell")?;
+        let read: SignatureFile = file.to_string().parse()?;
+        read.verify(&sa_pub, b"encrypted cell")?;
+        assert!(read.verify(&sa_pub, b"other cell").is_err());
+        Ok(())
+    }
+}
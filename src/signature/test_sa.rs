@@ -0,0 +1,78 @@
+//! A test Scheme Administrator, for exercising the signing and verification of cells
+//! without the IHO keys
+
+use super::{CellSigner, PrivateKey, PublicKey, SaCertificate, E};
+use rand::{CryptoRng, RngCore};
+
+/// an SA with a generated key. Its certificate is self-signed and data servers get keys
+/// certified by it, never use it outside of tests
+#[derive(Debug, Clone)]
+pub struct TestSa {
+    key: PrivateKey,
+}
+
+impl TestSa {
+    /// an SA with a new key, see `PrivateKey::generate`
+    pub fn generate<R: RngCore + CryptoRng>(rng: &mut R) -> TestSa {
+        TestSa::new(PrivateKey::generate(rng))
+    }
+
+    pub fn new(key: PrivateKey) -> TestSa {
+        TestSa { key }
+    }
+
+    pub fn key(&self) -> &PrivateKey {
+        &self.key
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        self.key.public_key()
+    }
+
+    /// the self-signed SA certificate in the S-63 ASCII format, the SA signature of its
+    /// own public key followed by the key, as written to IHO.PUB
+    pub fn certificate(&self) -> Result<String, E> {
+        let public_key = self.public_key();
+        let sig = self.key.certify(public_key)?;
+        let mut res = String::new();
+        super::write_part(&mut res, super::R_HEADER, &sig.r);
+        super::write_part(&mut res, super::S_HEADER, &sig.s);
+        res.push_str(&public_key.to_ascii());
+        Ok(res)
+    }
+
+    pub fn sa_certificate(&self) -> SaCertificate {
+        SaCertificate::Ascii(self.public_key().clone())
+    }
+
+    /// a data server with a new key sharing the domain parameters of the SA key, certified
+    /// by the SA
+    pub fn data_server<R: RngCore + CryptoRng>(&self, rng: &mut R) -> Result<CellSigner, E> {
+        let key = PrivateKey::generate_like(rng, self.public_key())?;
+        let certificate = self.key.certify(key.public_key())?;
+        Ok(CellSigner::new(key, certificate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dsa::BigUint;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sa() -> Result<(), E> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(63);
+        let (_, public) = super::super::tests::key(12345);
+        let sa = TestSa::new(PrivateKey::new(public, BigUint::from(12345u32))?);
+        let other = TestSa::new(PrivateKey::generate_like(&mut rng, sa.public_key())?);
+        assert_ne!(other.public_key(), sa.public_key());
+
+        let cert = SaCertificate::from_bytes(sa.certificate()?.as_bytes())?;
+        assert_eq!(cert, sa.sa_certificate());
+        let file = sa.data_server(&mut rng)?.sign(b"encrypted cell")?;
+        file.verify(cert.public_key(), b"encrypted cell")?;
+        assert!(file.verify(other.public_key(), b"encrypted cell").is_err());
+        Ok(())
+    }
+}
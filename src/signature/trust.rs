@@ -0,0 +1,105 @@
+//! The trusted SA keys, e.g. the IHO key and test keys
+
+use super::{Certificate, PublicKey, SaCertificate, Signature, E};
+
+/// the SA keys trusted to certify data servers
+pub trait TrustStore {
+    fn sa_keys(&self) -> Vec<&PublicKey>;
+
+    /// checks `sig` of `data` against every trusted key and gives the key that made it.
+    /// Fails with `Mismatch` when no key matches, or `InvalidKey` when none of the keys is
+    /// a valid DSA key
+    fn verify(&self, data: &[u8], sig: &Signature) -> Result<&PublicKey, E> {
+        let mut err = E::Mismatch;
+        for (i, key) in self.sa_keys().into_iter().enumerate() {
+            match key.verify(data, sig) {
+                Ok(()) => return Ok(key),
+                Err(E::InvalidKey) if i == 0 => err = E::InvalidKey,
+                Err(E::InvalidKey) => (),
+                Err(_) => err = E::Mismatch,
+            }
+        }
+        Err(err)
+    }
+}
+
+impl TrustStore for PublicKey {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        vec![self]
+    }
+}
+
+impl TrustStore for Certificate {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        vec![&self.public_key]
+    }
+}
+
+impl TrustStore for SaCertificate {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        vec![self.public_key()]
+    }
+}
+
+impl TrustStore for [PublicKey] {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        self.iter().collect()
+    }
+}
+
+impl TrustStore for Vec<PublicKey> {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        self.iter().collect()
+    }
+}
+
+impl TrustStore for [SaCertificate] {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        self.iter().map(SaCertificate::public_key).collect()
+    }
+}
+
+impl TrustStore for Vec<SaCertificate> {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        self.iter().map(SaCertificate::public_key).collect()
+    }
+}
+
+impl<T: TrustStore + ?Sized> TrustStore for &T {
+    fn sa_keys(&self) -> Vec<&PublicKey> {
+        (**self).sa_keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::{CellSigner, PrivateKey};
+    use dsa::BigUint;
+
+    #[test]
+    fn trust_store() -> Result<(), E> {
+        let keys: Vec<PublicKey> = [12345u32, 777]
+            .iter()
+            .map(|&x| super::super::tests::key(x).1)
+            .collect();
+        let test_sa = PrivateKey::new(keys[1].clone(), BigUint::from(777u32))?;
+        let (_, ds_pub) = super::super::tests::key(67890);
+        let ds = PrivateKey::new(ds_pub.clone(), BigUint::from(67890u32))?;
+        let file = CellSigner::new(ds, test_sa.certify(&ds_pub)?).sign(b"cell")?;
+
+        file.verify(&keys, b"cell")?;
+        assert!(file.verify(&keys[0], b"cell").is_err());
+        assert_eq!(
+            keys.verify(file.key_text().as_bytes(), &file.key_signature)?,
+            &keys[1]
+        );
+        let certs = vec![SaCertificate::Ascii(keys[0].clone())];
+        assert!(matches!(file.verify_key(&certs), Err(E::Mismatch)));
+        assert!(matches!(
+            Vec::<PublicKey>::new().verify(b"cell", &file.cell_signature),
+            Err(E::Mismatch)
+        ));
+        Ok(())
+    }
+}
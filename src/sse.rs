@@ -0,0 +1,496 @@
+//! The S-63 Security Scheme Error (SSE) codes ECDIS show to the user, and the codes of the
+//! errors and states of this crate
+
+use crate::client::{CellImport, ImportError, ImportReport};
+use crate::clock::Clock;
+use crate::decrypter;
+use crate::errors;
+use crate::exchange::UpdateCheck;
+use crate::expiry::{ExpiryNotice, ExpiryReport};
+use crate::permit::PermitRecord;
+#[cfg(feature = "signature")]
+use crate::signature::{self, CertificateStatus};
+use crate::up::PermitErr;
+use chrono::NaiveDate;
+use std::fmt;
+
+/// how many days before the permits expire `SubscriptionExpiring` is shown
+pub const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// the SSE codes, SSE 01 to SSE 27
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SseCode {
+    SelfSignedKeyInvalid = 1,
+    SelfSignedKeyFormat = 2,
+    DataServerCertificateInvalid = 3,
+    DataServerCertificateFormat = 4,
+    SaCertificateMissing = 5,
+    DataServerCertificateWrongSa = 6,
+    DataServerCertificateMissing = 7,
+    SaCertificateFormat = 8,
+    EncSignatureInvalid = 9,
+    NoPermitsForDataServer = 10,
+    CellPermitNotFound = 11,
+    CellPermitFormat = 12,
+    CellPermitInvalid = 13,
+    IncorrectSystemDate = 14,
+    SubscriptionExpired = 15,
+    EncCrcIncorrect = 16,
+    UserPermitInvalid = 17,
+    PermitFileNotFound = 18,
+    EncSignatureMissing = 19,
+    SubscriptionExpiring = 20,
+    DecryptionFailed = 21,
+    SaCertificateExpired = 22,
+    NonSequentialUpdate = 23,
+    EncSignatureFormat = 24,
+    PermitExpired = 25,
+    NotAuthenticated = 26,
+    NotUpToDate = 27,
+}
+
+const CODES: [SseCode; 27] = [
+    SseCode::SelfSignedKeyInvalid,
+    SseCode::SelfSignedKeyFormat,
+    SseCode::DataServerCertificateInvalid,
+    SseCode::DataServerCertificateFormat,
+    SseCode::SaCertificateMissing,
+    SseCode::DataServerCertificateWrongSa,
+    SseCode::DataServerCertificateMissing,
+    SseCode::SaCertificateFormat,
+    SseCode::EncSignatureInvalid,
+    SseCode::NoPermitsForDataServer,
+    SseCode::CellPermitNotFound,
+    SseCode::CellPermitFormat,
+    SseCode::CellPermitInvalid,
+    SseCode::IncorrectSystemDate,
+    SseCode::SubscriptionExpired,
+    SseCode::EncCrcIncorrect,
+    SseCode::UserPermitInvalid,
+    SseCode::PermitFileNotFound,
+    SseCode::EncSignatureMissing,
+    SseCode::SubscriptionExpiring,
+    SseCode::DecryptionFailed,
+    SseCode::SaCertificateExpired,
+    SseCode::NonSequentialUpdate,
+    SseCode::EncSignatureFormat,
+    SseCode::PermitExpired,
+    SseCode::NotAuthenticated,
+    SseCode::NotUpToDate,
+];
+
+impl SseCode {
+    /// every code, in order
+    pub fn all() -> &'static [SseCode] {
+        &CODES
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<SseCode> {
+        CODES.get(usize::from(code).checked_sub(1)?).copied()
+    }
+
+    /// the standard message shown with the code
+    pub fn message(self) -> &'static str {
+        match self {
+            SseCode::SelfSignedKeyInvalid => "Self Signed Key is invalid.",
+            SseCode::SelfSignedKeyFormat => "Format of Self Signed Key file is incorrect.",
+            SseCode::DataServerCertificateInvalid => {
+                "SA Signed Data Server Certificate is invalid."
+            }
+            SseCode::DataServerCertificateFormat => {
+                "Format of SA Signed DS Certificate is incorrect."
+            }
+            SseCode::SaCertificateMissing => {
+                "SA Digital Certificate (X509) file is not available. A valid certificate can \
+                 be obtained from the IHO website or your data supplier."
+            }
+            SseCode::DataServerCertificateWrongSa => {
+                "The SA Signed Data Server Certificate is invalid. The SA may have issued a new \
+                 public key or the ENC may originate from another service. A new SA public key \
+                 can be obtained from the IHO website or from your data supplier."
+            }
+            SseCode::DataServerCertificateMissing => {
+                "SA Signed DS Certificate file is not available. A valid certificate can be \
+                 obtained from the IHO website or your data supplier."
+            }
+            SseCode::SaCertificateFormat => {
+                "SA Digital Certificate (X509) file incorrect format. A valid certificate can \
+                 be obtained from the IHO website or your data supplier."
+            }
+            SseCode::EncSignatureInvalid => "ENC Signature is invalid.",
+            SseCode::NoPermitsForDataServer => {
+                "Permits not available for this data provider. Contact your data supplier to \
+                 obtain the correct permits."
+            }
+            SseCode::CellPermitNotFound => {
+                "Cell Permit not found. Load the permit file provided by the data supplier."
+            }
+            SseCode::CellPermitFormat => {
+                "Cell Permit format is incorrect. Contact your data supplier and obtain a new \
+                 permit file."
+            }
+            SseCode::CellPermitInvalid => {
+                "Cell Permit is invalid (checksum is incorrect) or the Cell Permit is for a \
+                 different system. Contact your data supplier and obtain a new permit file."
+            }
+            SseCode::IncorrectSystemDate => {
+                "Incorrect system date, check that the computer clock (if accessible) is set \
+                 correctly or contact your system supplier."
+            }
+            SseCode::SubscriptionExpired => {
+                "Subscription service has expired. Please contact your data supplier to renew \
+                 the subscription licence."
+            }
+            SseCode::EncCrcIncorrect => {
+                "ENC CRC value is incorrect. Contact your data supplier as ENC(s) may be \
+                 corrupted or missing data."
+            }
+            SseCode::UserPermitInvalid => {
+                "Invalid User Permit. Contact your system supplier to obtain a valid User \
+                 Permit."
+            }
+            SseCode::PermitFileNotFound => {
+                "Permit file not found. Load the permit file provided by the data supplier."
+            }
+            SseCode::EncSignatureMissing => {
+                "ENC Signature file not found. Contact your data supplier."
+            }
+            SseCode::SubscriptionExpiring => {
+                "Subscription service will expire in less than 30 days. Please contact your \
+                 data supplier to renew the subscription licence."
+            }
+            SseCode::DecryptionFailed => {
+                "Decryption failed no valid cell permit found. Permits may be for another \
+                 system or new permits may be required, please contact your supplier to obtain \
+                 a new licence."
+            }
+            SseCode::SaCertificateExpired => {
+                "SA Digital Certificate (X509) has expired. A new SA public key can be \
+                 obtained from the IHO website or from your data supplier."
+            }
+            SseCode::NonSequentialUpdate => {
+                "Non sequential update, previous update(s) missing try reloading from the base \
+                 media. If the problem persists contact your data supplier."
+            }
+            SseCode::EncSignatureFormat => {
+                "ENC Signature format incorrect, contact your data supplier."
+            }
+            SseCode::PermitExpired => {
+                "The permit for this ENC has expired. This cell may be out of date and MUST \
+                 NOT be used for NAVIGATION."
+            }
+            SseCode::NotAuthenticated => {
+                "This ENC is not authenticated by the IHO acting as the Scheme Administrator."
+            }
+            SseCode::NotUpToDate => {
+                "This ENC is not up to date. A New Edition, Re-issue or Update for this cell \
+                 is missing and therefore MUST NOT be used for NAVIGATION."
+            }
+        }
+    }
+}
+
+/// as shown to the user, e.g. "SSE 11 - Cell Permit not found. ..."
+impl fmt::Display for SseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SSE {:02} - {}", self.code(), self.message())
+    }
+}
+
+/// an SSE code for a cell, or for no cell in particular, as ECDIS show it to the user
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SseMessage {
+    pub code: SseCode,
+    pub cell: Option<String>,
+}
+
+impl SseMessage {
+    pub fn new(code: SseCode) -> SseMessage {
+        SseMessage { code, cell: None }
+    }
+
+    pub fn cell(mut self, cell: &str) -> Self {
+        self.cell = Some(cell.to_owned());
+        self
+    }
+
+    /// the message of the code, with the name of the cell where the standard text has one
+    pub fn text(&self) -> String {
+        match (self.code, &self.cell) {
+            (SseCode::PermitExpired, Some(cell)) => format!(
+                "The permit for ENC {} has expired. This cell may be out of date and MUST NOT \
+                 be used for NAVIGATION.",
+                cell
+            ),
+            _ => self.code.message().to_owned(),
+        }
+    }
+}
+
+/// the exact string to display, e.g. "SSE 25 - The permit for ENC GB100001 has expired. ..."
+impl fmt::Display for SseMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SSE {:02} - {}", self.code.code(), self.text())
+    }
+}
+
+impl decrypter::E {
+    /// the SSE code for the error, None for errors outside of the scheme such as IO errors
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self {
+            decrypter::E::PermitIsNone | decrypter::E::NoPermit(_) => {
+                Some(SseCode::CellPermitNotFound)
+            }
+            decrypter::E::DecryptionFailed(_)
+            | decrypter::E::WrongKey
+            | decrypter::E::EditionNotPermitted { .. } => Some(SseCode::DecryptionFailed),
+            decrypter::E::CrcMismatch { .. } | decrypter::E::CorruptArchive(_) => {
+                Some(SseCode::EncCrcIncorrect)
+            }
+            #[cfg(feature = "signature")]
+            decrypter::E::SignatureInvalid(e) => e.sse_code(),
+            _ => None,
+        }
+    }
+}
+
+impl errors::E {
+    /// the SSE code for errors reading permits, None for other errors
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self.root() {
+            errors::E::InvalidChksum => Some(SseCode::CellPermitInvalid),
+            errors::E::ParseCellPermit(_)
+            | errors::E::CellPermitTooShort
+            | errors::E::InvalidCellName(_)
+            | errors::E::InvalidKeyLength(_)
+            | errors::E::InvalidSli
+            | errors::E::InvalidDate(_)
+            | errors::E::ParseDateError(_)
+            | errors::E::ParseVersionError(_)
+            | errors::E::UnsupportedVersion(_)
+            | errors::E::MissingField(_)
+            | errors::E::InvalidField(_)
+            | errors::E::FromHex(_) => Some(SseCode::CellPermitFormat),
+            _ => None,
+        }
+    }
+}
+
+impl PermitErr {
+    /// the SSE code for errors reading user permits, None for IO errors
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self {
+            PermitErr::IoErr(_) => None,
+            PermitErr::AtLine(_, e) => e.sse_code(),
+            _ => Some(SseCode::UserPermitInvalid),
+        }
+    }
+}
+
+impl PermitRecord {
+    /// `PermitExpired` for an expired permit and `SubscriptionExpiring` for one expiring
+    /// within `EXPIRY_WARNING_DAYS` of `now`
+    pub fn sse_code(&self, now: NaiveDate) -> Option<SseCode> {
+        if self.is_expired(now) {
+            Some(SseCode::PermitExpired)
+        } else if self.expires_within(EXPIRY_WARNING_DAYS, now) {
+            Some(SseCode::SubscriptionExpiring)
+        } else {
+            None
+        }
+    }
+
+    pub fn sse_code_at<C: Clock>(&self, clock: C) -> Option<SseCode> {
+        self.sse_code(clock.today())
+    }
+}
+
+impl UpdateCheck {
+    /// `NonSequentialUpdate` for missing updates and `NotUpToDate` for the wrong edition
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self {
+            UpdateCheck::Ok | UpdateCheck::Cancelled { .. } => None,
+            UpdateCheck::MissingUpdates(_) => Some(SseCode::NonSequentialUpdate),
+            UpdateCheck::WrongEdition { .. } => Some(SseCode::NotUpToDate),
+        }
+    }
+}
+
+impl ImportError {
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self {
+            ImportError::Sequence(check) => check.sse_code(),
+            ImportError::PermitExpired(_) => Some(SseCode::PermitExpired),
+            ImportError::Decrypt(e) => e.sse_code(),
+        }
+    }
+}
+
+impl CellImport {
+    /// the SSE code to show for the cell, None for a cell imported, removed or skipped
+    pub fn sse_code(&self) -> Option<SseCode> {
+        self.error.as_ref().and_then(ImportError::sse_code)
+    }
+
+    pub fn sse_message(&self) -> Option<SseMessage> {
+        Some(SseMessage::new(self.sse_code()?).cell(&self.cell))
+    }
+}
+
+impl ImportReport {
+    /// the messages to show for the cells of the import, in the order of the cells
+    pub fn sse_messages(&self) -> Vec<SseMessage> {
+        self.cells
+            .iter()
+            .filter_map(CellImport::sse_message)
+            .collect()
+    }
+}
+
+impl ExpiryNotice {
+    pub fn sse_message(&self) -> SseMessage {
+        SseMessage::new(self.code).cell(&self.cell)
+    }
+}
+
+impl ExpiryReport {
+    /// the messages of the errors, then the warnings. `SubscriptionExpiring` is shown once
+    /// however many permits expire
+    pub fn sse_messages(&self) -> Vec<SseMessage> {
+        let mut res: Vec<_> = self.errors.iter().map(ExpiryNotice::sse_message).collect();
+        if !self.warnings.is_empty() {
+            res.push(SseMessage::new(SseCode::SubscriptionExpiring));
+        }
+        res
+    }
+}
+
+#[cfg(feature = "signature")]
+impl signature::E {
+    /// the SSE code for errors checking the signature of a cell, None for IO errors
+    pub fn sse_code(&self) -> Option<SseCode> {
+        match self {
+            signature::E::Io(_) => None,
+            signature::E::Parse(_) => Some(SseCode::EncSignatureFormat),
+            signature::E::InvalidKey => Some(SseCode::DataServerCertificateFormat),
+            signature::E::Certificate(_) => Some(SseCode::SaCertificateFormat),
+            signature::E::Mismatch => Some(SseCode::EncSignatureInvalid),
+        }
+    }
+}
+
+#[cfg(feature = "signature")]
+impl CertificateStatus {
+    /// the SSE code of a data server certificate, None when it is valid
+    pub fn sse_code(self) -> Option<SseCode> {
+        match self {
+            CertificateStatus::Valid => None,
+            CertificateStatus::Expired => Some(SseCode::SaCertificateExpired),
+            CertificateStatus::WrongSa => Some(SseCode::DataServerCertificateWrongSa),
+            CertificateStatus::Malformed => Some(SseCode::DataServerCertificateFormat),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permit::test_permit;
+
+    #[test]
+    fn codes() {
+        for (i, c) in SseCode::all().iter().enumerate() {
+            assert_eq!(usize::from(c.code()), i + 1);
+            assert_eq!(SseCode::from_code(c.code()), Some(*c));
+        }
+        assert_eq!(SseCode::from_code(0), None);
+        assert_eq!(SseCode::from_code(28), None);
+        assert_eq!(
+            SseCode::CellPermitNotFound.to_string(),
+            "SSE 11 - Cell Permit not found. Load the permit file provided by the data supplier."
+        );
+        assert!(SseCode::from_code(6)
+            .unwrap()
+            .message()
+            .contains("another service. A new"));
+    }
+
+    #[test]
+    fn messages() {
+        let notice = |cell: &str, code| ExpiryNotice {
+            cell: cell.into(),
+            expiry: NaiveDate::from_ymd_opt(2020, 6, 30).unwrap(),
+            days_left: 0,
+            code,
+        };
+        let report = ExpiryReport {
+            errors: vec![notice("GB100001", SseCode::PermitExpired)],
+            warnings: vec![
+                notice("GB100002", SseCode::SubscriptionExpiring),
+                notice("GB100003", SseCode::SubscriptionExpiring),
+            ],
+        };
+        let messages = report.sse_messages();
+        assert_eq!(
+            messages,
+            [
+                SseMessage::new(SseCode::PermitExpired).cell("GB100001"),
+                SseMessage::new(SseCode::SubscriptionExpiring),
+            ]
+        );
+        assert_eq!(
+            messages[0].to_string(),
+            "SSE 25 - The permit for ENC GB100001 has expired. This cell may be out of date \
+             and MUST NOT be used for NAVIGATION."
+        );
+        assert_eq!(
+            messages[1].to_string(),
+            SseCode::SubscriptionExpiring.to_string()
+        );
+        let permit = SseMessage::new(SseCode::CellPermitNotFound).cell("GB100001");
+        assert_eq!(permit.text(), SseCode::CellPermitNotFound.message());
+    }
+
+    #[test]
+    fn from_errors() {
+        assert_eq!(
+            decrypter::E::NoPermit("GB100001".into()).sse_code(),
+            Some(SseCode::CellPermitNotFound)
+        );
+        let failed = decrypter::E::DecryptionFailed(Box::new(decrypter::E::WrongKey));
+        assert_eq!(failed.sse_code(), Some(SseCode::DecryptionFailed));
+        assert_eq!(
+            decrypter::E::Read(std::io::ErrorKind::Other.into()).sse_code(),
+            None
+        );
+        assert_eq!(
+            errors::E::InvalidChksum.sse_code(),
+            Some(SseCode::CellPermitInvalid)
+        );
+        assert_eq!(
+            errors::E::CellPermitTooShort.sse_code(),
+            Some(SseCode::CellPermitFormat)
+        );
+        assert_eq!(
+            PermitErr::AtLine(2, Box::new(PermitErr::HashMisMatch)).sse_code(),
+            Some(SseCode::UserPermitInvalid)
+        );
+
+        let day = |d| NaiveDate::from_ymd_opt(2030, 1, d).unwrap();
+        let mut p = test_permit("GB100001", &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+        p.cell_permit.date = day(31);
+        assert_eq!(
+            p.sse_code(NaiveDate::from_ymd_opt(2029, 12, 1).unwrap()),
+            None
+        );
+        assert_eq!(p.sse_code(day(1)), Some(SseCode::SubscriptionExpiring));
+        assert_eq!(
+            p.sse_code(NaiveDate::from_ymd_opt(2030, 2, 1).unwrap()),
+            Some(SseCode::PermitExpired)
+        );
+    }
+}
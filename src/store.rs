@@ -0,0 +1,130 @@
+//! Mutable storage of installed permits
+
+use crate::errors::E;
+use crate::permit::{GetPermit, PermitRecord};
+use chrono::NaiveDate;
+use std::collections::HashMap;
+
+#[cfg(feature = "store-sqlite")]
+pub mod sqlite;
+
+pub trait PermitStore: GetPermit {
+    /// inserts the permit, returning the permit it replaced for the same cell
+    fn insert(&mut self, p: PermitRecord) -> Result<Option<PermitRecord>, E>;
+
+    fn remove(&mut self, cell: &str) -> Result<Option<PermitRecord>, E>;
+
+    /// sets a new expiry date, returns false if there is no permit for the cell
+    fn update_expiry(&mut self, cell: &str, date: NaiveDate) -> Result<bool, E>;
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a PermitRecord> + 'a>;
+
+    /// removes all permits expired at `now` and returns them
+    fn remove_expired(&mut self, now: NaiveDate) -> Result<Vec<PermitRecord>, E> {
+        let cells: Vec<String> = self
+            .iter()
+            .filter(|p| p.is_expired(now))
+            .map(|p| p.cell_permit.cell.clone())
+            .collect();
+        let mut res = Vec::new();
+        for cell in cells {
+            res.extend(self.remove(&cell)?);
+        }
+        Ok(res)
+    }
+}
+
+/// a PermitStore kept in memory
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    permits: HashMap<String, PermitRecord>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.permits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.permits.is_empty()
+    }
+}
+
+impl From<HashMap<String, PermitRecord>> for MemoryStore {
+    fn from(permits: HashMap<String, PermitRecord>) -> MemoryStore {
+        MemoryStore { permits }
+    }
+}
+
+impl GetPermit for MemoryStore {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.permits.get(cell)
+    }
+}
+
+impl PermitStore for MemoryStore {
+    fn insert(&mut self, p: PermitRecord) -> Result<Option<PermitRecord>, E> {
+        Ok(self.permits.insert(p.cell_permit.cell.clone(), p))
+    }
+
+    fn remove(&mut self, cell: &str) -> Result<Option<PermitRecord>, E> {
+        Ok(self.permits.remove(cell))
+    }
+
+    fn update_expiry(&mut self, cell: &str, date: NaiveDate) -> Result<bool, E> {
+        Ok(match self.permits.get_mut(cell) {
+            Some(p) => {
+                p.cell_permit.date = date;
+                p.original = None;
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a PermitRecord> + 'a> {
+        Box::new(self.permits.values())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permit::test_permit;
+
+    fn record(cell: &str, date: NaiveDate) -> PermitRecord {
+        let mut p = test_permit(cell, &[1, 2, 3, 4, 5], &[1, 2, 3, 4, 5]);
+        p.cell_permit.date = date;
+        p
+    }
+
+    #[test]
+    fn memory_store() -> Result<(), E> {
+        let date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+        let later = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        let mut store = MemoryStore::new();
+        assert!(store.insert(record("GB100001", date))?.is_none());
+        let mut read = record("GB100002", date);
+        read.original = Some(crate::permit::Original {
+            cell_permit: String::new(),
+            line: String::new(),
+        });
+        assert!(store.insert(read)?.is_none());
+        assert!(store.update_expiry("GB100002", later)?);
+        // the permit no longer reads like the line it came from
+        assert!(store.get_permit("GB100002").unwrap().original.is_none());
+        assert!(!store.update_expiry("GB100003", later)?);
+        assert_eq!(store.iter().count(), 2);
+
+        let expired = store.remove_expired(NaiveDate::from_ymd_opt(2020, 7, 1).unwrap())?;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].cell_permit.cell, "GB100001");
+        assert!(store.get_permit("GB100001").is_none());
+        assert!(store.remove("GB100002")?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! A PermitStore persisted in a SQLite database
+//!
+//! The permits are stored as PERMIT.TXT rows with the cell keys encrypted with the HW_ID,
+//! and kept in memory for lookups.
+
+use super::{MemoryStore, PermitStore};
+use crate::errors::E;
+use crate::permit::{self, GetPermit, PermitRecord, Section};
+use crate::secret::Zeroizing;
+use chrono::NaiveDate;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+pub struct SqliteStore {
+    conn: Connection,
+    hwid: Zeroizing<String>,
+    permits: MemoryStore,
+}
+
+impl From<rusqlite::Error> for E {
+    fn from(e: rusqlite::Error) -> E {
+        E::Store(e.to_string())
+    }
+}
+
+impl SqliteStore {
+    /// opens or creates the database at `path`, the HW_ID is used to encrypt the stored keys
+    pub fn open<P: AsRef<Path>>(path: P, hwid: &str) -> Result<SqliteStore, E> {
+        SqliteStore::with_connection(Connection::open(path)?, hwid)
+    }
+
+    pub fn open_in_memory(hwid: &str) -> Result<SqliteStore, E> {
+        SqliteStore::with_connection(Connection::open_in_memory()?, hwid)
+    }
+
+    fn with_connection(conn: Connection, hwid: &str) -> Result<SqliteStore, E> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS permits (
+                cell TEXT PRIMARY KEY,
+                section TEXT NOT NULL,
+                record TEXT NOT NULL
+            )",
+            [],
+        )?;
+        let mut permits = MemoryStore::new();
+        {
+            let mut stmt = conn.prepare("SELECT section, record FROM permits")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            for row in rows {
+                let (section, record) = row?;
+                permits.insert(decode(&section, &record, hwid)?)?;
+            }
+        }
+        Ok(SqliteStore {
+            conn,
+            hwid: Zeroizing::new(hwid.to_owned()),
+            permits,
+        })
+    }
+
+    /// reads the permit for `cell` from the database
+    pub fn load(&self, cell: &str) -> Result<Option<PermitRecord>, E> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT section, record FROM permits WHERE cell = ?1")?;
+        let mut rows = stmt.query(params![cell])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(decode(
+                &row.get::<_, String>(0)?,
+                &row.get::<_, String>(1)?,
+                &self.hwid,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, p: &PermitRecord) -> Result<(), E> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO permits (cell, section, record) VALUES (?1, ?2, ?3)",
+            params![
+                p.cell_permit.cell,
+                p.section.marker(),
+                p.serialize(&self.hwid)?
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+fn decode(section: &str, record: &str, hwid: &str) -> Result<PermitRecord, E> {
+    let section = Section::from_marker(section)
+        .ok_or_else(|| E::Store(format!("invalid section {}", section)))?;
+    permit::parse_raw_permit(record, section)?.decrypt_keys(hwid)
+}
+
+impl GetPermit for SqliteStore {
+    fn get_permit(&self, cell: &str) -> Option<&PermitRecord> {
+        self.permits.get_permit(cell)
+    }
+}
+
+impl PermitStore for SqliteStore {
+    fn insert(&mut self, p: PermitRecord) -> Result<Option<PermitRecord>, E> {
+        self.save(&p)?;
+        self.permits.insert(p)
+    }
+
+    fn remove(&mut self, cell: &str) -> Result<Option<PermitRecord>, E> {
+        self.conn
+            .execute("DELETE FROM permits WHERE cell = ?1", params![cell])?;
+        self.permits.remove(cell)
+    }
+
+    fn update_expiry(&mut self, cell: &str, date: NaiveDate) -> Result<bool, E> {
+        if !self.permits.update_expiry(cell, date)? {
+            return Ok(false);
+        }
+        if let Some(p) = self.permits.get_permit(cell) {
+            self.save(p)?;
+        }
+        Ok(true)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a PermitRecord> + 'a> {
+        self.permits.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permit::test_permit;
+
+    fn record(cell: &str) -> PermitRecord {
+        let mut p = test_permit(cell, &[1, 2, 3, 4, 5], &[6, 7, 8, 9, 10]);
+        p.cell_permit.date = NaiveDate::from_ymd_opt(2020, 6, 30).unwrap();
+        p.edition = Some(3);
+        p.comment = String::from("hej");
+        p
+    }
+
+    #[test]
+    fn persists() -> Result<(), E> {
+        let path = std::env::temp_dir().join(format!("s63-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut store = SqliteStore::open(&path, "12345")?;
+            store.insert(record("GB100001"))?;
+            store.insert(record("GB100002"))?;
+            store.remove("GB100002")?;
+            store.update_expiry("GB100001", NaiveDate::from_ymd_opt(2021, 6, 30).unwrap())?;
+        }
+        let store = SqliteStore::open(&path, "12345")?;
+        std::fs::remove_file(&path)?;
+
+        let mut expected = record("GB100001");
+        expected.cell_permit.date = NaiveDate::from_ymd_opt(2021, 6, 30).unwrap();
+        assert_eq!(store.get_permit("GB100001"), Some(&expected));
+        assert_eq!(store.load("GB100001")?, Some(expected));
+        assert!(store.get_permit("GB100002").is_none());
+        assert_eq!(store.iter().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_hwid() -> Result<(), E> {
+        let mut store = SqliteStore::open_in_memory("12345")?;
+        store.insert(record("GB100001"))?;
+        assert!(store.load("GB100001").is_ok());
+        store.hwid = Zeroizing::new(String::from("54321"));
+        let err = store.load("GB100001").unwrap_err();
+        assert!(matches!(err.root(), E::InvalidChksum));
+        Ok(())
+    }
+}
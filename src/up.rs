@@ -1,16 +1,33 @@
 //! Package for handling user permits, both creating and decrypting
 
+use crate::cipher::{BlockCipher, Blowfish};
 use byteorder::{BigEndian, ReadBytesExt};
 use crc;
-use crypto::blowfish::Blowfish;
-use crypto::symmetriccipher::BlockDecryptor;
-use crypto::symmetriccipher::BlockEncryptor;
 use hex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
-const PERMIT_LENGTH: usize = 16 + 8 + 4;
+mod batch;
+mod file;
+mod hwid;
+mod keystore;
+mod mid;
+pub use self::batch::*;
+pub use self::file::*;
+pub use self::hwid::*;
+pub use self::keystore::*;
+pub use self::mid::*;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+// the encrypted HW_ID and its checksum, followed by the M_ID
+const PERMIT_PREFIX_LENGTH: usize = 16 + 8;
 const KEY_LENGTH: usize = 5;
 const HWID_LENGTH: usize = 5;
-const ID_LENGTH: usize = 4;
+// M_IDs are 4 characters, later IHO assignments use 6
+const ID_LENGTHS: &[usize] = &[4, 6];
 
 #[derive(Debug)]
 pub enum PermitErr {
@@ -19,8 +36,44 @@ pub enum PermitErr {
     // the length of the hwid
     WrongLength { actual: usize, expected: usize },
     HashMisMatch,
+    // no key is known for the M_ID
+    UnknownMId(String),
+    // the M_KEY doesn't decrypt the permit to a HW_ID
+    WrongKey,
     HexErr(hex::FromHexError),
     Utf8Err(std::str::Utf8Error),
+    IoErr(std::io::Error),
+    // comments in user permit files are single line
+    InvalidComment,
+    // an error on a line of a user permit file
+    AtLine(usize, Box<PermitErr>),
+}
+
+impl fmt::Display for PermitErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PermitErr::NonHex => write!(f, "not a hex string"),
+            PermitErr::WrongLength { actual, expected } => {
+                write!(f, "wrong length {}, expected {}", actual, expected)
+            }
+            PermitErr::HashMisMatch => write!(f, "checksum mismatch"),
+            PermitErr::UnknownMId(id) => write!(f, "no M_KEY for M_ID {}", id),
+            PermitErr::WrongKey => write!(f, "the M_KEY doesn't decrypt the user permit"),
+            PermitErr::HexErr(e) => write!(f, "{}", e),
+            PermitErr::Utf8Err(e) => write!(f, "{}", e),
+            PermitErr::IoErr(e) => write!(f, "{}", e),
+            PermitErr::InvalidComment => write!(f, "invalid comment"),
+            PermitErr::AtLine(l, e) => write!(f, "line {}: {}", l, e),
+        }
+    }
+}
+
+impl std::error::Error for PermitErr {}
+
+impl From<std::io::Error> for PermitErr {
+    fn from(e: std::io::Error) -> PermitErr {
+        PermitErr::IoErr(e)
+    }
 }
 
 impl From<std::str::Utf8Error> for PermitErr {
@@ -35,44 +88,168 @@ impl From<hex::FromHexError> for PermitErr {
     }
 }
 
+/// how user permit, HW_ID and M_KEY input is treated
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputPolicy {
+    /// surrounding whitespace is removed and hex is upper-cased, as user input often is
+    /// pasted with lowercase letters or whitespace
+    #[default]
+    Normalize,
+    /// input is used as is
+    Strict,
+}
+
+impl InputPolicy {
+    pub fn apply(self, s: &str) -> Cow<'_, str> {
+        match self {
+            InputPolicy::Normalize => Cow::Owned(s.trim().to_ascii_uppercase()),
+            InputPolicy::Strict => Cow::Borrowed(s),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "UserPermitFields")
+)]
 pub struct UserPermit {
-    hwid: String,
+    hwid: Hwid,
     id: String,
 }
 
+// the serialized form of a user permit, validated by `UserPermit::new` when deserialized
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct UserPermitFields {
+    hwid: Hwid,
+    id: String,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<UserPermitFields> for UserPermit {
+    type Error = PermitErr;
+    fn try_from(up: UserPermitFields) -> Result<UserPermit, PermitErr> {
+        UserPermit::new(up.hwid.as_str(), &up.id)
+    }
+}
+
+/// the parts of an encrypted user permit, see `UserPermit::verify`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpParts {
+    /// the hex encoded encrypted HW_ID
+    pub enc_hwid: String,
+    /// the hex encoded crc32 of `enc_hwid`
+    pub checksum: String,
+    pub m_id: MId,
+}
+
 impl UserPermit {
     pub fn new(hwid: &str, id: &str) -> Result<UserPermit, PermitErr> {
+        UserPermit::new_with_policy(hwid, id, InputPolicy::Normalize)
+    }
+
+    pub fn new_with_policy(
+        hwid: &str,
+        id: &str,
+        policy: InputPolicy,
+    ) -> Result<UserPermit, PermitErr> {
+        let (hwid, id) = (policy.apply(hwid), policy.apply(id));
+        let (hwid, id) = (hwid.as_ref(), id.as_ref());
         validator(hwid, HWID_LENGTH)?;
-        validator(id, ID_LENGTH)?;
+        validate_id(id)?;
         Ok(UserPermit {
-            hwid: String::from(hwid),
+            hwid: Hwid(String::from(hwid)),
             id: String::from(id),
         })
     }
 
+    pub fn hwid(&self) -> &str {
+        self.hwid.as_str()
+    }
+
+    /// the manufacturer id
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn m_id(&self) -> MId {
+        MId(self.id.to_ascii_uppercase())
+    }
+
     pub fn decrypt(up: &str, key: &str) -> Result<UserPermit, PermitErr> {
+        UserPermit::decrypt_with_policy(up, key, InputPolicy::Normalize)
+    }
+
+    pub fn decrypt_with_policy(
+        up: &str,
+        key: &str,
+        policy: InputPolicy,
+    ) -> Result<UserPermit, PermitErr> {
+        UserPermit::decrypt_with_cipher::<Blowfish>(up, key, policy)
+    }
+
+    /// like `decrypt_with_policy` but with the cipher `C` instead of Blowfish
+    pub fn decrypt_with_cipher<C: BlockCipher>(
+        up: &str,
+        key: &str,
+        policy: InputPolicy,
+    ) -> Result<UserPermit, PermitErr> {
+        let (up, key) = (policy.apply(up), policy.apply(key));
+        let (up, key) = (up.as_ref(), key.as_ref());
         if !up.chars().chain(key.chars()).all(is_hex) {
             return Err(PermitErr::NonHex);
         }
         validator(key, KEY_LENGTH)?;
-        let (enc_hwid, _, id) = check_up_string(&up)?;
-        let crypto = Blowfish::new(key.as_bytes());
-        let enc = &mut [0u8; 8];
-        crypto.decrypt_block(hex::decode(enc_hwid)?.as_ref(), enc);
+        let (enc_hwid, _, id) = check_up_string(up)?;
+        let crypto = C::new(key.as_bytes());
+        let mut enc = Zeroizing::new([0u8; 8]);
+        crypto.decrypt_block(hex::decode(enc_hwid)?.as_ref(), &mut *enc);
+        // a wrong key gives garbage instead of a hex HW_ID and the padding
+        if enc[5..] != [3, 3, 3] || !enc[0..5].iter().all(u8::is_ascii_hexdigit) {
+            return Err(PermitErr::WrongKey);
+        }
 
         Ok(UserPermit {
-            hwid: String::from_utf8(enc[0..5].into()).unwrap(),
+            hwid: Hwid(enc[0..5].iter().map(|&b| char::from(b)).collect()),
             id: String::from(id),
         })
     }
 
+    /// checks the format and checksum of an encrypted user permit, no M_KEY is needed.
+    /// A permit that verifies can still fail to decrypt with the wrong M_KEY
+    pub fn verify(up: &str) -> Result<UpParts, PermitErr> {
+        let (enc_hwid, checksum, id) = check_up_string(up)?;
+        Ok(UpParts {
+            enc_hwid: enc_hwid.to_owned(),
+            checksum: checksum.to_owned(),
+            m_id: MId::new(id)?,
+        })
+    }
+
     pub fn encrypt(&self, key: &str) -> Result<String, PermitErr> {
+        self.encrypt_with_policy(key, InputPolicy::Normalize)
+    }
+
+    pub fn encrypt_with_policy(&self, key: &str, policy: InputPolicy) -> Result<String, PermitErr> {
+        self.encrypt_with_cipher::<Blowfish>(key, policy)
+    }
+
+    /// like `encrypt_with_policy` but with the cipher `C` instead of Blowfish
+    pub fn encrypt_with_cipher<C: BlockCipher>(
+        &self,
+        key: &str,
+        policy: InputPolicy,
+    ) -> Result<String, PermitErr> {
+        let key = policy.apply(key);
+        let key = key.as_ref();
         validator(key, KEY_LENGTH)?;
-        let c = Blowfish::new(key.as_bytes());
+        validator(self.hwid.as_str(), HWID_LENGTH)?;
+        let c = C::new(key.as_bytes());
         let enc = &mut [0u8; 8];
-        let dec = &mut [0u8; 8];
-        dec[0..5].copy_from_slice(self.hwid.as_bytes());
+        let dec = &mut *Zeroizing::new([0u8; 8]);
+        dec[0..5].copy_from_slice(self.hwid.as_str().as_bytes());
         dec[5] = 3;
         dec[6] = 3;
         dec[7] = 3;
@@ -84,12 +261,30 @@ impl UserPermit {
     }
 }
 
+/// the plain, unencrypted form `HWID/ID`, e.g. `12345/3130`
+impl fmt::Display for UserPermit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.hwid, self.id)
+    }
+}
+
+/// parses the form written by `Display`
+impl FromStr for UserPermit {
+    type Err = PermitErr;
+    fn from_str(s: &str) -> Result<UserPermit, PermitErr> {
+        match s.split_once('/') {
+            Some((hwid, id)) => UserPermit::new(hwid, id),
+            None => Err(PermitErr::WrongLength {
+                actual: s.len(),
+                expected: HWID_LENGTH + ID_LENGTHS[0] + 1,
+            }),
+        }
+    }
+}
+
 // returns true if c is a valid hexadecimal character else false
-#[rustfmt::skip]
 fn is_hex(c: char) -> bool {
-    (c >= '0' && c <= '9') ||
-    (c >= 'a' && c <= 'f') ||
-    (c >= 'A' && c <= 'F')
+    c.is_ascii_hexdigit()
 }
 
 // checks length of string and that all characters are valid hex
@@ -106,10 +301,27 @@ fn validator(a: &str, l: usize) -> Result<(), PermitErr> {
     Ok(())
 }
 
+// checks that the M_ID has one of the allowed lengths and is valid hex
+fn validate_id(id: &str) -> Result<(), PermitErr> {
+    if !ID_LENGTHS.contains(&id.len()) {
+        return Err(PermitErr::WrongLength {
+            actual: id.len(),
+            expected: ID_LENGTHS[0],
+        });
+    }
+    validator(id, id.len())
+}
+
 // sanity checks the encrypted userpermit
 // returns the different parts of the encrypted
 fn check_up_string(up: &str) -> Result<(&str, &str, &str), PermitErr> {
-    validator(up, PERMIT_LENGTH)?;
+    if !ID_LENGTHS.contains(&up.len().saturating_sub(PERMIT_PREFIX_LENGTH)) {
+        return Err(PermitErr::WrongLength {
+            actual: up.len(),
+            expected: PERMIT_PREFIX_LENGTH + ID_LENGTHS[0],
+        });
+    }
+    validator(up, up.len())?;
     let (enc_hwid, chksum, id) = (&up[..16], &up[16..24], &up[24..]);
 
     let chksum_u32 = hex::decode(chksum)?
@@ -127,6 +339,7 @@ fn check_up_string(up: &str) -> Result<(&str, &str, &str), PermitErr> {
 mod tests {
     use super::*;
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn is_hex_test() {
         assert_eq!("0123456789AaBbCcDdEeFf".chars().all(is_hex), true);
         assert_eq!("0123456789AaBbCcDdEeFfGg".chars().all(is_hex), false);
@@ -137,12 +350,12 @@ mod tests {
     fn encrypt_decrypt_test() -> Result<(), PermitErr> {
         let key1 = "12345";
         let up1 = UserPermit {
-            hwid: String::from("12345"),
+            hwid: Hwid(String::from("12345")),
             id: String::from("1111"),
         };
         let key2 = "abcde";
         let up2 = UserPermit {
-            hwid: String::from("12ab5"),
+            hwid: Hwid(String::from("12ab5")),
             id: String::from("1254"),
         };
         assert_eq!(up1, UserPermit::decrypt(up1.encrypt(key1)?.as_str(), key1)?);
@@ -151,12 +364,111 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn display_from_str() -> Result<(), PermitErr> {
+        let up = UserPermit::new("12345", "3130")?;
+        assert_eq!(up.hwid(), "12345");
+        assert_eq!(up.id(), "3130");
+        assert_eq!(up.to_string(), "12345/3130");
+        assert_eq!(up.to_string().parse::<UserPermit>()?, up);
+        assert!("123453130".parse::<UserPermit>().is_err());
+        assert!("1234/3130".parse::<UserPermit>().is_err());
+        assert!("1234G/3130".parse::<UserPermit>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn input_policy() -> Result<(), PermitErr> {
+        let up = UserPermit::decrypt(" 66b5cbfdf7e4139d5b6086c23130\n", "10121")?;
+        assert_eq!(up, UserPermit::new("12345", "3130")?);
+        assert!(matches!(
+            UserPermit::decrypt_with_policy(
+                "66b5cbfdf7e4139d5b6086c23130",
+                "10121",
+                InputPolicy::Strict
+            ),
+            Err(PermitErr::HashMisMatch)
+        ));
+
+        let up = UserPermit::new(" 12ab5", "3130 ")?;
+        assert_eq!(up.hwid(), "12AB5");
+        assert_eq!(up.encrypt("abcde")?, up.encrypt("ABCDE")?);
+        let strict = UserPermit::new_with_policy("12ab5", "3130", InputPolicy::Strict)?;
+        assert_eq!(strict.hwid(), "12ab5");
+        assert_ne!(
+            strict.encrypt_with_policy("abcde", InputPolicy::Strict)?,
+            strict.encrypt("abcde")?
+        );
+        assert!(UserPermit::new_with_policy(" 12ab5", "3130", InputPolicy::Strict).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<(), PermitErr> {
+        let parts = UserPermit::verify("66B5CBFDF7E4139D5B6086C23130")?;
+        assert_eq!(parts.enc_hwid, "66B5CBFDF7E4139D");
+        assert_eq!(parts.checksum, "5B6086C2");
+        assert_eq!(parts.m_id.as_str(), "3130");
+        assert!(matches!(
+            UserPermit::verify("66B5CBFDF7E4139D5B6086C33130"),
+            Err(PermitErr::HashMisMatch)
+        ));
+        assert!(matches!(
+            UserPermit::verify("66B5CBFDF7E4139D5B6086C2313"),
+            Err(PermitErr::WrongLength { .. })
+        ));
+        assert!(matches!(
+            UserPermit::verify("66B5CBFDF7E4139D5B6086C2313X"),
+            Err(PermitErr::NonHex)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_key() {
+        let up = "66B5CBFDF7E4139D5B6086C23130";
+        assert!(matches!(
+            UserPermit::decrypt(up, "00000"),
+            Err(PermitErr::WrongKey)
+        ));
+        let invalid = UserPermit {
+            hwid: Hwid(String::from("1234\u{fffd}")),
+            id: String::from("3130"),
+        };
+        assert!(invalid.encrypt("10121").is_err());
+    }
+
+    #[test]
+    fn six_character_m_id() -> Result<(), PermitErr> {
+        let up = UserPermit::new("12345", "313233")?;
+        let enc = up.encrypt("10121")?;
+        assert_eq!(enc.len(), 30);
+        assert_eq!(&enc[..24], "66B5CBFDF7E4139D5B6086C2");
+        assert_eq!(UserPermit::decrypt(&enc, "10121")?, up);
+        assert!(UserPermit::new("12345", "31323").is_err());
+        assert!(UserPermit::decrypt(&enc[..29], "10121").is_err());
+        assert!(UserPermit::decrypt("66B5CBFDF7E4139D5B6086C2313233A", "10121").is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() -> Result<(), PermitErr> {
+        let up = UserPermit::new("12345", "3130")?;
+        let json = serde_json::to_string(&up).unwrap();
+        assert_eq!(serde_json::from_str::<UserPermit>(&json).unwrap(), up);
+        assert!(serde_json::from_str::<UserPermit>(r#"{"hwid":"1234","id":"3130"}"#).is_err());
+        assert!(serde_json::from_str::<UserPermit>(r#"{"hwid":"12345","id":"31"}"#).is_err());
+        assert!(serde_json::from_str::<Hwid>(r#""1234G""#).is_err());
+        Ok(())
+    }
+
     #[test]
     fn decrypt() -> Result<(), PermitErr> {
         let key = "10121";
         let up = "66B5CBFDF7E4139D5B6086C23130";
         let expected = UserPermit {
-            hwid: String::from("12345"),
+            hwid: Hwid(String::from("12345")),
             id: String::from("3130"),
         };
         assert_eq!(expected, UserPermit::decrypt(up, key)?);
@@ -167,7 +479,7 @@ mod tests {
     fn encrypt() -> Result<(), PermitErr> {
         let key = "10121";
         let up = UserPermit {
-            hwid: String::from("12345"),
+            hwid: Hwid(String::from("12345")),
             id: String::from("3130"),
         };
         let expected = "66B5CBFDF7E4139D5B6086C23130";
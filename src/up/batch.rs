@@ -0,0 +1,80 @@
+//! Generating user permits for a batch of systems
+
+use super::{validator, MId, PermitErr, UserPermit, KEY_LENGTH};
+use std::io::Write;
+
+/// encrypts a user permit for each HW_ID with the manufacturer's M_ID and M_KEY. Yields the
+/// HW_ID and the encrypted user permit
+pub fn generate_user_permits<'a, I>(
+    hwids: I,
+    m_id: &'a MId,
+    m_key: &'a str,
+) -> impl Iterator<Item = Result<(String, String), PermitErr>> + 'a
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+    I::IntoIter: 'a,
+{
+    hwids.into_iter().map(move |hwid| {
+        let hwid = hwid.as_ref();
+        let up = UserPermit::new(hwid, m_id.as_str())?.encrypt(m_key)?;
+        Ok((hwid.to_owned(), up))
+    })
+}
+
+/// like `generate_user_permits` but writes a CSV with a `hwid,user_permit` header. Nothing
+/// is written unless all HW_IDs are valid. Returns the number of user permits written
+pub fn write_user_permits_csv<W, I>(
+    mut wtr: W,
+    hwids: I,
+    m_id: &MId,
+    m_key: &str,
+) -> Result<usize, PermitErr>
+where
+    W: Write,
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    validator(m_key, KEY_LENGTH)?;
+    let ups = generate_user_permits(hwids, m_id, m_key).collect::<Result<Vec<_>, _>>()?;
+    writeln!(wtr, "hwid,user_permit")?;
+    for (hwid, up) in &ups {
+        writeln!(wtr, "{},{}", hwid, up)?;
+    }
+    Ok(ups.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch() -> Result<(), PermitErr> {
+        let m_id = MId::new("3130")?;
+        let ups = generate_user_permits(&["12345", "ABCDE"], &m_id, "10121")
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            ups[0],
+            (
+                String::from("12345"),
+                String::from("66B5CBFDF7E4139D5B6086C23130")
+            )
+        );
+        assert_eq!(UserPermit::decrypt(&ups[1].1, "10121")?.hwid(), "ABCDE");
+
+        let mut csv = Vec::new();
+        assert_eq!(
+            write_user_permits_csv(&mut csv, vec!["12345"], &m_id, "10121")?,
+            1
+        );
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "hwid,user_permit\n12345,66B5CBFDF7E4139D5B6086C23130\n"
+        );
+
+        let mut csv = Vec::new();
+        assert!(write_user_permits_csv(&mut csv, ["12345", "1234"], &m_id, "10121").is_err());
+        assert!(csv.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! USERPERMIT.TXT files exchanging encrypted user permits
+//!
+//! One encrypted user permit per line, optionally followed by whitespace and a comment.
+//! Lines starting with `#` are comments and blank lines are ignored, e.g.
+//!
+//! ```text
+//! # vessels of Acme shipping
+//! 66B5CBFDF7E4139D5B6086C23130 M/S Acme
+//! ```
+
+use super::{MKeyStore, PermitErr, UpParts, UserPermit};
+use std::io::prelude::*;
+use std::io::BufReader;
+
+const LINE_END: &str = "\r\n";
+
+/// an encrypted user permit read from a file, the format and checksum are verified when
+/// it is read
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserPermitEntry {
+    /// 1-based line number in the file
+    pub line: usize,
+    pub permit: String,
+    pub comment: String,
+}
+
+impl UserPermitEntry {
+    /// the parts of the permit, see `UserPermit::verify`
+    pub fn parts(&self) -> Result<UpParts, PermitErr> {
+        UserPermit::verify(&self.permit)
+    }
+
+    /// decrypts the permit with the key of its M_ID
+    pub fn decrypt(&self, store: &MKeyStore) -> Result<UserPermit, PermitErr> {
+        UserPermit::decrypt_with_store(&self.permit, store)
+    }
+}
+
+/// iterates the user permits of a file, errors are wrapped in `PermitErr::AtLine`
+pub struct UserPermitFile<R: Read> {
+    rdr: BufReader<R>,
+    line: usize,
+}
+
+impl<R: Read> UserPermitFile<R> {
+    pub fn new(rdr: R) -> UserPermitFile<R> {
+        UserPermitFile {
+            rdr: BufReader::new(rdr),
+            line: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for UserPermitFile<R> {
+    type Item = Result<UserPermitEntry, PermitErr>;
+
+    fn next(&mut self) -> Option<Result<UserPermitEntry, PermitErr>> {
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            self.line += 1;
+            match self.rdr.read_line(&mut buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            let l = buf.trim_start_matches('\u{feff}').trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+            let (permit, comment) = l.split_once(char::is_whitespace).unwrap_or((l, ""));
+            return Some(match UserPermit::verify(permit) {
+                Ok(_) => Ok(UserPermitEntry {
+                    line: self.line,
+                    permit: permit.to_owned(),
+                    comment: comment.trim().to_owned(),
+                }),
+                Err(e) => Err(PermitErr::AtLine(self.line, Box::new(e))),
+            });
+        }
+    }
+}
+
+/// writes a USERPERMIT.TXT file
+pub struct UserPermitWriter<W: Write> {
+    wtr: W,
+}
+
+impl<W: Write> UserPermitWriter<W> {
+    pub fn new(wtr: W) -> UserPermitWriter<W> {
+        UserPermitWriter { wtr }
+    }
+
+    pub fn comment(&mut self, comment: &str) -> Result<(), PermitErr> {
+        for l in comment.lines() {
+            write!(self.wtr, "# {}{}", l, LINE_END)?;
+        }
+        Ok(())
+    }
+
+    /// writes an encrypted user permit, it is verified before it is written
+    pub fn write(&mut self, permit: &str, comment: &str) -> Result<(), PermitErr> {
+        UserPermit::verify(permit)?;
+        if comment.contains(['\r', '\n']) {
+            return Err(PermitErr::InvalidComment);
+        }
+        if comment.is_empty() {
+            write!(self.wtr, "{}{}", permit, LINE_END)?;
+        } else {
+            write!(self.wtr, "{} {}{}", permit, comment, LINE_END)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> W {
+        self.wtr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::up::MId;
+
+    #[test]
+    fn read_write() -> Result<(), PermitErr> {
+        let mut w = UserPermitWriter::new(Vec::new());
+        w.comment("vessels of Acme shipping")?;
+        w.write("66B5CBFDF7E4139D5B6086C23130", "M/S Acme")?;
+        w.write("66B5CBFDF7E4139D5B6086C23130", "")?;
+        assert!(w.write("66B5CBFDF7E4139D5B6086C33130", "").is_err());
+        assert!(matches!(
+            w.write("66B5CBFDF7E4139D5B6086C23130", "a\nb"),
+            Err(PermitErr::InvalidComment)
+        ));
+        let out = w.finish();
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "# vessels of Acme shipping\r\n\
+             66B5CBFDF7E4139D5B6086C23130 M/S Acme\r\n\
+             66B5CBFDF7E4139D5B6086C23130\r\n"
+        );
+
+        let entries = UserPermitFile::new(out.as_slice()).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 2);
+        assert_eq!(entries[0].comment, "M/S Acme");
+        assert_eq!(entries[1].comment, "");
+        assert_eq!(entries[1].parts()?.m_id.as_str(), "3130");
+        let mut built = entries[1].clone();
+        built.permit.truncate(20);
+        assert!(built.parts().is_err());
+
+        let mut store = MKeyStore::new();
+        store.insert(MId::new("3130")?, "10121")?;
+        assert_eq!(entries[0].decrypt(&store)?.hwid(), "12345");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_line() {
+        let s = "66B5CBFDF7E4139D5B6086C23130\n\n66B5CBFDF7E4139D5B6086C3\n";
+        let comments = "#\n".repeat(100_000) + s;
+        assert_eq!(UserPermitFile::new(comments.as_bytes()).count(), 2);
+        let res: Vec<_> = UserPermitFile::new(s.as_bytes()).collect();
+        assert!(res[0].is_ok());
+        assert!(matches!(
+            &res[1],
+            Err(PermitErr::AtLine(3, e)) if matches!(**e, PermitErr::WrongLength { .. })
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Hardware ids identifying a system
+
+use super::{validator, InputPolicy, PermitErr, HWID_LENGTH};
+#[cfg(feature = "rand")]
+use rand::Rng;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroize;
+
+#[cfg(feature = "rand")]
+const HEX: &[u8] = b"0123456789ABCDEF";
+// hex digits without 0/D and 8/B, which are easily mistaken for each other when read aloud
+// or from a printed label
+#[cfg(feature = "rand")]
+const UNAMBIGUOUS_HEX: &[u8] = b"1234567ACEF9";
+
+/// the HW_ID of a system, five hex characters. The HW_ID is zeroized on drop and redacted
+/// in `Debug`
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct Hwid(pub(super) String);
+
+impl Hwid {
+    /// a HW_ID from user input, it is normalized with `InputPolicy::Normalize`
+    pub fn new(hwid: &str) -> Result<Hwid, PermitErr> {
+        Hwid::new_with_policy(hwid, InputPolicy::Normalize)
+    }
+
+    pub fn new_with_policy(hwid: &str, policy: InputPolicy) -> Result<Hwid, PermitErr> {
+        let hwid = policy.apply(hwid);
+        validator(&hwid, HWID_LENGTH)?;
+        Ok(Hwid(hwid.into_owned()))
+    }
+
+    /// a random HW_ID, `rng` should be a cryptographically secure generator such as
+    /// `rand::rngs::OsRng`
+    #[cfg(feature = "rand")]
+    pub fn generate<R: Rng + ?Sized>(rng: &mut R) -> Hwid {
+        Hwid::generate_from(rng, HEX)
+    }
+
+    /// like `generate` but without the easily confused characters 0, D, 8 and B
+    #[cfg(feature = "rand")]
+    pub fn generate_unambiguous<R: Rng + ?Sized>(rng: &mut R) -> Hwid {
+        Hwid::generate_from(rng, UNAMBIGUOUS_HEX)
+    }
+
+    #[cfg(feature = "rand")]
+    fn generate_from<R: Rng + ?Sized>(rng: &mut R, chars: &[u8]) -> Hwid {
+        Hwid(
+            (0..HWID_LENGTH)
+                .map(|_| char::from(chars[rng.gen_range(0..chars.len())]))
+                .collect(),
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Hwid {
+    type Err = PermitErr;
+    fn from_str(s: &str) -> Result<Hwid, PermitErr> {
+        Hwid::new(s)
+    }
+}
+
+// deserialized HW_IDs are validated by `Hwid::new`
+impl TryFrom<String> for Hwid {
+    type Error = PermitErr;
+    fn try_from(s: String) -> Result<Hwid, PermitErr> {
+        let s = zeroize::Zeroizing::new(s);
+        Hwid::new(&s)
+    }
+}
+
+impl From<Hwid> for String {
+    fn from(hwid: Hwid) -> String {
+        hwid.0.clone()
+    }
+}
+
+impl fmt::Display for Hwid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Hwid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hwid(..)")
+    }
+}
+
+impl Drop for Hwid {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl AsRef<str> for Hwid {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hwid() {
+        assert_eq!(Hwid::new(" 12ab5").unwrap().as_str(), "12AB5");
+        assert_eq!(
+            Hwid::new_with_policy("12ab5", InputPolicy::Strict)
+                .unwrap()
+                .as_str(),
+            "12ab5"
+        );
+        assert!("1234".parse::<Hwid>().is_err());
+        assert_eq!(format!("{:?}", Hwid::new("12345").unwrap()), "Hwid(..)");
+        assert!("1234G".parse::<Hwid>().is_err());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn generate() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(63);
+        for _ in 0..100 {
+            let hwid = Hwid::generate(&mut rng);
+            assert!(Hwid::new(hwid.as_str()).is_ok());
+            let hwid = Hwid::generate_unambiguous(&mut rng);
+            assert!(Hwid::new(hwid.as_str()).is_ok());
+            assert!(!hwid.as_str().contains(['0', 'D', '8', 'B']));
+        }
+    }
+}
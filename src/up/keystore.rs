@@ -0,0 +1,96 @@
+//! Manufacturer keys held by a data server
+
+use super::{check_up_string, validator, InputPolicy, MId, PermitErr, UserPermit, KEY_LENGTH};
+use std::collections::HashMap;
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// maps M_IDs to their M_KEYs, the keys are zeroized on drop and left out of `Debug`
+#[derive(Clone, Default)]
+pub struct MKeyStore {
+    keys: HashMap<MId, Zeroizing<String>>,
+}
+
+impl fmt::Debug for MKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+impl MKeyStore {
+    pub fn new() -> MKeyStore {
+        MKeyStore::default()
+    }
+
+    /// adds or replaces the key of a manufacturer, the key is normalized
+    pub fn insert(&mut self, id: MId, key: &str) -> Result<Option<Zeroizing<String>>, PermitErr> {
+        let key = Zeroizing::new(InputPolicy::Normalize.apply(key).into_owned());
+        validator(&key, KEY_LENGTH)?;
+        Ok(self.keys.insert(id, key))
+    }
+
+    pub fn remove(&mut self, id: &MId) -> Option<Zeroizing<String>> {
+        self.keys.remove(id)
+    }
+
+    pub fn key(&self, id: &MId) -> Option<&str> {
+        self.keys.get(id).map(|k| k.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// decrypts the user permits, each with the key of its M_ID
+    pub fn decrypt_all<'a, I>(&self, ups: I) -> Vec<Result<UserPermit, PermitErr>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        ups.into_iter()
+            .map(|up| UserPermit::decrypt_with_store(up, self))
+            .collect()
+    }
+}
+
+impl UserPermit {
+    /// decrypts an encrypted user permit with the key of the M_ID it ends with
+    pub fn decrypt_with_store(up: &str, store: &MKeyStore) -> Result<UserPermit, PermitErr> {
+        let up = InputPolicy::Normalize.apply(up);
+        let (_, _, id) = check_up_string(&up)?;
+        let id = MId::new(id)?;
+        match store.key(&id) {
+            Some(key) => UserPermit::decrypt(&up, key),
+            None => Err(PermitErr::UnknownMId(id.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_with_store() -> Result<(), PermitErr> {
+        let mut store = MKeyStore::new();
+        assert!(store.insert(MId::new("3130")?, "1012").is_err());
+        store.insert(MId::new("3130")?, "10121")?;
+        let other = UserPermit::new("ABCDE", "4142")?;
+        store.insert(other.m_id(), "FEDCB")?;
+        let enc = other.encrypt("FEDCB")?;
+
+        let res = store.decrypt_all(vec![
+            "66B5CBFDF7E4139D5B6086C23130",
+            &enc,
+            "66B5CBFDF7E4139D5B6086C23131",
+        ]);
+        assert_eq!(res[0].as_ref().unwrap(), &UserPermit::new("12345", "3130")?);
+        assert_eq!(res[1].as_ref().unwrap(), &other);
+        assert!(matches!(&res[2], Err(PermitErr::UnknownMId(id)) if id == "3131"));
+        assert!(UserPermit::decrypt_with_store("66B5", &store).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! Manufacturer ids and the manufacturers they are assigned to
+
+use super::{validate_id, InputPolicy, PermitErr, UserPermit};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// M_IDs with a known manufacturer. The IHO keeps the list of assigned M_IDs confidential
+// between the IHO and the manufacturers, only the test manufacturer of the S-63 test data is
+// public. Applications register the manufacturers they know of
+const KNOWN: &[(&str, &str)] = &[("3130", "S-63 test data manufacturer")];
+
+/// a manufacturer id, the last 4 or 6 characters of a user permit
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MId(pub(super) String);
+
+impl MId {
+    pub fn new(id: &str) -> Result<MId, PermitErr> {
+        let id = InputPolicy::Normalize.apply(id);
+        validate_id(&id)?;
+        Ok(MId(id.into_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for MId {
+    type Err = PermitErr;
+    fn from_str(s: &str) -> Result<MId, PermitErr> {
+        MId::new(s)
+    }
+}
+
+impl fmt::Display for MId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl AsRef<str> for MId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// maps M_IDs to manufacturer names
+#[derive(Debug, Clone)]
+pub struct ManufacturerRegistry {
+    names: HashMap<MId, String>,
+}
+
+impl ManufacturerRegistry {
+    /// a registry without any manufacturers
+    pub fn empty() -> ManufacturerRegistry {
+        ManufacturerRegistry {
+            names: HashMap::new(),
+        }
+    }
+
+    /// a registry with the manufacturers known to this crate
+    pub fn new() -> ManufacturerRegistry {
+        let mut res = ManufacturerRegistry::empty();
+        for (id, name) in KNOWN {
+            res.register(MId(String::from(*id)), name);
+        }
+        res
+    }
+
+    /// adds or replaces a manufacturer, returning the name previously registered for `id`
+    pub fn register(&mut self, id: MId, name: &str) -> Option<String> {
+        self.names.insert(id, name.to_owned())
+    }
+
+    pub fn manufacturer(&self, id: &MId) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    /// the manufacturer that issued the user permit, if known
+    pub fn issuer(&self, up: &UserPermit) -> Option<&str> {
+        self.manufacturer(&up.m_id())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MId, &str)> {
+        self.names.iter().map(|(id, name)| (id, name.as_str()))
+    }
+}
+
+impl Default for ManufacturerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() -> Result<(), PermitErr> {
+        let mut reg = ManufacturerRegistry::new();
+        let up = UserPermit::decrypt("66B5CBFDF7E4139D5B6086C23130", "10121")?;
+        assert_eq!(up.m_id(), MId::new("3130")?);
+        assert_eq!(reg.issuer(&up), Some("S-63 test data manufacturer"));
+
+        let id: MId = "a1b2".parse()?;
+        assert_eq!(id.as_str(), "A1B2");
+        assert_eq!(reg.manufacturer(&id), None);
+        assert_eq!(reg.register(id.clone(), "Acme"), None);
+        assert_eq!(reg.manufacturer(&id), Some("Acme"));
+        assert_eq!(
+            reg.register(id.clone(), "Acme Marine"),
+            Some(String::from("Acme"))
+        );
+        assert_eq!(ManufacturerRegistry::empty().iter().count(), 0);
+
+        assert!(MId::new("313").is_err());
+        assert_eq!(MId::new("313233")?.as_str(), "313233");
+        assert!(MId::new("31G0").is_err());
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 extern crate chrono;
 extern crate rust_s63;
 
@@ -20,23 +22,30 @@ GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,
     );
     let cps: Vec<_> = pf.permits("12345").map(|x| x.unwrap()).collect();
     assert_eq!(cps.len(), 3);
+    let lines: Vec<_> = s.lines().skip(3).collect();
+    let original = |i: usize| {
+        Some(permit::Original {
+            cell_permit: lines[i][..64].to_string(),
+            line: lines[i].to_string(),
+        })
+    };
     let cps0cp = permit::CellPermit {
         cell: String::from("GB100001"),
         date: chrono::NaiveDate::from_ymd(2007, 12, 31),
-        key1: [54, 62, 171, 50, 198],
-        key2: [54, 62, 171, 50, 198],
+        key1: [54, 62, 171, 50, 198].into(),
+        key2: [54, 62, 171, 50, 198].into(),
     };
     let cps1cp = permit::CellPermit {
         cell: String::from("GB100002"),
         date: chrono::NaiveDate::from_ymd(2007, 12, 31),
-        key1: [73, 74, 128, 79, 106],
-        key2: [73, 74, 128, 79, 106],
+        key1: [73, 74, 128, 79, 106].into(),
+        key2: [73, 74, 128, 79, 106].into(),
     };
     let cps2cp = permit::CellPermit {
         cell: String::from("GB100004"),
         date: chrono::NaiveDate::from_ymd(2007, 12, 31),
-        key1: [89, 44, 236, 217, 52],
-        key2: [89, 44, 236, 217, 52],
+        key1: [89, 44, 236, 217, 52].into(),
+        key2: [89, 44, 236, 217, 52].into(),
     };
     assert_eq!(
         cps[0],
@@ -46,6 +55,9 @@ GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,
             edition: Some(1),
             data_server_id: String::from("GB"),
             comment: String::from("hej"),
+            section: permit::Section::Enc,
+            extras: Vec::new(),
+            original: original(0),
         }
     );
     assert_eq!(
@@ -56,6 +68,9 @@ GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,
             edition: Some(0),
             data_server_id: String::from("GB"),
             comment: String::from(""),
+            section: permit::Section::Enc,
+            extras: Vec::new(),
+            original: original(1),
         }
     );
     assert_eq!(
@@ -66,8 +81,470 @@ GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,
             edition: None,
             data_server_id: String::from("GB"),
             comment: String::from(""),
+            section: permit::Section::Enc,
+            extras: Vec::new(),
+            original: original(2),
+        }
+    );
+    // PermitRecord equality ignores where the record was read from
+    for (i, p) in cps.iter().enumerate() {
+        assert_eq!(p.original, original(i));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn write_permit_file() -> Result<(), failure::Error> {
+    let s = ":DATE 20071023 10:20\r
+:VERSION 2\r
+:ENC\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej\r
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,\r
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,\r
+:ECS\r
+";
+    let (md, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let mut w = permit::PermitFileWriter::new(Vec::new(), &md, "12345")?;
+    for p in pf.permits("12345") {
+        w.write_permit(&p?)?;
+    }
+    let out = w.finish()?;
+    assert_eq!(String::from_utf8(out)?, s);
+    Ok(())
+}
+
+#[test]
+fn generate_permits() -> Result<(), failure::Error> {
+    let up = rust_s63::up::UserPermit::decrypt("66B5CBFDF7E4139D5B6086C23130", "10121")
+        .map_err(|e| failure::format_err!("{:?}", e))?;
+    let cells = vec![
+        permit::CellPermit {
+            cell: String::from("GB100001"),
+            date: chrono::NaiveDate::from_ymd_opt(2007, 12, 31).unwrap(),
+            key1: [54, 62, 171, 50, 198].into(),
+            key2: [54, 62, 171, 50, 198].into(),
+        },
+        permit::CellPermit {
+            cell: String::from("GB100002"),
+            date: chrono::NaiveDate::from_ymd_opt(2008, 1, 31).unwrap(),
+            key1: [73, 74, 128, 79, 106].into(),
+            key2: [1, 2, 3, 4, 5].into(),
+        },
+    ];
+    let records = permit::generate_permits(&up, "GB", cells)?;
+    assert_eq!(records.len(), 2);
+
+    let md = permit::MetaData {
+        date: NaiveDate::from_ymd_opt(2007, 10, 23)
+            .unwrap()
+            .and_hms_opt(10, 20, 0)
+            .unwrap(),
+        version: 2,
+    };
+    let mut w = permit::PermitFileWriter::new(Vec::new(), &md, up.hwid())?;
+    for r in &records {
+        w.write_permit(r)?;
+    }
+    let out = w.finish()?;
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(out))?;
+    let parsed: Vec<_> = pf.permits(up.hwid()).collect::<Result<_, _>>()?;
+    assert_eq!(parsed, records);
+    Ok(())
+}
+
+#[test]
+fn raw_permits() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+:ECS";
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let raw: Vec<_> = pf.raw_permits().collect::<Result<_, _>>()?;
+    assert_eq!(raw.len(), 1);
+    assert_eq!(raw[0].cell_permit.cell, "GB100001");
+    assert_eq!(raw[0].cell_permit.eck1, "517C1E9A4BCF3826");
+    assert_eq!(raw[0].comment, "hej");
+    assert!(raw[0].cell_permit.decrypt_keys("54321").is_err());
+    let cp = raw[0].cell_permit.decrypt_keys("12345")?;
+    assert_eq!(cp.key1, [54, 62, 171, 50, 198]);
+    Ok(())
+}
+
+#[test]
+fn lenient_permits() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FD,1,0,GB,
+GB100004200712,0,,GB,
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,
+:ECS";
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let mut permits = pf.permits_lenient("12345");
+    let cells: Vec<_> = permits.by_ref().map(|p| p.cell_permit.cell).collect();
+    assert_eq!(cells, vec!["GB100001", "GB100004"]);
+    let errors = permits.into_errors();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].line, 5);
+    assert!(matches!(
+        errors[0].reason.root(),
+        rust_s63::errors::E::InvalidChksum
+    ));
+    assert_eq!(errors[1].line, 6);
+    match &errors[1].reason {
+        rust_s63::errors::E::Parse(ctx) => {
+            assert_eq!(ctx.line, Some(6));
+            assert_eq!(ctx.field, rust_s63::errors::Field::CellPermit);
+            assert_eq!(ctx.raw.as_deref(), Some("GB100004200712,0,,GB,"));
         }
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(
+        errors[0].reason.to_string(),
+        "line 5: invalid checksum: Invalid Checksum in \"GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FD,1,0,GB,\""
     );
+    Ok(())
+}
+
+#[test]
+fn many_blank_lines() -> Result<(), failure::Error> {
+    let s = format!(
+        ":DATE 20071023 10:20\n:VERSION 2\n{}:ENC\n{}GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,\n",
+        ":ECS\n".repeat(100_000),
+        "\n".repeat(100_000)
+    );
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let raw: Vec<_> = pf.raw_permits().collect::<Result<_, _>>()?;
+    assert_eq!(raw.len(), 1);
+    assert_eq!(raw[0].cell_permit.cell, "GB100001");
+    Ok(())
+}
+
+#[test]
+fn lenient_permits_read_error() -> Result<(), failure::Error> {
+    struct Failing;
+    impl std::io::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("broken"))
+        }
+    }
+    let header = ":DATE 20071023 10:20\n:VERSION 2\n:ENC\n";
+    let rdr = std::io::Read::chain(std::io::Cursor::new(header), Failing);
+    let (_, pf) = permit::PermitFile::new(rdr)?;
+    let mut permits = pf.permits_lenient("12345");
+    assert!(permits.next().is_none());
+    assert!(permits.next().is_none());
+    let errors = permits.into_errors();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0].reason.root(),
+        rust_s63::errors::E::IoErr(_)
+    ));
+    Ok(())
+}
+
+#[test]
+fn permit_sections() -> Result<(), failure::Error> {
+    let s = ":DATE 20071023 10:20\r
+:VERSION 2\r
+:ENC\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej\r
+:ECS\r
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,\r
+";
+    let (md, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let cps: Vec<_> = pf.permits("12345").collect::<Result<_, _>>()?;
+    assert_eq!(cps[0].section, permit::Section::Enc);
+    assert_eq!(cps[1].section, permit::Section::Ecs);
+
+    let mut w = permit::PermitFileWriter::new(Vec::new(), &md, "12345")?;
+    for p in &cps {
+        w.write_permit(p)?;
+    }
+    assert!(w.write_permit(&cps[0]).is_err());
+    assert_eq!(String::from_utf8(w.finish()?)?, s);
+    Ok(())
+}
+
+#[test]
+fn multi_hwid_permits() -> Result<(), failure::Error> {
+    let md = permit::MetaData {
+        date: NaiveDate::from_ymd_opt(2007, 10, 23)
+            .unwrap()
+            .and_hms_opt(10, 20, 0)
+            .unwrap(),
+        version: 2,
+    };
+    let cell = |cell: &str| {
+        permit::PermitRecord::builder()
+            .cell_permit(
+                permit::CellPermit::builder()
+                    .cell(cell)
+                    .date(NaiveDate::from_ymd_opt(2008, 1, 31).unwrap())
+                    .key1(&[1, 2, 3, 4, 5])
+                    .build()
+                    .unwrap(),
+            )
+            .data_server_id("GB")
+            .build()
+            .unwrap()
+    };
+    let mut out = Vec::new();
+    md.write(&mut out)?;
+    out.extend(cell("GB100001").serialize("12345")?.bytes());
+    out.extend(b"\r\n");
+    out.extend(cell("GB100002").serialize("ABCDE")?.bytes());
+
+    let permits = permit::permit_from_rdr_multi(out.as_slice(), &["ABCDE", "12345"])?;
+    assert_eq!(permits.matched_hwid("GB100001"), Some("12345"));
+    assert_eq!(permits.matched_hwid("GB100002"), Some("ABCDE"));
+    assert!(permit::permit_from_rdr_multi(out.as_slice(), &["12345"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn permits_from_dir() -> Result<(), failure::Error> {
+    let dir = std::env::temp_dir().join(format!("s63-permit-dir-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("V01X01"))?;
+    std::fs::create_dir_all(dir.join("V01X02"))?;
+    std::fs::write(
+        dir.join("V01X01").join("PERMIT.TXT"),
+        ":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+:ECS",
+    )?;
+    std::fs::write(
+        dir.join("V01X02").join("permit.txt"),
+        ":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+:ECS",
+    )?;
+    std::fs::write(dir.join("PERMIT.TXT"), "garbage")?;
+    std::fs::write(dir.join("README.TXT"), "garbage")?;
+
+    let permits = permit::permit_from_dir(&dir, "12345");
+    std::fs::remove_dir_all(&dir)?;
+    let permits = permits?;
+    assert_eq!(permits.permits.len(), 2);
+    assert_eq!(permits.errors.len(), 1);
+    assert_eq!(permits.errors[0].0, dir.join("PERMIT.TXT"));
+    Ok(())
+}
+
+#[test]
+fn tolerate_whitespace_and_bom() -> Result<(), failure::Error> {
+    let s = "\u{feff}:DATE 20071023 10:20 \r
+:VERSION 2\r
+ :ENC\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31 ,0, 1,GB,hej  \r
+\r
+  GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,\r
+   \r
+:ECS\r
+";
+    let (md, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    assert_eq!(md.version, 2);
+    let cps: Vec<_> = pf.permits("12345").collect::<Result<_, _>>()?;
+    assert_eq!(cps.len(), 2);
+    assert_eq!(cps[0].edition, Some(1));
+    assert_eq!(cps[0].comment, "hej");
+    assert_eq!(cps[1].cell_permit.cell, "GB100002");
+
+    let s = ":DATE 20071023 10:20\r
+:VERSION 2\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A3,0,1,GB,hej\r
+";
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    assert!(pf.permits("12345").next().unwrap().is_err());
+    Ok(())
+}
+
+#[test]
+fn checksum_policy() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+:ECS";
+    let permits = |policy| -> Result<_, failure::Error> {
+        let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+        Ok(pf.permits_with_policy("54321", policy))
+    };
+    let strict: Vec<_> = permits(permit::ChecksumPolicy::Strict)?.collect();
+    assert!(strict.iter().all(|p| p.is_err()));
+
+    let mut warn = permits(permit::ChecksumPolicy::Warn)?;
+    let records: Vec<_> = warn.by_ref().collect::<Result<_, _>>()?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(warn.warnings().len(), 2);
+    assert_eq!(warn.warnings()[1].line, 5);
+
+    let mut skip = permits(permit::ChecksumPolicy::Skip)?;
+    assert_eq!(skip.by_ref().collect::<Result<Vec<_>, _>>()?.len(), 2);
+    assert!(skip.warnings().is_empty());
+    Ok(())
+}
+
+#[test]
+fn comment_with_commas_and_latin1() -> Result<(), failure::Error> {
+    let mut s = b":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej, du
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,"
+        .to_vec();
+    s.extend(b"S\xf6dergren\r\n");
+    s.extend(
+        "GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,Ålesund".bytes(),
+    );
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let cps: Vec<_> = pf.permits("12345").collect::<Result<_, _>>()?;
+    assert_eq!(cps[0].comment, "hej, du");
+    assert_eq!(cps[1].comment, "Södergren");
+    assert_eq!(cps[2].comment, "Ålesund");
+    Ok(())
+}
+
+#[test]
+fn validate_permit_file() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+GB100004200712,0,,GB,
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,again
+:ECS
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,ecs";
+    let validate = |key, y, m, d| -> Result<_, failure::Error> {
+        let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+        let now = NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        Ok(pf.validate_at(key, rust_s63::clock::FixedClock(now))?)
+    };
+
+    let report = validate("12345", 2007, 12, 1)?;
+    assert_eq!(report.total, 5);
+    assert!(report.checksum_failures.is_empty());
+    assert!(report.expired.is_empty());
+    assert_eq!(report.unparsable.len(), 1);
+    assert_eq!(report.unparsable[0].line, 6);
+    assert_eq!(report.duplicates, vec![(7, "GB100001".to_string())]);
+    assert_eq!(report.valid(), 3);
+    assert!(!report.is_ok());
+
+    let report = validate("54321", 2008, 1, 1)?;
+    assert_eq!(report.checksum_failures.len(), 4);
+    assert_eq!(report.checksum_failures[0].line, 4);
+    assert_eq!(report.expired.len(), 4);
+    assert_eq!(report.valid(), 0);
+    Ok(())
+}
+
+#[test]
+fn original_permit_lines() -> Result<(), failure::Error> {
+    let s = ":DATE 20071023 10:20\r
+:VERSION 2\r
+:ENC\r
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31, 0,1,GB,hej\r
+:ECS\r
+";
+    let (md, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let p = pf.permits("12345").next().unwrap()?;
+    let original = p.original.as_ref().unwrap();
+    assert_eq!(
+        original.cell_permit,
+        "GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31"
+    );
+    assert_eq!(original.line, s.lines().nth(3).unwrap().trim_end());
+
+    let mut out = Vec::new();
+    md.write(&mut out)?;
+    out.extend(format!(":ENC\r\n{}\r\n:ECS\r\n", original.line).bytes());
+    assert_eq!(String::from_utf8(out)?, s);
+
+    let built = permit::PermitRecord::builder()
+        .cell_permit(p.cell_permit)
+        .data_server_id("GB")
+        .build()?;
+    assert!(built.original.is_none());
+    Ok(())
+}
+
+#[test]
+fn extra_columns() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej, du,X1,42
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,,,
+GB1000042007123164B51D24FB77ADB364B51D24FB77ADB3EEA2291965966391,0,,GB,only
+:ECS";
+    let (md, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let cps: Vec<_> = pf
+        .extra_columns(2)
+        .permits("12345")
+        .collect::<Result<_, _>>()?;
+    assert_eq!(cps[0].comment, "hej, du");
+    assert_eq!(cps[0].extras, vec!["X1", "42"]);
+    assert_eq!(cps[1].comment, "");
+    assert_eq!(cps[1].extras, vec!["", ""]);
+    assert_eq!(cps[2].comment, "only");
+    assert!(cps[2].extras.is_empty());
+
+    let mut w = permit::PermitFileWriter::new(Vec::new(), &md, "12345")?;
+    for p in &cps {
+        w.write_permit(p)?;
+    }
+    let out = String::from_utf8(w.finish()?)?;
+    assert_eq!(out.replace("\r\n", "\n"), format!("{}\n", s));
+
+    let (_, pf) = permit::PermitFile::new(std::io::Cursor::new(s))?;
+    let p = pf.permits("12345").next().unwrap()?;
+    assert_eq!(p.comment, "hej, du,X1,42");
+    assert!(p.extras.is_empty());
+    Ok(())
+}
+
+#[test]
+fn reencrypt_permits() -> Result<(), failure::Error> {
+    let s = r":DATE 20071023 10:20
+:VERSION 2
+:ENC
+GB10000120071231517C1E9A4BCF3826517C1E9A4BCF38263A5A80B723886A31,0,1,GB,hej
+GB10000220071231BBA63203A5992420BBA63203A5992420ED56CD0F5F7390FC,1,0,GB,
+:ECS";
+    let mut out = Vec::new();
+    assert_eq!(
+        permit::reencrypt_permits(s.as_bytes(), &mut out, "12345", "54321")?,
+        2
+    );
+    let read = |data: &[u8], key| -> Result<Vec<_>, failure::Error> {
+        let (_, pf) = permit::PermitFile::new(data)?;
+        Ok(pf.permits(key).collect::<Result<_, _>>()?)
+    };
+    assert_eq!(read(&out, "54321")?, read(s.as_bytes(), "12345")?);
+    assert!(permit::PermitFile::new(out.as_slice())?
+        .1
+        .permits("12345")
+        .all(|p| p.is_err()));
 
+    let (_, pf) = permit::PermitFile::new(s.as_bytes())?;
+    let raw = pf.raw_permits().next().unwrap()?;
+    let cp = raw.cell_permit.reencrypt("12345", "54321")?;
+    assert_eq!(&cp[..16], "GB10000120071231");
+    assert_eq!(String::from_utf8(out)?.lines().nth(3).unwrap()[..64], cp);
+    assert!(raw.cell_permit.reencrypt("54321", "12345").is_err());
     Ok(())
 }